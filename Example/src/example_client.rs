@@ -1,8 +1,5 @@
-use RustFL::client::{WeightsUpdate, Config, SimpleCNN, Client, Value,
-                     get_train_data, start_training, kind,
-                     nn::{self, Conv2D, Linear, Module, Optimizer, OptimizerConfig, Sgd, VarStore},
-                     Device, Kind, Tensor,error, info, warn,Deserialize, Serialize, Debug};
-use RustFL::secure_dp_utils::{DPMechanism,generate_fernet_key,secret_share_weights,encrypt_share};
+use RustFL::client::{error, Device, FederatedClient, info};
+use RustFL::settings::Settings;
 
 //Client example is contributed by Sainath Talaknati & Sharvani Chelumalla
 // Main function to initialize and start the training process.
@@ -10,29 +7,20 @@ use RustFL::secure_dp_utils::{DPMechanism,generate_fernet_key,secret_share_weigh
 async fn main() {
     env_logger::init();
 
-
-    let config = Config::new(0.5, 128, 0.5, 5, 0.5, 1.5);
+    // Settings file given as first argument, rustfl.toml by default.
+    let path = std::env::args().nth(1).unwrap_or("rustfl.toml".to_string());
+    let settings = Settings::load(Some(&path)).expect("Invalid settings");
 
     let device = if tch::Cuda::is_available() { Device::Cuda(0) } else { Device::Cpu };
-    let vs = VarStore::new(device);
-    let mut simple_cnn_model = SimpleCNN::new(&vs.root());
-    let mut optimizer = Sgd::default().build(&vs, config.learning_rate).unwrap();
-
-    // Define the loss function.
-    let criterion = |output: &Tensor, target: &Tensor| {
-        output
-            .cross_entropy_for_logits(target)
-            .mean(Kind::Float)
-    };
+    let mut client = FederatedClient::new(settings.client.config(), &settings.client.server_url, device)
+        .expect("Failed to create the federated client");
 
     // Load the training data.
-    let train_loader = get_train_data("mnist_data/MNIST/raw".to_string());
-
-    let encryption_key = generate_fernet_key();
+    let train_loader = client.load_train_data(&settings.client.data_dir);
 
-    let get_url = "http://0.0.0.0:8081/get_model";
-    let post_url = "http://0.0.0.0:8081/update_model";
-    let (loss_value,trained_weights,model_version) = start_training(train_loader,&mut simple_cnn_model, &mut optimizer, &criterion, device,get_url).await;
-    RustFL::client::send_local_model_weights(trained_weights, loss_value, model_version.clone(), &mut simple_cnn_model, encryption_key.as_str(), device, get_url, post_url).await;
+    if let Err(e) = client.run(&train_loader).await {
+        error!("{}", e);
+        std::process::exit(1);
+    }
     info!("Model training has been completed.");
-}
\ No newline at end of file
+}
@@ -0,0 +1,379 @@
+//Aggregation strategies used by the server to build the next global model
+//
+//FedProx only changes the local objective of the clients (see `Config::proximal_mu`),
+//its server side is plain `FedAvg`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::secure_dp_utils::weighted_average;
+use crate::state_dict::{check_same_layout, StateDict};
+
+/// A decoded client update ready to be aggregated
+#[derive(Debug, Clone)]
+pub struct ClientUpdate {
+    pub weights: StateDict,
+    /// Weight of the update in the average, the number of samples by default.
+    pub weight: f64,
+}
+
+impl ClientUpdate {
+    /// Update weighted by the number of samples it was trained on
+    pub fn new(weights: StateDict, num_samples: usize) -> Self {
+        ClientUpdate {
+            weights,
+            weight: num_samples as f64,
+        }
+    }
+
+    /// Update trained on a global model `staleness` versions old, its weight discounted by `staleness_function`
+    pub fn stale(weights: StateDict, num_samples: usize, staleness: usize, staleness_function: &StalenessFunction) -> Self {
+        ClientUpdate {
+            weights,
+            weight: num_samples as f64 * staleness_function.weight(staleness),
+        }
+    }
+}
+
+/// Discount of updates trained on an older global model, the staleness functions of FedAsync (Xie et al., 2019)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "function", rename_all = "snake_case", deny_unknown_fields)]
+pub enum StalenessFunction {
+    /// Stale updates weigh as much as fresh ones.
+    Constant,
+    /// `(1 + staleness)^-exponent`
+    Polynomial { exponent: f64 },
+    /// 1 up to `cutoff` versions, then `1 / (slope * (staleness - cutoff) + 1)`
+    Hinge { slope: f64, cutoff: usize },
+}
+
+impl StalenessFunction {
+    /// Weight factor of an update `staleness` versions old, 1 for up to date updates
+    pub fn weight(&self, staleness: usize) -> f64 {
+        match *self {
+            StalenessFunction::Constant => 1.0,
+            StalenessFunction::Polynomial { exponent } => (1.0 + staleness as f64).powf(-exponent),
+            StalenessFunction::Hinge { slope, cutoff } if staleness > cutoff => {
+                1.0 / (slope * (staleness - cutoff) as f64 + 1.0)
+            }
+            StalenessFunction::Hinge { .. } => 1.0,
+        }
+    }
+
+    /// Checks that the function stays in (0, 1]
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            StalenessFunction::Polynomial { exponent } if !(exponent >= 0.0 && exponent.is_finite()) => {
+                Err(format!("Staleness exponent must be non-negative, got {}", exponent))
+            }
+            StalenessFunction::Hinge { slope, .. } if !(slope >= 0.0 && slope.is_finite()) => {
+                Err(format!("Staleness slope must be non-negative, got {}", slope))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Rule used by the server to combine client updates into the next global model
+pub trait Aggregator: Send {
+    /// Name of the strategy, used in logs and responses
+    fn name(&self) -> &str;
+
+    /// Computes the next global model from the current one and the client updates
+    fn aggregate(&mut self, global: &StateDict, updates: &[ClientUpdate]) -> Result<StateDict, String>;
+
+    /// State carried from one round to the next, saved in checkpoints; `None` for stateless strategies
+    fn state(&self) -> Option<Value> {
+        None
+    }
+
+    /// Restores the state saved by `state`
+    fn restore_state(&mut self, _state: Value) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Weighted average of the client models
+#[derive(Debug, Clone, Default)]
+pub struct FedAvg;
+
+impl Aggregator for FedAvg {
+    fn name(&self) -> &str {
+        "FedAvg"
+    }
+
+    fn aggregate(&mut self, global: &StateDict, updates: &[ClientUpdate]) -> Result<StateDict, String> {
+        let average = average_updates(updates)?;
+        check_same_layout(global, &average)?;
+        Ok(average)
+    }
+}
+
+/// FedAvg with server momentum applied to the averaged client delta
+#[derive(Debug, Clone)]
+pub struct FedAvgM {
+    pub server_learning_rate: f64,
+    pub momentum: f64,
+    velocity: Option<StateDict>,
+}
+
+impl FedAvgM {
+    /// Server momentum with the given learning rate and momentum factor
+    pub fn new(server_learning_rate: f64, momentum: f64) -> Self {
+        FedAvgM {
+            server_learning_rate,
+            momentum,
+            velocity: None,
+        }
+    }
+}
+
+impl Aggregator for FedAvgM {
+    fn name(&self) -> &str {
+        "FedAvgM"
+    }
+
+    fn aggregate(&mut self, global: &StateDict, updates: &[ClientUpdate]) -> Result<StateDict, String> {
+        let delta = pseudo_gradient(global, updates)?;
+        let velocity = self.velocity.get_or_insert_with(|| zeros_like(global));
+        check_same_layout(global, velocity)?;
+
+        let mut next = global.clone();
+        for ((param, v), d) in next.iter_mut().zip(velocity.iter_mut()).zip(delta.iter()) {
+            for ((x, v), d) in param.data.iter_mut().zip(v.data.iter_mut()).zip(d.data.iter()) {
+                *v = self.momentum * *v + d;
+                *x += self.server_learning_rate * *v;
+            }
+        }
+
+        Ok(next)
+    }
+    fn state(&self) -> Option<Value> {
+        serde_json::to_value(&self.velocity).ok()
+    }
+
+    fn restore_state(&mut self, state: Value) -> Result<(), String> {
+        self.velocity = serde_json::from_value(state).map_err(|e| format!("Invalid FedAvgM state: {}", e))?;
+        Ok(())
+    }
+}
+
+/// Second moment update rule of the adaptive server optimizers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerOptimizer {
+    Adagrad,
+    Adam,
+    Yogi,
+}
+
+/// Adaptive federated optimization (FedAdagrad, FedAdam and FedYogi) from Reddi et al.
+#[derive(Debug, Clone)]
+pub struct FedOpt {
+    pub optimizer: ServerOptimizer,
+    pub server_learning_rate: f64,
+    pub beta_1: f64,
+    pub beta_2: f64,
+    /// Adaptivity, keeps the update finite when the second moment is close to zero.
+    pub tau: f64,
+    first_moment: Option<StateDict>,
+    second_moment: Option<StateDict>,
+}
+
+impl FedOpt {
+    /// Adaptive server optimizer with user defined hyper-parameters
+    pub fn new(optimizer: ServerOptimizer, server_learning_rate: f64, beta_1: f64, beta_2: f64, tau: f64) -> Self {
+        FedOpt {
+            optimizer,
+            server_learning_rate,
+            beta_1,
+            beta_2,
+            tau,
+            first_moment: None,
+            second_moment: None,
+        }
+    }
+
+    /// FedAdagrad with the default betas of the paper
+    pub fn fed_adagrad(server_learning_rate: f64, tau: f64) -> Self {
+        FedOpt::new(ServerOptimizer::Adagrad, server_learning_rate, 0.9, 0.99, tau)
+    }
+
+    /// FedAdam with the default betas of the paper
+    pub fn fed_adam(server_learning_rate: f64, tau: f64) -> Self {
+        FedOpt::new(ServerOptimizer::Adam, server_learning_rate, 0.9, 0.99, tau)
+    }
+
+    /// FedYogi with the default betas of the paper
+    pub fn fed_yogi(server_learning_rate: f64, tau: f64) -> Self {
+        FedOpt::new(ServerOptimizer::Yogi, server_learning_rate, 0.9, 0.99, tau)
+    }
+}
+
+impl Aggregator for FedOpt {
+    fn name(&self) -> &str {
+        match self.optimizer {
+            ServerOptimizer::Adagrad => "FedAdagrad",
+            ServerOptimizer::Adam => "FedAdam",
+            ServerOptimizer::Yogi => "FedYogi",
+        }
+    }
+
+    fn aggregate(&mut self, global: &StateDict, updates: &[ClientUpdate]) -> Result<StateDict, String> {
+        let delta = pseudo_gradient(global, updates)?;
+        let first_moment = self.first_moment.get_or_insert_with(|| zeros_like(global));
+        let second_moment = self.second_moment.get_or_insert_with(|| zeros_like(global));
+        check_same_layout(global, first_moment)?;
+        check_same_layout(global, second_moment)?;
+
+        let mut next = global.clone();
+        for (((param, m), v), d) in next
+            .iter_mut()
+            .zip(first_moment.iter_mut())
+            .zip(second_moment.iter_mut())
+            .zip(delta.iter())
+        {
+            for (((x, m), v), &d) in param
+                .data
+                .iter_mut()
+                .zip(m.data.iter_mut())
+                .zip(v.data.iter_mut())
+                .zip(d.data.iter())
+            {
+                *m = self.beta_1 * *m + (1.0 - self.beta_1) * d;
+                let d_squared = d * d;
+                *v = match self.optimizer {
+                    ServerOptimizer::Adagrad => *v + d_squared,
+                    ServerOptimizer::Adam => self.beta_2 * *v + (1.0 - self.beta_2) * d_squared,
+                    ServerOptimizer::Yogi => *v - (1.0 - self.beta_2) * d_squared * (*v - d_squared).signum(),
+                };
+                *x += self.server_learning_rate * *m / (v.sqrt() + self.tau);
+            }
+        }
+
+        Ok(next)
+    }
+    fn state(&self) -> Option<Value> {
+        serde_json::to_value((&self.first_moment, &self.second_moment)).ok()
+    }
+
+    fn restore_state(&mut self, state: Value) -> Result<(), String> {
+        (self.first_moment, self.second_moment) =
+            serde_json::from_value(state).map_err(|e| format!("Invalid {} state: {}", self.name(), e))?;
+        Ok(())
+    }
+}
+
+/// Weighted average of the client models
+pub fn average_updates(updates: &[ClientUpdate]) -> Result<StateDict, String> {
+    let state_dicts = updates.iter().map(|u| u.weights.clone()).collect::<Vec<_>>();
+    let weights = updates.iter().map(|u| u.weight).collect::<Vec<_>>();
+    weighted_average(&state_dicts, &weights)
+}
+
+/// Averaged client delta `average - global`, the negative of the server gradient
+pub fn pseudo_gradient(global: &StateDict, updates: &[ClientUpdate]) -> Result<StateDict, String> {
+    let mut delta = average_updates(updates)?;
+    check_same_layout(global, &delta)?;
+    for (d, g) in delta.iter_mut().zip(global.iter()) {
+        for (d, g) in d.data.iter_mut().zip(g.data.iter()) {
+            *d -= g;
+        }
+    }
+    Ok(delta)
+}
+
+/// State dict with the layout of `state_dict` and all values set to zero
+pub fn zeros_like(state_dict: &StateDict) -> StateDict {
+    let mut zeros = state_dict.clone();
+    zeros.iter_mut().for_each(|named_tensor| named_tensor.data.fill(0.0));
+    zeros
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_dict::state_dict;
+
+    // Test that FedAvg weights the client models by their number of samples
+    #[test]
+    fn test_fed_avg() {
+        let global = state_dict(vec![0.0, 0.0]);
+        let updates = vec![
+            ClientUpdate::new(state_dict(vec![1.0, 2.0]), 10),
+            ClientUpdate::new(state_dict(vec![4.0, 8.0]), 20),
+        ];
+
+        let next = FedAvg.aggregate(&global, &updates).unwrap();
+        assert_eq!(next[0].data, vec![3.0, 6.0]);
+    }
+
+    // Test that server momentum accumulates the deltas across rounds
+    #[test]
+    fn test_fed_avg_m() {
+        let mut aggregator = FedAvgM::new(1.0, 0.5);
+        let global = state_dict(vec![0.0]);
+
+        let first = aggregator.aggregate(&global, &[ClientUpdate::new(state_dict(vec![1.0]), 1)]).unwrap();
+        assert_eq!(first[0].data, vec![1.0]);
+
+        // The delta is 1.0 again, the velocity becomes 0.5 * 1.0 + 1.0
+        let second = aggregator.aggregate(&first, &[ClientUpdate::new(state_dict(vec![2.0]), 1)]).unwrap();
+        assert_eq!(second[0].data, vec![2.5]);
+
+        // A restored aggregator continues with the saved velocity
+        let mut restored = FedAvgM::new(1.0, 0.5);
+        restored.restore_state(aggregator.state().unwrap()).unwrap();
+        let third = restored.aggregate(&second, &[ClientUpdate::new(state_dict(vec![3.5]), 1)]).unwrap();
+        assert_eq!(third[0].data, vec![3.5 + 0.75]);
+    }
+
+    // Test that the adaptive optimizers move the model in the direction of the clients
+    #[test]
+    fn test_fed_opt() {
+        let mut aggregators = [
+            FedOpt::fed_adagrad(0.1, 1e-3),
+            FedOpt::fed_adam(0.1, 1e-3),
+            FedOpt::fed_yogi(0.1, 1e-3),
+        ];
+        for aggregator in aggregators.iter_mut() {
+            let mut global = state_dict(vec![0.0, 0.0]);
+            for _ in 0..5 {
+                let updates = vec![ClientUpdate::new(state_dict(vec![1.0, -1.0]), 1)];
+                global = aggregator.aggregate(&global, &updates).unwrap();
+            }
+            assert!(global[0].data[0] > 0.0 && global[0].data[0] <= 1.0, "{}", aggregator.name());
+            assert!(global[0].data[1] < 0.0 && global[0].data[1] >= -1.0, "{}", aggregator.name());
+        }
+    }
+
+    // Test that stale updates weigh less in the average
+    #[test]
+    fn test_staleness_function() {
+        let polynomial = StalenessFunction::Polynomial { exponent: 0.5 };
+        let hinge = StalenessFunction::Hinge { slope: 0.5, cutoff: 2 };
+        assert_eq!(polynomial.weight(0), 1.0);
+        assert_eq!(polynomial.weight(3), 0.5);
+        assert_eq!(hinge.weight(2), 1.0);
+        assert_eq!(hinge.weight(4), 0.5);
+        assert_eq!(StalenessFunction::Constant.weight(100), 1.0);
+
+        let global = state_dict(vec![0.0]);
+        let inverse = StalenessFunction::Polynomial { exponent: 1.0 };
+        let updates = vec![
+            ClientUpdate::stale(state_dict(vec![0.0]), 10, 0, &inverse),
+            ClientUpdate::stale(state_dict(vec![3.0]), 10, 1, &inverse),
+        ];
+        // The stale update counts for half of its samples: 3.0 * 5 / 15
+        assert_eq!(FedAvg.aggregate(&global, &updates).unwrap()[0].data, vec![1.0]);
+    }
+
+    // Test that updates with a different layout are rejected
+    #[test]
+    fn test_aggregate_layout_mismatch() {
+        let global = state_dict(vec![0.0, 0.0]);
+        let updates = vec![ClientUpdate::new(state_dict(vec![1.0]), 1)];
+
+        assert!(FedAvg.aggregate(&global, &updates).is_err());
+        assert!(FedOpt::fed_adam(0.1, 1e-3).aggregate(&global, &updates).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+//Asynchronous federated learning: FedAsync (Xie et al., 2019) and FedBuff (Nguyen et al., 2022)
+//
+//The server no longer waits for the clients of a round. Every update is weighted by a staleness function
+//of the number of versions published since the model it was trained on. FedAsync mixes each update into the
+//global model right away. FedBuff buffers the deltas of K updates, each taken against the model its client
+//started from, and applies their average with a server learning rate. Both publish a new version every
+//time the global model changes. FedBuff keeps the recent global models to compute the deltas of stale updates.
+
+use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use crate::aggregation::{zeros_like, StalenessFunction};
+use crate::state_dict::{check_same_layout, StateDict};
+
+/// How asynchronous updates are applied to the global model
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AsyncStrategy {
+    /// Every update is mixed in: `(1 - a) * global + a * local` with `a = mixing_rate * staleness weight`.
+    FedAsync { mixing_rate: f64 },
+    /// `buffer_size` staleness-weighted deltas are averaged and applied with `server_learning_rate`.
+    FedBuff { buffer_size: usize, server_learning_rate: f64 },
+}
+
+impl AsyncStrategy {
+    /// Number of updates behind every new version
+    pub fn buffer_size(&self) -> usize {
+        match *self {
+            AsyncStrategy::FedAsync { .. } => 1,
+            AsyncStrategy::FedBuff { buffer_size, .. } => buffer_size,
+        }
+    }
+
+    /// Checks that the strategy parameters are in their valid range
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            AsyncStrategy::FedAsync { mixing_rate } if !(mixing_rate > 0.0 && mixing_rate <= 1.0) => {
+                Err(format!("FedAsync mixing rate must be in (0, 1], got {}", mixing_rate))
+            }
+            AsyncStrategy::FedBuff { buffer_size: 0, .. } => Err("FedBuff buffer size must be at least 1".to_string()),
+            AsyncStrategy::FedBuff { server_learning_rate, .. } if !(server_learning_rate > 0.0 && server_learning_rate.is_finite()) => {
+                Err(format!("FedBuff server learning rate must be positive, got {}", server_learning_rate))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Buffered deltas and recent global models of an asynchronous aggregator, saved in checkpoints
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AsyncAggregatorState {
+    pub buffer: Vec<StateDict>,
+    pub history: Vec<(usize, StateDict)>,
+}
+
+/// Server state of asynchronous aggregation
+pub struct AsyncAggregator {
+    pub strategy: AsyncStrategy,
+    pub staleness_function: StalenessFunction,
+    /// Oldest update accepted, in versions behind the global model.
+    pub max_staleness: usize,
+    /// Staleness-weighted deltas waiting for the buffer to fill.
+    buffer: Vec<StateDict>,
+    /// Recent global models by version, the newest last.
+    history: VecDeque<(usize, StateDict)>,
+}
+
+impl AsyncAggregator {
+    pub fn new(strategy: AsyncStrategy, staleness_function: StalenessFunction, max_staleness: usize) -> Result<Self, String> {
+        strategy.validate()?;
+        staleness_function.validate()?;
+        Ok(AsyncAggregator {
+            strategy,
+            staleness_function,
+            max_staleness,
+            buffer: Vec::new(),
+            history: VecDeque::new(),
+        })
+    }
+
+    /// Number of updates waiting in the buffer
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn state(&self) -> AsyncAggregatorState {
+        AsyncAggregatorState {
+            buffer: self.buffer.clone(),
+            history: self.history.iter().cloned().collect(),
+        }
+    }
+
+    /// Restores the buffer and the history saved by `state`
+    pub fn restore_state(&mut self, state: AsyncAggregatorState) {
+        self.buffer = state.buffer;
+        self.history = state.history.into();
+    }
+
+    /// Drops the buffered deltas, e.g. once the global model they were taken against is rolled back
+    pub fn clear_buffer(&mut self) {
+        self.buffer.clear();
+    }
+
+    /// Adds the update of a client trained on `model_version` while the server is at `global_version` with
+    /// `global`. Returns the next global model once enough updates are buffered.
+    pub fn add_update(
+        &mut self,
+        global: &StateDict,
+        global_version: usize,
+        model_version: usize,
+        local: &StateDict,
+    ) -> Result<Option<StateDict>, String> {
+        check_same_layout(global, local)?;
+        if matches!(self.strategy, AsyncStrategy::FedBuff { .. }) {
+            self.record_global(global, global_version);
+        }
+        if model_version > global_version || global_version - model_version > self.max_staleness {
+            return Err(format!(
+                "Update trained on version {} is not accepted at version {} (tolerated staleness {})",
+                model_version, global_version, self.max_staleness
+            ));
+        }
+        let weight = self.staleness_function.weight(global_version - model_version);
+
+        match self.strategy {
+            AsyncStrategy::FedAsync { mixing_rate } => {
+                let alpha = mixing_rate * weight;
+                let mut next = global.clone();
+                for (n, l) in next.iter_mut().zip(local.iter()) {
+                    for (n, l) in n.data.iter_mut().zip(l.data.iter()) {
+                        *n = (1.0 - alpha) * *n + alpha * l;
+                    }
+                }
+                Ok(Some(next))
+            }
+            AsyncStrategy::FedBuff { buffer_size, server_learning_rate } => {
+                let base = self
+                    .history
+                    .iter()
+                    .find(|(version, _)| *version == model_version)
+                    .map(|(_, model)| model)
+                    .ok_or(format!("Global model of version {} is no longer known", model_version))?;
+                let mut delta = local.clone();
+                for (d, b) in delta.iter_mut().zip(base.iter()) {
+                    for (d, b) in d.data.iter_mut().zip(b.data.iter()) {
+                        *d = weight * (*d - b);
+                    }
+                }
+                self.buffer.push(delta);
+                if self.buffer.len() < buffer_size {
+                    return Ok(None);
+                }
+
+                let mut next = zeros_like(global);
+                for delta in self.buffer.drain(..) {
+                    for (n, d) in next.iter_mut().zip(delta.iter()) {
+                        for (n, d) in n.data.iter_mut().zip(d.data.iter()) {
+                            *n += server_learning_rate * d / buffer_size as f64;
+                        }
+                    }
+                }
+                for (n, g) in next.iter_mut().zip(global.iter()) {
+                    for (n, g) in n.data.iter_mut().zip(g.data.iter()) {
+                        *n += g;
+                    }
+                }
+                Ok(Some(next))
+            }
+        }
+    }
+
+    /// Remembers the global model of `version` as long as updates trained on it are accepted
+    fn record_global(&mut self, global: &StateDict, version: usize) {
+        if self.history.back().map(|(last, _)| *last) != Some(version) {
+            self.history.push_back((version, global.clone()));
+        }
+        while self.history.front().is_some_and(|(oldest, _)| oldest + self.max_staleness < version) {
+            self.history.pop_front();
+        }
+    }
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_dict::state_dict;
+
+    // Test that FedAsync mixes every update with a rate discounted by its staleness
+    #[test]
+    fn test_fed_async() {
+        let strategy = AsyncStrategy::FedAsync { mixing_rate: 0.5 };
+        let staleness = StalenessFunction::Polynomial { exponent: 1.0 };
+        let mut aggregator = AsyncAggregator::new(strategy, staleness, 4).unwrap();
+
+        let next = aggregator.add_update(&state_dict(vec![0.0]), 0, 0, &state_dict(vec![4.0])).unwrap();
+        assert_eq!(next.unwrap()[0].data, vec![2.0]);
+        // One version old: mixing rate 0.5 / 2
+        let next = aggregator.add_update(&state_dict(vec![2.0]), 1, 0, &state_dict(vec![6.0])).unwrap();
+        assert_eq!(next.unwrap()[0].data, vec![3.0]);
+        assert!(aggregator.add_update(&state_dict(vec![3.0]), 9, 2, &state_dict(vec![6.0])).is_err());
+    }
+
+    // Test that FedBuff waits for K updates and takes each delta against the model its client started from
+    #[test]
+    fn test_fed_buff() {
+        let strategy = AsyncStrategy::FedBuff { buffer_size: 2, server_learning_rate: 1.0 };
+        let mut aggregator = AsyncAggregator::new(strategy, StalenessFunction::Constant, 2).unwrap();
+
+        let v0 = state_dict(vec![0.0]);
+        assert!(aggregator.add_update(&v0, 0, 0, &state_dict(vec![2.0])).unwrap().is_none());
+        let v1 = aggregator.add_update(&v0, 0, 0, &state_dict(vec![4.0])).unwrap().unwrap();
+        assert_eq!(v1[0].data, vec![3.0]);
+
+        // A delta of 1 against version 0 and of 2 against version 1
+        assert!(aggregator.add_update(&v1, 1, 0, &state_dict(vec![1.0])).unwrap().is_none());
+        let v2 = aggregator.add_update(&v1, 1, 1, &state_dict(vec![5.0])).unwrap().unwrap();
+        assert_eq!(v2[0].data, vec![4.5]);
+        assert_eq!(aggregator.buffered(), 0);
+
+        // A restored aggregator still knows version 1 and the buffered delta
+        assert!(aggregator.add_update(&v2, 2, 1, &state_dict(vec![4.0])).unwrap().is_none());
+        let mut restored = AsyncAggregator::new(strategy, StalenessFunction::Constant, 2).unwrap();
+        restored.restore_state(aggregator.state());
+        assert_eq!(restored.buffered(), 1);
+        let v3 = restored.add_update(&v2, 2, 1, &state_dict(vec![3.0])).unwrap().unwrap();
+        assert_eq!(v3[0].data, vec![5.0]);
+    }
+
+    // Test that invalid strategies are rejected
+    #[test]
+    fn test_async_strategy_validation() {
+        let buffer = AsyncStrategy::FedBuff { buffer_size: 0, server_learning_rate: 1.0 };
+        assert!(AsyncAggregator::new(buffer, StalenessFunction::Constant, 1).is_err());
+        let mixing = AsyncStrategy::FedAsync { mixing_rate: 1.5 };
+        assert!(AsyncAggregator::new(mixing, StalenessFunction::Constant, 1).is_err());
+        let hinge = StalenessFunction::Hinge { slope: -1.0, cutoff: 1 };
+        assert!(AsyncAggregator::new(AsyncStrategy::FedAsync { mixing_rate: 0.5 }, hinge, 1).is_err());
+    }
+}
@@ -0,0 +1,401 @@
+//Client registration and authentication
+//
+//A client registers once and gets an id with a bearer token, or with the right to sign its requests when it
+//registers an Ed25519 public key. The server keeps the SHA-256 of the tokens, never the tokens themselves.
+//A signature covers the method, the path, a timestamp and the SHA-256 of the body. Requests whose timestamp
+//is more than `MAX_CLOCK_SKEW_SECS` away from the server clock are refused, which bounds replays.
+//Revoked clients are refused from then on, and the public key of a revoked client cannot register again.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Header naming the client of a signed request
+pub const CLIENT_ID_HEADER: &str = "x-client-id";
+/// Header holding the Unix time a request was signed at
+pub const TIMESTAMP_HEADER: &str = "x-timestamp";
+/// Header holding the base64 Ed25519 signature of a request
+pub const SIGNATURE_HEADER: &str = "x-signature";
+/// Largest difference in seconds between the timestamp of a signed request and the server clock
+pub const MAX_CLOCK_SKEW_SECS: u64 = 300;
+
+/// Body of a registration, a bearer token is issued when no public key is given
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RegistrationRequest {
+    /// Base64 Ed25519 public key the client will sign its requests with.
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// Secret shared with the operator of the server, when registration is restricted.
+    #[serde(default)]
+    pub registration_secret: Option<String>,
+}
+
+/// Identity issued to a registered client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Registration {
+    pub client_id: String,
+    /// Bearer token of the client, absent for clients signing their requests.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Authentication headers of a request
+#[derive(Debug, Clone, Default)]
+pub struct RequestCredentials {
+    /// `Authorization` header, `Bearer <token>`.
+    pub authorization: Option<String>,
+    pub client_id: Option<String>,
+    pub timestamp: Option<String>,
+    pub signature: Option<String>,
+}
+
+impl RequestCredentials {
+    /// Whether the request is signed, its body is then needed to authenticate it
+    pub fn is_signed(&self) -> bool {
+        self.signature.is_some()
+    }
+}
+
+enum Credential {
+    TokenHash([u8; 32]),
+    PublicKey(VerifyingKey),
+}
+
+/// Registered and revoked clients saved in checkpoints, the registration secret comes from the settings
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientRegistryState {
+    /// SHA-256 of the bearer token of every token client.
+    pub token_hashes: BTreeMap<String, [u8; 32]>,
+    /// Base64 Ed25519 public key of every signing client.
+    pub public_keys: BTreeMap<String, String>,
+    pub revoked: BTreeSet<String>,
+    /// Public keys of revoked clients, refused on registration.
+    pub banned_keys: BTreeSet<[u8; 32]>,
+    pub next_id: u64,
+}
+
+/// Registered clients of the server
+pub struct ClientRegistry {
+    clients: BTreeMap<String, Credential>,
+    revoked: BTreeSet<String>,
+    banned_keys: BTreeSet<[u8; 32]>,
+    registration_secret: Option<String>,
+    next_id: u64,
+}
+
+impl ClientRegistry {
+    /// Registry accepting every client, or only the ones presenting `registration_secret`
+    pub fn new(registration_secret: Option<String>) -> Self {
+        ClientRegistry {
+            clients: BTreeMap::new(),
+            revoked: BTreeSet::new(),
+            banned_keys: BTreeSet::new(),
+            registration_secret,
+            next_id: 1,
+        }
+    }
+
+    pub fn num_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Issues a client id, with a bearer token unless the client registers a public key
+    pub fn register(&mut self, request: &RegistrationRequest) -> Result<Registration, String> {
+        if let Some(secret) = &self.registration_secret {
+            if request.registration_secret.as_ref() != Some(secret) {
+                return Err("Invalid registration secret".to_string());
+            }
+        }
+        let client_id = format!("client-{}", self.next_id);
+
+        let (credential, token) = match &request.public_key {
+            Some(public_key) => {
+                let public_key = decode_public_key(public_key)?;
+                if self.banned_keys.contains(public_key.as_bytes()) {
+                    return Err("This public key was revoked".to_string());
+                }
+                (Credential::PublicKey(public_key), None)
+            }
+            None => {
+                let secret: [u8; 32] = thread_rng().gen();
+                let token = format!("{}.{}", client_id, URL_SAFE_NO_PAD.encode(secret));
+                (Credential::TokenHash(Sha256::digest(token.as_bytes()).into()), Some(token))
+            }
+        };
+        self.next_id += 1;
+        self.clients.insert(client_id.clone(), credential);
+        Ok(Registration { client_id, token })
+    }
+
+    /// Id of the client that sent a request with these credentials at Unix time `now`
+    pub fn authenticate(
+        &self,
+        credentials: &RequestCredentials,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        now: u64,
+    ) -> Result<String, String> {
+        let client_id = match (&credentials.authorization, &credentials.client_id) {
+            (_, Some(client_id)) if credentials.is_signed() => client_id.clone(),
+            (Some(authorization), _) => {
+                let token = authorization.strip_prefix("Bearer ").ok_or("Unsupported authorization scheme")?;
+                token.split_once('.').map(|(client_id, _)| client_id.to_string()).ok_or("Malformed bearer token")?
+            }
+            _ => return Err("Missing credentials".to_string()),
+        };
+        if self.revoked.contains(&client_id) {
+            return Err(format!("Client {} is revoked", client_id));
+        }
+
+        match self.clients.get(&client_id) {
+            Some(Credential::TokenHash(hash)) => {
+                let token = credentials.authorization.as_deref().and_then(|a| a.strip_prefix("Bearer ")).unwrap_or_default();
+                if Sha256::digest(token.as_bytes()).as_slice() != hash {
+                    return Err("Invalid bearer token".to_string());
+                }
+            }
+            Some(Credential::PublicKey(public_key)) => {
+                let timestamp = credentials
+                    .timestamp
+                    .as_deref()
+                    .and_then(|t| t.parse::<u64>().ok())
+                    .ok_or("Missing or malformed timestamp")?;
+                if timestamp.abs_diff(now) > MAX_CLOCK_SKEW_SECS {
+                    return Err("Request timestamp is too far from the server clock".to_string());
+                }
+                let signature = credentials
+                    .signature
+                    .as_deref()
+                    .and_then(|s| STANDARD.decode(s).ok())
+                    .and_then(|s| Signature::from_slice(&s).ok())
+                    .ok_or("Missing or malformed signature")?;
+                public_key
+                    .verify(&signing_message(method, path, timestamp, body), &signature)
+                    .map_err(|_| "Invalid request signature".to_string())?;
+            }
+            None => return Err(format!("Unknown client {}", client_id)),
+        }
+        Ok(client_id)
+    }
+
+    /// Bans a client, its requests are refused and its public key cannot register again
+    pub fn revoke(&mut self, client_id: &str) -> bool {
+        match self.clients.remove(client_id) {
+            Some(credential) => {
+                if let Credential::PublicKey(public_key) = credential {
+                    self.banned_keys.insert(public_key.to_bytes());
+                }
+                self.revoked.insert(client_id.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_revoked(&self, client_id: &str) -> bool {
+        self.revoked.contains(client_id)
+    }
+
+    pub fn state(&self) -> ClientRegistryState {
+        let mut state = ClientRegistryState {
+            revoked: self.revoked.clone(),
+            banned_keys: self.banned_keys.clone(),
+            next_id: self.next_id,
+            ..Default::default()
+        };
+        for (client_id, credential) in &self.clients {
+            match credential {
+                Credential::TokenHash(hash) => {
+                    state.token_hashes.insert(client_id.clone(), *hash);
+                }
+                Credential::PublicKey(public_key) => {
+                    state.public_keys.insert(client_id.clone(), STANDARD.encode(public_key.as_bytes()));
+                }
+            }
+        }
+        state
+    }
+
+    /// Restores the clients saved by `state`, keeping the registration secret of the registry
+    pub fn restore_state(&mut self, state: ClientRegistryState) -> Result<(), String> {
+        let mut clients = state
+            .token_hashes
+            .into_iter()
+            .map(|(client_id, hash)| (client_id, Credential::TokenHash(hash)))
+            .collect::<BTreeMap<_, _>>();
+        for (client_id, public_key) in state.public_keys {
+            clients.insert(client_id, Credential::PublicKey(decode_public_key(&public_key)?));
+        }
+        self.clients = clients;
+        self.revoked = state.revoked;
+        self.banned_keys = state.banned_keys;
+        self.next_id = state.next_id.max(1);
+        Ok(())
+    }
+}
+
+/// Credentials of a registered client, attached to its requests
+#[derive(Clone)]
+pub enum ClientCredentials {
+    Bearer { client_id: String, token: String },
+    Ed25519 { client_id: String, signing_key: SigningKey },
+}
+
+impl ClientCredentials {
+    pub fn client_id(&self) -> &str {
+        match self {
+            ClientCredentials::Bearer { client_id, .. } | ClientCredentials::Ed25519 { client_id, .. } => client_id,
+        }
+    }
+
+    /// Headers authenticating a request to `path` (with its query) sent at Unix time `now`
+    pub fn headers(&self, method: &str, path: &str, body: &[u8], now: u64) -> Vec<(&'static str, String)> {
+        match self {
+            ClientCredentials::Bearer { token, .. } => vec![("authorization", format!("Bearer {}", token))],
+            ClientCredentials::Ed25519 { client_id, signing_key } => {
+                let signature = signing_key.sign(&signing_message(method, path, now, body));
+                vec![
+                    (CLIENT_ID_HEADER, client_id.clone()),
+                    (TIMESTAMP_HEADER, now.to_string()),
+                    (SIGNATURE_HEADER, STANDARD.encode(signature.to_bytes())),
+                ]
+            }
+        }
+    }
+}
+
+/// Fresh Ed25519 key pair of a client
+pub fn generate_signing_key() -> SigningKey {
+    SigningKey::from_bytes(&thread_rng().gen())
+}
+
+/// Base64 public key of a signing key, as sent in a registration
+pub fn encode_public_key(signing_key: &SigningKey) -> String {
+    STANDARD.encode(signing_key.verifying_key().as_bytes())
+}
+
+fn decode_public_key(public_key: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = STANDARD
+        .decode(public_key)
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|_| "Public key must be 32 bytes".to_string())?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| e.to_string())
+}
+
+/// Bytes signed for a request: method, path, timestamp and body hash on separate lines
+pub fn signing_message(method: &str, path: &str, timestamp: u64, body: &[u8]) -> Vec<u8> {
+    let body_hash = Sha256::digest(body).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}\n{}\n{}\n{}", method.to_uppercase(), path, timestamp, body_hash).into_bytes()
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_credentials(headers: Vec<(&'static str, String)>) -> RequestCredentials {
+        let header = |name: &str| headers.iter().find(|(n, _)| *n == name).map(|(_, v)| v.clone());
+        RequestCredentials {
+            authorization: header("authorization"),
+            client_id: header(CLIENT_ID_HEADER),
+            timestamp: header(TIMESTAMP_HEADER),
+            signature: header(SIGNATURE_HEADER),
+        }
+    }
+
+    // Test that a bearer token authenticates its client only
+    #[test]
+    fn test_bearer_token() {
+        let mut registry = ClientRegistry::new(None);
+        let registration = registry.register(&RegistrationRequest::default()).unwrap();
+        let credentials = ClientCredentials::Bearer {
+            client_id: registration.client_id.clone(),
+            token: registration.token.unwrap(),
+        };
+        let headers = request_credentials(credentials.headers("GET", "/get_model", b"", 0));
+        assert_eq!(registry.authenticate(&headers, "GET", "/get_model", b"", 0).unwrap(), registration.client_id);
+
+        let forged = RequestCredentials {
+            authorization: Some(format!("Bearer {}.AAAA", registration.client_id)),
+            ..Default::default()
+        };
+        assert!(registry.authenticate(&forged, "GET", "/get_model", b"", 0).is_err());
+        assert!(registry.authenticate(&RequestCredentials::default(), "GET", "/get_model", b"", 0).is_err());
+    }
+
+    // Test that signatures bind the body and the time of a request
+    #[test]
+    fn test_signed_request() {
+        let mut registry = ClientRegistry::new(Some("secret".to_string()));
+        let signing_key = generate_signing_key();
+        let mut request = RegistrationRequest { public_key: Some(encode_public_key(&signing_key)), registration_secret: None };
+        assert!(registry.register(&request).is_err());
+        request.registration_secret = Some("secret".to_string());
+        let registration = registry.register(&request).unwrap();
+        assert!(registration.token.is_none());
+
+        let credentials = ClientCredentials::Ed25519 { client_id: registration.client_id.clone(), signing_key };
+        let now = unix_time();
+        let headers = request_credentials(credentials.headers("POST", "/update_model", b"{}", now));
+        assert!(registry.authenticate(&headers, "POST", "/update_model", b"{}", now + 10).is_ok());
+        assert!(registry.authenticate(&headers, "POST", "/update_model", b"{\"a\":1}", now).is_err());
+        assert!(registry.authenticate(&headers, "POST", "/check_in", b"{}", now).is_err());
+        assert!(registry.authenticate(&headers, "POST", "/update_model", b"{}", now + 2 * MAX_CLOCK_SKEW_SECS).is_err());
+    }
+
+    // Test that revoked clients are refused and cannot register their key again
+    #[test]
+    fn test_revocation() {
+        let mut registry = ClientRegistry::new(None);
+        let signing_key = generate_signing_key();
+        let request = RegistrationRequest { public_key: Some(encode_public_key(&signing_key)), registration_secret: None };
+        let registration = registry.register(&request).unwrap();
+        let credentials = ClientCredentials::Ed25519 { client_id: registration.client_id.clone(), signing_key };
+        let headers = request_credentials(credentials.headers("GET", "/get_model", b"", 100));
+
+        assert!(registry.revoke(&registration.client_id));
+        assert!(registry.is_revoked(&registration.client_id));
+        assert!(registry.authenticate(&headers, "GET", "/get_model", b"", 100).is_err());
+        assert!(registry.register(&request).is_err());
+        assert!(!registry.revoke("client-42"));
+    }
+
+    // Test that a restored registry keeps its clients, its revocations and its next client id
+    #[test]
+    fn test_registry_state() {
+        let mut registry = ClientRegistry::new(None);
+        let token_client = registry.register(&RegistrationRequest::default()).unwrap();
+        let signing_key = generate_signing_key();
+        let request = RegistrationRequest { public_key: Some(encode_public_key(&signing_key)), registration_secret: None };
+        let signing_client = registry.register(&request).unwrap();
+        let revoked_key = generate_signing_key();
+        let revoked_request = RegistrationRequest { public_key: Some(encode_public_key(&revoked_key)), registration_secret: None };
+        let revoked_client = registry.register(&revoked_request).unwrap();
+        registry.revoke(&revoked_client.client_id);
+
+        let mut restored = ClientRegistry::new(None);
+        restored.restore_state(serde_json::from_value(serde_json::to_value(registry.state()).unwrap()).unwrap()).unwrap();
+        assert_eq!(restored.state(), registry.state());
+        let credentials = ClientCredentials::Bearer { client_id: token_client.client_id.clone(), token: token_client.token.unwrap() };
+        let headers = request_credentials(credentials.headers("GET", "/get_model", b"", 100));
+        assert_eq!(restored.authenticate(&headers, "GET", "/get_model", b"", 100).unwrap(), token_client.client_id);
+        let credentials = ClientCredentials::Ed25519 { client_id: signing_client.client_id.clone(), signing_key };
+        let headers = request_credentials(credentials.headers("GET", "/get_model", b"", 100));
+        assert_eq!(restored.authenticate(&headers, "GET", "/get_model", b"", 100).unwrap(), signing_client.client_id);
+        assert!(restored.is_revoked(&revoked_client.client_id));
+        assert!(restored.register(&revoked_request).is_err());
+        assert_eq!(restored.register(&RegistrationRequest::default()).unwrap().client_id, "client-4");
+    }
+}
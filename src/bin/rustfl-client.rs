@@ -0,0 +1,140 @@
+//Command-line client of RustFL
+//
+//Settings come from an optional TOML/YAML file, then `RUSTFL_CLIENT_*` environment variables, then flags.
+
+use clap::{Parser, Subcommand};
+use log::{error, info};
+use RustFL::client::{count_samples, send_secure_model_weights, Device, FederatedClient};
+use RustFL::settings::{ClientSettings, Settings};
+
+/// Federated learning client training on MNIST
+#[derive(Parser)]
+#[command(name = "rustfl-client", version, about)]
+struct Cli {
+    /// TOML or YAML settings file
+    #[arg(short, long, global = true)]
+    config: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Trains on the local data and sends the update to the server
+    Train {
+        #[command(flatten)]
+        args: ClientArgs,
+        /// Takes part in secure aggregation with this non-zero client id instead of sending a plain update
+        #[arg(long)]
+        secure_aggregation: Option<u64>,
+    },
+    /// Prints the effective settings as TOML
+    Config(ClientArgs),
+}
+
+/// Flags overriding the client settings
+#[derive(clap::Args)]
+struct ClientArgs {
+    /// Base URL of the server
+    #[arg(short, long)]
+    server_url: Option<String>,
+    /// Directory of the MNIST training files
+    #[arg(short, long)]
+    data_dir: Option<String>,
+    #[arg(short, long)]
+    rounds: Option<usize>,
+    #[arg(short, long)]
+    batch_size: Option<usize>,
+    #[arg(short, long)]
+    learning_rate: Option<f64>,
+    /// Epsilon of the noise added to every upload
+    #[arg(long)]
+    epsilon: Option<f64>,
+    /// L2 bound of the update, the sensitivity of the upload noise
+    #[arg(long)]
+    sensitivity: Option<f64>,
+    /// Total epsilon the client may spend
+    #[arg(long)]
+    privacy_budget: Option<f64>,
+    /// Trains with DP-SGD, spreading the privacy budget over the rounds
+    #[arg(long)]
+    dp_sgd: bool,
+    /// Identifier of the client, needed to check in with servers selecting their clients
+    #[arg(long)]
+    client_id: Option<String>,
+    /// Trains on the CPU even if CUDA is available
+    #[arg(long)]
+    cpu: bool,
+}
+
+impl ClientArgs {
+    fn apply(&self, settings: &mut ClientSettings) {
+        if let Some(server_url) = &self.server_url {
+            settings.server_url = server_url.clone();
+        }
+        if let Some(data_dir) = &self.data_dir {
+            settings.data_dir = data_dir.clone();
+        }
+        settings.num_rounds = self.rounds.unwrap_or(settings.num_rounds);
+        settings.batch_size = self.batch_size.unwrap_or(settings.batch_size);
+        settings.learning_rate = self.learning_rate.unwrap_or(settings.learning_rate);
+        settings.epsilon = self.epsilon.unwrap_or(settings.epsilon);
+        settings.sensitivity = self.sensitivity.unwrap_or(settings.sensitivity);
+        settings.privacy_budget = self.privacy_budget.or(settings.privacy_budget);
+        settings.dp_sgd |= self.dp_sgd;
+        if let Some(client_id) = &self.client_id {
+            settings.client_id = Some(client_id.clone());
+        }
+    }
+}
+
+fn load_settings(config: Option<&String>, args: &ClientArgs) -> Result<Settings, String> {
+    let mut settings = Settings::load(config)?;
+    args.apply(&mut settings.client);
+    settings.validate()?;
+    Ok(settings)
+}
+
+async fn train(config: Option<&String>, args: &ClientArgs, secure_aggregation: Option<u64>) -> Result<(), String> {
+    let settings = load_settings(config, args)?.client;
+    let device = if !args.cpu && tch::Cuda::is_available() { Device::Cuda(0) } else { Device::Cpu };
+    let mut client = FederatedClient::new(settings.config(), &settings.server_url, device)?;
+    if let Some(tls) = &settings.tls {
+        client = client.with_tls(tls)?;
+    }
+    if let Some(authentication) = settings.authentication {
+        client.register(authentication.signed(), settings.registration_secret.clone()).await?;
+    }
+    let train_loader = client.load_train_data(&settings.data_dir);
+
+    match secure_aggregation {
+        Some(client_id) => {
+            let (_, trained_weights, _) = client.train(&train_loader).await?;
+            let num_samples = count_samples(&train_loader);
+            let model_version = send_secure_model_weights(client.connection(), &trained_weights, num_samples, client_id, &settings.server_url).await?;
+            if let Some(model_version) = model_version {
+                info!("Secure aggregation produced global model version {}", model_version);
+            }
+        }
+        None => client.run(&train_loader).await?,
+    }
+    info!("Model training has been completed.");
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let cli = Cli::parse();
+
+    let result = match &cli.command {
+        Command::Train { args, secure_aggregation } => train(cli.config.as_ref(), args, *secure_aggregation).await,
+        Command::Config(args) => load_settings(cli.config.as_ref(), args)
+            .and_then(|settings| toml::to_string(&settings.client).map_err(|e| e.to_string()))
+            .map(|text| print!("{}", text)),
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,90 @@
+//Command-line server of RustFL
+//
+//Settings come from an optional TOML/YAML file, then `RUSTFL_SERVER_*` environment variables, then flags.
+
+use clap::{Parser, Subcommand};
+use RustFL::server::{run_server, run_server_tls};
+use RustFL::settings::{ServerSettings, Settings};
+
+/// Federated learning server
+#[derive(Parser)]
+#[command(name = "rustfl-server", version, about)]
+struct Cli {
+    /// TOML or YAML settings file
+    #[arg(short, long, global = true)]
+    config: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Starts the server
+    Run(ServerArgs),
+    /// Prints the effective settings as TOML
+    Config(ServerArgs),
+}
+
+/// Flags overriding the server settings
+#[derive(clap::Args)]
+struct ServerArgs {
+    /// Address to bind to
+    #[arg(long)]
+    host: Option<String>,
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Number of client updates aggregated into each global model
+    #[arg(short, long)]
+    aggregation_goal: Option<usize>,
+    /// Surviving clients needed to unmask a secure aggregation round
+    #[arg(long)]
+    secure_aggregation_threshold: Option<usize>,
+    /// Total epsilon the released models may spend
+    #[arg(long)]
+    privacy_budget: Option<f64>,
+    #[arg(long)]
+    target_delta: Option<f64>,
+}
+
+impl ServerArgs {
+    fn apply(&self, settings: &mut ServerSettings) {
+        if let Some(host) = &self.host {
+            settings.host = host.clone();
+        }
+        settings.port = self.port.unwrap_or(settings.port);
+        settings.aggregation_goal = self.aggregation_goal.unwrap_or(settings.aggregation_goal);
+        settings.secure_aggregation_threshold = self.secure_aggregation_threshold.or(settings.secure_aggregation_threshold);
+        settings.privacy_budget = self.privacy_budget.or(settings.privacy_budget);
+        settings.target_delta = self.target_delta.unwrap_or(settings.target_delta);
+    }
+}
+
+fn load_settings(config: Option<&String>, args: &ServerArgs) -> Result<Settings, String> {
+    let mut settings = Settings::load(config)?;
+    args.apply(&mut settings.server);
+    settings.validate()?;
+    Ok(settings)
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init();
+    let cli = Cli::parse();
+    let to_io = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+
+    match &cli.command {
+        Command::Run(args) => {
+            let settings = load_settings(cli.config.as_ref(), args).map_err(to_io)?;
+            let app_state = settings.server.app_state().map_err(to_io)?;
+            match &settings.server.tls {
+                Some(tls) => run_server_tls(app_state, settings.server.bind_address(), tls).await,
+                None => run_server(app_state, settings.server.bind_address()).await,
+            }
+        }
+        Command::Config(args) => {
+            let settings = load_settings(cli.config.as_ref(), args).map_err(to_io)?;
+            print!("{}", toml::to_string(&settings.server).map_err(|e| to_io(e.to_string()))?);
+            Ok(())
+        }
+    }
+}
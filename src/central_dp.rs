@@ -0,0 +1,221 @@
+//Server-side (central) differential privacy: DP-FedAvg of McMahan et al. (2018)
+//with the adaptive clipping of Andrew et al. (2021)
+//
+//The server clips every client delta to an L2 norm, averages the clipped deltas with equal weights
+//(sample counts would change the sensitivity) and adds Gaussian noise once to the average.
+//The noisy average is then handed to the wrapped aggregator, so server optimizers still apply.
+
+use log::info;
+use rand::thread_rng;
+use rand_distr::{Distribution, Normal};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::aggregation::{zeros_like, Aggregator, ClientUpdate};
+use crate::privacy_accountant::SampledGaussian;
+use crate::state_dict::{check_same_layout, StateDict};
+
+/// Adaptation of the clipping norm towards a quantile of the update norms
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AdaptiveClipping {
+    /// Fraction of the client updates that should be left unclipped.
+    pub target_quantile: f64,
+    /// Step size of the geometric update of the clipping norm.
+    pub learning_rate: f64,
+    /// Noise multiplier of the count of unclipped updates.
+    pub count_noise_multiplier: f64,
+}
+
+/// Aggregator wrapper clipping client updates and adding Gaussian noise to their average
+pub struct CentralDp {
+    inner: Box<dyn Aggregator>,
+    name: String,
+    /// Current L2 bound of every client delta.
+    pub clip_norm: f64,
+    /// Noise standard deviation of the sum of deltas divided by `clip_norm`.
+    pub noise_multiplier: f64,
+    pub adaptive_clipping: Option<AdaptiveClipping>,
+}
+
+impl CentralDp {
+    /// DP-FedAvg with a fixed clipping norm on top of `inner`
+    pub fn new(inner: Box<dyn Aggregator>, clip_norm: f64, noise_multiplier: f64) -> Self {
+        let name = format!("DP-{}", inner.name());
+        CentralDp {
+            inner,
+            name,
+            clip_norm,
+            noise_multiplier,
+            adaptive_clipping: None,
+        }
+    }
+
+    /// DP-FedAvg whose clipping norm starts at `initial_clip_norm` and follows `adaptive_clipping`.
+    /// The noise of the count is taken out of the total noise multiplier so that the privacy cost stays the same.
+    pub fn adaptive(
+        inner: Box<dyn Aggregator>,
+        initial_clip_norm: f64,
+        noise_multiplier: f64,
+        adaptive_clipping: AdaptiveClipping,
+    ) -> Result<Self, String> {
+        if !(0.0..=1.0).contains(&adaptive_clipping.target_quantile) {
+            return Err(format!("Target quantile must be in [0, 1], got {}", adaptive_clipping.target_quantile));
+        }
+        if adaptive_clipping.count_noise_multiplier <= noise_multiplier / 2.0 {
+            return Err(format!(
+                "Count noise multiplier must exceed {} to leave noise for the updates",
+                noise_multiplier / 2.0
+            ));
+        }
+        let mut central_dp = CentralDp::new(inner, initial_clip_norm, noise_multiplier);
+        central_dp.adaptive_clipping = Some(adaptive_clipping);
+        Ok(central_dp)
+    }
+
+    /// Mechanism of one aggregation for the privacy accountant, `sampling_rate` being the
+    /// fraction of the client population taking part in a round
+    pub fn round_mechanism(&self, sampling_rate: f64) -> SampledGaussian {
+        SampledGaussian {
+            sampling_rate,
+            noise_multiplier: self.noise_multiplier,
+            steps: 1,
+        }
+    }
+
+    /// Noise multiplier left for the deltas once the count of unclipped updates took its share
+    fn update_noise_multiplier(&self) -> f64 {
+        match self.adaptive_clipping {
+            Some(adaptive) if self.noise_multiplier > 0.0 => {
+                let remaining = self.noise_multiplier.powi(-2) - (2.0 * adaptive.count_noise_multiplier).powi(-2);
+                remaining.powf(-0.5)
+            }
+            _ => self.noise_multiplier,
+        }
+    }
+}
+
+impl Aggregator for CentralDp {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn aggregate(&mut self, global: &StateDict, updates: &[ClientUpdate]) -> Result<StateDict, String> {
+        if updates.is_empty() {
+            return Err("No client updates to aggregate".to_string());
+        }
+        let num_clients = updates.len() as f64;
+        let mut rng = thread_rng();
+
+        let mut average = zeros_like(global);
+        let mut unclipped = 0usize;
+        for update in updates {
+            check_same_layout(global, &update.weights)?;
+            let norm = update
+                .weights
+                .iter()
+                .zip(global.iter())
+                .flat_map(|(l, g)| l.data.iter().zip(g.data.iter()).map(|(l, g)| (l - g) * (l - g)))
+                .sum::<f64>()
+                .sqrt();
+            if !norm.is_finite() {
+                return Err("Client update is not finite".to_string());
+            }
+            if norm <= self.clip_norm {
+                unclipped += 1;
+            }
+            let scale = if norm > self.clip_norm { self.clip_norm / norm } else { 1.0 };
+            for ((a, l), g) in average.iter_mut().zip(update.weights.iter()).zip(global.iter()) {
+                for ((a, l), g) in a.data.iter_mut().zip(l.data.iter()).zip(g.data.iter()) {
+                    *a += scale * (l - g) / num_clients;
+                }
+            }
+        }
+
+        // Noise of the sum, divided like the sum by the number of clients
+        let noise_std = self.update_noise_multiplier() * self.clip_norm / num_clients;
+        let noise = Normal::new(0.0, noise_std).map_err(|e| e.to_string())?;
+        for (a, g) in average.iter_mut().zip(global.iter()) {
+            for (a, g) in a.data.iter_mut().zip(g.data.iter()) {
+                *a = g + *a + noise.sample(&mut rng);
+            }
+        }
+
+        if let Some(adaptive) = self.adaptive_clipping {
+            let count_noise = Normal::new(0.0, adaptive.count_noise_multiplier).map_err(|e| e.to_string())?;
+            let unclipped_fraction = (unclipped as f64 + count_noise.sample(&mut rng)) / num_clients;
+            self.clip_norm *= (-adaptive.learning_rate * (unclipped_fraction - adaptive.target_quantile)).exp();
+            info!("Clipping norm adapted to {:.4}", self.clip_norm);
+        }
+
+        self.inner.aggregate(global, &[ClientUpdate { weights: average, weight: 1.0 }])
+    }
+    /// The clipping norm adapted so far and the state of the inner strategy
+    fn state(&self) -> Option<Value> {
+        Some(serde_json::json!({ "clip_norm": self.clip_norm, "inner": self.inner.state() }))
+    }
+
+    fn restore_state(&mut self, state: Value) -> Result<(), String> {
+        self.clip_norm = state["clip_norm"].as_f64().ok_or("Invalid central DP state: no clip_norm".to_string())?;
+        match state.get("inner").cloned() {
+            Some(Value::Null) | None => Ok(()),
+            Some(inner) => self.inner.restore_state(inner),
+        }
+    }
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregation::FedAvg;
+    use crate::state_dict::state_dict;
+
+    // Test that updates are clipped and averaged with equal weights
+    #[test]
+    fn test_central_dp_clipping() {
+        let mut aggregator = CentralDp::new(Box::new(FedAvg), 1.0, 0.0);
+        let global = state_dict(vec![1.0, 1.0]);
+        let updates = vec![
+            ClientUpdate::new(state_dict(vec![1.3, 1.4]), 10),
+            ClientUpdate::new(state_dict(vec![301.0, 401.0]), 1000),
+        ];
+
+        let next = aggregator.aggregate(&global, &updates).unwrap();
+        // (0.3 + 0.6) / 2 and (0.4 + 0.8) / 2: the outlier is clipped to norm 1 and sample counts are ignored
+        assert!((next[0].data[0] - 1.45).abs() < 1e-12);
+        assert!((next[0].data[1] - 1.6).abs() < 1e-12);
+        assert_eq!(aggregator.name(), "DP-FedAvg");
+    }
+
+    // Test that the noise is added once to the average
+    #[test]
+    fn test_central_dp_noise() {
+        let mut aggregator = CentralDp::new(Box::new(FedAvg), 1.0, 1.0);
+        let global = state_dict(vec![0.0; 10000]);
+        let updates = (0..10).map(|_| ClientUpdate::new(state_dict(vec![0.0; 10000]), 1)).collect::<Vec<_>>();
+
+        let next = aggregator.aggregate(&global, &updates).unwrap();
+        let std = (next[0].data.iter().map(|v| v * v).sum::<f64>() / 10000.0).sqrt();
+        // Noise of std noise_multiplier * clip_norm on the sum of 10 updates
+        assert!((std - 0.1).abs() < 0.01, "std = {}", std);
+    }
+
+    // Test that the clipping norm moves towards the target quantile of the update norms
+    #[test]
+    fn test_adaptive_clipping() {
+        let adaptive = AdaptiveClipping {
+            target_quantile: 0.5,
+            learning_rate: 0.2,
+            count_noise_multiplier: 1e-9,
+        };
+        assert!(CentralDp::adaptive(Box::new(FedAvg), 0.1, 1.0, adaptive).is_err());
+
+        let mut aggregator = CentralDp::adaptive(Box::new(FedAvg), 0.1, 0.0, adaptive).unwrap();
+        let global = state_dict(vec![0.0]);
+        // Update norms 1, 2, ..., 10, the median is between 5 and 6
+        let updates = (1..=10).map(|i| ClientUpdate::new(state_dict(vec![i as f64]), 1)).collect::<Vec<_>>();
+        for _ in 0..200 {
+            aggregator.aggregate(&global, &updates).unwrap();
+        }
+        assert!(aggregator.clip_norm > 4.5 && aggregator.clip_norm < 6.5, "clip_norm = {}", aggregator.clip_norm);
+    }
+}
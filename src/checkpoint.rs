@@ -0,0 +1,227 @@
+//Checkpoints of the server state
+//
+//A checkpoint is a model file written by the server (`model-<version>.ot`) and a JSON file with the version,
+//the round and the updates it received, the aggregator state, the privacy spent and the registered clients
+//(`checkpoint-<version>.json`). Both are written to temporary files and renamed, the JSON last, so a checkpoint
+//is only found once it is complete. A checkpoint is saved again under the same version when the round or the
+//clients changed since. The newest checkpoints are kept, the others are deleted.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::async_aggregation::AsyncAggregatorState;
+use crate::auth::ClientRegistryState;
+use crate::privacy_accountant::PrivacyAccountant;
+use crate::round::{PendingRound, RoundSummary};
+use crate::server::WeightsUpdate;
+
+/// Where and how often the server saves checkpoints
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckpointConfig {
+    /// Directory of the checkpoints, created if missing.
+    pub dir: String,
+    /// Seconds between two checkpoints, only taken when the global model changed.
+    pub interval_secs: u64,
+    /// Number of checkpoints kept.
+    pub keep: usize,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        CheckpointConfig {
+            dir: "checkpoints".to_string(),
+            interval_secs: 60,
+            keep: 3,
+        }
+    }
+}
+
+impl CheckpointConfig {
+    /// Checks that checkpoints are taken and kept
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval_secs == 0 || self.keep == 0 {
+            return Err("checkpoint.interval_secs and checkpoint.keep must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Server state saved with the global model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub model_version: usize,
+    /// Round open when the checkpoint was taken, opened again on resume.
+    pub round_id: u64,
+    pub last_round: Option<RoundSummary>,
+    /// Participants and updates of the open round.
+    #[serde(default)]
+    pub pending_round: Option<PendingRound<WeightsUpdate>>,
+    /// Registered and revoked clients.
+    #[serde(default)]
+    pub clients: Option<ClientRegistryState>,
+    /// State of the aggregation strategy, e.g. server optimizer moments.
+    pub aggregator: Option<Value>,
+    /// Buffer and history of asynchronous aggregation.
+    pub asynchronous: Option<AsyncAggregatorState>,
+    /// Privacy spent by the released global models.
+    pub privacy_accountant: PrivacyAccountant,
+    /// Unix time the checkpoint was taken at.
+    pub saved_at: u64,
+}
+
+/// Directory of checkpoints
+#[derive(Debug)]
+pub struct CheckpointStore {
+    pub config: CheckpointConfig,
+    /// Version of the newest checkpoint in the directory.
+    last_version: Option<usize>,
+    /// Last checkpoint saved by this store.
+    last_saved: Option<Checkpoint>,
+}
+
+impl CheckpointStore {
+    pub fn new(config: CheckpointConfig) -> Result<Self, String> {
+        config.validate()?;
+        fs::create_dir_all(&config.dir).map_err(|e| format!("Cannot create {}: {}", config.dir, e))?;
+        let mut store = CheckpointStore { config, last_version: None, last_saved: None };
+        store.last_version = store.versions()?.last().copied();
+        Ok(store)
+    }
+
+    pub fn last_version(&self) -> Option<usize> {
+        self.last_version
+    }
+
+    /// Whether the last checkpoint saved holds the same state as `checkpoint`, whenever they were taken
+    pub fn is_saved(&self, checkpoint: &Checkpoint) -> bool {
+        self.last_saved
+            .as_ref()
+            .is_some_and(|saved| *saved == Checkpoint { saved_at: saved.saved_at, ..checkpoint.clone() })
+    }
+
+    /// Path of the model file of the checkpoint of `version`
+    pub fn model_path(&self, version: usize) -> PathBuf {
+        Path::new(&self.config.dir).join(format!("model-{:08}.ot", version))
+    }
+
+    fn metadata_path(&self, version: usize) -> PathBuf {
+        Path::new(&self.config.dir).join(format!("checkpoint-{:08}.json", version))
+    }
+
+    /// Saves a checkpoint, `save_model` writing the global model to the path it is given.
+    /// Returns the path of the checkpoint file.
+    pub fn save<F: FnOnce(&Path) -> Result<(), String>>(&mut self, checkpoint: &Checkpoint, save_model: F) -> Result<PathBuf, String> {
+        let version = checkpoint.model_version;
+        let model_path = self.model_path(version);
+        let temporary = model_path.with_extension("ot.tmp");
+        save_model(&temporary)?;
+        fs::rename(&temporary, &model_path).map_err(|e| format!("Cannot write {}: {}", model_path.display(), e))?;
+
+        let metadata_path = self.metadata_path(version);
+        let temporary = metadata_path.with_extension("json.tmp");
+        let metadata = serde_json::to_vec(checkpoint).map_err(|e| e.to_string())?;
+        fs::write(&temporary, metadata)
+            .and_then(|_| fs::rename(&temporary, &metadata_path))
+            .map_err(|e| format!("Cannot write {}: {}", metadata_path.display(), e))?;
+        self.last_version = Some(version);
+        self.last_saved = Some(checkpoint.clone());
+        self.prune()?;
+        Ok(metadata_path)
+    }
+
+    /// Newest complete checkpoint with the path of its model file
+    pub fn latest(&self) -> Result<Option<(Checkpoint, PathBuf)>, String> {
+        let version = match self.versions()?.last() {
+            Some(version) => *version,
+            None => return Ok(None),
+        };
+        let metadata_path = self.metadata_path(version);
+        let metadata = fs::read(&metadata_path).map_err(|e| format!("Cannot read {}: {}", metadata_path.display(), e))?;
+        let checkpoint = serde_json::from_slice(&metadata).map_err(|e| format!("Invalid checkpoint {}: {}", metadata_path.display(), e))?;
+        Ok(Some((checkpoint, self.model_path(version))))
+    }
+
+    /// Versions of the complete checkpoints, oldest first
+    fn versions(&self) -> Result<Vec<usize>, String> {
+        let entries = fs::read_dir(&self.config.dir).map_err(|e| format!("Cannot read {}: {}", self.config.dir, e))?;
+        let mut versions = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                name.strip_prefix("checkpoint-")?.strip_suffix(".json")?.parse::<usize>().ok()
+            })
+            .filter(|version| self.model_path(*version).exists())
+            .collect::<Vec<_>>();
+        versions.sort_unstable();
+        Ok(versions)
+    }
+
+    /// Deletes all but the newest `keep` checkpoints
+    fn prune(&self) -> Result<(), String> {
+        let versions = self.versions()?;
+        for version in &versions[..versions.len().saturating_sub(self.config.keep)] {
+            for path in [self.metadata_path(*version), self.model_path(*version)] {
+                fs::remove_file(&path).map_err(|e| format!("Cannot delete {}: {}", path.display(), e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(model_version: usize) -> Checkpoint {
+        Checkpoint {
+            model_version,
+            round_id: model_version as u64,
+            last_round: None,
+            pending_round: None,
+            clients: None,
+            aggregator: Some(serde_json::json!({ "clip_norm": 0.5 })),
+            asynchronous: None,
+            privacy_accountant: PrivacyAccountant::new(8.0, 1e-5),
+            saved_at: 0,
+        }
+    }
+
+    // Test that the newest checkpoint is found again and older ones are pruned
+    #[test]
+    fn test_checkpoint_store() {
+        let dir = std::env::temp_dir().join(format!("rustfl-checkpoints-{}", std::process::id()));
+        let config = CheckpointConfig { dir: dir.to_string_lossy().to_string(), interval_secs: 1, keep: 2 };
+        let mut store = CheckpointStore::new(config.clone()).unwrap();
+        assert!(store.latest().unwrap().is_none());
+
+        for version in 1..=3 {
+            store.save(&checkpoint(version), |path| fs::write(path, [version as u8]).map_err(|e| e.to_string())).unwrap();
+        }
+        // A model file without its checkpoint file is an interrupted save
+        fs::write(store.model_path(4), [4]).unwrap();
+
+        let store = CheckpointStore::new(config).unwrap();
+        assert_eq!(store.last_version(), Some(3));
+        let (latest, model_path) = store.latest().unwrap().unwrap();
+        assert_eq!((latest.model_version, latest.round_id), (3, 3));
+        assert_eq!(latest.aggregator.unwrap()["clip_norm"], 0.5);
+        assert_eq!(fs::read(model_path).unwrap(), vec![3]);
+        assert!(!store.model_path(1).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Test that a failing model save leaves no checkpoint behind
+    #[test]
+    fn test_checkpoint_failed_save() {
+        let dir = std::env::temp_dir().join(format!("rustfl-checkpoints-failed-{}", std::process::id()));
+        let config = CheckpointConfig { dir: dir.to_string_lossy().to_string(), interval_secs: 1, keep: 2 };
+        let mut store = CheckpointStore::new(config).unwrap();
+        assert!(store.save(&checkpoint(1), |_| Err("disk full".to_string())).is_err());
+        assert!(store.latest().unwrap().is_none());
+        assert!(CheckpointStore::new(CheckpointConfig { keep: 0, ..Default::default() }).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
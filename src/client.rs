@@ -8,21 +8,44 @@ pub use reqwest::Client;
 pub use serde_json::Value;
 pub use tch::{kind, nn::{self, Conv2D, Linear, Module, Optimizer, OptimizerConfig, Sgd, VarStore}, Device, Kind, Tensor};
 pub use serde::{Deserialize, Serialize};
-use crate::secure_dp_utils::{DPMechanism,secret_share_weights,encrypt_share};
+use crate::auth::{encode_public_key, generate_signing_key, unix_time, ClientCredentials, Registration, RegistrationRequest};
+use crate::distributed_dp::SkellamMechanism;
+use crate::key_management::{client_session_key, EncryptionHeader, ServerPublicKey};
+use crate::paillier::{weighted_values, DecryptionKeyShare, PaillierPublicKey, PartialDecryption};
+use crate::secure_aggregation::{encode_weighted_input, AdvertisedKeys, EncryptedShare, SecureAggregationClient};
+use crate::privacy_accountant::{PrivacyAccountant, SampledGaussian};
+use crate::round::CheckInStatus;
+use crate::selection::CheckIn;
+use crate::secure_dp_utils::{share_state_dict, DPMechanism};
+use crate::tls::ClientTlsConfig;
+use crate::state_dict::{load_state_dict, state_dict_from_var_store, StateDict};
 
 //Implemented by Sharvani Chelumalla
 /// Struct to represent weight updates sent to the server.
 #[derive(Serialize, Deserialize)]
 pub struct WeightsUpdate {
-    /// Weights of the model.
+    /// Weights of the model, one encoded named tensor per parameter.
     pub model_weights: Vec<String>,
     pub num_samples: usize,
     pub loss: f64,
     pub model_version: usize,
+    /// Present when `model_weights` are encrypted with a session key agreed with the server.
+    #[serde(default)]
+    pub encryption: Option<EncryptionHeader>,
+    /// Identifier of the client, lets the server refuse duplicate updates within a round.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Round the update was trained for, the current round of the server when `None`.
+    #[serde(default)]
+    pub round_id: Option<u64>,
+    /// Threshold of the Shamir shares `model_weights` hold, plain named tensors when `None`.
+    #[serde(default)]
+    pub share_threshold: Option<usize>,
 }
 
 //Implemented by Sharvani Chelumalla
 /// Configurations required for training and noise mechanism
+#[derive(Debug, Clone)]
 pub struct Config {
     pub learning_rate: f64,
     pub batch_size: usize,
@@ -30,6 +53,19 @@ pub struct Config {
     pub num_rounds: usize,
     pub sensitivity: f64,
     pub epsilon: f64,
+    /// Weight of the FedProx proximal term, 0 trains with plain FedAvg.
+    pub proximal_mu: f64,
+    /// Total epsilon the client may spend across DP-SGD training and uploads, infinite only tracks the spending.
+    pub privacy_budget: f64,
+    /// Delta of the upload noise calibration and of the guarantee tracked by the privacy accountant.
+    pub target_delta: f64,
+    /// Trains with DP-SGD, spreading what is left of `privacy_budget` over all rounds of local training after
+    /// reserving the noise of the upload.
+    pub dp_sgd: bool,
+    /// L2 bound of every per-sample gradient in DP-SGD.
+    pub max_grad_norm: f64,
+    /// Identifier sent with every update, anonymous when `None`.
+    pub client_id: Option<String>,
 }
 
 //Implemented by Sharvani Chelumalla
@@ -48,10 +84,33 @@ impl Config {
             noise_level,
             num_rounds,
             sensitivity,
-            epsilon
+            epsilon,
+            proximal_mu: 0.0,
+            privacy_budget: f64::INFINITY,
+            target_delta: 1e-5,
+            dp_sgd: false,
+            max_grad_norm: 1.0,
+            client_id: None,
         }
     }
 
+    /// Gaussian mechanism applied to every upload, clipping updates to an L2 norm of `sensitivity`.
+    /// Fails unless epsilon and the sensitivity are positive and the delta is in (0, 1).
+    pub fn dp_mechanism(&self) -> Result<DPMechanism, String> {
+        DPMechanism::gaussian(self.epsilon, self.target_delta, self.sensitivity)
+    }
+
+    /// Gaussian mechanism of one noisy upload, as recorded by the privacy accountant
+    pub fn upload_mechanism(&self) -> Result<SampledGaussian, String> {
+        let dp_mechanism = self.dp_mechanism()?;
+        Ok(SampledGaussian::single(dp_mechanism.noise_std() / dp_mechanism.sensitivity))
+    }
+
+    /// Privacy accountant enforcing the configured budget
+    pub fn privacy_accountant(&self) -> PrivacyAccountant {
+        PrivacyAccountant::new(self.privacy_budget, self.target_delta)
+    }
+
     /// Default configuration values if not defined by user
     pub fn default() -> Self {
         Config {
@@ -61,7 +120,59 @@ impl Config {
             num_rounds: 3,
             sensitivity: 1.0,  // Sensitivity of the function (adjust as necessary)
             epsilon: 0.5,  // Privacy budget (adjust as necessary)
+            proximal_mu: 0.0,
+            privacy_budget: f64::INFINITY,
+            target_delta: 1e-5,
+            dp_sgd: false,
+            max_grad_norm: 1.0,
+            client_id: None,
+        }
+    }
+}
+
+/// DP-SGD parameters of a client: clipping bound and calibrated noise.
+/// The privacy spent is recorded by the accountant of the client, shared with its uploads.
+pub struct DpSgd {
+    /// L2 bound of every per-sample gradient.
+    pub max_grad_norm: f64,
+    /// Noise standard deviation divided by `max_grad_norm`.
+    pub noise_multiplier: f64,
+}
+
+impl DpSgd {
+    /// DP-SGD with a user defined noise multiplier
+    pub fn new(max_grad_norm: f64, noise_multiplier: f64) -> Self {
+        DpSgd {
+            max_grad_norm,
+            noise_multiplier,
+        }
+    }
+
+    /// DP-SGD whose noise spends what is left of the budget of `accountant` over `steps` steps at
+    /// `sampling_rate`, once the `reserved` mechanisms are accounted for
+    pub fn for_budget(
+        max_grad_norm: f64,
+        accountant: &PrivacyAccountant,
+        reserved: &[SampledGaussian],
+        sampling_rate: f64,
+        steps: usize,
+    ) -> Result<Self, String> {
+        let noise_multiplier = accountant.noise_multiplier_within_budget(sampling_rate, steps, reserved)?;
+        info!("DP-SGD noise multiplier: {:.4}", noise_multiplier);
+        Ok(DpSgd::new(max_grad_norm, noise_multiplier))
+    }
+
+    /// DP-SGD for the configured rounds over the given training batches, within the budget left in `accountant`
+    /// after the upload that follows the training
+    pub fn from_config(config: &Config, train_loader: &[(Tensor, Tensor)], accountant: &PrivacyAccountant) -> Result<Self, String> {
+        let num_samples = count_samples(train_loader);
+        let batch_size = train_loader.first().map(|(_, target)| target.size()[0] as usize).unwrap_or(0);
+        if num_samples == 0 {
+            return Err("No training data".to_string());
         }
+        let sampling_rate = batch_size as f64 / num_samples as f64;
+        let steps = config.num_rounds * train_loader.len();
+        DpSgd::for_budget(config.max_grad_norm, accountant, &[config.upload_mechanism()?], sampling_rate, steps)
     }
 }
 
@@ -79,10 +190,10 @@ pub struct SimpleCNN {
 impl SimpleCNN {
     ///Construction of CNN layers
     pub fn new(vs: &nn::Path) -> SimpleCNN {
-        let conv1 = nn::conv2d(vs, 1, 32, 3, Default::default());
+        let conv1 = nn::conv2d(vs / "conv1", 1, 32, 3, Default::default());
         //let conv2 = nn::conv2d(vs, 32, 64, 3, Default::default());
-        let fc1 = nn::linear(vs, 32 * 13 * 13, 128, Default::default());
-        let fc2 = nn::linear(vs, 128, 10, Default::default());
+        let fc1 = nn::linear(vs / "fc1", 32 * 13 * 13, 128, Default::default());
+        let fc2 = nn::linear(vs / "fc2", 128, 10, Default::default());
 
         SimpleCNN {
             conv1,
@@ -119,8 +230,9 @@ impl SimpleCNN {
 }
 
 //Implemented by Sainath Talaknati
-/// Function to load and normalize training data using the path directory of dataset
-pub fn get_train_data(data_dir: String) -> Vec<(Tensor, Tensor)> {
+/// Function to load and normalize training data using the path directory of dataset,
+/// batched with the configured batch size
+pub fn get_train_data(data_dir: String, config: &Config) -> Vec<(Tensor, Tensor)> {
     #[derive(Debug)]
     /// Normalizing the values for dataset for optimal values
     struct Normalize {
@@ -166,7 +278,7 @@ pub fn get_train_data(data_dir: String) -> Vec<(Tensor, Tensor)> {
     }
     let mut batches = Vec::new();
 
-    for chunk in train_dataset.chunks(Config::default().batch_size) {
+    for chunk in train_dataset.chunks(config.batch_size) {
         if chunk.is_empty() {
             continue; // Skip empty chunks
         }
@@ -186,82 +298,117 @@ pub fn get_train_data(data_dir: String) -> Vec<(Tensor, Tensor)> {
 }
 
 //Implemented by Sainath Talaknati
-/// Asynchronously start the training process.
+/// Asynchronously start the training process for the configured number of rounds.
+#[allow(clippy::too_many_arguments)]
 pub async fn start_training(
+    client: &ServerConnection,
     train_loader: Vec<(Tensor, Tensor)>,
     model: &mut SimpleCNN,
+    vs: &mut VarStore,
+    optimizer: &mut Optimizer,
+    criterion: &dyn Fn(&Tensor, &Tensor) -> Tensor,
+    device: Device,
+    get_url: &str,
+    config: &Config,
+    accountant: &mut PrivacyAccountant,
+) -> Result<(f64, StateDict, usize), String> {
+    run_training(client, &train_loader, model, vs, optimizer, criterion, device, get_url, config, accountant).await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_training(
+    client: &ServerConnection,
+    train_loader: &[(Tensor, Tensor)],
+    model: &mut SimpleCNN,
+    vs: &mut VarStore,
     optimizer: &mut Optimizer,
     criterion: &dyn Fn(&Tensor, &Tensor) -> Tensor,
     device: Device,
     get_url: &str,
-) -> (f64, Vec<f64>,usize){
-    let client = Client::new();
-
-    // Fetch initial model version.
-    let initial_response = client.get(get_url).send().await.unwrap();
-    let data: Value = initial_response.json().await.unwrap();
-    let model_version = data.get("model_version")
-        .and_then(|v| v.as_f64())
-        .map(|v| v as usize)
-        .expect("model_version is not a valid Integer");
+    config: &Config,
+    accountant: &mut PrivacyAccountant,
+) -> Result<(f64, StateDict, usize), String> {
     // Training for a defined number of rounds.
     let mut loss_value= 0.0 ;
     let mut trained_weights= vec![];
-    for round_num in 0..Config::default().num_rounds {
+    let mut model_version = None;
+    let dp_sgd = if config.dp_sgd {
+        Some(DpSgd::from_config(config, train_loader, accountant).map_err(|e| format!("Failed to calibrate DP-SGD: {}", e))?)
+    } else {
+        None
+    };
+    for round_num in 0..config.num_rounds {
         info!("Round {}", round_num + 1);
-        fetch_global_model(model,get_url).await.unwrap();
+        // The update is tagged with the version of the global model it was trained on.
+        model_version = Some(fetch_global_model(client, vs, get_url).await?);
+        let global_weights = state_dict_from_var_store(vs)?;
 
         // Train the local model and send weights to the server.
-        let (avg_loss, train_weights) = train_local_model(&train_loader, model, optimizer, criterion, device);
+        let (avg_loss, train_weights) = match dp_sgd.as_ref() {
+            Some(dp_sgd) => train_local_model_dp(train_loader, model, vs, optimizer, criterion, device, dp_sgd, accountant)?,
+            None => train_local_model(train_loader, model, vs, optimizer, criterion, device, config.proximal_mu)?,
+        };
         loss_value = avg_loss;
-        trained_weights = train_weights
+        // Bound the update so that the noise added on upload has a real sensitivity.
+        trained_weights = config
+            .dp_mechanism()?
+            .clip_update(&global_weights, &train_weights)
+            .map_err(|e| format!("Trained parameters do not match the global model: {}", e))?
     }
-    info!("Training completed for 3 rounds");
-    (loss_value,trained_weights,model_version)
+    let model_version = model_version.ok_or("No training round is configured".to_string())?;
+    info!("Training completed for {} rounds", config.num_rounds);
+    Ok((loss_value,trained_weights,model_version))
 
 }
 
 //Implemented by Sainath Talaknati
-/// Asynchronously fetch the global model from the server.
-pub async fn fetch_global_model<'a>(model: &'a SimpleCNN,get_url: &str) -> Result<&'a SimpleCNN, reqwest::Error> {
-    let client = Client::new();
-
+/// Asynchronously fetch the global model from the server and load it into the var store.
+/// Returns the version of the fetched global model.
+pub async fn fetch_global_model(client: &ServerConnection, vs: &mut VarStore, get_url: &str) -> Result<usize, String> {
     // Send GET request to fetch the global model.
-    let response = client.get(get_url).send().await?;
+    let response = client.get(get_url).await?;
 
     if response.status().is_success() {
-        let data: Value = response.json().await?;
+        let data: Value = response.json().await.map_err(|e| e.to_string())?;
 
-        let _global_model_weights = data.get("model_state_dict").unwrap();
-        let _model_version = data.get("model_version").cloned();
+        let global_model_weights: StateDict = data.get("model_state_dict")
+            .cloned()
+            .ok_or("Response has no model_state_dict".to_string())
+            .and_then(|v| serde_json::from_value(v).map_err(|e| format!("Invalid model_state_dict: {}", e)))?;
+        let model_version = data.get("model_version")
+            .and_then(|v| v.as_u64())
+            .ok_or("model_version is not a valid Integer".to_string())? as usize;
 
         // Load the fetched global model weights into the model.
-        /*******************
-         model.load_state_dict(global_model_weights, false)?;
-        *********************/
+        load_state_dict(vs, &global_model_weights)
+            .map_err(|e| format!("Failed to load global model: {}", e))?;
 
-        info!("Fetched global model");
-        //data.get("model_version")
-        Ok(model)
+        info!("Fetched global model, Version: {}", model_version);
+        Ok(model_version)
     } else {
-        error!("Failed to fetch global model");
-        Err(reqwest::Error::from(response.error_for_status().unwrap_err()))
+        Err(format!("Failed to fetch global model: {}", response.status()))
     }
 }
 
 //Implemented by Sainath Talaknati
 /// Function to train the local model.
+/// Returns the average loss and the named parameters of the trained model.
 pub fn train_local_model(
-    train_loader: &Vec<(Tensor, Tensor)>,
+    train_loader: &[(Tensor, Tensor)],
     model: &mut SimpleCNN,
+    vs: &VarStore,
     optimizer: &mut Optimizer,
     criterion: &dyn Fn(&Tensor, &Tensor) -> Tensor,
-    device: Device
-) -> (f64, Vec<f64>) {
+    device: Device,
+    proximal_mu: f64
+) -> Result<(f64, StateDict), String> {
     //model.train();
     let mut running_loss = 0.0;
     info!("Training");
 
+    // Snapshot of the fetched global weights, anchor of the FedProx proximal term.
+    let global_weights = vs.trainable_variables().iter().map(|t| t.detach().copy()).collect::<Vec<_>>();
+
     for (batch_idx, (data, target)) in train_loader.iter().enumerate() {
         let data = data.to(device); //.view([-1,1,28,28]); ON HOLD
         //info!("Data shape: {:?}", data.size());
@@ -280,7 +427,11 @@ pub fn train_local_model(
         let output = model.forward(&data);
         //info!("Output shape: {:?}", output.size());
         let loss = criterion(&output, &target);
-        loss.backward();
+        if proximal_mu > 0.0 {
+            (&loss + proximal_term(vs, &global_weights, proximal_mu)).backward();
+        } else {
+            loss.backward();
+        }
         optimizer.step();
 
         running_loss += loss.double_value(&[]);
@@ -293,58 +444,567 @@ pub fn train_local_model(
 
     let avg_loss = running_loss / train_loader.len() as f64;
     info!("Average Loss: {}", avg_loss);
-    /*******************
-    (avg_loss, model.state_dict())
-    *******************/
-    (avg_loss, vec![1f64, 2f64])
+    let trained_weights = state_dict_from_var_store(vs).map_err(|e| format!("Failed to read the trained parameters: {}", e))?;
+    Ok((avg_loss, trained_weights))
+
+}
+
+/// Trains the local model with DP-SGD: every per-sample gradient is clipped to `max_grad_norm` and
+/// Gaussian noise of std `noise_multiplier * max_grad_norm` is added to the sum of each batch.
+/// The epoch is recorded by `accountant` and refused if it would exceed the budget.
+/// Fixed shuffled batches are accounted as Poisson subsampling at rate `batch_size / num_samples`.
+#[allow(clippy::too_many_arguments)]
+pub fn train_local_model_dp(
+    train_loader: &[(Tensor, Tensor)],
+    model: &mut SimpleCNN,
+    vs: &VarStore,
+    optimizer: &mut Optimizer,
+    criterion: &dyn Fn(&Tensor, &Tensor) -> Tensor,
+    device: Device,
+    dp_sgd: &DpSgd,
+    accountant: &mut PrivacyAccountant
+) -> Result<(f64, StateDict), String> {
+    let num_samples = count_samples(train_loader);
+    let batch_size = train_loader.first().map(|(_, target)| target.size()[0]).unwrap_or(0);
+    if num_samples == 0 {
+        return Err("No training data".to_string());
+    }
+    let epoch = SampledGaussian {
+        sampling_rate: batch_size as f64 / num_samples as f64,
+        noise_multiplier: dp_sgd.noise_multiplier,
+        steps: train_loader.len(),
+    };
+    let epsilon = accountant.spend(&epoch)?;
+    info!("DP-SGD training, epsilon spent after this epoch: {:.3}", epsilon);
+
+    let variables = vs.trainable_variables();
+    let noise_std = dp_sgd.noise_multiplier * dp_sgd.max_grad_norm;
+    let mut running_loss = 0.0;
+
+    for (batch_idx, (data, target)) in train_loader.iter().enumerate() {
+        let target = target.to(device);
+        let size = target.size()[0];
+        // Batches hold the flattened images one after the other, one row per sample is needed to slice them
+        let data = data.to(device).reshape([size, -1]);
+        let mut clipped_sum = variables.iter().map(|v| v.zeros_like()).collect::<Vec<_>>();
+        let mut batch_loss = 0.0;
+
+        // Per-sample gradients, each clipped to max_grad_norm before being summed
+        for i in 0..size {
+            optimizer.zero_grad();
+            let loss = criterion(&model.forward(&data.narrow(0, i, 1)), &target.narrow(0, i, 1));
+            loss.backward();
+            batch_loss += loss.double_value(&[]);
+
+            let gradients = variables.iter().map(|v| v.grad()).collect::<Vec<_>>();
+            let norm = gradients
+                .iter()
+                .map(|g| g.square().sum(Kind::Double).double_value(&[]))
+                .sum::<f64>()
+                .sqrt();
+            let scale = (dp_sgd.max_grad_norm / (norm + 1e-12)).min(1.0);
+            tch::no_grad(|| {
+                for (sum, gradient) in clipped_sum.iter_mut().zip(gradients.iter()) {
+                    *sum += gradient * scale;
+                }
+            });
+        }
+
+        // Noisy average gradient installed in place of the last per-sample gradient
+        tch::no_grad(|| {
+            for (variable, sum) in variables.iter().zip(clipped_sum.iter()) {
+                let noise = sum.randn_like() * noise_std;
+                variable.grad().copy_(&((sum + noise) / size as f64));
+            }
+        });
+        optimizer.step();
+
+        running_loss += batch_loss / size as f64;
+        if batch_idx % 100 == 0 {
+            info!("Batch {}/{}, Loss: {}", batch_idx, train_loader.len(), batch_loss / size as f64);
+        }
+    }
+
+    let avg_loss = running_loss / train_loader.len() as f64;
+    info!("Average Loss: {}", avg_loss);
+    Ok((avg_loss, state_dict_from_var_store(vs)?))
+}
+
+/// FedProx proximal term `mu / 2 * ||w - w_global||^2` keeping the local model close to the global one
+pub fn proximal_term(vs: &VarStore, global_weights: &[Tensor], proximal_mu: f64) -> Tensor {
+    let squared_distances = vs
+        .trainable_variables()
+        .iter()
+        .zip(global_weights.iter())
+        .map(|(weight, global_weight)| (weight - global_weight).square().sum(Kind::Float))
+        .collect::<Vec<_>>();
+    Tensor::stack(&squared_distances, 0).sum(Kind::Float) * (proximal_mu / 2.0)
+}
+
+/// Counts the training samples in the batches returned by `get_train_data`
+pub fn count_samples(train_loader: &[(Tensor, Tensor)]) -> usize {
+    train_loader
+        .iter()
+        .map(|(_, target)| target.size()[0] as usize)
+        .sum()
+}
 
+/// Fetches the public key the server currently expects updates to be encrypted for
+pub async fn fetch_server_public_key(client: &ServerConnection, key_url: &str) -> Result<ServerPublicKey, String> {
+    let response = client.get(key_url).await?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to fetch the server public key: {}", response.status()));
+    }
+    response.json().await.map_err(|e| format!("Invalid server public key: {}", e))
 }
 
+/// Shamir shares every upload is split into
+pub const UPLOAD_SHARES: usize = 3;
+/// Shares the server needs to recover an upload
+pub const UPLOAD_SHARE_THRESHOLD: usize = 2;
+
 //Implemented by Sainath Talaknati
 /// To Asynchronously send local model weights to the server.
+/// Noise calibrated to the clipping bound of `Config::dp_mechanism` is added to every named parameter
+/// (the update is clipped by `start_training`), which is then split into `UPLOAD_SHARES` Shamir shares.
+/// Every share is encrypted with a session key agreed with the server public key served at `key_url`, and
+/// the shares are uploaded for round `round_id`, the current round of the server when `None`.
+/// The upload is refused if it would exceed the privacy budget, and recorded by `accountant` once the server
+/// answered it.
+#[allow(clippy::too_many_arguments)]
 pub async fn send_local_model_weights(
-    weights: Vec<f64>,
+    client: &ServerConnection,
+    weights: StateDict,
+    num_samples: usize,
     loss_value: f64,
     model_version: usize,
-    model: &SimpleCNN,
-    encryption_key: &str,
-    _device: Device,
+    round_id: Option<u64>,
+    vs: &mut VarStore,
+    accountant: &mut PrivacyAccountant,
+    config: &Config,
     get_url: &str,
-    post_url: &str
-) {
-    let dp_mechanism = DPMechanism::new(Config::default().epsilon, Config::default().sensitivity);
-    let model_weights_list_noisy: Vec<f64> = dp_mechanism.add_noise(&weights);
-    let shared_weights = secret_share_weights(model_weights_list_noisy, 3, 2, Config::default().noise_level);
-    let encrypted_shares = shared_weights
+    post_url: &str,
+    key_url: &str
+) -> Result<(), String> {
+    let dp_mechanism = config.dp_mechanism()?;
+    let upload = config.upload_mechanism()?;
+    if !accountant.can_spend(&upload) {
+        return Err("Privacy budget exhausted: the update cannot be uploaded".to_string());
+    }
+
+    let (encryption, session_key) = fetch_server_public_key(client, key_url).await.and_then(|key| client_session_key(&key))?;
+
+    let noisy_weights = weights
+        .into_iter()
+        .map(|mut named_tensor| {
+            named_tensor.data = dp_mechanism.add_noise(&named_tensor.data);
+            named_tensor
+        })
+        .collect::<StateDict>();
+    let encrypted_shares = share_state_dict(&noisy_weights, UPLOAD_SHARES, UPLOAD_SHARE_THRESHOLD)?
         .iter()
-        .map(|share| encrypt_share(&format!("{:?}", share), encryption_key))
-        .map(|encrypted_bytes| String::from_utf8(encrypted_bytes.unwrap()).unwrap())
-        .collect();
+        .map(|shared_tensor| {
+            serde_json::to_string(shared_tensor)
+                .map(|payload| session_key.encrypt(payload.as_bytes()))
+                .map_err(|e| format!("Failed to encode a share of '{}': {}", shared_tensor.name, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
     let client_updates = WeightsUpdate {
         model_weights: encrypted_shares,
-        num_samples: weights.len() as usize,
+        num_samples,
         loss: loss_value as f64,
         model_version,
+        encryption: Some(encryption),
+        client_id: config.client_id.clone(),
+        round_id,
+        share_threshold: Some(UPLOAD_SHARE_THRESHOLD),
     };
 
-    let client = Client::new();
     // Send the weight update as a JSON payload.
-    let response = client.post(post_url)
-        .json(&client_updates)
-        .send()
-        .await.unwrap();
+    let response = client.post_json(post_url, &client_updates).await?;
+    // The server received the noisy weights once it answered, even when it refuses them
+    let epsilon = accountant.spend(&upload)?;
+    info!("Privacy spent after this upload: epsilon = {:.3}", epsilon);
 
     if response.status().is_success() {
         info!("Model update successful");
     } else if response.status().as_u16() == 409 {
-        warn!("Model version mismatch. Fetching the latest model.");
-        fetch_global_model(model,get_url).await.unwrap(); // Fetch the latest model if there's a version mismatch.
+        let body = response.json::<Value>().await.unwrap_or_default();
+        warn!("Update refused by the server: {}. Fetching the latest model.", body);
+        // Fetch the latest model if there's a version mismatch.
+        fetch_global_model(client, vs, get_url).await?;
     } else {
-        error!("Failed to send model update: {}", response.status());
+        return Err(format!("Failed to send model update: {}", response.status()));
+    }
+    Ok(())
+}
+
+/// HTTP connection to the server, authenticating every request when the client is registered
+#[derive(Clone, Default)]
+pub struct ServerConnection {
+    http: Client,
+    credentials: Option<ClientCredentials>,
+}
+
+impl ServerConnection {
+    /// Connection authenticating its requests with `credentials`
+    pub fn new(credentials: ClientCredentials) -> Self {
+        ServerConnection { http: Client::new(), credentials: Some(credentials) }
+    }
+
+    pub fn credentials(&self) -> Option<&ClientCredentials> {
+        self.credentials.as_ref()
+    }
+
+    /// Talks to the server over TLS, trusting the CA and presenting the client certificate of `tls`
+    pub fn with_tls(mut self, tls: &ClientTlsConfig) -> Result<Self, String> {
+        self.http = tls.http_client()?;
+        Ok(self)
+    }
+
+    /// Sends a GET request to `url`
+    pub async fn get(&self, url: &str) -> Result<reqwest::Response, String> {
+        let request = self.authenticate(self.http.get(url), "GET", url, b"")?;
+        request.send().await.map_err(|e| e.to_string())
+    }
+
+    /// Sends `body` as JSON in a POST request to `url`
+    pub async fn post_json<T: Serialize + ?Sized>(&self, url: &str, body: &T) -> Result<reqwest::Response, String> {
+        let body = serde_json::to_vec(body).map_err(|e| e.to_string())?;
+        let request = self.http.post(url).header("content-type", "application/json");
+        let request = self.authenticate(request, "POST", url, &body)?;
+        request.body(body).send().await.map_err(|e| e.to_string())
+    }
+
+    fn authenticate(&self, request: reqwest::RequestBuilder, method: &str, url: &str, body: &[u8]) -> Result<reqwest::RequestBuilder, String> {
+        let credentials = match &self.credentials {
+            Some(credentials) => credentials,
+            None => return Ok(request),
+        };
+        // The server verifies signatures against the path and query it received
+        let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        Ok(credentials
+            .headers(method, &path, body, unix_time())
+            .into_iter()
+            .fold(request, |request, (name, value)| request.header(name, value)))
+    }
+}
+
+/// Loss function of the local training, called with the model output and the target
+pub type Criterion = Box<dyn Fn(&Tensor, &Tensor) -> Tensor>;
+
+/// Federated learning client owning its configuration, model, optimizer and connection to the server
+pub struct FederatedClient {
+    pub config: Config,
+    /// Base URL of the server, e.g. `http://0.0.0.0:8081`.
+    pub server_url: String,
+    pub device: Device,
+    pub vs: VarStore,
+    pub model: SimpleCNN,
+    pub optimizer: Optimizer,
+    /// Privacy spent by the DP-SGD training and the uploads of this client, enforcing `config.privacy_budget`.
+    pub accountant: PrivacyAccountant,
+    criterion: Criterion,
+    http: ServerConnection,
+    /// Loss of the last local training, reported when checking in.
+    last_loss: Option<f64>,
+    /// Round the server selected this client for, sent along with its update.
+    round_id: Option<u64>,
+}
+
+impl FederatedClient {
+    /// Client training a fresh `SimpleCNN` with SGD at the configured learning rate and a cross-entropy loss
+    pub fn new(config: Config, server_url: &str, device: Device) -> Result<Self, String> {
+        let vs = VarStore::new(device);
+        let model = SimpleCNN::new(&vs.root());
+        let optimizer = Sgd::default().build(&vs, config.learning_rate).map_err(|e| e.to_string())?;
+        Ok(FederatedClient {
+            accountant: config.privacy_accountant(),
+            config,
+            server_url: server_url.trim_end_matches('/').to_string(),
+            device,
+            vs,
+            model,
+            optimizer,
+            criterion: Box::new(|output: &Tensor, target: &Tensor| output.cross_entropy_for_logits(target).mean(Kind::Float)),
+            http: ServerConnection::default(),
+            last_loss: None,
+            round_id: None,
+        })
+    }
+
+    /// Talks to the server over TLS, trusting the CA and presenting the client certificate of `tls`
+    pub fn with_tls(mut self, tls: &ClientTlsConfig) -> Result<Self, String> {
+        self.http = self.http.with_tls(tls)?;
+        Ok(self)
+    }
+
+    /// Connection to the server with the TLS configuration and credentials of this client
+    pub fn connection(&self) -> &ServerConnection {
+        &self.http
+    }
+
+    /// Replaces the loss function used for local training
+    pub fn with_criterion<F: Fn(&Tensor, &Tensor) -> Tensor + 'static>(mut self, criterion: F) -> Self {
+        self.criterion = Box::new(criterion);
+        self
+    }
+
+    /// Full URL of a server endpoint
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.server_url, path)
+    }
+
+    /// Loads the MNIST training data batched with the configured batch size
+    pub fn load_train_data(&self, data_dir: &str) -> Vec<(Tensor, Tensor)> {
+        get_train_data(data_dir.to_string(), &self.config)
+    }
+
+    /// Fetches the global model into the local model, returns its version
+    pub async fn fetch_global_model(&mut self) -> Result<usize, String> {
+        let get_url = self.url("/get_model");
+        fetch_global_model(&self.http, &mut self.vs, &get_url).await
+    }
+
+    /// Trains for the configured number of rounds, returns the loss, the clipped weights and the model version
+    pub async fn train(&mut self, train_loader: &[(Tensor, Tensor)]) -> Result<(f64, StateDict, usize), String> {
+        let get_url = self.url("/get_model");
+        run_training(
+            &self.http,
+            train_loader,
+            &mut self.model,
+            &mut self.vs,
+            &mut self.optimizer,
+            self.criterion.as_ref(),
+            self.device,
+            &get_url,
+            &self.config,
+            &mut self.accountant,
+        ).await
+    }
+
+    /// Adds the configured noise to the trained weights, shares and encrypts them for the server and uploads them
+    /// for the round this client was selected for
+    pub async fn send_update(&mut self, weights: StateDict, num_samples: usize, loss_value: f64, model_version: usize) -> Result<(), String> {
+        let (get_url, post_url, key_url) = (self.url("/get_model"), self.url("/update_model"), self.url("/public_key"));
+        send_local_model_weights(
+            &self.http,
+            weights,
+            num_samples,
+            loss_value,
+            model_version,
+            self.round_id,
+            &mut self.vs,
+            &mut self.accountant,
+            &self.config,
+            &get_url,
+            &post_url,
+            &key_url,
+        ).await
+    }
+
+    /// Registers with the server, which then authenticates every request of this client with an Ed25519
+    /// signature when `signed` or with a bearer token otherwise. The issued id becomes `Config::client_id`.
+    pub async fn register(&mut self, signed: bool, registration_secret: Option<String>) -> Result<String, String> {
+        let signing_key = signed.then(generate_signing_key);
+        let request = RegistrationRequest { public_key: signing_key.as_ref().map(encode_public_key), registration_secret };
+        let data = post_to_server(&self.http, &self.url("/register"), &request).await?;
+        let registration: Registration = serde_json::from_value(data).map_err(|e| e.to_string())?;
+
+        let client_id = registration.client_id.clone();
+        let credentials = match signing_key {
+            Some(signing_key) => ClientCredentials::Ed25519 { client_id, signing_key },
+            None => ClientCredentials::Bearer {
+                client_id,
+                token: registration.token.ok_or("The server issued no bearer token".to_string())?,
+            },
+        };
+        self.http.credentials = Some(credentials);
+        self.config.client_id = Some(registration.client_id.clone());
+        info!("Registered with the server as {}", registration.client_id);
+        Ok(registration.client_id)
+    }
+
+    /// Checks in with the server until it selects this client, for at most `MAX_POLL_SECS`, returns the id of
+    /// the round. Needs `Config::client_id`, servers aggregating asynchronously answer without a round id.
+    pub async fn wait_for_selection(&self, num_samples: usize) -> Result<Option<u64>, String> {
+        let client_id = self.config.client_id.clone().ok_or("Checking in needs a client id".to_string())?;
+        let check_in = CheckIn { client_id, num_samples, loss: self.last_loss, availability: None };
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(MAX_POLL_SECS);
+        loop {
+            let data = post_to_server(&self.http, &self.url("/check_in"), &check_in).await?;
+            let status: CheckInStatus = serde_json::from_value(data["status"].clone()).map_err(|e| e.to_string())?;
+            if status == CheckInStatus::Selected {
+                info!("Selected for round {}", data["round_id"]);
+                return Ok(data["round_id"].as_u64());
+            }
+            if std::time::Instant::now() >= deadline {
+                return Err(format!("Not selected for a round after {} seconds", MAX_POLL_SECS));
+            }
+            // Retries no later than the deadline, whatever the server asks
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now()).as_secs_f64();
+            let retry_after = data["retry_after_secs"].as_f64().unwrap_or(1.0).max(0.5).min(remaining);
+            info!("Check-in {:?} for round {}, retrying in {:.1}s", status, data["round_id"], retry_after);
+            tokio::time::sleep(std::time::Duration::from_secs_f64(retry_after)).await;
+        }
+    }
+
+    /// Trains on `train_loader` and sends the resulting update, after being selected when the client has an id
+    pub async fn run(&mut self, train_loader: &[(Tensor, Tensor)]) -> Result<(), String> {
+        if self.config.client_id.is_some() {
+            self.round_id = self.wait_for_selection(count_samples(train_loader)).await?;
+        }
+        let (loss_value, trained_weights, model_version) = self.train(train_loader).await?;
+        self.last_loss = Some(loss_value);
+        self.send_update(trained_weights, count_samples(train_loader), loss_value, model_version).await
+    }
+}
+
+/// Takes part in a secure aggregation round of the server at `server_url`.
+/// The server only learns the sum of the sample-weighted models of the surviving clients.
+/// Returns the new global model version if this client's message finished the round.
+pub async fn send_secure_model_weights(
+    client: &ServerConnection,
+    weights: &StateDict,
+    num_samples: usize,
+    client_id: u64,
+    server_url: &str
+) -> Result<Option<usize>, String> {
+    run_secure_aggregation(client, client_id, server_url, |_| Ok(encode_weighted_input(weights, num_samples))).await
+}
+
+/// Takes part in a distributed DP secure aggregation round: the delta from `global_weights` is clipped
+/// and noised with the Skellam mechanism announced by the server, so that only the noisy sum is revealed.
+pub async fn send_secure_dp_model_weights(
+    client: &ServerConnection,
+    global_weights: &StateDict,
+    weights: &StateDict,
+    client_id: u64,
+    server_url: &str
+) -> Result<Option<usize>, String> {
+    run_secure_aggregation(client, client_id, server_url, |status| {
+        let mechanism: Option<SkellamMechanism> = serde_json::from_value(status["distributed_dp"].clone()).map_err(|e| e.to_string())?;
+        mechanism
+            .ok_or("The server does not run distributed DP".to_string())?
+            .encode_update(global_weights, weights)
+    }).await
+}
+
+/// Runs the secure aggregation protocol with the input built by `encode` from the round status
+async fn run_secure_aggregation<F: FnOnce(&Value) -> Result<Vec<u64>, String>>(
+    client: &ServerConnection,
+    client_id: u64,
+    server_url: &str,
+    encode: F
+) -> Result<Option<usize>, String> {
+    let status = poll_server(client, &format!("{}/secure_aggregation/status", server_url)).await?;
+    let threshold = status.get("threshold")
+        .and_then(|v| v.as_u64())
+        .ok_or("threshold is not a valid Integer".to_string())? as usize;
+    let input = encode(&status)?;
+    let mut secure_client = SecureAggregationClient::new(client_id, threshold)?;
+
+    post_to_server(client, &format!("{}/secure_aggregation/advertise_keys", server_url), &secure_client.advertise_keys()).await?;
+    let data = poll_server(client, &format!("{}/secure_aggregation/roster", server_url)).await?;
+    let roster: Vec<AdvertisedKeys> = serde_json::from_value(data["roster"].clone()).map_err(|e| e.to_string())?;
+
+    let share_keys = secure_client.share_keys(&roster)?;
+    post_to_server(client, &format!("{}/secure_aggregation/share_keys", server_url), &share_keys).await?;
+    let data = poll_server(client, &format!("{}/secure_aggregation/shares/{}", server_url, client_id)).await?;
+    let share_senders: Vec<u64> = serde_json::from_value(data["share_senders"].clone()).map_err(|e| e.to_string())?;
+    let encrypted_shares: Vec<EncryptedShare> = serde_json::from_value(data["encrypted_shares"].clone()).map_err(|e| e.to_string())?;
+
+    let masked_input = secure_client.mask_input(&input, &share_senders, &encrypted_shares)?;
+    post_to_server(client, &format!("{}/secure_aggregation/masked_input", server_url), &masked_input).await?;
+    let data = poll_server(client, &format!("{}/secure_aggregation/survivors", server_url)).await?;
+    let survivors: Vec<u64> = serde_json::from_value(data["survivors"].clone()).map_err(|e| e.to_string())?;
+
+    let data = post_to_server(client, &format!("{}/secure_aggregation/unmask", server_url), &secure_client.unmask(&survivors)?).await?;
+    info!("Secure aggregation round completed for client {}", client_id);
+    Ok(data.get("model_version").and_then(|v| v.as_u64()).map(|v| v as usize))
+}
+
+/// Encrypts the sample-weighted model with the server's Paillier public key and uploads it.
+/// The server can only sum the ciphertexts, the key holders decrypt the aggregate.
+pub async fn send_paillier_model_weights(
+    client: &ServerConnection,
+    weights: &StateDict,
+    num_samples: usize,
+    loss_value: f64,
+    model_version: usize,
+    server_url: &str
+) -> Result<(), String> {
+    let data = poll_server(client, &format!("{}/paillier/public_key", server_url)).await?;
+    let public_key: PaillierPublicKey = serde_json::from_value(data["public_key"].clone()).map_err(|e| e.to_string())?;
+
+    let client_updates = WeightsUpdate {
+        model_weights: public_key.encrypt_values(&weighted_values(weights, num_samples))?,
+        num_samples,
+        loss: loss_value,
+        model_version,
+        encryption: None,
+        client_id: None,
+        round_id: None,
+        share_threshold: None,
+    };
+    post_to_server(client, &format!("{}/paillier/update_model", server_url), &client_updates).await?;
+    info!("Encrypted model update successful");
+    Ok(())
+}
+
+/// Waits for the encrypted aggregate and uploads the partial decryption of a key holder
+pub async fn send_partial_decryption(client: &ServerConnection, share: &DecryptionKeyShare, server_url: &str) -> Result<(), String> {
+    let data = poll_server(client, &format!("{}/paillier/public_key", server_url)).await?;
+    let public_key: PaillierPublicKey = serde_json::from_value(data["public_key"].clone()).map_err(|e| e.to_string())?;
+    let data = poll_server(client, &format!("{}/paillier/aggregate", server_url)).await?;
+    let aggregate: Vec<String> = serde_json::from_value(data["aggregate"].clone()).map_err(|e| e.to_string())?;
+
+    let message = PartialDecryption {
+        index: share.index,
+        partial_decryption: share.partial_decrypt(&public_key, &aggregate)?,
+    };
+    post_to_server(client, &format!("{}/paillier/partial_decryption", server_url), &message).await?;
+    info!("Partial decryption of key holder {} sent", share.index);
+    Ok(())
+}
+
+/// Longest time the client waits for the server, e.g. for the other clients of a secure aggregation round or to
+/// be selected for a round
+pub const MAX_POLL_SECS: u64 = 600;
+
+/// Polls an endpoint until the server reaches the state serving it (409 until then), for at most `MAX_POLL_SECS`
+async fn poll_server(client: &ServerConnection, url: &str) -> Result<Value, String> {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(MAX_POLL_SECS);
+    loop {
+        let response = client.get(url).await?;
+        if response.status().is_success() {
+            return response.json().await.map_err(|e| e.to_string());
+        }
+        if response.status().as_u16() != 409 {
+            return Err(format!("Request failed: {}", response.status()));
+        }
+        if std::time::Instant::now() >= deadline {
+            return Err(format!("Gave up on {} after {} seconds", url, MAX_POLL_SECS));
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
     }
 }
 
+async fn post_to_server<T: Serialize>(client: &ServerConnection, url: &str, body: &T) -> Result<Value, String> {
+    let response = client.post_json(url, body).await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let message = response.text().await.unwrap_or_default();
+        return Err(format!("Request failed: {} {}", status, message));
+    }
+    response.json().await.map_err(|e| e.to_string())
+}
+
 //Unit tests are contributed by Sharvani Chelumalla & Sai Pranavi Reddy Patlolla
 //Tests
 #[cfg(test)]
@@ -362,6 +1022,40 @@ mod tests {
         assert_eq!(config.num_rounds, 3);
         assert_eq!(config.sensitivity, 1.0);
         assert_eq!(config.epsilon, 0.5);
+        assert_eq!(config.proximal_mu, 0.0);
+        assert!(config.privacy_budget.is_infinite());
+        assert_eq!(config.privacy_accountant().spent_epsilon(), 0.0);
+    }
+
+    // Test that the federated client keeps the user configuration
+    #[test]
+    fn test_federated_client_config() {
+        let mut config = Config::new(0.5, 128, 0.5, 5, 0.5, 1.5);
+        config.privacy_budget = 8.0;
+        let client = FederatedClient::new(config, "http://0.0.0.0:8081/", Device::Cpu).unwrap();
+
+        assert_eq!(client.config.batch_size, 128);
+        assert_eq!(client.config.num_rounds, 5);
+        assert_eq!(client.config.dp_mechanism().unwrap().sensitivity, 0.5);
+        assert_eq!(client.accountant.epsilon_budget, 8.0);
+        assert_eq!(client.url("/get_model"), "http://0.0.0.0:8081/get_model");
+        assert_eq!(client.vs.trainable_variables().len(), 6);
+    }
+
+    #[test]
+    fn test_proximal_term() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let _model = SimpleCNN::new(&vs.root());
+        let global_weights = vs.trainable_variables().iter().map(|t| t.detach().copy()).collect::<Vec<_>>();
+
+        // The proximal term vanishes at the global weights
+        assert_eq!(proximal_term(&vs, &global_weights, 0.1).double_value(&[]), 0.0);
+
+        // Moving one bias by 1.0 costs mu / 2
+        let bias = vs.variables()["fc2.bias"].shallow_clone();
+        tch::no_grad(|| { let _ = bias.get(0).g_add_scalar_(1.0); });
+        let term = proximal_term(&vs, &global_weights, 0.1).double_value(&[]);
+        assert!((term - 0.05).abs() < 1e-6);
     }
 
     #[test]
@@ -406,10 +1100,50 @@ mod tests {
         ];
 
         let criterion = |output: &Tensor, target: &Tensor| output.mse_loss(target, tch::Reduction::Mean);
-        let (avg_loss, _) = train_local_model(&dummy_data, &mut model, &mut optimizer, &criterion, Device::Cpu);
+        let initial_weights = state_dict_from_var_store(&vs).unwrap();
+        let (avg_loss, trained_weights) = train_local_model(&dummy_data, &mut model, &vs, &mut optimizer, &criterion, Device::Cpu, 0.0).unwrap();
 
         // Check that the average loss is a number (not NaN or Infinity)
         assert!(avg_loss.is_finite());
+
+        // Check that the real parameters are returned and have been updated by training
+        assert_eq!(trained_weights.len(), 6);
+        assert_eq!(trained_weights[0].name, "conv1.bias");
+        assert_ne!(trained_weights, initial_weights);
+    }
+
+    // Test that DP-SGD trains, records its epochs and stops once the budget is spent
+    #[test]
+    fn test_train_local_model_dp() {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let mut model = SimpleCNN::new(&vs.root());
+        let mut optimizer = Sgd::default().build(&vs, 0.01).unwrap();
+        // Batches shaped like the ones of `get_train_data`, the images of a batch concatenated along rows
+        let dummy_data = vec![
+            (Tensor::randn([2 * 28, 28], (kind::Kind::Float, Device::Cpu)), Tensor::randn([2, 10], (kind::Kind::Float, Device::Cpu))),
+            (Tensor::randn([2 * 28, 28], (kind::Kind::Float, Device::Cpu)), Tensor::randn([2, 10], (kind::Kind::Float, Device::Cpu))),
+        ];
+        let criterion = |output: &Tensor, target: &Tensor| output.mse_loss(target, tch::Reduction::Mean);
+        // An unlimited budget cannot be spread over the rounds
+        let config = Config::default();
+        assert!(DpSgd::from_config(&config, &dummy_data, &config.privacy_accountant()).is_err());
+
+        // Two epochs and the upload that follows them share one budget
+        let mut accountant = PrivacyAccountant::new(4.0, 1e-5);
+        let upload = SampledGaussian::single(2.0);
+        let dp_sgd = DpSgd::for_budget(1.0, &accountant, &[upload], 0.5, 2 * dummy_data.len()).unwrap();
+        let initial_weights = state_dict_from_var_store(&vs).unwrap();
+
+        let (avg_loss, trained_weights) = train_local_model_dp(&dummy_data, &mut model, &vs, &mut optimizer, &criterion, Device::Cpu, &dp_sgd, &mut accountant).unwrap();
+        assert!(avg_loss.is_finite());
+        assert_ne!(trained_weights, initial_weights);
+
+        assert!(train_local_model_dp(&dummy_data, &mut model, &vs, &mut optimizer, &criterion, Device::Cpu, &dp_sgd, &mut accountant).is_ok());
+        assert!(accountant.spend(&upload).is_ok());
+        assert!(accountant.spent_epsilon() <= 4.0);
+        assert!(train_local_model_dp(&dummy_data, &mut model, &vs, &mut optimizer, &criterion, Device::Cpu, &dp_sgd, &mut accountant).is_err());
+        // Training again cannot start over with the full budget
+        assert!(DpSgd::for_budget(1.0, &accountant, &[upload], 0.5, 2 * dummy_data.len()).is_err());
     }
 
     /********************************************************************
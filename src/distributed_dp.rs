@@ -0,0 +1,267 @@
+//Distributed differential privacy with the Skellam mechanism of Agarwal, Kairouz and Liu (2021)
+//
+//Every client clips its model delta, scales it to integers with conditional randomized rounding and
+//adds Skellam noise, the difference of two Poisson variables. Skellam noise is closed under summation,
+//so the sum computed by secure aggregation carries the noise of all the clients while each of them only
+//added a share of it. The integers live in the secure aggregation field and wrap around modulo its prime,
+//the mechanism refuses parameters for which the sum could wrap.
+
+use rand::{thread_rng, Rng};
+use rand_distr::{Distribution, Poisson};
+use serde::{Deserialize, Serialize};
+use crate::secure_dp_utils::FIELD_PRIME;
+use crate::state_dict::{check_same_layout, StateDict};
+
+/// Bits of the clipping norm once scaled to integers, the rounding error is relative to `2^bits`
+pub const QUANTIZATION_BITS: u32 = 16;
+
+/// Probability bound of the conditional rounding, e^-0.5 as suggested by Kairouz et al. (2021)
+const ROUNDING_BETA: f64 = 0.6065306597126334;
+
+/// Number of noise standard deviations the sum is allowed to reach before it could wrap around
+const WRAP_AROUND_TAIL: f64 = 20.0;
+
+/// Skellam mechanism shared by the clients of a secure aggregation round
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SkellamMechanism {
+    /// L2 bound of every client delta.
+    pub clip_norm: f64,
+    /// Standard deviation of the noise of the sum divided by `clip_norm`, once all clients contributed.
+    pub noise_multiplier: f64,
+    /// Number of clients expected in a round, each adds a `1 / num_clients` share of the noise variance.
+    pub num_clients: usize,
+    /// Number of model parameters.
+    pub dimension: usize,
+    /// Float value of one integer step.
+    pub granularity: f64,
+}
+
+impl SkellamMechanism {
+    /// Mechanism for `num_clients` clients updating `dimension` parameters, checked against wrap-around
+    pub fn new(clip_norm: f64, noise_multiplier: f64, num_clients: usize, dimension: usize) -> Result<Self, String> {
+        if !(clip_norm > 0.0 && clip_norm.is_finite()) || noise_multiplier < 0.0 || num_clients == 0 {
+            return Err("Clipping norm and number of clients must be positive".to_string());
+        }
+        let mechanism = SkellamMechanism {
+            clip_norm,
+            noise_multiplier,
+            num_clients,
+            dimension,
+            granularity: clip_norm / (1u64 << QUANTIZATION_BITS) as f64,
+        };
+        let bound = mechanism.sum_bound();
+        if bound >= (FIELD_PRIME / 2) as f64 {
+            return Err(format!("The sum could reach {:.3e} and wrap around the field", bound));
+        }
+        Ok(mechanism)
+    }
+
+    /// Clipping norm in integer units
+    fn integer_clip_norm(&self) -> f64 {
+        self.clip_norm / self.granularity
+    }
+
+    /// L2 norm bound of a rounded client delta, the sensitivity in integer units
+    pub fn l2_sensitivity(&self) -> f64 {
+        let c = self.integer_clip_norm();
+        let d = self.dimension as f64;
+        let conditional = c * c + d / 4.0 + (2.0 * (1.0 / ROUNDING_BETA).ln()).sqrt() * (c + d.sqrt() / 2.0);
+        conditional.min((c + d.sqrt()).powi(2)).sqrt()
+    }
+
+    /// L1 norm bound of a rounded client delta
+    pub fn l1_sensitivity(&self) -> f64 {
+        let l2 = self.l2_sensitivity();
+        (l2 * l2).min((self.dimension as f64).sqrt() * l2)
+    }
+
+    /// Skellam parameter of the noise each client adds to a coordinate, half its variance
+    pub fn client_mu(&self) -> f64 {
+        let std = self.noise_multiplier * self.integer_clip_norm();
+        std * std / (2.0 * self.num_clients as f64)
+    }
+
+    /// Largest magnitude a coordinate of the sum may reach with overwhelming probability
+    pub fn sum_bound(&self) -> f64 {
+        let noise_std = (2.0 * self.client_mu() * self.num_clients as f64).sqrt();
+        self.num_clients as f64 * self.l2_sensitivity() + WRAP_AROUND_TAIL * noise_std
+    }
+
+    /// Client side: clipped, rounded and noised delta between `local` and `global` as field elements.
+    /// The last element counts the client so that the server knows how many deltas were summed.
+    pub fn encode_update(&self, global: &StateDict, local: &StateDict) -> Result<Vec<u64>, String> {
+        check_same_layout(global, local)?;
+        let delta = local
+            .iter()
+            .zip(global.iter())
+            .flat_map(|(l, g)| l.data.iter().zip(g.data.iter()).map(|(l, g)| l - g))
+            .collect::<Vec<_>>();
+        if delta.len() != self.dimension {
+            return Err(format!("Update has {} parameters, the mechanism expects {}", delta.len(), self.dimension));
+        }
+        let norm = delta.iter().map(|v| v * v).sum::<f64>().sqrt();
+        if !norm.is_finite() {
+            return Err("Client update is not finite".to_string());
+        }
+        let scale = self.integer_clip_norm() / norm.max(self.clip_norm);
+        let scaled = delta.iter().map(|v| v * scale).collect::<Vec<_>>();
+
+        let mut rng = thread_rng();
+        let noise = skellam(self.client_mu())?;
+        let mut input = conditional_rounding(&scaled, self.l2_sensitivity(), &mut rng)
+            .into_iter()
+            .map(|v| to_field(v + noise(&mut rng)))
+            .collect::<Vec<_>>();
+        input.push(1);
+        Ok(input)
+    }
+
+    /// Server side: next global model from the secure sum of encoded updates and the number of clients in it
+    pub fn decode_average(&self, global: &StateDict, aggregate: &[u64]) -> Result<(StateDict, usize), String> {
+        if aggregate.len() != self.dimension + 1 {
+            return Err(format!("Aggregate has {} values, the model needs {}", aggregate.len(), self.dimension + 1));
+        }
+        let num_clients = aggregate[self.dimension] as usize;
+        if num_clients == 0 || num_clients > self.num_clients {
+            return Err(format!("Aggregate counts {} clients", num_clients));
+        }
+
+        let mut average = global.clone();
+        let mut sums = aggregate.iter();
+        for named_tensor in average.iter_mut() {
+            for (v, &sum) in named_tensor.data.iter_mut().zip(sums.by_ref()) {
+                *v += from_field(sum) as f64 * self.granularity / num_clients as f64;
+            }
+        }
+        Ok((average, num_clients))
+    }
+
+    /// RDP at every order of one round in which `num_clients` clients added their noise
+    pub fn round_rdp(&self, orders: &[u32], num_clients: usize) -> Vec<f64> {
+        let mu = self.client_mu() * num_clients as f64;
+        let l2 = self.l2_sensitivity();
+        let l1 = self.l1_sensitivity();
+        orders
+            .iter()
+            .map(|&order| {
+                if mu <= 0.0 {
+                    return f64::INFINITY;
+                }
+                let alpha = order as f64;
+                alpha * l2 * l2 / (4.0 * mu)
+                    + (((2.0 * alpha - 1.0) * l2 * l2 + 6.0 * l1) / (16.0 * mu * mu)).min(3.0 * l1 / (4.0 * mu))
+            })
+            .collect()
+    }
+}
+
+/// Sampler of Skellam(mu) integers, the difference of two Poisson(mu) variables
+fn skellam<R: Rng>(mu: f64) -> Result<impl Fn(&mut R) -> i64, String> {
+    let poisson = if mu > 0.0 {
+        Some(Poisson::new(mu).map_err(|e| e.to_string())?)
+    } else {
+        None
+    };
+    Ok(move |rng: &mut R| match &poisson {
+        Some(poisson) => poisson.sample(rng) as i64 - poisson.sample(rng) as i64,
+        None => 0,
+    })
+}
+
+/// Unbiased randomized rounding, repeated until the L2 norm stays within `bound`
+fn conditional_rounding<R: Rng>(values: &[f64], bound: f64, rng: &mut R) -> Vec<i64> {
+    loop {
+        let rounded = values
+            .iter()
+            .map(|&v| {
+                let floor = v.floor();
+                floor as i64 + rng.gen_bool(v - floor) as i64
+            })
+            .collect::<Vec<_>>();
+        if rounded.iter().map(|&v| (v * v) as f64).sum::<f64>() <= bound * bound {
+            return rounded;
+        }
+    }
+}
+
+fn to_field(value: i64) -> u64 {
+    value.rem_euclid(FIELD_PRIME as i64) as u64
+}
+
+/// Centered representative of a field element
+fn from_field(value: u64) -> i64 {
+    if value > FIELD_PRIME / 2 {
+        -((FIELD_PRIME - value) as i64)
+    } else {
+        value as i64
+    }
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::privacy_accountant::{compute_rdp, default_orders, SampledGaussian};
+    use crate::secure_dp_utils::field_add;
+    use crate::state_dict::state_dict;
+
+    fn secure_sum(inputs: &[Vec<u64>]) -> Vec<u64> {
+        inputs.iter().fold(vec![0; inputs[0].len()], |sum, input| {
+            sum.iter().zip(input).map(|(&s, &v)| field_add(s, v)).collect()
+        })
+    }
+
+    // Test that the sum of noiseless encodings decodes to the average of the clipped deltas
+    #[test]
+    fn test_skellam_encoding_roundtrip() {
+        let mechanism = SkellamMechanism::new(1.0, 0.0, 3, 2).unwrap();
+        let global = state_dict(vec![1.0, -1.0]);
+        let locals = [vec![1.3, -0.6], vec![0.9, -1.1], vec![31.0, 39.0]];
+        let inputs = locals
+            .iter()
+            .map(|local| mechanism.encode_update(&global, &state_dict(local.clone())).unwrap())
+            .collect::<Vec<_>>();
+
+        let (average, num_clients) = mechanism.decode_average(&global, &secure_sum(&inputs)).unwrap();
+        assert_eq!(num_clients, 3);
+        // Deltas (0.3, 0.4), (-0.1, -0.1) and (30, 40) clipped to (0.6, 0.8)
+        assert!((average[0].data[0] - (1.0 + 0.8 / 3.0)).abs() < 1e-4);
+        assert!((average[0].data[1] - (-1.0 + 1.1 / 3.0)).abs() < 1e-4);
+    }
+
+    // Test that the shares of noise add up to the target noise of the sum
+    #[test]
+    fn test_skellam_noise_of_the_sum() {
+        let mechanism = SkellamMechanism::new(1.0, 2.0, 4, 5000).unwrap();
+        let global = state_dict(vec![0.0; 5000]);
+        let inputs = (0..4)
+            .map(|_| mechanism.encode_update(&global, &global).unwrap())
+            .collect::<Vec<_>>();
+
+        let (average, _) = mechanism.decode_average(&global, &secure_sum(&inputs)).unwrap();
+        let std = (average[0].data.iter().map(|v| v * v).sum::<f64>() / 5000.0).sqrt();
+        // Noise of std noise_multiplier * clip_norm on the sum of 4 deltas
+        assert!((std - 0.5).abs() < 0.03, "std = {}", std);
+    }
+
+    // Test that the accounting is close to the Gaussian one, slightly worse because of the rounding
+    #[test]
+    fn test_skellam_rdp() {
+        let mechanism = SkellamMechanism::new(1.0, 1.5, 10, 1000).unwrap();
+        let orders = default_orders();
+        let skellam = mechanism.round_rdp(&orders, 10);
+        let gaussian = compute_rdp(&SampledGaussian::single(1.5), &orders);
+        for (s, g) in skellam.iter().zip(gaussian.iter()) {
+            assert!(s >= g && *s < 1.05 * g, "{} vs {}", s, g);
+        }
+        // Fewer clients added noise, the round costs more
+        assert!(mechanism.round_rdp(&orders, 5)[0] > skellam[0]);
+    }
+
+    // Test that parameters for which the sum could wrap around are refused
+    #[test]
+    fn test_skellam_wrap_around() {
+        assert!(SkellamMechanism::new(1.0, 1.0, 1 << 48, 10).is_err());
+        assert!(SkellamMechanism::new(1.0, 1.0, 100, 10).is_ok());
+    }
+}
@@ -0,0 +1,211 @@
+//Key distribution for encrypted client updates
+//
+//The server publishes an X25519 public key per round. A client derives a Fernet session key from an
+//ephemeral key pair and the server key (ECDH followed by HKDF-SHA256) and sends its ephemeral public
+//key along with the encrypted update, so that only the server can derive the same session key.
+
+use std::collections::BTreeMap;
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE}, Engine};
+use fernet::Fernet;
+use hkdf::Hkdf;
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use crate::secure_aggregation::decode_public_key;
+
+/// Context string binding the derived keys to this protocol
+const SESSION_KEY_INFO: &[u8] = b"RustFL session key";
+
+/// Public key published by the server for one round
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerPublicKey {
+    pub round: u64,
+    /// Base64 X25519 public key.
+    pub public_key: String,
+}
+
+/// Sent with an encrypted update so that the server can derive the session key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptionHeader {
+    /// Round of the server key the client used.
+    pub round: u64,
+    /// Base64 ephemeral X25519 public key of the client.
+    pub client_public_key: String,
+}
+
+/// Symmetric key shared by one client and the server for one update
+#[derive(Clone)]
+pub struct SessionKey {
+    fernet: Fernet,
+}
+
+impl SessionKey {
+    /// Encrypts a payload into a Fernet token
+    pub fn encrypt(&self, plaintext: &[u8]) -> String {
+        self.fernet.encrypt(plaintext)
+    }
+
+    /// Decrypts a Fernet token produced with the same session key
+    pub fn decrypt(&self, token: &str) -> Result<Vec<u8>, String> {
+        self.fernet.decrypt(token).map_err(|_| "Failed to decrypt with the session key".to_string())
+    }
+}
+
+/// Storage of the server key pairs, one per round
+pub trait KeyStore: Send {
+    /// Round whose key clients should use
+    fn current_round(&self) -> u64;
+
+    /// Public key of a round that is still retained
+    fn public_key(&self, round: u64) -> Option<ServerPublicKey>;
+
+    /// Session key agreed with the client that sent `header`
+    fn session_key(&self, header: &EncryptionHeader) -> Result<SessionKey, String>;
+
+    /// Starts a new round with a fresh key pair and forgets the keys that are too old
+    fn rotate(&mut self) -> ServerPublicKey;
+}
+
+/// Key store keeping the key pairs of the last rounds in memory
+pub struct InMemoryKeyStore {
+    /// Number of rounds whose keys are kept, so that updates sent just before a rotation still decrypt.
+    pub retained_rounds: usize,
+    keys: BTreeMap<u64, StaticSecret>,
+}
+
+impl InMemoryKeyStore {
+    /// Key store starting at round 0 and keeping the keys of `retained_rounds` rounds
+    pub fn new(retained_rounds: usize) -> Self {
+        let mut keys = BTreeMap::new();
+        keys.insert(0, StaticSecret::random_from_rng(thread_rng()));
+        InMemoryKeyStore {
+            retained_rounds: retained_rounds.max(1),
+            keys,
+        }
+    }
+}
+
+impl KeyStore for InMemoryKeyStore {
+    fn current_round(&self) -> u64 {
+        *self.keys.keys().next_back().unwrap_or(&0)
+    }
+
+    fn public_key(&self, round: u64) -> Option<ServerPublicKey> {
+        self.keys.get(&round).map(|secret| ServerPublicKey {
+            round,
+            public_key: STANDARD.encode(PublicKey::from(secret).as_bytes()),
+        })
+    }
+
+    fn session_key(&self, header: &EncryptionHeader) -> Result<SessionKey, String> {
+        let secret = self
+            .keys
+            .get(&header.round)
+            .ok_or(format!("The server key of round {} is not available anymore", header.round))?;
+        let client_public_key = decode_public_key(&header.client_public_key)?;
+        let shared_secret = secret.diffie_hellman(&client_public_key);
+        derive_session_key(
+            shared_secret.as_bytes(),
+            header.round,
+            &client_public_key,
+            &PublicKey::from(secret),
+        )
+    }
+
+    fn rotate(&mut self) -> ServerPublicKey {
+        let round = self.current_round() + 1;
+        self.keys.insert(round, StaticSecret::random_from_rng(thread_rng()));
+        while self.keys.len() > self.retained_rounds {
+            self.keys.pop_first();
+        }
+        self.public_key(round).unwrap()
+    }
+}
+
+/// Client side: derives a fresh session key for the published server key.
+/// The header has to be sent along with the data encrypted under the session key.
+pub fn client_session_key(server_key: &ServerPublicKey) -> Result<(EncryptionHeader, SessionKey), String> {
+    let server_public_key = decode_public_key(&server_key.public_key)?;
+    let secret = EphemeralSecret::random_from_rng(thread_rng());
+    let client_public_key = PublicKey::from(&secret);
+    let shared_secret = secret.diffie_hellman(&server_public_key);
+
+    let session_key = derive_session_key(shared_secret.as_bytes(), server_key.round, &client_public_key, &server_public_key)?;
+    let header = EncryptionHeader {
+        round: server_key.round,
+        client_public_key: STANDARD.encode(client_public_key.as_bytes()),
+    };
+    Ok((header, session_key))
+}
+
+/// HKDF-SHA256 of the shared secret, bound to the round and both public keys
+fn derive_session_key(
+    shared_secret: &[u8],
+    round: u64,
+    client_public_key: &PublicKey,
+    server_public_key: &PublicKey,
+) -> Result<SessionKey, String> {
+    let mut info = SESSION_KEY_INFO.to_vec();
+    info.extend_from_slice(&round.to_be_bytes());
+    info.extend_from_slice(client_public_key.as_bytes());
+    info.extend_from_slice(server_public_key.as_bytes());
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(&info, &mut key)
+        .map_err(|e| e.to_string())?;
+    let fernet = Fernet::new(&URL_SAFE.encode(key)).ok_or("Invalid session key".to_string())?;
+    Ok(SessionKey { fernet })
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that the server derives the session key of the client
+    #[test]
+    fn test_session_key_agreement() {
+        let key_store = InMemoryKeyStore::new(2);
+        let server_key = key_store.public_key(key_store.current_round()).unwrap();
+
+        let (header, client_key) = client_session_key(&server_key).unwrap();
+        let token = client_key.encrypt(b"model weights");
+
+        let server_session_key = key_store.session_key(&header).unwrap();
+        assert_eq!(server_session_key.decrypt(&token).unwrap(), b"model weights");
+    }
+
+    // Test that another party cannot decrypt the update
+    #[test]
+    fn test_session_key_wrong_party() {
+        let key_store = InMemoryKeyStore::new(2);
+        let other_store = InMemoryKeyStore::new(2);
+        let (header, client_key) = client_session_key(&key_store.public_key(0).unwrap()).unwrap();
+        let token = client_key.encrypt(b"model weights");
+
+        assert!(other_store.session_key(&header).unwrap().decrypt(&token).is_err());
+        let (_, other_client_key) = client_session_key(&key_store.public_key(0).unwrap()).unwrap();
+        assert!(other_client_key.decrypt(&token).is_err());
+    }
+
+    // Test that rotation keeps the previous round and forgets older ones
+    #[test]
+    fn test_key_rotation() {
+        let mut key_store = InMemoryKeyStore::new(2);
+        let first_key = key_store.public_key(0).unwrap();
+        let (header, client_key) = client_session_key(&first_key).unwrap();
+        let token = client_key.encrypt(b"late update");
+
+        let second_key = key_store.rotate();
+        assert_eq!(second_key.round, 1);
+        assert_ne!(second_key.public_key, first_key.public_key);
+        assert_eq!(key_store.session_key(&header).unwrap().decrypt(&token).unwrap(), b"late update");
+
+        key_store.rotate();
+        assert_eq!(key_store.current_round(), 2);
+        assert!(key_store.public_key(0).is_none());
+        assert!(key_store.session_key(&header).is_err());
+    }
+}
@@ -9,4 +9,55 @@ pub mod client;
 pub mod server;
 
 ///Module for Noise and Encryption Mechanism
-pub mod secure_dp_utils;
\ No newline at end of file
+pub mod secure_dp_utils;
+
+///Module for Model state dict transport
+pub mod state_dict;
+
+///Module for Aggregation strategies
+pub mod aggregation;
+
+///Module for Byzantine-robust aggregation rules
+pub mod robust_aggregation;
+
+///Module for Secure aggregation with pairwise masks
+pub mod secure_aggregation;
+
+///Module for Additively homomorphic aggregation with Paillier encryption
+pub mod paillier;
+
+///Module for Key distribution and session keys
+pub mod key_management;
+
+///Module for Privacy accounting across rounds
+pub mod privacy_accountant;
+
+///Module for Central differential privacy on the server
+pub mod central_dp;
+
+///Module for Distributed differential privacy with Skellam noise
+pub mod distributed_dp;
+
+///Module for Deployment settings from files and environment variables
+pub mod settings;
+
+///Module for Round orchestration of the server
+pub mod round;
+
+///Module for Asynchronous aggregation with FedAsync and FedBuff
+pub mod async_aggregation;
+
+///Module for Client selection strategies
+pub mod selection;
+
+///Module for Client registration and authentication
+pub mod auth;
+
+///Module for TLS and mutual TLS between clients and servers
+pub mod tls;
+
+///Module for Checkpoints of the server state
+pub mod checkpoint;
+
+///Module for Model version history and rollback
+pub mod model_registry;
@@ -0,0 +1,151 @@
+//Registry of the recent global models
+//
+//The server keeps the last models it published with the metrics of the round that produced them, so that
+//clients can download a given version and an operator can roll back to a model from before a bad round.
+//A rollback publishes the old weights, with the aggregator state saved alongside them, as a new version:
+//versions only grow, so clients holding a newer model than the restored one notice the change.
+
+use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::state_dict::StateDict;
+
+/// How a global model was produced, fields are `None` when the aggregation does not reveal them
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RoundMetrics {
+    /// Synchronous round aggregated into the model.
+    pub round_id: Option<u64>,
+    pub num_clients: Option<usize>,
+    pub num_samples: Option<usize>,
+    /// Training loss reported by the clients, weighted by their number of samples.
+    pub mean_loss: Option<f64>,
+    /// Version whose weights were restored, for models published by a rollback.
+    pub rolled_back_from: Option<usize>,
+}
+
+impl RoundMetrics {
+    /// Metrics of a round from the number of samples and the loss of every client
+    pub fn from_clients(round_id: Option<u64>, clients: &[(usize, f64)]) -> Self {
+        let num_samples = clients.iter().map(|(num_samples, _)| num_samples).sum::<usize>();
+        let mean_loss = (num_samples > 0).then(|| {
+            clients.iter().map(|(num_samples, loss)| *num_samples as f64 * loss).sum::<f64>() / num_samples as f64
+        });
+        RoundMetrics {
+            round_id,
+            num_clients: Some(clients.len()),
+            num_samples: Some(num_samples),
+            mean_loss,
+            rolled_back_from: None,
+        }
+    }
+}
+
+/// Published global model
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelRecord {
+    pub version: usize,
+    pub state_dict: StateDict,
+    /// State of the aggregation strategy right after this model, restored with it on rollback.
+    pub aggregator: Option<Value>,
+    pub metrics: RoundMetrics,
+    /// Unix time the model was published at.
+    pub published_at: u64,
+}
+
+/// Version and metrics of a retained model, as listed on `/models`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelSummary {
+    pub version: usize,
+    pub metrics: RoundMetrics,
+    pub published_at: u64,
+}
+
+/// Last `capacity` global models, the newest last
+pub struct ModelRegistry {
+    capacity: usize,
+    models: VecDeque<ModelRecord>,
+}
+
+impl ModelRegistry {
+    pub fn new(capacity: usize) -> Result<Self, String> {
+        if capacity == 0 {
+            return Err("The model registry must retain at least one model".to_string());
+        }
+        Ok(ModelRegistry { capacity, models: VecDeque::with_capacity(capacity) })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Adds a newly published model, forgetting the oldest one when the registry is full
+    pub fn record(&mut self, record: ModelRecord) -> Result<(), String> {
+        if let Some(newest) = self.models.back() {
+            if record.version <= newest.version {
+                return Err(format!("Version {} is not newer than version {}", record.version, newest.version));
+            }
+        }
+        if self.models.len() == self.capacity {
+            self.models.pop_front();
+        }
+        self.models.push_back(record);
+        Ok(())
+    }
+
+    pub fn get(&self, version: usize) -> Option<&ModelRecord> {
+        self.models.iter().find(|record| record.version == version)
+    }
+
+    /// Retained models, the oldest first
+    pub fn summaries(&self) -> Vec<ModelSummary> {
+        self.models
+            .iter()
+            .map(|record| ModelSummary {
+                version: record.version,
+                metrics: record.metrics.clone(),
+                published_at: record.published_at,
+            })
+            .collect()
+    }
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_dict::state_dict;
+
+    fn record(version: usize) -> ModelRecord {
+        ModelRecord {
+            version,
+            state_dict: state_dict(vec![version as f64]),
+            aggregator: None,
+            metrics: RoundMetrics::from_clients(Some(version as u64), &[(10, 1.0), (30, 2.0)]),
+            published_at: 0,
+        }
+    }
+
+    // Test that only the newest models are retained, in version order
+    #[test]
+    fn test_model_registry() {
+        let mut registry = ModelRegistry::new(2).unwrap();
+        for version in 0..3 {
+            registry.record(record(version)).unwrap();
+        }
+        assert!(registry.get(0).is_none());
+        assert_eq!(registry.get(2).unwrap().state_dict[0].data, vec![2.0]);
+        let versions = registry.summaries().iter().map(|summary| summary.version).collect::<Vec<_>>();
+        assert_eq!(versions, vec![1, 2]);
+        assert!(registry.record(record(2)).is_err());
+        assert!(ModelRegistry::new(0).is_err());
+    }
+
+    // Test that the round loss is weighted by the samples of the clients
+    #[test]
+    fn test_round_metrics() {
+        let metrics = RoundMetrics::from_clients(Some(4), &[(10, 1.0), (30, 2.0)]);
+        assert_eq!((metrics.num_clients, metrics.num_samples), (Some(2), Some(40)));
+        assert_eq!(metrics.mean_loss, Some(1.75));
+        assert_eq!(RoundMetrics::from_clients(None, &[]).mean_loss, None);
+    }
+}
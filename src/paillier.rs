@@ -0,0 +1,585 @@
+//Additively homomorphic aggregation with the Paillier cryptosystem
+//
+//Clients encrypt their sample-weighted models with a shared public key, the server multiplies
+//the ciphertexts (adding the plaintexts) and only the aggregate is ever decrypted. The private key
+//can be split among several key holders who must all take part in the decryption. Ciphertexts and partial
+//decryptions must be units modulo n^2, and every key holder index is bound to the first client that used it.
+
+use std::collections::BTreeMap;
+use num_bigint::{BigInt, BigUint, RandBigInt};
+use num_integer::Integer;
+use num_traits::{One, ToPrimitive, Zero};
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+use crate::secure_dp_utils::FIXED_POINT_SCALE;
+use crate::state_dict::StateDict;
+
+/// Bits reserved for every value packed into a plaintext
+const SLOT_BITS: usize = 128;
+
+/// Offset added to every encoded value so that negative values fit in an unsigned slot.
+/// Sums of up to 2^26 clients fit in a slot with this offset.
+const SLOT_OFFSET_BITS: usize = 100;
+
+/// Miller-Rabin rounds used when generating primes
+const PRIMALITY_ROUNDS: usize = 40;
+
+/// Paillier public key with generator `n + 1`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaillierPublicKey {
+    /// Modulus as a hexadecimal string.
+    pub n: String,
+}
+
+/// Paillier private key
+#[derive(Debug, Clone)]
+pub struct PaillierPrivateKey {
+    pub public_key: PaillierPublicKey,
+    lambda: BigUint,
+    mu: BigUint,
+}
+
+/// Additive share of the decryption exponent held by one key holder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecryptionKeyShare {
+    pub index: usize,
+    /// Share of the exponent as a hexadecimal string.
+    pub exponent: String,
+}
+
+/// Partial decryption of the pending aggregate sent by a key holder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartialDecryption {
+    pub index: usize,
+    pub partial_decryption: Vec<String>,
+}
+
+/// Generates a key pair with a modulus of `bits` bits, 2048 or more for real deployments
+pub fn generate_keypair(bits: u64) -> Result<(PaillierPublicKey, PaillierPrivateKey), String> {
+    if bits < 256 || bits % 2 == 1 {
+        return Err(format!("Key size must be an even number of at least 256 bits, got {}", bits));
+    }
+    loop {
+        let p = generate_prime(bits / 2);
+        let q = generate_prime(bits / 2);
+        if p == q {
+            continue;
+        }
+        let n = &p * &q;
+        let phi = (&p - 1u32) * (&q - 1u32);
+        if !n.gcd(&phi).is_one() {
+            continue;
+        }
+        let lambda = (&p - 1u32).lcm(&(&q - 1u32));
+        let mu = match lambda.modinv(&n) {
+            Some(mu) => mu,
+            None => continue,
+        };
+        let public_key = PaillierPublicKey { n: n.to_str_radix(16) };
+        return Ok((public_key.clone(), PaillierPrivateKey { public_key, lambda, mu }));
+    }
+}
+
+impl PaillierPublicKey {
+    /// Modulus of the key
+    pub fn modulus(&self) -> Result<BigUint, String> {
+        parse_hex(&self.n)
+    }
+
+    /// Number of values packed into one ciphertext
+    pub fn slots_per_ciphertext(&self) -> Result<usize, String> {
+        let slots = (self.modulus()?.bits() as usize - 1) / SLOT_BITS;
+        if slots == 0 {
+            return Err("Modulus is too small to hold a value".to_string());
+        }
+        Ok(slots)
+    }
+
+    /// Encrypts a plaintext smaller than the modulus
+    pub fn encrypt(&self, plaintext: &BigUint) -> Result<BigUint, String> {
+        let n = self.modulus()?;
+        if plaintext >= &n {
+            return Err("Plaintext is larger than the modulus".to_string());
+        }
+        let n_squared = &n * &n;
+        let mut rng = thread_rng();
+        let r = loop {
+            let r = rng.gen_biguint_below(&n);
+            if !r.is_zero() && r.gcd(&n).is_one() {
+                break r;
+            }
+        };
+        // (n + 1)^m = 1 + m * n mod n^2
+        let g_m = (BigUint::one() + plaintext * &n) % &n_squared;
+        Ok(g_m * r.modpow(&n, &n_squared) % &n_squared)
+    }
+
+    /// Parses a hexadecimal ciphertext or partial decryption, which must be in [1, n^2) and coprime to n
+    pub fn parse_ciphertext(&self, value: &str) -> Result<BigUint, String> {
+        let n = self.modulus()?;
+        let ciphertext = parse_hex(value)?;
+        if ciphertext.is_zero() || ciphertext >= &n * &n || !ciphertext.gcd(&n).is_one() {
+            return Err(format!("{:?} is not a valid ciphertext for this key", value));
+        }
+        Ok(ciphertext)
+    }
+
+    /// Homomorphic addition of the plaintexts of two ciphertexts
+    pub fn add(&self, a: &BigUint, b: &BigUint) -> Result<BigUint, String> {
+        let n = self.modulus()?;
+        Ok(a * b % (&n * &n))
+    }
+
+    /// Encodes the values in fixed point, packs them and encrypts them as hexadecimal ciphertexts
+    pub fn encrypt_values(&self, values: &[f64]) -> Result<Vec<String>, String> {
+        let slots = self.slots_per_ciphertext()?;
+        values
+            .chunks(slots)
+            .map(|chunk| {
+                let mut plaintext = BigUint::zero();
+                for (i, &value) in chunk.iter().enumerate() {
+                    plaintext |= encode_slot(value)? << (i * SLOT_BITS);
+                }
+                Ok(self.encrypt(&plaintext)?.to_str_radix(16))
+            })
+            .collect()
+    }
+
+    /// Sums encrypted vectors of the same length without decrypting them
+    pub fn sum_encrypted(&self, encrypted: &[Vec<String>]) -> Result<Vec<String>, String> {
+        let first = encrypted.first().ok_or("No ciphertexts to sum".to_string())?;
+        let mut sum = first.iter().map(|c| self.parse_ciphertext(c)).collect::<Result<Vec<_>, String>>()?;
+        for ciphertexts in &encrypted[1..] {
+            if ciphertexts.len() != sum.len() {
+                return Err(format!("Expected {} ciphertexts, got {}", sum.len(), ciphertexts.len()));
+            }
+            for (s, c) in sum.iter_mut().zip(ciphertexts.iter()) {
+                *s = self.add(s, &self.parse_ciphertext(c)?)?;
+            }
+        }
+        Ok(sum.iter().map(|s| s.to_str_radix(16)).collect())
+    }
+}
+
+impl PaillierPrivateKey {
+    /// Decrypts a ciphertext
+    pub fn decrypt(&self, ciphertext: &BigUint) -> Result<BigUint, String> {
+        let n = self.public_key.modulus()?;
+        let n_squared = &n * &n;
+        Ok(l_function(&ciphertext.modpow(&self.lambda, &n_squared), &n)? * &self.mu % &n)
+    }
+
+    /// Decrypts the sum of the encrypted vectors of `num_clients` clients into `num_values` values
+    pub fn decrypt_values(&self, ciphertexts: &[String], num_values: usize, num_clients: usize) -> Result<Vec<f64>, String> {
+        let plaintexts = ciphertexts
+            .iter()
+            .map(|c| self.decrypt(&self.public_key.parse_ciphertext(c)?))
+            .collect::<Result<Vec<_>, String>>()?;
+        unpack_values(&self.public_key, &plaintexts, num_values, num_clients)
+    }
+
+    /// Splits the decryption exponent into `key_holders` additive shares, all of them are needed to decrypt.
+    /// The private key should be discarded once the shares are handed out.
+    pub fn split(&self, key_holders: usize) -> Result<Vec<DecryptionKeyShare>, String> {
+        if key_holders == 0 {
+            return Err("At least one key holder is needed".to_string());
+        }
+        let n = self.public_key.modulus()?;
+        // d = 0 mod lambda and d = 1 mod n, so c^d = 1 + m * n mod n^2
+        let modulus = &n * &self.lambda;
+        let exponent = &self.lambda * &self.mu % &modulus;
+
+        let mut rng = thread_rng();
+        let mut shares = (1..key_holders).map(|_| rng.gen_biguint_below(&modulus)).collect::<Vec<_>>();
+        let sum = shares.iter().fold(BigUint::zero(), |acc, s| (acc + s) % &modulus);
+        shares.push((exponent + &modulus - sum) % &modulus);
+
+        Ok(shares
+            .into_iter()
+            .enumerate()
+            .map(|(index, exponent)| DecryptionKeyShare { index, exponent: exponent.to_str_radix(16) })
+            .collect())
+    }
+}
+
+impl DecryptionKeyShare {
+    /// Partial decryption of the ciphertexts with this share, reveals nothing on its own
+    pub fn partial_decrypt(&self, public_key: &PaillierPublicKey, ciphertexts: &[String]) -> Result<Vec<String>, String> {
+        let n = public_key.modulus()?;
+        let n_squared = &n * &n;
+        let exponent = parse_hex(&self.exponent)?;
+        ciphertexts
+            .iter()
+            .map(|c| Ok(public_key.parse_ciphertext(c)?.modpow(&exponent, &n_squared).to_str_radix(16)))
+            .collect()
+    }
+}
+
+/// Combines the partial decryptions of every key holder into `num_values` values
+pub fn combine_partial_decryptions(
+    public_key: &PaillierPublicKey,
+    partial_decryptions: &[Vec<String>],
+    num_values: usize,
+    num_clients: usize,
+) -> Result<Vec<f64>, String> {
+    let n = public_key.modulus()?;
+    let n_squared = &n * &n;
+    let first = partial_decryptions.first().ok_or("No partial decryptions to combine".to_string())?;
+    let mut products = vec![BigUint::one(); first.len()];
+    for partial in partial_decryptions {
+        if partial.len() != products.len() {
+            return Err(format!("Expected {} partial decryptions, got {}", products.len(), partial.len()));
+        }
+        for (p, c) in products.iter_mut().zip(partial.iter()) {
+            *p = &*p * public_key.parse_ciphertext(c)? % &n_squared;
+        }
+    }
+    let plaintexts = products.iter().map(|p| l_function(p, &n)).collect::<Result<Vec<_>, String>>()?;
+    unpack_values(public_key, &plaintexts, num_values, num_clients)
+}
+
+/// Values of a model weighted by its number of samples, followed by the number of samples
+pub fn weighted_values(weights: &StateDict, num_samples: usize) -> Vec<f64> {
+    let mut values = weights
+        .iter()
+        .flat_map(|t| t.data.iter())
+        .map(|&v| v * num_samples as f64)
+        .collect::<Vec<_>>();
+    values.push(num_samples as f64);
+    values
+}
+
+/// Turns the sum of weighted values into the average model and the total number of samples
+pub fn weighted_values_average(layout: &StateDict, sums: &[f64]) -> Result<(StateDict, usize), String> {
+    let numel: usize = layout.iter().map(|t| t.data.len()).sum();
+    if sums.len() != numel + 1 {
+        return Err(format!("Aggregate has {} values, the model needs {}", sums.len(), numel + 1));
+    }
+    let total_samples = sums[numel].round();
+    if total_samples <= 0.0 {
+        return Err("Aggregate holds no samples".to_string());
+    }
+
+    let mut average = layout.clone();
+    let mut values = sums.iter();
+    for named_tensor in average.iter_mut() {
+        for (v, &sum) in named_tensor.data.iter_mut().zip(values.by_ref()) {
+            *v = sum / total_samples;
+        }
+    }
+    Ok((average, total_samples as usize))
+}
+
+/// Server side state of the Paillier aggregation: running encrypted sum and pending decryption
+#[derive(Debug, Clone)]
+pub struct EncryptedAggregation {
+    pub public_key: PaillierPublicKey,
+    /// Number of key holders that must partially decrypt the aggregate.
+    pub key_holders: usize,
+    encrypted_updates: Vec<Vec<String>>,
+    pending: Option<(Vec<String>, usize)>,
+    partial_decryptions: BTreeMap<usize, Vec<String>>,
+    /// Client bound to every key holder index that took part so far.
+    key_holder_ids: BTreeMap<usize, String>,
+}
+
+impl EncryptedAggregation {
+    /// Aggregation under `public_key` whose private key is split among `key_holders`
+    pub fn new(public_key: PaillierPublicKey, key_holders: usize) -> Self {
+        EncryptedAggregation {
+            public_key,
+            key_holders,
+            encrypted_updates: Vec::new(),
+            pending: None,
+            partial_decryptions: BTreeMap::new(),
+            key_holder_ids: BTreeMap::new(),
+        }
+    }
+
+    /// Number of encrypted updates waiting to be summed
+    pub fn num_updates(&self) -> usize {
+        self.encrypted_updates.len()
+    }
+
+    /// Stores an encrypted update of the current round
+    pub fn add_update(&mut self, ciphertexts: Vec<String>) -> Result<(), String> {
+        if let Some(first) = self.encrypted_updates.first() {
+            if first.len() != ciphertexts.len() {
+                return Err(format!("Expected {} ciphertexts, got {}", first.len(), ciphertexts.len()));
+            }
+        }
+        ciphertexts.iter().try_for_each(|c| self.public_key.parse_ciphertext(c).map(|_| ()))?;
+        self.encrypted_updates.push(ciphertexts);
+        Ok(())
+    }
+
+    /// Sums the stored updates into the aggregate handed to the key holders
+    pub fn close_round(&mut self) -> Result<(), String> {
+        if self.pending.is_some() {
+            return Err("The previous aggregate is still waiting for decryption".to_string());
+        }
+        let sum = self.public_key.sum_encrypted(&self.encrypted_updates)?;
+        self.pending = Some((sum, self.encrypted_updates.len()));
+        self.encrypted_updates.clear();
+        Ok(())
+    }
+
+    /// Drops the stored updates and the pending aggregate with its partial decryptions, e.g. once the global
+    /// model they were trained on is rolled back. Key holders keep their index.
+    pub fn reset(&mut self) {
+        self.encrypted_updates.clear();
+        self.pending = None;
+        self.partial_decryptions.clear();
+    }
+
+    /// Encrypted aggregate and number of clients in it, once the round is closed
+    pub fn pending_aggregate(&self) -> Option<&(Vec<String>, usize)> {
+        self.pending.as_ref()
+    }
+
+    /// Stores the partial decryption of key holder `index` sent by `client_id`. The first client sending a
+    /// partial decryption for an index holds it from then on. Returns the decrypted sums once every key holder
+    /// took part.
+    pub fn add_partial_decryption(
+        &mut self,
+        index: usize,
+        client_id: &str,
+        partial_decryption: Vec<String>,
+        num_values: usize,
+    ) -> Result<Option<Vec<f64>>, String> {
+        let (aggregate, num_clients) = self.pending.as_ref().ok_or("No aggregate is waiting for decryption".to_string())?;
+        if index >= self.key_holders {
+            return Err(format!("Unknown key holder {}", index));
+        }
+        match self.key_holder_ids.get(&index) {
+            Some(holder) if holder != client_id => return Err(format!("Key holder {} is held by another client", index)),
+            None if self.key_holder_ids.values().any(|holder| holder == client_id) => {
+                return Err(format!("Client {} already holds another key holder index", client_id));
+            }
+            _ => {}
+        }
+        if self.partial_decryptions.contains_key(&index) {
+            return Err(format!("Key holder {} already sent its partial decryption", index));
+        }
+        if partial_decryption.len() != aggregate.len() {
+            return Err(format!("Expected {} partial decryptions, got {}", aggregate.len(), partial_decryption.len()));
+        }
+        partial_decryption.iter().try_for_each(|p| self.public_key.parse_ciphertext(p).map(|_| ()))?;
+        self.key_holder_ids.insert(index, client_id.to_string());
+        self.partial_decryptions.insert(index, partial_decryption);
+        if self.partial_decryptions.len() < self.key_holders {
+            return Ok(None);
+        }
+
+        let partials = self.partial_decryptions.values().cloned().collect::<Vec<_>>();
+        let sums = combine_partial_decryptions(&self.public_key, &partials, num_values, *num_clients);
+        self.pending = None;
+        self.partial_decryptions.clear();
+        sums.map(Some)
+    }
+}
+
+fn parse_hex(value: &str) -> Result<BigUint, String> {
+    BigUint::parse_bytes(value.as_bytes(), 16).ok_or(format!("Invalid hexadecimal number {:?}", value))
+}
+
+/// L(x) = (x - 1) / n, only defined for x = 1 mod n
+fn l_function(x: &BigUint, n: &BigUint) -> Result<BigUint, String> {
+    if x.is_zero() || !((x - 1u32) % n).is_zero() {
+        return Err("Decryption failed: the ciphertext or a partial decryption is invalid".to_string());
+    }
+    Ok((x - 1u32) / n)
+}
+
+fn encode_slot(value: f64) -> Result<BigUint, String> {
+    let scaled = (value * FIXED_POINT_SCALE).round();
+    if !scaled.is_finite() || scaled.abs() >= 2f64.powi(SLOT_OFFSET_BITS as i32 - 1) {
+        return Err(format!("Value {} cannot be encoded", value));
+    }
+    let offset = BigInt::one() << SLOT_OFFSET_BITS;
+    let encoded = offset + BigInt::from(scaled as i128);
+    encoded.to_biguint().ok_or(format!("Value {} cannot be encoded", value))
+}
+
+fn unpack_values(public_key: &PaillierPublicKey, plaintexts: &[BigUint], num_values: usize, num_clients: usize) -> Result<Vec<f64>, String> {
+    let slots = public_key.slots_per_ciphertext()?;
+    if plaintexts.len() != num_values.div_ceil(slots) {
+        return Err(format!("{} plaintexts cannot hold {} values", plaintexts.len(), num_values));
+    }
+    if num_clients >= 1 << (SLOT_BITS - SLOT_OFFSET_BITS - 2) {
+        return Err(format!("Too many clients in one aggregate: {}", num_clients));
+    }
+    let mask = (BigUint::one() << SLOT_BITS) - 1u32;
+    let offset = BigInt::from(num_clients) << SLOT_OFFSET_BITS;
+
+    let mut values = Vec::with_capacity(num_values);
+    for i in 0..num_values {
+        let slot = (&plaintexts[i / slots] >> ((i % slots) * SLOT_BITS)) & &mask;
+        let scaled = BigInt::from(slot) - &offset;
+        values.push(scaled.to_f64().ok_or("Decrypted value is out of range".to_string())? / FIXED_POINT_SCALE);
+    }
+    Ok(values)
+}
+
+fn generate_prime(bits: u64) -> BigUint {
+    let mut rng = thread_rng();
+    loop {
+        let mut candidate = rng.gen_biguint(bits);
+        // The two top bits make the product of two primes exactly 2 * bits long.
+        candidate.set_bit(bits - 1, true);
+        candidate.set_bit(bits - 2, true);
+        candidate.set_bit(0, true);
+        if is_probable_prime(&candidate, PRIMALITY_ROUNDS) {
+            return candidate;
+        }
+    }
+}
+
+/// Miller-Rabin primality test preceded by trial division
+fn is_probable_prime(n: &BigUint, rounds: usize) -> bool {
+    const SMALL_PRIMES: [u32; 15] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47];
+    for p in SMALL_PRIMES {
+        if *n == BigUint::from(p) {
+            return true;
+        }
+        if (n % p).is_zero() {
+            return false;
+        }
+    }
+    if *n < BigUint::from(53u32) {
+        return false;
+    }
+
+    let n_minus_one = n - 1u32;
+    let s = n_minus_one.trailing_zeros().unwrap_or(0);
+    let d = &n_minus_one >> s;
+    let mut rng = thread_rng();
+    'witness: for _ in 0..rounds {
+        let a = rng.gen_biguint_range(&BigUint::from(2u32), &n_minus_one);
+        let mut x = a.modpow(&d, n);
+        if x.is_one() || x == n_minus_one {
+            continue;
+        }
+        for _ in 1..s {
+            x = x.modpow(&BigUint::from(2u32), n);
+            if x == n_minus_one {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_dict::NamedTensor;
+
+    // Test that encryption is additively homomorphic
+    #[test]
+    fn test_homomorphic_addition() {
+        let (public_key, private_key) = generate_keypair(512).unwrap();
+        let a = public_key.encrypt(&BigUint::from(1234u32)).unwrap();
+        let b = public_key.encrypt(&BigUint::from(4321u32)).unwrap();
+
+        assert_ne!(a, public_key.encrypt(&BigUint::from(1234u32)).unwrap());
+        assert_eq!(private_key.decrypt(&public_key.add(&a, &b).unwrap()).unwrap(), BigUint::from(5555u32));
+    }
+
+    // Test that packed signed values survive encryption, summation and decryption
+    #[test]
+    fn test_encrypted_sum_of_values() {
+        let (public_key, private_key) = generate_keypair(512).unwrap();
+        let first = vec![1.5, -2.25, 0.0, 3.0, -0.125];
+        let second = vec![-0.5, 1.0, 7.0, -3.0, -0.125];
+
+        let encrypted = vec![public_key.encrypt_values(&first).unwrap(), public_key.encrypt_values(&second).unwrap()];
+        assert_eq!(encrypted[0].len(), 2);
+
+        let sum = public_key.sum_encrypted(&encrypted).unwrap();
+        let decrypted = private_key.decrypt_values(&sum, 5, 2).unwrap();
+        assert_eq!(decrypted, vec![1.0, -1.25, 7.0, 0.0, -0.25]);
+    }
+
+    // Test that the key holders can only decrypt the aggregate together
+    #[test]
+    fn test_split_decryption() {
+        let (public_key, private_key) = generate_keypair(512).unwrap();
+        let shares = private_key.split(3).unwrap();
+        let sum = public_key
+            .sum_encrypted(&[public_key.encrypt_values(&[2.0, -1.0]).unwrap(), public_key.encrypt_values(&[4.0, 0.5]).unwrap()])
+            .unwrap();
+
+        let partials = shares.iter().map(|s| s.partial_decrypt(&public_key, &sum).unwrap()).collect::<Vec<_>>();
+        assert_eq!(combine_partial_decryptions(&public_key, &partials, 2, 2).unwrap(), vec![6.0, -0.5]);
+
+        let missing_one = combine_partial_decryptions(&public_key, &partials[..2], 2, 2);
+        assert!(missing_one.map_or(true, |values| values != vec![6.0, -0.5]));
+    }
+
+    // Test the server side state going from encrypted updates to the weighted average
+    #[test]
+    fn test_encrypted_aggregation() {
+        let (public_key, private_key) = generate_keypair(512).unwrap();
+        let shares = private_key.split(2).unwrap();
+        let layout = vec![NamedTensor {
+            name: "fc.weight".to_string(),
+            shape: vec![2],
+            dtype: "Float".to_string(),
+            data: vec![0.0, 0.0],
+        }];
+        let mut first = layout.clone();
+        first[0].data = vec![1.0, -1.0];
+        let mut second = layout.clone();
+        second[0].data = vec![4.0, 2.0];
+
+        let mut aggregation = EncryptedAggregation::new(public_key.clone(), 2);
+        aggregation.add_update(public_key.encrypt_values(&weighted_values(&first, 20)).unwrap()).unwrap();
+        aggregation.add_update(public_key.encrypt_values(&weighted_values(&second, 10)).unwrap()).unwrap();
+        aggregation.close_round().unwrap();
+
+        let (aggregate, num_clients) = aggregation.pending_aggregate().cloned().unwrap();
+        assert_eq!(num_clients, 2);
+        let partial = shares[0].partial_decrypt(&public_key, &aggregate).unwrap();
+        assert_eq!(aggregation.add_partial_decryption(0, "client-1", partial, 3).unwrap(), None);
+        let partial = shares[1].partial_decrypt(&public_key, &aggregate).unwrap();
+        let sums = aggregation.add_partial_decryption(1, "client-2", partial, 3).unwrap().unwrap();
+
+        let (average, total_samples) = weighted_values_average(&layout, &sums).unwrap();
+        assert_eq!(total_samples, 30);
+        assert_eq!(average[0].data, vec![2.0, 0.0]);
+        assert!(aggregation.pending_aggregate().is_none());
+    }
+
+    // Test that invalid ciphertexts, repeated partial decryptions and foreign key holders are refused
+    #[test]
+    fn test_invalid_partial_decryptions() {
+        let (public_key, private_key) = generate_keypair(512).unwrap();
+        let n = public_key.modulus().unwrap();
+        let shares = private_key.split(2).unwrap();
+        for invalid in ["0".to_string(), (&n * &n).to_str_radix(16), n.to_str_radix(16)] {
+            assert!(public_key.parse_ciphertext(&invalid).is_err());
+        }
+
+        let mut aggregation = EncryptedAggregation::new(public_key.clone(), 2);
+        assert!(aggregation.add_update(vec!["0".to_string()]).is_err());
+        aggregation.add_update(public_key.encrypt_values(&[1.0]).unwrap()).unwrap();
+        aggregation.close_round().unwrap();
+        let (aggregate, _) = aggregation.pending_aggregate().cloned().unwrap();
+
+        assert!(aggregation.add_partial_decryption(0, "client-1", vec!["0".to_string()], 1).is_err());
+        let partial = shares[0].partial_decrypt(&public_key, &aggregate).unwrap();
+        assert_eq!(aggregation.add_partial_decryption(0, "client-1", partial.clone(), 1).unwrap(), None);
+        assert!(aggregation.add_partial_decryption(0, "client-1", partial.clone(), 1).is_err());
+        assert!(aggregation.add_partial_decryption(1, "client-1", partial, 1).is_err());
+        let partial = shares[1].partial_decrypt(&public_key, &aggregate).unwrap();
+        assert!(aggregation.add_partial_decryption(0, "client-2", partial.clone(), 1).is_err());
+
+        // A partial decryption that does not combine into a valid plaintext is an error, not a panic
+        let forged = vec![public_key.encrypt(&BigUint::from(2u32)).unwrap().to_str_radix(16)];
+        assert!(aggregation.add_partial_decryption(1, "client-2", forged, 1).is_err());
+        assert!(aggregation.pending_aggregate().is_none());
+    }
+}
@@ -0,0 +1,338 @@
+//Rényi differential privacy accountant for the sampled Gaussian mechanism
+//
+//Every use of a Gaussian mechanism (a noisy upload, a DP-SGD step, a noisy aggregation) adds its
+//RDP at a fixed set of integer orders. The total is converted to (epsilon, delta) with the
+//conversion of Canonne, Kamath and Steinke (2020), tighter than the classic `rdp + log(1/delta)/(alpha-1)`.
+
+use serde::{Deserialize, Serialize};
+
+/// Integer Rényi orders tracked by default
+pub fn default_orders() -> Vec<u32> {
+    (2..=64).chain([80, 96, 128, 160, 192, 256]).collect()
+}
+
+/// Gaussian mechanism applied to a Poisson subsample of the data, `steps` times
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SampledGaussian {
+    /// Probability for each record to take part in one step, 1 without subsampling.
+    pub sampling_rate: f64,
+    /// Noise standard deviation divided by the L2 sensitivity.
+    pub noise_multiplier: f64,
+    pub steps: usize,
+}
+
+impl SampledGaussian {
+    /// Single use of the Gaussian mechanism on the full data, e.g. one noisy model upload
+    pub fn single(noise_multiplier: f64) -> Self {
+        SampledGaussian {
+            sampling_rate: 1.0,
+            noise_multiplier,
+            steps: 1,
+        }
+    }
+}
+
+/// Tracks the privacy spent by a party across rounds and enforces a budget
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrivacyAccountant {
+    /// Maximal epsilon this party may spend, `f64::INFINITY` only tracks the spending.
+    pub epsilon_budget: f64,
+    pub target_delta: f64,
+    pub orders: Vec<u32>,
+    rdp: Vec<f64>,
+    rounds: usize,
+}
+
+impl PrivacyAccountant {
+    /// Accountant with an (epsilon, delta) budget tracked at the default orders
+    pub fn new(epsilon_budget: f64, target_delta: f64) -> Self {
+        let orders = default_orders();
+        PrivacyAccountant {
+            epsilon_budget,
+            target_delta,
+            rdp: vec![0.0; orders.len()],
+            orders,
+            rounds: 0,
+        }
+    }
+
+    /// Number of mechanisms recorded so far
+    pub fn rounds(&self) -> usize {
+        self.rounds
+    }
+
+    /// Epsilon spent so far for the target delta
+    pub fn spent_epsilon(&self) -> f64 {
+        rdp_to_epsilon(&self.orders, &self.rdp, self.target_delta).0
+    }
+
+    /// Epsilon that is left before the budget is exhausted
+    pub fn remaining_epsilon(&self) -> f64 {
+        (self.epsilon_budget - self.spent_epsilon()).max(0.0)
+    }
+
+    /// Epsilon that would be spent after also running `mechanism`
+    pub fn epsilon_after(&self, mechanism: &SampledGaussian) -> f64 {
+        self.epsilon_after_rdp(&compute_rdp(mechanism, &self.orders))
+    }
+
+    /// Epsilon that would be spent after also adding `rdp`, given at `self.orders`
+    pub fn epsilon_after_rdp(&self, rdp: &[f64]) -> f64 {
+        let rdp = self
+            .rdp
+            .iter()
+            .zip(rdp)
+            .map(|(spent, added)| spent + added)
+            .collect::<Vec<_>>();
+        rdp_to_epsilon(&self.orders, &rdp, self.target_delta).0
+    }
+
+    /// Whether `mechanism` can run without exceeding the budget
+    pub fn can_spend(&self, mechanism: &SampledGaussian) -> bool {
+        self.epsilon_after(mechanism) <= self.epsilon_budget
+    }
+
+    /// Smallest noise multiplier keeping `steps` steps at `sampling_rate` within the budget, on top of the
+    /// privacy spent so far and of the `reserved` mechanisms that will also run
+    pub fn noise_multiplier_within_budget(&self, sampling_rate: f64, steps: usize, reserved: &[SampledGaussian]) -> Result<f64, String> {
+        let reserved_rdp = reserved.iter().fold(self.rdp.clone(), |mut rdp, mechanism| {
+            for (total, added) in rdp.iter_mut().zip(compute_rdp(mechanism, &self.orders)) {
+                *total += added;
+            }
+            rdp
+        });
+        search_noise_multiplier(self.epsilon_budget, |noise_multiplier| {
+            let mechanism = SampledGaussian { sampling_rate, noise_multiplier, steps };
+            let rdp = reserved_rdp
+                .iter()
+                .zip(compute_rdp(&mechanism, &self.orders))
+                .map(|(reserved, added)| reserved + added)
+                .collect::<Vec<_>>();
+            rdp_to_epsilon(&self.orders, &rdp, self.target_delta).0
+        })
+    }
+
+    /// Takes over the privacy spent by `saved`, e.g. an accountant restored from a checkpoint, keeping the budget
+    /// and delta of this one
+    pub fn restore_spending(&mut self, saved: &PrivacyAccountant) -> Result<(), String> {
+        if saved.orders != self.orders {
+            return Err("The saved privacy accountant tracks other Rényi orders".to_string());
+        }
+        self.rdp = saved.rdp.clone();
+        self.rounds = saved.rounds;
+        Ok(())
+    }
+
+    /// Records `mechanism` and returns the total epsilon spent.
+    /// Nothing is recorded and an error is returned if it would exceed the budget.
+    pub fn spend(&mut self, mechanism: &SampledGaussian) -> Result<f64, String> {
+        self.spend_rdp(&compute_rdp(mechanism, &self.orders))
+    }
+
+    /// Records a mechanism given by its RDP at `self.orders`, e.g. a mechanism that is not Gaussian
+    pub fn spend_rdp(&mut self, rdp: &[f64]) -> Result<f64, String> {
+        if rdp.len() != self.orders.len() {
+            return Err(format!("RDP has {} orders, the accountant tracks {}", rdp.len(), self.orders.len()));
+        }
+        let epsilon = self.epsilon_after_rdp(rdp);
+        if epsilon > self.epsilon_budget {
+            return Err(format!(
+                "Privacy budget exhausted: spending would reach epsilon {:.3} of the budget {:.3}",
+                epsilon, self.epsilon_budget
+            ));
+        }
+        for (spent, added) in self.rdp.iter_mut().zip(rdp) {
+            *spent += added;
+        }
+        self.rounds += 1;
+        Ok(epsilon)
+    }
+}
+
+/// RDP of the sampled Gaussian mechanism at every order (Mironov, Talwar and Zhang 2019)
+pub fn compute_rdp(mechanism: &SampledGaussian, orders: &[u32]) -> Vec<f64> {
+    orders
+        .iter()
+        .map(|&order| mechanism.steps as f64 * rdp_single_step(mechanism.sampling_rate, mechanism.noise_multiplier, order))
+        .collect()
+}
+
+/// Converts RDP to (epsilon, delta), returns the epsilon and the order achieving it
+pub fn rdp_to_epsilon(orders: &[u32], rdp: &[f64], delta: f64) -> (f64, u32) {
+    orders
+        .iter()
+        .zip(rdp.iter())
+        .map(|(&order, &rdp)| {
+            let alpha = order as f64;
+            let epsilon = rdp - (delta.ln() + alpha.ln()) / (alpha - 1.0) + ((alpha - 1.0) / alpha).ln();
+            (epsilon.max(0.0), order)
+        })
+        .fold((f64::INFINITY, 0), |best, candidate| if candidate.0 < best.0 { candidate } else { best })
+}
+
+/// Smallest noise multiplier keeping `steps` steps at `sampling_rate` within (target_epsilon, target_delta),
+/// found by binary search
+pub fn noise_multiplier_for(target_epsilon: f64, target_delta: f64, sampling_rate: f64, steps: usize) -> Result<f64, String> {
+    let orders = default_orders();
+    search_noise_multiplier(target_epsilon, |noise_multiplier| {
+        let mechanism = SampledGaussian { sampling_rate, noise_multiplier, steps };
+        rdp_to_epsilon(&orders, &compute_rdp(&mechanism, &orders), target_delta).0
+    })
+}
+
+/// Binary search of the smallest noise multiplier whose `epsilon` stays within `target_epsilon`
+fn search_noise_multiplier<F: Fn(f64) -> f64>(target_epsilon: f64, epsilon: F) -> Result<f64, String> {
+    if !(target_epsilon > 0.0 && target_epsilon.is_finite()) {
+        return Err(format!("Target epsilon must be positive and finite, got {}", target_epsilon));
+    }
+    let mut high = 1.0;
+    while epsilon(high) > target_epsilon {
+        high *= 2.0;
+        if high > 1e6 {
+            return Err(format!("No noise multiplier reaches epsilon {}", target_epsilon));
+        }
+    }
+    let mut low = 0.0;
+    while high - low > 1e-4 * high {
+        let middle = (low + high) / 2.0;
+        if epsilon(middle) > target_epsilon {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    Ok(high)
+}
+
+fn rdp_single_step(sampling_rate: f64, noise_multiplier: f64, order: u32) -> f64 {
+    if sampling_rate <= 0.0 {
+        return 0.0;
+    }
+    if noise_multiplier <= 0.0 {
+        return f64::INFINITY;
+    }
+    let alpha = order as f64;
+    let variance = noise_multiplier * noise_multiplier;
+    if sampling_rate >= 1.0 {
+        return alpha / (2.0 * variance);
+    }
+
+    // log A_alpha = log sum_k C(alpha, k) (1 - q)^(alpha - k) q^k exp((k^2 - k) / (2 sigma^2))
+    let mut log_binomial = 0.0;
+    let mut log_a = f64::NEG_INFINITY;
+    for k in 0..=order {
+        if k > 0 {
+            log_binomial += ((order - k + 1) as f64).ln() - (k as f64).ln();
+        }
+        let k = k as f64;
+        let term = log_binomial
+            + (alpha - k) * (1.0 - sampling_rate).ln()
+            + k * sampling_rate.ln()
+            + (k * k - k) / (2.0 * variance);
+        log_a = log_add_exp(log_a, term);
+    }
+    log_a / (alpha - 1.0)
+}
+
+fn log_add_exp(a: f64, b: f64) -> f64 {
+    let (high, low) = if a > b { (a, b) } else { (b, a) };
+    if low == f64::NEG_INFINITY {
+        return high;
+    }
+    high + (low - high).exp().ln_1p()
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test the RDP of the Gaussian mechanism without subsampling
+    #[test]
+    fn test_rdp_without_subsampling() {
+        let rdp = compute_rdp(&SampledGaussian::single(2.0), &[2, 10]);
+        assert!((rdp[0] - 2.0 / 8.0).abs() < 1e-12);
+        assert!((rdp[1] - 10.0 / 8.0).abs() < 1e-12);
+    }
+
+    // Test that subsampling amplifies privacy and matches the usual DP-SGD figures
+    #[test]
+    fn test_subsampled_gaussian_epsilon() {
+        let mut accountant = PrivacyAccountant::new(f64::INFINITY, 1e-5);
+        // MNIST DP-SGD: 60000 samples, batches of 256, noise 1.1, 60 epochs
+        let mechanism = SampledGaussian {
+            sampling_rate: 256.0 / 60000.0,
+            noise_multiplier: 1.1,
+            steps: 60 * 60000 / 256,
+        };
+        let epsilon = accountant.spend(&mechanism).unwrap();
+        assert!(epsilon > 2.5 && epsilon < 3.2, "epsilon = {}", epsilon);
+
+        let full_batch = PrivacyAccountant::new(f64::INFINITY, 1e-5).epsilon_after(&SampledGaussian {
+            sampling_rate: 1.0,
+            ..mechanism
+        });
+        assert!(full_batch > 10.0 * epsilon);
+    }
+
+    // Test that the calibrated noise multiplier spends the target epsilon
+    #[test]
+    fn test_noise_multiplier_for() {
+        let noise_multiplier = noise_multiplier_for(3.0, 1e-5, 0.01, 1000).unwrap();
+        let mechanism = SampledGaussian { sampling_rate: 0.01, noise_multiplier, steps: 1000 };
+        let epsilon = PrivacyAccountant::new(3.0, 1e-5).epsilon_after(&mechanism);
+        assert!(epsilon <= 3.0 && epsilon > 2.95, "epsilon = {}", epsilon);
+        assert!(noise_multiplier_for(f64::INFINITY, 1e-5, 0.01, 1000).is_err());
+    }
+
+    // Test that the accountant refuses to spend past the budget
+    #[test]
+    fn test_privacy_budget() {
+        let mut accountant = PrivacyAccountant::new(10.0, 1e-5);
+        let upload = SampledGaussian::single(2.0);
+
+        let mut rounds = 0;
+        while accountant.spend(&upload).is_ok() {
+            rounds += 1;
+        }
+        assert!(rounds > 0);
+        assert_eq!(accountant.rounds(), rounds);
+        assert!(accountant.spent_epsilon() <= 10.0);
+        assert!(!accountant.can_spend(&upload));
+        assert!(accountant.remaining_epsilon() < 10.0);
+    }
+
+    // Test that restoring the spending keeps the configured budget and delta
+    #[test]
+    fn test_restore_spending() {
+        let mut saved = PrivacyAccountant::new(4.0, 1e-5);
+        saved.spend(&SampledGaussian::single(2.0)).unwrap();
+        let mut accountant = PrivacyAccountant::new(16.0, 1e-6);
+        accountant.restore_spending(&saved).unwrap();
+
+        assert_eq!(accountant.epsilon_budget, 16.0);
+        assert_eq!(accountant.target_delta, 1e-6);
+        assert_eq!(accountant.rounds(), 1);
+        assert!(accountant.spent_epsilon() > saved.spent_epsilon());
+
+        let mut other_orders = PrivacyAccountant::new(4.0, 1e-5);
+        other_orders.orders = vec![2, 4];
+        assert!(accountant.restore_spending(&other_orders).is_err());
+    }
+
+    // Test that the noise calibrated within the budget leaves room for the reserved mechanisms
+    #[test]
+    fn test_noise_multiplier_within_budget() {
+        let mut accountant = PrivacyAccountant::new(3.0, 1e-5);
+        accountant.spend(&SampledGaussian::single(5.0)).unwrap();
+        let upload = SampledGaussian::single(4.0);
+        let noise_multiplier = accountant.noise_multiplier_within_budget(0.01, 1000, &[upload]).unwrap();
+
+        accountant.spend(&SampledGaussian { sampling_rate: 0.01, noise_multiplier, steps: 1000 }).unwrap();
+        assert!(accountant.spend(&upload).is_ok());
+        assert!(accountant.spent_epsilon() > 2.95, "epsilon = {}", accountant.spent_epsilon());
+        // No noise helps once the reserved mechanisms alone exceed the budget
+        assert!(accountant.noise_multiplier_within_budget(0.01, 1000, &[SampledGaussian::single(0.5)]).is_err());
+        assert!(PrivacyAccountant::new(f64::INFINITY, 1e-5).noise_multiplier_within_budget(0.01, 1000, &[]).is_err());
+    }
+}
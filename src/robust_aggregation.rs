@@ -0,0 +1,375 @@
+//Byzantine-robust aggregation rules for federations with untrusted participants
+//
+//The rules work on the flattened client models and ignore the client-reported number of
+//samples, since a malicious client can inflate it to dominate a weighted average.
+
+use log::warn;
+use crate::aggregation::{Aggregator, ClientUpdate};
+use crate::state_dict::{check_same_layout, StateDict};
+
+/// Coordinate-wise median of the client models
+#[derive(Debug, Clone, Default)]
+pub struct CoordinateMedian;
+
+impl Aggregator for CoordinateMedian {
+    fn name(&self) -> &str {
+        "CoordinateMedian"
+    }
+
+    fn aggregate(&mut self, global: &StateDict, updates: &[ClientUpdate]) -> Result<StateDict, String> {
+        let vectors = flatten_updates(global, updates)?;
+        let aggregated = coordinate_wise(&vectors, median);
+        Ok(unflatten(global, &aggregated))
+    }
+}
+
+/// Coordinate-wise mean after removing the `beta` fraction of largest and smallest values
+#[derive(Debug, Clone)]
+pub struct TrimmedMean {
+    /// Fraction trimmed on each side, in `[0, 0.5)`.
+    pub beta: f64,
+}
+
+impl TrimmedMean {
+    /// Trimmed mean removing `beta * n` values on each side of every coordinate
+    pub fn new(beta: f64) -> Self {
+        TrimmedMean { beta }
+    }
+}
+
+impl Aggregator for TrimmedMean {
+    fn name(&self) -> &str {
+        "TrimmedMean"
+    }
+
+    fn aggregate(&mut self, global: &StateDict, updates: &[ClientUpdate]) -> Result<StateDict, String> {
+        if !(0.0..0.5).contains(&self.beta) {
+            return Err(format!("Trimmed mean needs beta in [0, 0.5), got {}", self.beta));
+        }
+        let vectors = flatten_updates(global, updates)?;
+        let trimmed = (self.beta * vectors.len() as f64).floor() as usize;
+        let aggregated = coordinate_wise(&vectors, |values| {
+            values.sort_by(|a, b| a.total_cmp(b));
+            mean(&values[trimmed..values.len() - trimmed])
+        });
+        Ok(unflatten(global, &aggregated))
+    }
+}
+
+/// Krum, or Multi-Krum when more than one model is selected
+#[derive(Debug, Clone)]
+pub struct Krum {
+    /// Number of Byzantine clients tolerated.
+    pub num_byzantine: usize,
+    /// Number of selected models averaged together, 1 for plain Krum.
+    pub num_selected: usize,
+}
+
+impl Krum {
+    /// Krum selecting the single most central model
+    pub fn new(num_byzantine: usize) -> Self {
+        Krum { num_byzantine, num_selected: 1 }
+    }
+
+    /// Multi-Krum averaging the `num_selected` most central models
+    pub fn multi(num_byzantine: usize, num_selected: usize) -> Self {
+        Krum { num_byzantine, num_selected }
+    }
+}
+
+impl Aggregator for Krum {
+    fn name(&self) -> &str {
+        if self.num_selected > 1 { "MultiKrum" } else { "Krum" }
+    }
+
+    fn aggregate(&mut self, global: &StateDict, updates: &[ClientUpdate]) -> Result<StateDict, String> {
+        let vectors = flatten_updates(global, updates)?;
+        if self.num_selected == 0 || self.num_selected > vectors.len() {
+            return Err(format!("Krum cannot select {} of {} models", self.num_selected, vectors.len()));
+        }
+        let selected = krum_select(&vectors, self.num_byzantine, self.num_selected)?;
+        let selected_vectors = selected.iter().map(|&i| vectors[i].clone()).collect::<Vec<_>>();
+        let aggregated = coordinate_wise(&selected_vectors, |values| mean(values));
+        Ok(unflatten(global, &aggregated))
+    }
+}
+
+/// Bulyan: Krum selection followed by a coordinate-wise trimmed mean around the median
+#[derive(Debug, Clone)]
+pub struct Bulyan {
+    /// Number of Byzantine clients tolerated, needs at least `4f + 3` clients.
+    pub num_byzantine: usize,
+}
+
+impl Bulyan {
+    /// Bulyan tolerating `num_byzantine` malicious clients
+    pub fn new(num_byzantine: usize) -> Self {
+        Bulyan { num_byzantine }
+    }
+}
+
+impl Aggregator for Bulyan {
+    fn name(&self) -> &str {
+        "Bulyan"
+    }
+
+    fn aggregate(&mut self, global: &StateDict, updates: &[ClientUpdate]) -> Result<StateDict, String> {
+        let f = self.num_byzantine;
+        let mut remaining = flatten_updates(global, updates)?;
+        if remaining.len() < 4 * f + 3 {
+            return Err(format!("Bulyan with f = {} needs at least {} clients, got {}", f, 4 * f + 3, remaining.len()));
+        }
+
+        // Recursively pick theta = n - 2f models with Krum. The last picks run on fewer
+        // than 2f + 3 models, so at least one neighbour is always scored, and the last model
+        // left when f = 0 is picked without scoring.
+        let theta = remaining.len() - 2 * f;
+        let mut selected = Vec::with_capacity(theta);
+        while selected.len() < theta {
+            let index = match remaining.len() {
+                1 => 0,
+                n => lowest_krum_scores(&remaining, n.saturating_sub(f + 2).clamp(1, n - 1), 1)[0],
+            };
+            selected.push(remaining.remove(index));
+        }
+
+        // Average the beta = theta - 2f values closest to the median of every coordinate.
+        let beta = theta - 2 * f;
+        let aggregated = coordinate_wise(&selected, |values| {
+            let med = median(values);
+            values.sort_by(|a, b| (*a - med).abs().total_cmp(&(*b - med).abs()));
+            mean(&values[..beta])
+        });
+        Ok(unflatten(global, &aggregated))
+    }
+}
+
+/// Geometric median of the client models computed with the Weiszfeld algorithm
+#[derive(Debug, Clone)]
+pub struct GeometricMedian {
+    pub max_iterations: usize,
+    /// Stops when the estimate moves less than this distance.
+    pub tolerance: f64,
+}
+
+impl GeometricMedian {
+    /// Weiszfeld iterations with user defined stopping criteria
+    pub fn new(max_iterations: usize, tolerance: f64) -> Self {
+        GeometricMedian { max_iterations, tolerance }
+    }
+}
+
+impl Default for GeometricMedian {
+    fn default() -> Self {
+        GeometricMedian::new(100, 1e-6)
+    }
+}
+
+impl Aggregator for GeometricMedian {
+    fn name(&self) -> &str {
+        "GeometricMedian"
+    }
+
+    fn aggregate(&mut self, global: &StateDict, updates: &[ClientUpdate]) -> Result<StateDict, String> {
+        let vectors = flatten_updates(global, updates)?;
+        let mut estimate = coordinate_wise(&vectors, |values| mean(values));
+
+        for _ in 0..self.max_iterations {
+            // Smoothing term avoids a division by zero when the estimate hits a client model.
+            let inverse_distances = vectors
+                .iter()
+                .map(|v| 1.0 / squared_distance(v, &estimate).sqrt().max(1e-12))
+                .collect::<Vec<_>>();
+            let total: f64 = inverse_distances.iter().sum();
+
+            let mut next = vec![0.0; estimate.len()];
+            for (v, w) in vectors.iter().zip(inverse_distances.iter()) {
+                for (n, x) in next.iter_mut().zip(v.iter()) {
+                    *n += w / total * x;
+                }
+            }
+
+            let shift = squared_distance(&next, &estimate).sqrt();
+            estimate = next;
+            if shift < self.tolerance {
+                break;
+            }
+        }
+
+        Ok(unflatten(global, &estimate))
+    }
+}
+
+/// Indices of the `num_selected` models with the lowest Krum scores
+pub fn krum_select(vectors: &[Vec<f64>], num_byzantine: usize, num_selected: usize) -> Result<Vec<usize>, String> {
+    let n = vectors.len();
+    if n < 2 * num_byzantine + 3 {
+        return Err(format!(
+            "Krum with f = {} needs at least {} clients, got {}",
+            num_byzantine,
+            2 * num_byzantine + 3,
+            n
+        ));
+    }
+
+    Ok(lowest_krum_scores(vectors, n - num_byzantine - 2, num_selected))
+}
+
+/// Indices of the `count` models with the smallest sum of squared distances to their closest neighbours
+fn lowest_krum_scores(vectors: &[Vec<f64>], neighbours: usize, count: usize) -> Vec<usize> {
+    let n = vectors.len();
+    let mut scores = (0..n)
+        .map(|i| {
+            let mut distances = (0..n)
+                .filter(|&j| j != i)
+                .map(|j| squared_distance(&vectors[i], &vectors[j]))
+                .collect::<Vec<_>>();
+            distances.sort_by(|a, b| a.total_cmp(b));
+            (i, distances[..neighbours].iter().sum::<f64>())
+        })
+        .collect::<Vec<_>>();
+    scores.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    scores.iter().take(count).map(|&(i, _)| i).collect()
+}
+
+/// Flattens the client models, dropping the ones holding NaN or infinite values
+fn flatten_updates(global: &StateDict, updates: &[ClientUpdate]) -> Result<Vec<Vec<f64>>, String> {
+    let mut vectors = Vec::with_capacity(updates.len());
+    for update in updates {
+        check_same_layout(global, &update.weights)?;
+        let vector = update.weights.iter().flat_map(|t| t.data.iter().copied()).collect::<Vec<_>>();
+        if vector.iter().all(|v| v.is_finite()) {
+            vectors.push(vector);
+        } else {
+            warn!("Dropping a client update with non-finite weights");
+        }
+    }
+    if vectors.is_empty() {
+        return Err("No valid client updates to aggregate".to_string());
+    }
+    Ok(vectors)
+}
+
+/// Writes a flat vector back into the layout of the global model
+fn unflatten(global: &StateDict, vector: &[f64]) -> StateDict {
+    let mut state_dict = global.clone();
+    let mut offset = 0;
+    for named_tensor in state_dict.iter_mut() {
+        let len = named_tensor.data.len();
+        named_tensor.data.copy_from_slice(&vector[offset..offset + len]);
+        offset += len;
+    }
+    state_dict
+}
+
+/// Applies `reduce` to the values of every coordinate across the vectors
+fn coordinate_wise<F: FnMut(&mut [f64]) -> f64>(vectors: &[Vec<f64>], mut reduce: F) -> Vec<f64> {
+    let mut values = vec![0.0; vectors.len()];
+    (0..vectors[0].len())
+        .map(|coordinate| {
+            for (value, vector) in values.iter_mut().zip(vectors.iter()) {
+                *value = vector[coordinate];
+            }
+            reduce(&mut values)
+        })
+        .collect()
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let middle = values.len() / 2;
+    if values.len() % 2 == 1 {
+        values[middle]
+    } else {
+        (values[middle - 1] + values[middle]) / 2.0
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn squared_distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregation::FedAvg;
+    use crate::state_dict::state_dict;
+
+    // Seven honest clients close to 1.0 and two attackers pushing the model to 1e6.
+    // The attackers also claim a huge number of samples.
+    fn poisoned_updates() -> Vec<ClientUpdate> {
+        let mut updates = (0..7)
+            .map(|i| ClientUpdate::new(state_dict(vec![1.0 + 0.01 * i as f64, -1.0 - 0.01 * i as f64]), 100))
+            .collect::<Vec<_>>();
+        updates.push(ClientUpdate::new(state_dict(vec![1e6, 1e6]), 1_000_000));
+        updates.push(ClientUpdate::new(state_dict(vec![-1e6, 1e6]), 1_000_000));
+        updates
+    }
+
+    fn assert_not_hijacked(aggregator: &mut dyn Aggregator) {
+        let global = state_dict(vec![0.0, 0.0]);
+        let next = aggregator.aggregate(&global, &poisoned_updates()).unwrap();
+        assert!((next[0].data[0] - 1.03).abs() < 0.1, "{}: {:?}", aggregator.name(), next[0].data);
+        assert!((next[0].data[1] + 1.03).abs() < 0.1, "{}: {:?}", aggregator.name(), next[0].data);
+    }
+
+    // Test that plain FedAvg is hijacked by a single round of poisoned updates
+    #[test]
+    fn test_fed_avg_is_hijacked() {
+        let global = state_dict(vec![0.0, 0.0]);
+        let next = FedAvg.aggregate(&global, &poisoned_updates()).unwrap();
+        assert!(next[0].data[1] > 1e5);
+    }
+
+    // Test that every robust rule keeps the model close to the honest clients
+    #[test]
+    fn test_robust_aggregators_resist_poisoning() {
+        assert_not_hijacked(&mut CoordinateMedian);
+        assert_not_hijacked(&mut TrimmedMean::new(0.25));
+        assert_not_hijacked(&mut Krum::new(2));
+        assert_not_hijacked(&mut Krum::multi(2, 4));
+        assert_not_hijacked(&mut Bulyan::new(1));
+        assert_not_hijacked(&mut GeometricMedian::default());
+    }
+
+    // Test that Bulyan without Byzantine clients averages every model
+    #[test]
+    fn test_bulyan_without_byzantine_clients() {
+        let global = state_dict(vec![0.0]);
+        let updates = [1.0, 2.0, 6.0].iter().map(|&v| ClientUpdate::new(state_dict(vec![v]), 1)).collect::<Vec<_>>();
+
+        let next = Bulyan::new(0).aggregate(&global, &updates).unwrap();
+        assert_eq!(next[0].data, vec![3.0]);
+    }
+
+    // Test that NaN updates are dropped instead of poisoning the median
+    #[test]
+    fn test_non_finite_updates_are_dropped() {
+        let global = state_dict(vec![0.0]);
+        let updates = vec![
+            ClientUpdate::new(state_dict(vec![1.0]), 1),
+            ClientUpdate::new(state_dict(vec![f64::NAN]), 1),
+            ClientUpdate::new(state_dict(vec![3.0]), 1),
+        ];
+
+        let next = CoordinateMedian.aggregate(&global, &updates).unwrap();
+        assert_eq!(next[0].data, vec![2.0]);
+    }
+
+    // Test that the rules refuse to run with too few clients for their guarantees
+    #[test]
+    fn test_robust_aggregators_need_enough_clients() {
+        let global = state_dict(vec![0.0]);
+        let updates = (0..4).map(|i| ClientUpdate::new(state_dict(vec![i as f64]), 1)).collect::<Vec<_>>();
+
+        assert!(Krum::new(1).aggregate(&global, &updates).is_err());
+        assert!(Bulyan::new(1).aggregate(&global, &updates).is_err());
+        assert!(TrimmedMean::new(0.5).aggregate(&global, &updates).is_err());
+    }
+}
@@ -0,0 +1,532 @@
+//Round lifecycle of the server: open -> selecting -> training -> aggregating -> closed
+//
+//A round opens empty. The first client to join moves it to selecting, and more clients join until
+//`max_participants` is reached or the selection deadline passes with at least `min_participants`. The
+//participants are then fixed and the round waits for their updates until all of them arrived or the
+//training deadline passes. With at least `min_participants` updates the round is aggregated, otherwise
+//it is abandoned. Either way it closes and the next round opens with the next id.
+//With a client selector, clients check in as candidates instead and the selector samples the participants
+//among them when selection closes.
+//Deadlines are checked whenever the round is used, no timer runs in the background.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use crate::selection::{CheckIn, ClientSelector};
+
+/// Phase of a round
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundPhase {
+    /// No participant yet.
+    Open,
+    /// Clients are joining the round.
+    Selecting,
+    /// Participants are fixed, waiting for their updates.
+    Training,
+    /// Updates are complete and wait to be aggregated.
+    Aggregating,
+    /// The round is over, the next one opens right away.
+    Closed,
+}
+
+/// How a round ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundOutcome {
+    Aggregated,
+    /// Too few updates arrived before the training deadline.
+    Abandoned,
+}
+
+/// Answer to a client checking in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckInStatus {
+    /// The client takes part in the current round.
+    Selected,
+    /// The client is a candidate, the cohort is not selected yet.
+    Waiting,
+    /// The client should check in again for the next round.
+    NotSelected,
+}
+
+/// Participation limits and deadlines of every round
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoundConfig {
+    /// Participants needed to start training, and updates needed to aggregate.
+    pub min_participants: usize,
+    /// Participants after which the round stops selecting.
+    pub max_participants: usize,
+    /// Time from the first participant until selection ends, unlimited when `None`.
+    pub selection_timeout: Option<Duration>,
+    /// Time from the end of selection until stragglers are dropped, unlimited when `None`.
+    pub training_timeout: Option<Duration>,
+}
+
+impl RoundConfig {
+    /// Rounds of exactly `participants` clients without deadlines, aggregating once all of them sent an update
+    pub fn fixed(participants: usize) -> Self {
+        RoundConfig {
+            min_participants: participants,
+            max_participants: participants,
+            selection_timeout: None,
+            training_timeout: None,
+        }
+    }
+}
+
+/// Summary of the last closed round
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoundSummary {
+    pub round_id: u64,
+    pub outcome: RoundOutcome,
+    pub participants: usize,
+    pub updates: usize,
+}
+
+/// Status of the current round served to clients
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoundStatus {
+    pub round_id: u64,
+    pub phase: RoundPhase,
+    pub participants: Vec<String>,
+    pub updates: usize,
+    /// Clients that checked in and wait for the selection.
+    pub candidates: usize,
+    pub min_participants: usize,
+    pub max_participants: usize,
+    /// Seconds left before the deadline of the current phase, if it has one.
+    pub deadline_in_secs: Option<f64>,
+    pub last_round: Option<RoundSummary>,
+}
+
+/// Participants and updates of the current round kept across restarts, deadlines are not saved
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingRound<T> {
+    pub phase: RoundPhase,
+    pub participants: BTreeSet<String>,
+    pub updates: BTreeMap<String, T>,
+    pub anonymous_clients: u64,
+}
+
+/// Current round of the server holding the updates of its participants
+pub struct RoundManager<T> {
+    config: RoundConfig,
+    round_id: u64,
+    phase: RoundPhase,
+    participants: BTreeSet<String>,
+    updates: BTreeMap<String, T>,
+    deadline: Option<Instant>,
+    last_round: Option<RoundSummary>,
+    anonymous_clients: u64,
+    /// Strategy sampling the participants among the candidates, first come first served when `None`.
+    selector: Option<Box<dyn ClientSelector>>,
+    candidates: BTreeMap<String, CheckIn>,
+    not_selected: BTreeSet<String>,
+}
+
+impl<T> RoundManager<T> {
+    /// First round, with id 0
+    pub fn new(config: RoundConfig) -> Result<Self, String> {
+        if config.min_participants == 0 || config.min_participants > config.max_participants {
+            return Err(format!(
+                "Participants must satisfy 1 <= min ({}) <= max ({})",
+                config.min_participants, config.max_participants
+            ));
+        }
+        Ok(RoundManager {
+            config,
+            round_id: 0,
+            phase: RoundPhase::Open,
+            participants: BTreeSet::new(),
+            updates: BTreeMap::new(),
+            deadline: None,
+            last_round: None,
+            anonymous_clients: 0,
+            selector: None,
+            candidates: BTreeMap::new(),
+            not_selected: BTreeSet::new(),
+        })
+    }
+
+    /// Samples the participants of every round with `selector` among the clients that checked in
+    pub fn set_selector(&mut self, selector: Box<dyn ClientSelector>) {
+        self.selector = Some(selector);
+    }
+
+    pub fn config(&self) -> &RoundConfig {
+        &self.config
+    }
+
+    pub fn round_id(&self) -> u64 {
+        self.round_id
+    }
+
+    pub fn phase(&self) -> RoundPhase {
+        self.phase
+    }
+
+    /// Adds a client to the round while it is selecting
+    pub fn join(&mut self, client_id: &str, now: Instant) -> Result<(), String> {
+        self.tick(now);
+        if self.participants.contains(client_id) {
+            return Ok(());
+        }
+        if !matches!(self.phase, RoundPhase::Open | RoundPhase::Selecting) {
+            return Err(format!("Round {} is not selecting participants", self.round_id));
+        }
+        self.participants.insert(client_id.to_string());
+        if self.phase == RoundPhase::Open {
+            self.phase = RoundPhase::Selecting;
+            self.deadline = self.config.selection_timeout.map(|timeout| now + timeout);
+        }
+        if self.participants.len() >= self.config.max_participants {
+            self.start_training(now);
+        }
+        Ok(())
+    }
+
+    /// Registers a client for the current round. Without a selector it joins right away, otherwise it is a
+    /// candidate until the cohort is selected: once `max_participants` clients checked in, or at the selection
+    /// deadline when there is one.
+    pub fn check_in(&mut self, check_in: CheckIn, now: Instant) -> CheckInStatus {
+        self.tick(now);
+        if self.participants.contains(&check_in.client_id) {
+            return CheckInStatus::Selected;
+        }
+        if self.not_selected.contains(&check_in.client_id) || !matches!(self.phase, RoundPhase::Open | RoundPhase::Selecting) {
+            return CheckInStatus::NotSelected;
+        }
+        if self.selector.is_none() {
+            return match self.join(&check_in.client_id, now) {
+                Ok(()) => CheckInStatus::Selected,
+                Err(_) => CheckInStatus::NotSelected,
+            };
+        }
+
+        let client_id = check_in.client_id.clone();
+        self.candidates.insert(client_id.clone(), check_in);
+        if self.phase == RoundPhase::Open {
+            self.phase = RoundPhase::Selecting;
+            self.deadline = self.config.selection_timeout.map(|timeout| now + timeout);
+        }
+        if self.deadline.is_none() && self.candidates.len() >= self.config.max_participants {
+            self.select_cohort(now);
+        }
+        if self.participants.contains(&client_id) {
+            CheckInStatus::Selected
+        } else if self.not_selected.contains(&client_id) {
+            CheckInStatus::NotSelected
+        } else {
+            CheckInStatus::Waiting
+        }
+    }
+
+    /// Records the update of a client for round `round_id` (the current round when `None`).
+    /// Without a selector, clients sending an update while the round is selecting join it and anonymous
+    /// clients get a fresh id.
+    /// Late, duplicate and unselected updates are refused.
+    pub fn submit(&mut self, client_id: Option<String>, round_id: Option<u64>, update: T, now: Instant) -> Result<(), String> {
+        self.tick(now);
+        if let Some(round_id) = round_id {
+            if round_id != self.round_id {
+                return Err(format!("Update for round {} but the current round is {}", round_id, self.round_id));
+            }
+        }
+        let client_id = client_id.unwrap_or_else(|| {
+            self.anonymous_clients += 1;
+            format!("anonymous-{}", self.anonymous_clients)
+        });
+        if self.updates.contains_key(&client_id) {
+            return Err(format!("Client {} already sent its update for round {}", client_id, self.round_id));
+        }
+        if !self.participants.contains(&client_id) {
+            if self.selector.is_some() {
+                return Err(format!("Client {} was not selected for round {}", client_id, self.round_id));
+            }
+            self.join(&client_id, now)
+                .map_err(|_| format!("Client {} is not a participant of round {}", client_id, self.round_id))?;
+        }
+        if self.phase == RoundPhase::Aggregating {
+            return Err(format!("Round {} is already aggregating", self.round_id));
+        }
+
+        self.updates.insert(client_id, update);
+        if self.phase == RoundPhase::Training && self.updates.len() == self.participants.len() {
+            self.phase = RoundPhase::Aggregating;
+        }
+        Ok(())
+    }
+
+    /// Applies the deadlines that passed at `now`
+    pub fn tick(&mut self, now: Instant) {
+        match self.deadline {
+            Some(deadline) if now >= deadline => {}
+            _ => return,
+        }
+        match self.phase {
+            RoundPhase::Selecting if self.selector.is_some() && self.candidates.len() >= self.config.min_participants => {
+                self.select_cohort(now)
+            }
+            RoundPhase::Selecting if self.selector.is_none() && self.participants.len() >= self.config.min_participants => {
+                self.start_training(now)
+            }
+            RoundPhase::Training if self.updates.len() >= self.config.min_participants => {
+                self.phase = RoundPhase::Aggregating;
+                self.deadline = None;
+            }
+            RoundPhase::Training => self.close(RoundOutcome::Abandoned),
+            // Selection goes on until enough clients joined
+            _ => {}
+        }
+    }
+
+    /// Opens round `round_id` again after a restart, without participants until `restore_pending`
+    pub fn resume(&mut self, round_id: u64, last_round: Option<RoundSummary>) {
+        self.round_id = round_id;
+        self.last_round = last_round;
+        self.phase = RoundPhase::Open;
+        self.participants.clear();
+        self.updates.clear();
+        self.candidates.clear();
+        self.not_selected.clear();
+        self.deadline = None;
+    }
+
+    /// Participants and updates of the current round, saved in checkpoints
+    pub fn pending(&self) -> PendingRound<T>
+    where
+        T: Clone,
+    {
+        PendingRound {
+            phase: self.phase,
+            participants: self.participants.clone(),
+            updates: self.updates.clone(),
+            anonymous_clients: self.anonymous_clients,
+        }
+    }
+
+    /// Restores the participants and updates saved by `pending` after `resume`. The deadline of the phase
+    /// starts over at `now`, and candidates waiting for a selector check in again.
+    pub fn restore_pending(&mut self, pending: PendingRound<T>, now: Instant) {
+        self.anonymous_clients = pending.anonymous_clients;
+        self.participants = pending.participants;
+        self.updates = pending.updates;
+        self.phase = pending.phase;
+        self.deadline = match pending.phase {
+            RoundPhase::Selecting if self.participants.is_empty() && self.selector.is_some() => {
+                self.phase = RoundPhase::Open;
+                None
+            }
+            RoundPhase::Selecting => self.config.selection_timeout.map(|timeout| now + timeout),
+            RoundPhase::Training => self.config.training_timeout.map(|timeout| now + timeout),
+            _ => None,
+        };
+    }
+
+    /// Whether the updates of the round are ready to be aggregated
+    pub fn ready_to_aggregate(&self) -> bool {
+        self.phase == RoundPhase::Aggregating
+    }
+
+    /// Updates received in the current round, kept until `finish`
+    pub fn updates(&self) -> Vec<&T> {
+        self.updates.values().collect()
+    }
+
+    /// Takes the updates of an aggregating round, closes it and opens the next round
+    pub fn finish(&mut self) -> Result<Vec<T>, String> {
+        if self.phase != RoundPhase::Aggregating {
+            return Err(format!("Round {} is not ready to be aggregated", self.round_id));
+        }
+        let updates = std::mem::take(&mut self.updates).into_values().collect::<Vec<_>>();
+        self.close(RoundOutcome::Aggregated);
+        self.last_round.as_mut().unwrap().updates = updates.len();
+        Ok(updates)
+    }
+
+    /// Closes the current round without aggregating it and opens the next one
+    pub fn abandon(&mut self) {
+        self.close(RoundOutcome::Abandoned);
+    }
+
+    /// Status of the current round at `now`
+    pub fn status(&self, now: Instant) -> RoundStatus {
+        RoundStatus {
+            round_id: self.round_id,
+            phase: self.phase,
+            participants: self.participants.iter().cloned().collect(),
+            updates: self.updates.len(),
+            candidates: self.candidates.len(),
+            min_participants: self.config.min_participants,
+            max_participants: self.config.max_participants,
+            deadline_in_secs: self.deadline.map(|deadline| deadline.saturating_duration_since(now).as_secs_f64()),
+            last_round: self.last_round.clone(),
+        }
+    }
+
+    /// Participants sampled by the selector, or more time to check in if it selected too few candidates
+    fn select_cohort(&mut self, now: Instant) {
+        let candidates = self.candidates.values().cloned().collect::<Vec<_>>();
+        let selector = match self.selector.as_mut() {
+            Some(selector) => selector,
+            None => return,
+        };
+        let cohort = selector.select(&candidates, self.config.max_participants);
+        if cohort.len() < self.config.min_participants {
+            self.deadline = self.config.selection_timeout.map(|timeout| now + timeout);
+            return;
+        }
+        self.participants = cohort.into_iter().collect();
+        self.not_selected = self
+            .candidates
+            .keys()
+            .filter(|client_id| !self.participants.contains(*client_id))
+            .cloned()
+            .collect();
+        self.candidates.clear();
+        self.start_training(now);
+    }
+
+    fn start_training(&mut self, now: Instant) {
+        self.phase = RoundPhase::Training;
+        self.deadline = self.config.training_timeout.map(|timeout| now + timeout);
+        if !self.participants.is_empty() && self.updates.len() == self.participants.len() {
+            self.phase = RoundPhase::Aggregating;
+            self.deadline = None;
+        }
+    }
+
+    fn close(&mut self, outcome: RoundOutcome) {
+        self.phase = RoundPhase::Closed;
+        let summary = RoundSummary {
+            round_id: self.round_id,
+            outcome,
+            participants: self.participants.len(),
+            updates: self.updates.len(),
+        };
+        self.resume(self.round_id + 1, Some(summary));
+    }
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that clients sending updates without joining fill the round like the former aggregation goal
+    #[test]
+    fn test_round_without_check_in() {
+        let mut rounds = RoundManager::new(RoundConfig::fixed(2)).unwrap();
+        let now = Instant::now();
+        rounds.submit(None, None, 1, now).unwrap();
+        assert_eq!(rounds.phase(), RoundPhase::Selecting);
+        rounds.submit(None, Some(0), 2, now).unwrap();
+        assert!(rounds.ready_to_aggregate());
+        assert_eq!(rounds.updates(), vec![&1, &2]);
+
+        assert_eq!(rounds.finish().unwrap(), vec![1, 2]);
+        assert_eq!(rounds.round_id(), 1);
+        assert_eq!(rounds.phase(), RoundPhase::Open);
+        assert_eq!(rounds.status(now).last_round.unwrap().outcome, RoundOutcome::Aggregated);
+    }
+
+    // Test that late, duplicate and unselected updates are refused
+    #[test]
+    fn test_round_refuses_updates() {
+        let mut rounds = RoundManager::new(RoundConfig::fixed(2)).unwrap();
+        let now = Instant::now();
+        rounds.join("a", now).unwrap();
+        rounds.join("b", now).unwrap();
+        assert_eq!(rounds.phase(), RoundPhase::Training);
+        assert!(rounds.join("c", now).is_err());
+
+        rounds.submit(Some("a".to_string()), None, 1, now).unwrap();
+        assert!(rounds.submit(Some("a".to_string()), None, 1, now).is_err());
+        assert!(rounds.submit(Some("c".to_string()), None, 3, now).is_err());
+        assert!(rounds.submit(Some("b".to_string()), Some(7), 2, now).is_err());
+        rounds.submit(Some("b".to_string()), None, 2, now).unwrap();
+        rounds.finish().unwrap();
+
+        // An update for round 0 arriving after it closed
+        assert!(rounds.submit(Some("b".to_string()), Some(0), 2, now).is_err());
+    }
+
+    // Test that deadlines end selection and drop stragglers, or abandon the round
+    #[test]
+    fn test_round_deadlines() {
+        let config = RoundConfig {
+            min_participants: 2,
+            max_participants: 4,
+            selection_timeout: Some(Duration::from_secs(10)),
+            training_timeout: Some(Duration::from_secs(60)),
+        };
+        let mut rounds = RoundManager::new(config).unwrap();
+        let start = Instant::now();
+        for client in ["a", "b", "c"] {
+            rounds.join(client, start).unwrap();
+        }
+        assert_eq!(rounds.status(start).deadline_in_secs, Some(10.0));
+
+        rounds.tick(start + Duration::from_secs(11));
+        assert_eq!(rounds.phase(), RoundPhase::Training);
+        rounds.submit(Some("a".to_string()), None, 1, start + Duration::from_secs(20)).unwrap();
+        rounds.submit(Some("b".to_string()), None, 2, start + Duration::from_secs(30)).unwrap();
+        rounds.tick(start + Duration::from_secs(72));
+        assert!(rounds.ready_to_aggregate());
+        assert_eq!(rounds.finish().unwrap().len(), 2);
+
+        // Only one update before the training deadline
+        rounds.join("a", start).unwrap();
+        rounds.join("b", start).unwrap();
+        rounds.tick(start + Duration::from_secs(11));
+        rounds.submit(Some("a".to_string()), None, 1, start + Duration::from_secs(20)).unwrap();
+        rounds.tick(start + Duration::from_secs(72));
+        assert_eq!(rounds.round_id(), 2);
+        assert_eq!(rounds.status(start).last_round.unwrap().outcome, RoundOutcome::Abandoned);
+    }
+
+    // Test that a resumed round keeps its participants and updates with a new training deadline
+    #[test]
+    fn test_round_pending_restore() {
+        let config = RoundConfig { training_timeout: Some(Duration::from_secs(60)), ..RoundConfig::fixed(2) };
+        let mut rounds = RoundManager::new(config).unwrap();
+        let start = Instant::now();
+        rounds.join("a", start).unwrap();
+        rounds.join("b", start).unwrap();
+        rounds.submit(Some("a".to_string()), None, 1, start).unwrap();
+
+        let mut restarted = RoundManager::new(config).unwrap();
+        let later = start + Duration::from_secs(100);
+        restarted.resume(rounds.round_id(), None);
+        restarted.restore_pending(rounds.pending(), later);
+        assert_eq!(restarted.phase(), RoundPhase::Training);
+        assert_eq!(restarted.status(later).deadline_in_secs, Some(60.0));
+        assert!(restarted.submit(Some("a".to_string()), None, 1, later).is_err());
+        restarted.submit(Some("b".to_string()), None, 2, later).unwrap();
+        assert_eq!(restarted.finish().unwrap(), vec![1, 2]);
+    }
+
+    // Test that the selector picks the cohort among the candidates that checked in
+    #[test]
+    fn test_round_client_selection() {
+        let mut rounds = RoundManager::new(RoundConfig::fixed(2)).unwrap();
+        rounds.set_selector(Box::new(crate::selection::PowerOfChoice { candidate_set_size: 3 }));
+        let now = Instant::now();
+        let check_in = |client_id: &str, loss: f64| CheckIn {
+            client_id: client_id.to_string(),
+            num_samples: 10,
+            loss: Some(loss),
+            availability: None,
+        };
+
+        assert_eq!(rounds.check_in(check_in("a", 1.0), now), CheckInStatus::Waiting);
+        assert!(rounds.submit(Some("a".to_string()), None, 1, now).is_err());
+        // The second check-in fills the pool of max_participants candidates
+        assert_eq!(rounds.check_in(check_in("b", 3.0), now), CheckInStatus::Selected);
+        assert_eq!(rounds.check_in(check_in("a", 1.0), now), CheckInStatus::Selected);
+        assert_eq!(rounds.check_in(check_in("c", 2.0), now), CheckInStatus::NotSelected);
+        assert_eq!(rounds.phase(), RoundPhase::Training);
+    }
+}
@@ -0,0 +1,689 @@
+//Secure aggregation protocol of Bonawitz et al. with pairwise masks and dropout recovery
+//
+//Rounds: advertise keys -> share keys -> masked input collection -> unmasking.
+//The server only learns the sum of the inputs of the clients that survived the masked
+//input collection. Clients are trusted to follow the protocol (no consistency check round).
+
+use std::collections::{BTreeMap, BTreeSet};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE}, Engine};
+use fernet::Fernet;
+use rand::{thread_rng, Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::secure_dp_utils::{
+    decode_fixed_point, encode_fixed_point, field_add, field_sub, reconstruct_field_values, share_field_values_at,
+    Share, FIELD_PRIME,
+};
+use crate::state_dict::StateDict;
+
+/// Number of field elements holding a 32 byte key, 4 bytes per element
+const KEY_ELEMENTS: usize = 8;
+
+/// Public keys a client advertises in the first round
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdvertisedKeys {
+    /// Non-zero identifier of the client, also its Shamir share index.
+    pub client_id: u64,
+    /// Base64 X25519 key used to encrypt the shares sent to this client.
+    pub cipher_public_key: String,
+    /// Base64 X25519 key used to derive the pairwise masks.
+    pub mask_public_key: String,
+}
+
+/// Share of a client's secrets encrypted for another client, relayed by the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptedShare {
+    pub from: u64,
+    pub to: u64,
+    pub ciphertext: String,
+}
+
+/// Message of the share keys round
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareKeysMessage {
+    pub client_id: u64,
+    pub encrypted_shares: Vec<EncryptedShare>,
+}
+
+/// Message of the masked input collection round
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaskedInput {
+    pub client_id: u64,
+    pub masked_input: Vec<u64>,
+}
+
+/// Share held by a client of a secret owned by another client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OwnedShare {
+    pub owner: u64,
+    pub share: Share,
+}
+
+/// Message of the unmasking round: self-mask shares of the survivors and
+/// mask key shares of the clients that dropped out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnmaskMessage {
+    pub client_id: u64,
+    pub self_mask_shares: Vec<OwnedShare>,
+    pub secret_key_shares: Vec<OwnedShare>,
+}
+
+/// Plaintext of an `EncryptedShare`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SharePayload {
+    from: u64,
+    to: u64,
+    secret_key_share: Share,
+    self_mask_share: Share,
+}
+
+/// Rounds of the protocol as seen by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum SecAggPhase {
+    AdvertiseKeys,
+    ShareKeys,
+    MaskedInputCollection,
+    Unmasking,
+    Finished,
+}
+
+/// Server side state of one secure aggregation round
+#[derive(Debug, Clone)]
+pub struct SecureAggregationServer {
+    /// Number of clients expected in the round, a phase closes once all of them answered.
+    pub expected_clients: usize,
+    /// Minimum number of clients needed in every phase, also the Shamir threshold.
+    pub threshold: usize,
+    phase: SecAggPhase,
+    advertised: BTreeMap<u64, AdvertisedKeys>,
+    relayed_shares: BTreeMap<u64, Vec<EncryptedShare>>,
+    share_senders: BTreeSet<u64>,
+    masked_inputs: BTreeMap<u64, Vec<u64>>,
+    unmask_messages: BTreeMap<u64, UnmaskMessage>,
+    aggregate: Option<Vec<u64>>,
+}
+
+impl SecureAggregationServer {
+    /// New round waiting for `expected_clients` clients, tolerating drops down to `threshold` clients
+    pub fn new(expected_clients: usize, threshold: usize) -> Result<Self, String> {
+        if threshold == 0 || threshold > expected_clients {
+            return Err(format!("Threshold must be between 1 and {}, got {}", expected_clients, threshold));
+        }
+        Ok(SecureAggregationServer {
+            expected_clients,
+            threshold,
+            phase: SecAggPhase::AdvertiseKeys,
+            advertised: BTreeMap::new(),
+            relayed_shares: BTreeMap::new(),
+            share_senders: BTreeSet::new(),
+            masked_inputs: BTreeMap::new(),
+            unmask_messages: BTreeMap::new(),
+            aggregate: None,
+        })
+    }
+
+    /// Current round of the protocol
+    pub fn phase(&self) -> SecAggPhase {
+        self.phase
+    }
+
+    /// Round 0: registers the public keys of a client
+    pub fn advertise_keys(&mut self, keys: AdvertisedKeys) -> Result<(), String> {
+        self.expect_phase(SecAggPhase::AdvertiseKeys)?;
+        if keys.client_id == 0 || keys.client_id >= FIELD_PRIME {
+            return Err(format!("Invalid client id {}", keys.client_id));
+        }
+        decode_public_key(&keys.cipher_public_key)?;
+        decode_public_key(&keys.mask_public_key)?;
+        if self.advertised.contains_key(&keys.client_id) {
+            return Err(format!("Client {} already advertised its keys", keys.client_id));
+        }
+        self.advertised.insert(keys.client_id, keys);
+
+        if self.advertised.len() == self.expected_clients {
+            self.close_phase()?;
+        }
+        Ok(())
+    }
+
+    /// Public keys of the clients taking part in the round, available once round 0 is closed
+    pub fn roster(&self) -> Result<Vec<AdvertisedKeys>, String> {
+        self.expect_phase_after(SecAggPhase::ShareKeys)?;
+        Ok(self.advertised.values().cloned().collect())
+    }
+
+    /// Round 1: stores the encrypted shares of a client for relaying
+    pub fn share_keys(&mut self, message: ShareKeysMessage) -> Result<(), String> {
+        self.expect_phase(SecAggPhase::ShareKeys)?;
+        self.expect_member(message.client_id, self.advertised.keys())?;
+        if self.share_senders.contains(&message.client_id) {
+            return Err(format!("Client {} already shared its keys", message.client_id));
+        }
+        for encrypted_share in &message.encrypted_shares {
+            if encrypted_share.from != message.client_id || !self.advertised.contains_key(&encrypted_share.to) {
+                return Err(format!("Invalid share from {} to {}", encrypted_share.from, encrypted_share.to));
+            }
+        }
+        for encrypted_share in message.encrypted_shares {
+            self.relayed_shares.entry(encrypted_share.to).or_default().push(encrypted_share);
+        }
+        self.share_senders.insert(message.client_id);
+
+        if self.share_senders.len() == self.advertised.len() {
+            self.close_phase()?;
+        }
+        Ok(())
+    }
+
+    /// Clients that shared their keys and the encrypted shares addressed to `client_id`
+    pub fn shares_for(&self, client_id: u64) -> Result<(Vec<u64>, Vec<EncryptedShare>), String> {
+        self.expect_phase_after(SecAggPhase::MaskedInputCollection)?;
+        self.expect_member(client_id, self.share_senders.iter())?;
+        let shares = self
+            .relayed_shares
+            .get(&client_id)
+            .map(|shares| shares.iter().filter(|s| self.share_senders.contains(&s.from)).cloned().collect())
+            .unwrap_or_default();
+        Ok((self.share_senders.iter().copied().collect(), shares))
+    }
+
+    /// Round 2: stores the masked input of a client
+    pub fn submit_masked_input(&mut self, message: MaskedInput) -> Result<(), String> {
+        self.expect_phase(SecAggPhase::MaskedInputCollection)?;
+        self.expect_member(message.client_id, self.share_senders.iter())?;
+        if self.masked_inputs.contains_key(&message.client_id) {
+            return Err(format!("Client {} already sent its masked input", message.client_id));
+        }
+        if let Some(first) = self.masked_inputs.values().next() {
+            if first.len() != message.masked_input.len() {
+                return Err(format!("Expected {} masked values, got {}", first.len(), message.masked_input.len()));
+            }
+        }
+        if message.masked_input.iter().any(|&v| v >= FIELD_PRIME) {
+            return Err("Masked input is not in the field".to_string());
+        }
+        self.masked_inputs.insert(message.client_id, message.masked_input);
+
+        if self.masked_inputs.len() == self.share_senders.len() {
+            self.close_phase()?;
+        }
+        Ok(())
+    }
+
+    /// Clients whose masked input is part of the aggregate, available once round 2 is closed
+    pub fn survivors(&self) -> Result<Vec<u64>, String> {
+        self.expect_phase_after(SecAggPhase::Unmasking)?;
+        Ok(self.masked_inputs.keys().copied().collect())
+    }
+
+    /// Round 3: stores the shares needed to remove the masks
+    pub fn unmask(&mut self, message: UnmaskMessage) -> Result<(), String> {
+        self.expect_phase(SecAggPhase::Unmasking)?;
+        self.expect_member(message.client_id, self.masked_inputs.keys())?;
+        if self.unmask_messages.contains_key(&message.client_id) {
+            return Err(format!("Client {} already sent its unmasking shares", message.client_id));
+        }
+        // A client revealing both secrets of the same owner would let the server unmask its input.
+        for shares in [&message.self_mask_shares, &message.secret_key_shares] {
+            if shares.iter().any(|s| s.share.index != message.client_id) {
+                return Err(format!("Client {} sent shares it does not hold", message.client_id));
+            }
+        }
+        if message.self_mask_shares.iter().any(|s| !self.masked_inputs.contains_key(&s.owner))
+            || message.secret_key_shares.iter().any(|s| self.masked_inputs.contains_key(&s.owner))
+        {
+            return Err(format!("Client {} sent shares of the wrong secrets", message.client_id));
+        }
+        self.unmask_messages.insert(message.client_id, message);
+
+        if self.unmask_messages.len() == self.masked_inputs.len() {
+            self.close_phase()?;
+        }
+        Ok(())
+    }
+
+    /// Closes the current phase without waiting for the missing clients, e.g. after a timeout
+    pub fn advance(&mut self) -> Result<(), String> {
+        self.close_phase()
+    }
+
+    /// Sum of the survivors' inputs in the field, available once the round is finished
+    pub fn aggregate(&self) -> Option<&Vec<u64>> {
+        self.aggregate.as_ref()
+    }
+
+    fn close_phase(&mut self) -> Result<(), String> {
+        let responded = match self.phase {
+            SecAggPhase::AdvertiseKeys => self.advertised.len(),
+            SecAggPhase::ShareKeys => self.share_senders.len(),
+            SecAggPhase::MaskedInputCollection => self.masked_inputs.len(),
+            SecAggPhase::Unmasking => self.unmask_messages.len(),
+            SecAggPhase::Finished => return Err("Secure aggregation round is already finished".to_string()),
+        };
+        if responded < self.threshold {
+            return Err(format!(
+                "Only {} clients responded in {:?}, at least {} are needed",
+                responded, self.phase, self.threshold
+            ));
+        }
+
+        self.phase = match self.phase {
+            SecAggPhase::AdvertiseKeys => SecAggPhase::ShareKeys,
+            SecAggPhase::ShareKeys => SecAggPhase::MaskedInputCollection,
+            SecAggPhase::MaskedInputCollection => SecAggPhase::Unmasking,
+            _ => {
+                self.aggregate = Some(self.unmasked_sum()?);
+                SecAggPhase::Finished
+            }
+        };
+        Ok(())
+    }
+
+    /// Sums the masked inputs and removes the self masks of the survivors and the
+    /// pairwise masks the survivors shared with the clients that dropped out
+    fn unmasked_sum(&self) -> Result<Vec<u64>, String> {
+        let len = self.masked_inputs.values().next().map(|v| v.len()).unwrap_or(0);
+        let mut sum = vec![0; len];
+        for masked_input in self.masked_inputs.values() {
+            add_assign(&mut sum, masked_input);
+        }
+
+        for &survivor in self.masked_inputs.keys() {
+            let shares = self.collect_shares(survivor, |m| &m.self_mask_shares);
+            let seed = elements_to_key(&reconstruct_field_values(&shares, self.threshold)?)?;
+            sub_assign(&mut sum, &prg(seed, len));
+        }
+
+        for &dropped in self.share_senders.iter().filter(|id| !self.masked_inputs.contains_key(id)) {
+            let shares = self.collect_shares(dropped, |m| &m.secret_key_shares);
+            let secret = StaticSecret::from(elements_to_key(&reconstruct_field_values(&shares, self.threshold)?)?);
+            for &survivor in self.masked_inputs.keys() {
+                let public_key = decode_public_key(&self.advertised[&survivor].mask_public_key)?;
+                let mask = prg(pairwise_seed(&secret, &public_key), len);
+                // The survivor added the mask if its id is smaller than the dropped client's id.
+                if survivor < dropped {
+                    sub_assign(&mut sum, &mask);
+                } else {
+                    add_assign(&mut sum, &mask);
+                }
+            }
+        }
+
+        Ok(sum)
+    }
+
+    fn collect_shares<F: Fn(&UnmaskMessage) -> &Vec<OwnedShare>>(&self, owner: u64, shares_of: F) -> Vec<Share> {
+        self.unmask_messages
+            .values()
+            .flat_map(|m| shares_of(m).iter())
+            .filter(|s| s.owner == owner)
+            .map(|s| s.share.clone())
+            .collect()
+    }
+
+    fn expect_phase(&self, phase: SecAggPhase) -> Result<(), String> {
+        if self.phase != phase {
+            return Err(format!("Secure aggregation is in {:?}, not {:?}", self.phase, phase));
+        }
+        Ok(())
+    }
+
+    fn expect_phase_after(&self, phase: SecAggPhase) -> Result<(), String> {
+        if self.phase < phase {
+            return Err(format!("Secure aggregation is still in {:?}", self.phase));
+        }
+        Ok(())
+    }
+
+    fn expect_member<'a, I: IntoIterator<Item = &'a u64>>(&self, client_id: u64, members: I) -> Result<(), String> {
+        if !members.into_iter().any(|&id| id == client_id) {
+            return Err(format!("Client {} is not part of this phase", client_id));
+        }
+        Ok(())
+    }
+}
+
+/// Client side state of one secure aggregation round
+pub struct SecureAggregationClient {
+    pub client_id: u64,
+    pub threshold: usize,
+    cipher_secret: StaticSecret,
+    mask_secret: StaticSecret,
+    self_mask_seed: [u8; 32],
+    roster: BTreeMap<u64, AdvertisedKeys>,
+    received: BTreeMap<u64, SharePayload>,
+}
+
+impl SecureAggregationClient {
+    /// Generates fresh keys and self mask for one round
+    pub fn new(client_id: u64, threshold: usize) -> Result<Self, String> {
+        if client_id == 0 || client_id >= FIELD_PRIME {
+            return Err(format!("Invalid client id {}", client_id));
+        }
+        let mut self_mask_seed = [0u8; 32];
+        thread_rng().fill_bytes(&mut self_mask_seed);
+        Ok(SecureAggregationClient {
+            client_id,
+            threshold,
+            cipher_secret: StaticSecret::random_from_rng(thread_rng()),
+            mask_secret: StaticSecret::random_from_rng(thread_rng()),
+            self_mask_seed,
+            roster: BTreeMap::new(),
+            received: BTreeMap::new(),
+        })
+    }
+
+    /// Round 0: public keys to advertise
+    pub fn advertise_keys(&self) -> AdvertisedKeys {
+        AdvertisedKeys {
+            client_id: self.client_id,
+            cipher_public_key: STANDARD.encode(PublicKey::from(&self.cipher_secret).as_bytes()),
+            mask_public_key: STANDARD.encode(PublicKey::from(&self.mask_secret).as_bytes()),
+        }
+    }
+
+    /// Round 1: Shamir-shares the mask key and the self mask seed among the roster
+    pub fn share_keys(&mut self, roster: &[AdvertisedKeys]) -> Result<ShareKeysMessage, String> {
+        if roster.len() < self.threshold {
+            return Err(format!("Roster has {} clients, at least {} are needed", roster.len(), self.threshold));
+        }
+        if !roster.iter().any(|k| k.client_id == self.client_id) {
+            return Err(format!("Client {} is not in the roster", self.client_id));
+        }
+        self.roster = roster.iter().map(|k| (k.client_id, k.clone())).collect();
+
+        let indices = self.roster.keys().copied().collect::<Vec<_>>();
+        let secret_key_shares = share_field_values_at(&key_to_elements(&self.mask_secret.to_bytes()), &indices, self.threshold)?;
+        let self_mask_shares = share_field_values_at(&key_to_elements(&self.self_mask_seed), &indices, self.threshold)?;
+
+        let mut encrypted_shares = Vec::new();
+        for (secret_key_share, self_mask_share) in secret_key_shares.into_iter().zip(self_mask_shares) {
+            let to = secret_key_share.index;
+            let payload = SharePayload { from: self.client_id, to, secret_key_share, self_mask_share };
+            if to == self.client_id {
+                // Our own share is kept locally and revealed in the unmasking round like the others.
+                self.received.insert(to, payload);
+                continue;
+            }
+            let plaintext = serde_json::to_vec(&payload).map_err(|e| e.to_string())?;
+            let ciphertext = self.share_cipher(to)?.encrypt(&plaintext);
+            encrypted_shares.push(EncryptedShare { from: self.client_id, to, ciphertext });
+        }
+
+        Ok(ShareKeysMessage { client_id: self.client_id, encrypted_shares })
+    }
+
+    /// Round 2: decrypts the received shares and masks the input with the self mask and
+    /// the pairwise masks of every client that shared its keys
+    pub fn mask_input(&mut self, input: &[u64], share_senders: &[u64], shares: &[EncryptedShare]) -> Result<MaskedInput, String> {
+        if !share_senders.contains(&self.client_id) {
+            return Err(format!("Client {} is not part of the masked input collection", self.client_id));
+        }
+        for encrypted_share in shares {
+            if encrypted_share.to != self.client_id || !share_senders.contains(&encrypted_share.from) {
+                return Err(format!("Unexpected share from {} to {}", encrypted_share.from, encrypted_share.to));
+            }
+            let plaintext = self
+                .share_cipher(encrypted_share.from)?
+                .decrypt(&encrypted_share.ciphertext)
+                .map_err(|_| format!("Failed to decrypt the share from {}", encrypted_share.from))?;
+            let payload: SharePayload = serde_json::from_slice(&plaintext).map_err(|e| e.to_string())?;
+            if payload.from != encrypted_share.from || payload.to != self.client_id {
+                return Err(format!("Share from {} has a forged header", encrypted_share.from));
+            }
+            self.received.insert(payload.from, payload);
+        }
+
+        let mut masked_input = input.iter().map(|&v| v % FIELD_PRIME).collect::<Vec<_>>();
+        add_assign(&mut masked_input, &prg(self.self_mask_seed, input.len()));
+        for &other in share_senders.iter().filter(|&&id| id != self.client_id) {
+            let keys = self.roster.get(&other).ok_or(format!("Client {} is not in the roster", other))?;
+            let mask = prg(pairwise_seed(&self.mask_secret, &decode_public_key(&keys.mask_public_key)?), input.len());
+            if self.client_id < other {
+                add_assign(&mut masked_input, &mask);
+            } else {
+                sub_assign(&mut masked_input, &mask);
+            }
+        }
+
+        Ok(MaskedInput { client_id: self.client_id, masked_input })
+    }
+
+    /// Round 3: reveals the self mask shares of the survivors and the mask key shares of the dropped clients
+    pub fn unmask(&self, survivors: &[u64]) -> Result<UnmaskMessage, String> {
+        if survivors.len() < self.threshold {
+            return Err(format!("Only {} survivors, at least {} are needed", survivors.len(), self.threshold));
+        }
+        if !survivors.contains(&self.client_id) {
+            return Err(format!("Client {} is not a survivor", self.client_id));
+        }
+        let mut message = UnmaskMessage {
+            client_id: self.client_id,
+            self_mask_shares: Vec::new(),
+            secret_key_shares: Vec::new(),
+        };
+        for (&owner, payload) in &self.received {
+            if survivors.contains(&owner) {
+                message.self_mask_shares.push(OwnedShare { owner, share: payload.self_mask_share.clone() });
+            } else {
+                message.secret_key_shares.push(OwnedShare { owner, share: payload.secret_key_share.clone() });
+            }
+        }
+        Ok(message)
+    }
+
+    /// Fernet cipher keyed with the Diffie-Hellman secret shared with another client
+    fn share_cipher(&self, other: u64) -> Result<Fernet, String> {
+        let keys = self.roster.get(&other).ok_or(format!("Client {} is not in the roster", other))?;
+        let shared_secret = self.cipher_secret.diffie_hellman(&decode_public_key(&keys.cipher_public_key)?);
+        let key = Sha256::new().chain_update(shared_secret.as_bytes()).chain_update(b"share").finalize();
+        Fernet::new(&URL_SAFE.encode(key)).ok_or("Invalid share key".to_string())
+    }
+}
+
+/// Encodes a client model as field elements weighted by its number of samples.
+/// The last element holds the number of samples so that the server can average the sum.
+pub fn encode_weighted_input(weights: &StateDict, num_samples: usize) -> Vec<u64> {
+    let mut input = weights
+        .iter()
+        .flat_map(|t| t.data.iter())
+        .map(|&v| encode_fixed_point(v * num_samples as f64))
+        .collect::<Vec<_>>();
+    input.push(encode_fixed_point(num_samples as f64));
+    input
+}
+
+/// Decodes the sum of weighted inputs into the average model and the total number of samples
+pub fn decode_weighted_average(layout: &StateDict, aggregate: &[u64]) -> Result<(StateDict, usize), String> {
+    let numel: usize = layout.iter().map(|t| t.data.len()).sum();
+    if aggregate.len() != numel + 1 {
+        return Err(format!("Aggregate has {} values, the model needs {}", aggregate.len(), numel + 1));
+    }
+    let total_samples = decode_fixed_point(aggregate[numel]).round();
+    if total_samples <= 0.0 {
+        return Err("Aggregate holds no samples".to_string());
+    }
+
+    let mut average = layout.clone();
+    let mut values = aggregate.iter();
+    for named_tensor in average.iter_mut() {
+        for (v, &sum) in named_tensor.data.iter_mut().zip(values.by_ref()) {
+            *v = decode_fixed_point(sum) / total_samples;
+        }
+    }
+    Ok((average, total_samples as usize))
+}
+
+/// X25519 public key from its base64 encoding
+pub fn decode_public_key(encoded: &str) -> Result<PublicKey, String> {
+    let bytes: [u8; 32] = STANDARD
+        .decode(encoded)
+        .map_err(|e| format!("Invalid public key: {}", e))?
+        .try_into()
+        .map_err(|_| "Public keys must be 32 bytes".to_string())?;
+    Ok(PublicKey::from(bytes))
+}
+
+fn pairwise_seed(secret: &StaticSecret, public_key: &PublicKey) -> [u8; 32] {
+    let shared_secret = secret.diffie_hellman(public_key);
+    Sha256::new().chain_update(shared_secret.as_bytes()).chain_update(b"mask").finalize().into()
+}
+
+/// Expands a seed into `len` uniform field elements
+fn prg(seed: [u8; 32], len: usize) -> Vec<u64> {
+    let mut rng = ChaCha20Rng::from_seed(seed);
+    (0..len).map(|_| rng.gen_range(0..FIELD_PRIME)).collect()
+}
+
+fn key_to_elements(key: &[u8; 32]) -> Vec<u64> {
+    key.chunks(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()) as u64)
+        .collect()
+}
+
+fn elements_to_key(elements: &[u64]) -> Result<[u8; 32], String> {
+    if elements.len() != KEY_ELEMENTS || elements.iter().any(|&e| e > u32::MAX as u64) {
+        return Err("Reconstructed key is malformed".to_string());
+    }
+    let mut key = [0u8; 32];
+    for (chunk, &element) in key.chunks_mut(4).zip(elements) {
+        chunk.copy_from_slice(&(element as u32).to_le_bytes());
+    }
+    Ok(key)
+}
+
+fn add_assign(sum: &mut [u64], values: &[u64]) {
+    sum.iter_mut().zip(values).for_each(|(s, &v)| *s = field_add(*s, v));
+}
+
+fn sub_assign(sum: &mut [u64], values: &[u64]) {
+    sum.iter_mut().zip(values).for_each(|(s, &v)| *s = field_sub(*s, v));
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_dict::NamedTensor;
+
+    /// Runs a full round with in-process clients. Clients in `drop_before_masking` vanish after
+    /// sharing their keys, clients in `drop_before_unmasking` after sending their masked input.
+    fn run_round(
+        inputs: &BTreeMap<u64, Vec<u64>>,
+        threshold: usize,
+        drop_before_masking: &[u64],
+        drop_before_unmasking: &[u64],
+    ) -> Result<Vec<u64>, String> {
+        let mut server = SecureAggregationServer::new(inputs.len(), threshold)?;
+        let mut clients = inputs
+            .keys()
+            .map(|&id| (id, SecureAggregationClient::new(id, threshold).unwrap()))
+            .collect::<BTreeMap<_, _>>();
+
+        for client in clients.values() {
+            server.advertise_keys(client.advertise_keys())?;
+        }
+        let roster = server.roster()?;
+        for client in clients.values_mut() {
+            server.share_keys(client.share_keys(&roster)?)?;
+        }
+
+        for (&id, client) in clients.iter_mut().filter(|(id, _)| !drop_before_masking.contains(id)) {
+            let (senders, shares) = server.shares_for(id)?;
+            server.submit_masked_input(client.mask_input(&inputs[&id], &senders, &shares)?)?;
+        }
+        if !drop_before_masking.is_empty() {
+            server.advance()?;
+        }
+
+        let survivors = server.survivors()?;
+        for (_, client) in clients.iter().filter(|(id, _)| survivors.contains(id) && !drop_before_unmasking.contains(id)) {
+            server.unmask(client.unmask(&survivors)?)?;
+        }
+        if !drop_before_unmasking.is_empty() {
+            server.advance()?;
+        }
+
+        server.aggregate().cloned().ok_or("Round did not finish".to_string())
+    }
+
+    fn inputs() -> BTreeMap<u64, Vec<u64>> {
+        (1..=5u64).map(|id| (id * 7, vec![id, 100 * id, encode_fixed_point(-(id as f64))])).collect()
+    }
+
+    fn plain_sum(inputs: &BTreeMap<u64, Vec<u64>>, ids: &[u64]) -> Vec<u64> {
+        let mut sum = vec![0; 3];
+        ids.iter().for_each(|id| add_assign(&mut sum, &inputs[id]));
+        sum
+    }
+
+    // Test that the server learns exactly the sum when everybody survives
+    #[test]
+    fn test_secure_aggregation_without_dropouts() {
+        let inputs = inputs();
+        let ids = inputs.keys().copied().collect::<Vec<_>>();
+        assert_eq!(run_round(&inputs, 3, &[], &[]).unwrap(), plain_sum(&inputs, &ids));
+    }
+
+    // Test that the pairwise masks of a client that dropped after sharing its keys are removed
+    #[test]
+    fn test_secure_aggregation_with_dropout_before_masking() {
+        let inputs = inputs();
+        let aggregate = run_round(&inputs, 3, &[14, 28], &[]).unwrap();
+        assert_eq!(aggregate, plain_sum(&inputs, &[7, 21, 35]));
+    }
+
+    // Test that a survivor may drop during unmasking as long as the threshold is met
+    #[test]
+    fn test_secure_aggregation_with_dropout_before_unmasking() {
+        let inputs = inputs();
+        let aggregate = run_round(&inputs, 3, &[35], &[7]).unwrap();
+        assert_eq!(aggregate, plain_sum(&inputs, &[7, 14, 21, 28]));
+    }
+
+    // Test that the round aborts when too few clients are left to unmask
+    #[test]
+    fn test_secure_aggregation_below_threshold() {
+        assert!(run_round(&inputs(), 3, &[7, 14, 21], &[]).is_err());
+        assert!(run_round(&inputs(), 3, &[], &[7, 14, 21]).is_err());
+    }
+
+    // Test that a single masked input looks nothing like the input
+    #[test]
+    fn test_masked_input_hides_input() {
+        let roster_clients = (1..=3u64).map(|id| SecureAggregationClient::new(id, 2).unwrap()).collect::<Vec<_>>();
+        let roster = roster_clients.iter().map(|c| c.advertise_keys()).collect::<Vec<_>>();
+        let mut client = SecureAggregationClient::new(1, 2).unwrap();
+        client.cipher_secret = roster_clients[0].cipher_secret.clone();
+        client.mask_secret = roster_clients[0].mask_secret.clone();
+        client.share_keys(&roster).unwrap();
+
+        let masked = client.mask_input(&[0, 0, 0, 0], &[1, 2, 3], &[]).unwrap();
+        assert!(masked.masked_input.iter().all(|&v| v != 0));
+    }
+
+    // Test that weighted inputs decode into the sample-weighted average
+    #[test]
+    fn test_weighted_input_encoding() {
+        let layout = vec![NamedTensor {
+            name: "fc.weight".to_string(),
+            shape: vec![2],
+            dtype: "Float".to_string(),
+            data: vec![0.0, 0.0],
+        }];
+        let mut first = layout.clone();
+        first[0].data = vec![1.0, -1.0];
+        let mut second = layout.clone();
+        second[0].data = vec![4.0, 2.0];
+
+        let mut sum = encode_weighted_input(&first, 20);
+        add_assign(&mut sum, &encode_weighted_input(&second, 10));
+
+        let (average, total_samples) = decode_weighted_average(&layout, &sum).unwrap();
+        assert_eq!(total_samples, 30);
+        assert!((average[0].data[0] - 2.0).abs() < 1e-6);
+        assert!((average[0].data[1] - 0.0).abs() < 1e-6);
+    }
+}
@@ -4,65 +4,408 @@
 
 use fernet::Fernet;
 use rand::{thread_rng, Rng};
-use rand_distr::{Normal, Distribution};
+use rand_distr::{Normal, Distribution, Open01};
+use serde::{Deserialize, Serialize};
+use crate::state_dict::{check_same_layout, decode_state_dict, NamedTensor, StateDict};
+
+/// Default delta of the Gaussian mechanism
+pub const DEFAULT_DELTA: f64 = 1e-5;
+
+/// Distribution of the noise added by `DPMechanism`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseDistribution {
+    /// (epsilon, delta)-DP, the sensitivity is an L2 bound.
+    Gaussian,
+    /// Pure epsilon-DP, the sensitivity is an L1 bound.
+    Laplace,
+}
 
 //Implemented by Sharvani Chelumalla
 /// Structure for noise parameters
 pub struct DPMechanism {
     pub(crate) epsilon: f64,
-    pub(crate) sensitivity: f64
+    pub(crate) sensitivity: f64,
+    pub(crate) distribution: NoiseDistribution,
+    noise_std: f64,
 }
 //Implemented by Sharvani Chelumalla
 impl DPMechanism {
-    /// Takes the default parameters or the ones defined by user
-    pub fn new(epsilon: f64, sensitivity: f64) -> DPMechanism {
-        DPMechanism {
+    /// Takes the default parameters or the ones defined by user.
+    /// Gaussian mechanism with the default delta, updates are clipped to an L2 norm of `sensitivity`.
+    pub fn new(epsilon: f64, sensitivity: f64) -> Result<DPMechanism, String> {
+        DPMechanism::gaussian(epsilon, DEFAULT_DELTA, sensitivity)
+    }
+
+    /// (epsilon, delta) Gaussian mechanism, updates are clipped to an L2 norm of `sensitivity`
+    pub fn gaussian(epsilon: f64, delta: f64, sensitivity: f64) -> Result<DPMechanism, String> {
+        Ok(DPMechanism {
             epsilon,
-            sensitivity
+            sensitivity,
+            distribution: NoiseDistribution::Gaussian,
+            noise_std: analytic_gaussian_sigma(epsilon, delta, sensitivity)?,
+        })
+    }
+
+    /// Pure epsilon Laplace mechanism, updates are clipped to an L1 norm of `sensitivity`
+    pub fn laplace(epsilon: f64, sensitivity: f64) -> Result<DPMechanism, String> {
+        check_epsilon_and_sensitivity(epsilon, sensitivity)?;
+        Ok(DPMechanism {
+            epsilon,
+            sensitivity,
+            distribution: NoiseDistribution::Laplace,
+            noise_std: std::f64::consts::SQRT_2 * sensitivity / epsilon,
+        })
+    }
+
+    /// Standard deviation of the noise added to every value.
+    /// The Gaussian noise uses the analytic calibration of Balle and Wang (2018).
+    pub fn noise_std(&self) -> f64 {
+        self.noise_std
+    }
+
+    /// Scales `values` down so that their L2 (Gaussian) or L1 (Laplace) norm is at most the sensitivity
+    pub fn clip(&self, values: &[f64]) -> Vec<f64> {
+        let norm = match self.distribution {
+            NoiseDistribution::Gaussian => values.iter().map(|v| v * v).sum::<f64>().sqrt(),
+            NoiseDistribution::Laplace => values.iter().map(|v| v.abs()).sum::<f64>(),
+        };
+        let scale = if norm > self.sensitivity { self.sensitivity / norm } else { 1.0 };
+        values.iter().map(|v| v * scale).collect()
+    }
+
+    /// Clips the whole update `local - global`, across all parameters, and returns `global + clipped update`
+    pub fn clip_update(&self, global: &StateDict, local: &StateDict) -> Result<StateDict, String> {
+        check_same_layout(global, local)?;
+        let update = local
+            .iter()
+            .zip(global.iter())
+            .flat_map(|(l, g)| l.data.iter().zip(g.data.iter()).map(|(l, g)| l - g))
+            .collect::<Vec<_>>();
+        let mut clipped = self.clip(&update).into_iter();
+
+        let mut result = global.clone();
+        for named_tensor in result.iter_mut() {
+            for (value, delta) in named_tensor.data.iter_mut().zip(clipped.by_ref()) {
+                *value += delta;
+            }
         }
+        Ok(result)
     }
+
     //Implemented by Sharvani Chelumalla
     /// Add noise to weights for privacy concerns
     pub fn add_noise(&self, weights: &Vec<f64>) -> Vec<f64> {
-        let noise_std = self.sensitivity / self.epsilon;
-        let normal_dist = Normal::new(0.0, noise_std).unwrap();
         let mut rng = thread_rng();
+        match self.distribution {
+            NoiseDistribution::Gaussian => {
+                let normal_dist = Normal::new(0.0, self.noise_std).expect("the noise std is validated on construction");
 
-        // Adding Gaussian noise to each weight
-        weights
-            .iter()
-            .map(|&weight| weight + normal_dist.sample(&mut rng))
-            .collect()
+                // Adding Gaussian noise to each weight
+                weights
+                    .iter()
+                    .map(|&weight| weight + normal_dist.sample(&mut rng))
+                    .collect()
+            }
+            NoiseDistribution::Laplace => {
+                let scale = self.sensitivity / self.epsilon;
+
+                // Inverse CDF of the Laplace distribution
+                weights
+                    .iter()
+                    .map(|&weight| {
+                        // Open interval, u = -0.5 would give ln(0)
+                        let u = rng.sample::<f64, _>(Open01) - 0.5;
+                        weight - scale * u.signum() * (1.0 - 2.0 * u.abs()).ln()
+                    })
+                    .collect()
+            }
+        }
     }
 }
 
-//Implemented by Sharvani Chelumalla
-/// To add extra noise such that weights can be shared secretly
-pub fn secret_share_weights(weights: Vec<f64>, num_shares: usize, threshold: usize, _noise_level: f64) -> Vec<Vec<f64>> {
-    // Create a vector of vectors to hold shares for each shareholder
-    let mut shares = vec![vec![]; num_shares];
+/// Smallest sigma such that the Gaussian mechanism with L2 sensitivity `sensitivity` is (epsilon, delta)-DP,
+/// using the exact condition of the analytic Gaussian mechanism and a binary search
+pub fn analytic_gaussian_sigma(epsilon: f64, delta: f64, sensitivity: f64) -> Result<f64, String> {
+    check_epsilon_and_sensitivity(epsilon, sensitivity)?;
+    if !(delta > 0.0 && delta < 1.0) {
+        return Err(format!("Delta must be in (0, 1), got {}", delta));
+    }
+    // Privacy loss condition: Phi(s/(2 sigma) - eps sigma/s) - e^eps Phi(-s/(2 sigma) - eps sigma/s) <= delta
+    let delta_of = |sigma: f64| {
+        let a = sensitivity / (2.0 * sigma);
+        let b = epsilon * sigma / sensitivity;
+        normal_cdf(a - b) - (epsilon + log_normal_cdf(-a - b)).exp()
+    };
+
+    let mut high = sensitivity;
+    while delta_of(high) > delta {
+        high *= 2.0;
+    }
+    let mut low = 0.0;
+    for _ in 0..100 {
+        let middle = (low + high) / 2.0;
+        if delta_of(middle) > delta {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    Ok(high)
+}
+
+/// Checks the parameters shared by the Gaussian and Laplace mechanisms
+fn check_epsilon_and_sensitivity(epsilon: f64, sensitivity: f64) -> Result<(), String> {
+    if !(epsilon > 0.0 && epsilon.is_finite()) {
+        return Err(format!("Epsilon must be positive and finite, got {}", epsilon));
+    }
+    if !(sensitivity > 0.0 && sensitivity.is_finite()) {
+        return Err(format!("Sensitivity must be positive and finite, got {}", sensitivity));
+    }
+    Ok(())
+}
+
+/// Standard normal CDF
+fn normal_cdf(x: f64) -> f64 {
+    log_normal_cdf(x).exp()
+}
+
+/// Logarithm of the standard normal CDF, accurate far in the lower tail
+fn log_normal_cdf(x: f64) -> f64 {
+    let z = -x / std::f64::consts::SQRT_2;
+    if z >= 0.0 {
+        log_erfc(z) - std::f64::consts::LN_2
+    } else {
+        (1.0 - 0.5 * log_erfc(-z).exp()).ln()
+    }
+}
+
+/// Logarithm of the complementary error function for x >= 0, with a relative error below 1.2e-7
+/// (Chebyshev fit from Numerical Recipes)
+fn log_erfc(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.5 * x);
+    let polynomial = -1.26551223
+        + t * (1.00002368
+            + t * (0.37409196
+                + t * (0.09678418
+                    + t * (-0.18628806
+                        + t * (0.27886807 + t * (-1.13520398 + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))));
+    t.ln() - x * x + polynomial
+}
+
+/// Prime modulus of the field used for secret sharing, the Mersenne prime 2^61 - 1
+pub const FIELD_PRIME: u64 = (1 << 61) - 1;
+
+/// Scale of the fixed-point encoding of weights, giving a precision of about 6e-8
+pub const FIXED_POINT_SCALE: f64 = (1u64 << 24) as f64;
+
+/// Share of a vector of secrets held by one shareholder
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Share {
+    /// Point at which the sharing polynomials are evaluated, never 0.
+    pub index: u64,
+    pub values: Vec<u64>,
+}
+
+/// Addition in the field
+pub fn field_add(a: u64, b: u64) -> u64 {
+    ((a as u128 + b as u128) % FIELD_PRIME as u128) as u64
+}
+
+/// Subtraction in the field
+pub fn field_sub(a: u64, b: u64) -> u64 {
+    field_add(a, FIELD_PRIME - b % FIELD_PRIME)
+}
+
+/// Multiplication in the field
+pub fn field_mul(a: u64, b: u64) -> u64 {
+    ((a as u128 * b as u128) % FIELD_PRIME as u128) as u64
+}
+
+/// Multiplicative inverse in the field using Fermat's little theorem
+pub fn field_inv(a: u64) -> u64 {
+    let mut result = 1;
+    let mut base = a % FIELD_PRIME;
+    let mut exponent = FIELD_PRIME - 2;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = field_mul(result, base);
+        }
+        base = field_mul(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// Encodes a weight as a fixed-point field element, negative values wrap around the prime
+pub fn encode_fixed_point(weight: f64) -> u64 {
+    let scaled = (weight * FIXED_POINT_SCALE).round() as i64;
+    if scaled >= 0 {
+        scaled as u64 % FIELD_PRIME
+    } else {
+        FIELD_PRIME - (scaled.unsigned_abs() % FIELD_PRIME)
+    }
+}
+
+/// Decodes a fixed-point field element, values above half the prime are negative
+pub fn decode_fixed_point(value: u64) -> f64 {
+    if value > FIELD_PRIME / 2 {
+        -((FIELD_PRIME - value) as f64) / FIXED_POINT_SCALE
+    } else {
+        value as f64 / FIXED_POINT_SCALE
+    }
+}
+
+/// Splits field elements into `num_shares` Shamir shares, any `threshold` of which recover them
+pub fn share_field_values(secrets: &[u64], num_shares: usize, threshold: usize) -> Result<Vec<Share>, String> {
+    let indices = (1..=num_shares as u64).collect::<Vec<_>>();
+    share_field_values_at(secrets, &indices, threshold)
+}
+
+/// Splits field elements into one Shamir share per non-zero index, any `threshold` of which recover them
+pub fn share_field_values_at(secrets: &[u64], indices: &[u64], threshold: usize) -> Result<Vec<Share>, String> {
+    if threshold == 0 || threshold > indices.len() {
+        return Err(format!("Threshold must be between 1 and {}, got {}", indices.len(), threshold));
+    }
+    for (i, &index) in indices.iter().enumerate() {
+        if index % FIELD_PRIME == 0 || indices[..i].contains(&index) {
+            return Err(format!("Invalid or duplicate share index {}", index));
+        }
+    }
     let mut rng = thread_rng();
+    let mut shares = indices
+        .iter()
+        .map(|&index| Share { index, values: Vec::with_capacity(secrets.len()) })
+        .collect::<Vec<_>>();
+
+    for &secret in secrets {
+        // Random polynomial of degree (threshold - 1) with the secret as constant term
+        let mut coeffs = vec![secret % FIELD_PRIME];
+        coeffs.extend((1..threshold).map(|_| rng.gen_range(0..FIELD_PRIME)));
+
+        for share in shares.iter_mut() {
+            // Horner evaluation of the polynomial at the share index
+            let value = coeffs
+                .iter()
+                .rev()
+                .fold(0, |acc, &coeff| field_add(field_mul(acc, share.index), coeff));
+            share.values.push(value);
+        }
+    }
+
+    Ok(shares)
+}
 
-    for &weight in &weights {
-        // Generate random coefficients for the polynomial of degree (threshold - 1)
-        let mut coeffs: Vec<f64> = (0..(threshold - 1)).map(|_| rng.gen_range(0.0..100.0)).collect();  // Generate random coefficients
+/// Recovers field elements from at least `threshold` shares using Lagrange interpolation at 0
+pub fn reconstruct_field_values(shares: &[Share], threshold: usize) -> Result<Vec<u64>, String> {
+    if threshold == 0 || shares.len() < threshold {
+        return Err(format!("Need {} shares to reconstruct, got {}", threshold, shares.len()));
+    }
+    let shares = &shares[..threshold];
+    for (i, share) in shares.iter().enumerate() {
+        if share.index % FIELD_PRIME == 0 || shares[..i].iter().any(|s| s.index == share.index) {
+            return Err(format!("Invalid or duplicate share index {}", share.index));
+        }
+        if share.values.len() != shares[0].values.len() {
+            return Err("Shares hold a different number of values".to_string());
+        }
+    }
+
+    // Lagrange basis polynomials evaluated at 0
+    let basis = shares
+        .iter()
+        .map(|share| {
+            shares.iter().filter(|other| other.index != share.index).fold(1, |acc, other| {
+                let denominator = field_sub(other.index, share.index);
+                field_mul(acc, field_mul(other.index, field_inv(denominator)))
+            })
+        })
+        .collect::<Vec<_>>();
 
-        // Include the secret as the constant term of the polynomial
-        coeffs.insert(0, weight);
+    Ok((0..shares[0].values.len())
+        .map(|i| {
+            shares
+                .iter()
+                .zip(basis.iter())
+                .fold(0, |acc, (share, &b)| field_add(acc, field_mul(share.values[i], b)))
+        })
+        .collect())
+}
+
+//Implemented by Sharvani Chelumalla
+/// Splits the weights into Shamir shares over a prime field such that they can be shared secretly.
+/// Gaussian noise with standard deviation `noise_level` is added to each weight before sharing (0 disables it).
+pub fn secret_share_weights(weights: Vec<f64>, num_shares: usize, threshold: usize, noise_level: f64) -> Result<Vec<Share>, String> {
+    let noisy_weights = if noise_level > 0.0 {
+        let normal_dist = Normal::new(0.0, noise_level).map_err(|e| e.to_string())?;
+        let mut rng = thread_rng();
+        weights.iter().map(|&weight| weight + normal_dist.sample(&mut rng)).collect()
+    } else {
+        weights
+    };
+    let secrets = noisy_weights.iter().map(|&weight| encode_fixed_point(weight)).collect::<Vec<_>>();
 
-        for i in 0..num_shares {
-            // Calculate the share by evaluating the polynomial at (i + 1)
-            let x = (i + 1) as f64;
-            let share: f64 = coeffs.iter()
-                .enumerate()
-                //.map(|(idx, &coeff)| coeff * x.powi(idx as i32))  // x^idx * coeff
-                //.sum();
-                .fold(0.0, |acc, (idx, &coeff)| acc + coeff * (x.powi(idx as i32)));
-            shares[i].push(share);  // Append the share to the shares vector
+    share_field_values(&secrets, num_shares, threshold)
+}
+
+/// Recovers the weights from any `threshold` shares produced by `secret_share_weights`
+pub fn reconstruct_shares(shares: &[Share], threshold: usize) -> Result<Vec<f64>, String> {
+    Ok(reconstruct_field_values(shares, threshold)?
+        .into_iter()
+        .map(decode_fixed_point)
+        .collect())
+}
+
+/// Shamir share of a named tensor, uploaded in place of the tensor itself
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SharedTensor {
+    pub name: String,
+    pub shape: Vec<i64>,
+    pub dtype: String,
+    pub share: Share,
+}
+
+/// Splits every named tensor into `num_shares` shares, any `threshold` of which recover it
+pub fn share_state_dict(state_dict: &StateDict, num_shares: usize, threshold: usize) -> Result<Vec<SharedTensor>, String> {
+    let mut shared = Vec::with_capacity(state_dict.len() * num_shares);
+    for named_tensor in state_dict {
+        let secrets = named_tensor.data.iter().map(|&weight| encode_fixed_point(weight)).collect::<Vec<_>>();
+        for share in share_field_values(&secrets, num_shares, threshold)? {
+            shared.push(SharedTensor {
+                name: named_tensor.name.clone(),
+                shape: named_tensor.shape.clone(),
+                dtype: named_tensor.dtype.clone(),
+                share,
+            });
         }
     }
+    Ok(shared)
+}
 
-    shares  // Return the shares as a vector of vectors
+/// Recovers the named tensors shared by `share_state_dict`, in the order of their first share
+pub fn reconstruct_state_dict(shared: &[SharedTensor], threshold: usize) -> Result<StateDict, String> {
+    let mut names = Vec::<&str>::new();
+    for shared_tensor in shared {
+        if !names.contains(&shared_tensor.name.as_str()) {
+            names.push(&shared_tensor.name);
+        }
+    }
+    names
+        .into_iter()
+        .map(|name| {
+            let tensor_shares = shared.iter().filter(|s| s.name == name).collect::<Vec<_>>();
+            let first = tensor_shares[0];
+            if tensor_shares.iter().any(|s| s.shape != first.shape || s.dtype != first.dtype) {
+                return Err(format!("Shares of '{}' disagree on its shape or type", name));
+            }
+            let shares = tensor_shares.iter().map(|s| s.share.clone()).collect::<Vec<_>>();
+            Ok(NamedTensor {
+                name: name.to_string(),
+                shape: first.shape.clone(),
+                dtype: first.dtype.clone(),
+                data: reconstruct_shares(&shares, threshold)?,
+            })
+        })
+        .collect()
 }
 
 //Implemented by Sainath Talaknati
@@ -84,21 +427,44 @@ pub fn generate_fernet_key() -> String{
 }
 
 //Implemented by Sai Pranavi Reddy Patlolla
-/// Aggregates the received encrypted weights with the global model weights
-pub fn fed_avg_encrypted(weights_updates: Vec<Vec<String>>) -> Vec<String> {
-    let mut aggregated_weights = Vec::new();
+/// Decodes the weights received from the clients and averages them weighted by their number of samples
+pub fn fed_avg_encrypted(weights_updates: Vec<Vec<String>>, num_samples: &[usize]) -> Result<StateDict, String> {
+    let state_dicts = weights_updates
+        .iter()
+        .map(|payloads| decode_state_dict(payloads))
+        .collect::<Result<Vec<_>, String>>()?;
+    let sample_weights = num_samples.iter().map(|&n| n as f64).collect::<Vec<_>>();
 
-    // Perform simple aggregation (just for demonstration)
-    for i in 0..weights_updates[0].len() {
-        let encrypted_sum = weights_updates
-            .iter()
-            .fold(weights_updates[0][i].clone(), |sum, client_weights| {
-                sum + &client_weights[i] // Simplified string concatenation
-            });
-        aggregated_weights.push(encrypted_sum);
+    weighted_average(&state_dicts, &sample_weights)
+}
+
+/// Averages every parameter of the state dicts using the given weights
+pub fn weighted_average(state_dicts: &[StateDict], weights: &[f64]) -> Result<StateDict, String> {
+    if state_dicts.is_empty() {
+        return Err("No client updates to aggregate".to_string());
+    }
+    if state_dicts.len() != weights.len() {
+        return Err(format!("Got {} updates but {} weights", state_dicts.len(), weights.len()));
+    }
+    let total_weight: f64 = weights.iter().sum();
+    if total_weight <= 0.0 || !total_weight.is_finite() {
+        return Err("The total weight of the client updates must be positive".to_string());
+    }
+
+    let mut aggregated = state_dicts[0].clone();
+    aggregated.iter_mut().for_each(|named_tensor| named_tensor.data.fill(0.0));
+
+    for (state_dict, &weight) in state_dicts.iter().zip(weights.iter()) {
+        check_same_layout(&aggregated, state_dict)?;
+        let scale = weight / total_weight;
+        for (aggregated_tensor, named_tensor) in aggregated.iter_mut().zip(state_dict.iter()) {
+            for (sum, value) in aggregated_tensor.data.iter_mut().zip(named_tensor.data.iter()) {
+                *sum += scale * value;
+            }
+        }
     }
 
-    aggregated_weights
+    Ok(aggregated)
 }
 
 //Tests
@@ -106,13 +472,14 @@ pub fn fed_avg_encrypted(weights_updates: Vec<Vec<String>>) -> Vec<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::state_dict::{encode_named_tensor, NamedTensor};
     use rand::thread_rng;
     use rand_distr::{Normal, Distribution};
 
     // Test for DPMechanism: add_noise function
     #[test]
     fn test_add_noise() {
-        let dp_mechanism = DPMechanism::new(1.0, 1.0);
+        let dp_mechanism = DPMechanism::new(1.0, 1.0).unwrap();
         let weights = vec![1.0, 2.0, 3.0];
 
         let noisy_weights = dp_mechanism.add_noise(&weights);
@@ -124,6 +491,76 @@ mod tests {
         }
     }
 
+    // Test the analytic Gaussian calibration against the classic one
+    #[test]
+    fn test_analytic_gaussian_sigma() {
+        let sigma = DPMechanism::gaussian(1.0, 1e-5, 1.0).unwrap().noise_std();
+        let classic_sigma = (2.0 * (1.25f64 / 1e-5).ln()).sqrt();
+        assert!(sigma > 3.6 && sigma < 3.9, "sigma = {}", sigma);
+        assert!(sigma < classic_sigma);
+
+        // The noise scales with the sensitivity and shrinks with epsilon
+        assert!((DPMechanism::gaussian(1.0, 1e-5, 2.0).unwrap().noise_std() - 2.0 * sigma).abs() < 1e-6);
+        assert!(DPMechanism::gaussian(4.0, 1e-5, 1.0).unwrap().noise_std() < sigma);
+    }
+
+    // Test that mechanisms whose noise cannot be calibrated are refused
+    #[test]
+    fn test_invalid_dp_mechanism() {
+        assert!(DPMechanism::gaussian(0.0, 1e-5, 1.0).is_err());
+        assert!(DPMechanism::gaussian(1.0, 0.0, 1.0).is_err());
+        assert!(DPMechanism::gaussian(1.0, 1.0, 1.0).is_err());
+        assert!(DPMechanism::gaussian(1.0, 1e-5, 0.0).is_err());
+        assert!(DPMechanism::new(f64::INFINITY, 1.0).is_err());
+        assert!(DPMechanism::laplace(-1.0, 1.0).is_err());
+        assert!(DPMechanism::laplace(1.0, f64::NAN).is_err());
+    }
+
+    // Test that whole updates are clipped to the L2 and L1 bounds
+    #[test]
+    fn test_clip_update() {
+        let global = vec![
+            NamedTensor { name: "a".to_string(), shape: vec![1], dtype: "Float".to_string(), data: vec![1.0] },
+            NamedTensor { name: "b".to_string(), shape: vec![1], dtype: "Float".to_string(), data: vec![-1.0] },
+        ];
+        let mut local = global.clone();
+        local[0].data[0] += 3.0;
+        local[1].data[0] += 4.0;
+
+        let clipped = DPMechanism::new(1.0, 1.0).unwrap().clip_update(&global, &local).unwrap();
+        assert!((clipped[0].data[0] - 1.6).abs() < 1e-12);
+        assert!((clipped[1].data[0] + 0.2).abs() < 1e-12);
+
+        assert_eq!(DPMechanism::laplace(1.0, 7.0).unwrap().clip(&[3.0, -4.0]), vec![3.0, -4.0]);
+        assert_eq!(DPMechanism::laplace(1.0, 3.5).unwrap().clip(&[3.0, -4.0]), vec![1.5, -2.0]);
+    }
+
+    // Test that the Laplace noise has the expected scale
+    #[test]
+    fn test_laplace_noise() {
+        let dp_mechanism = DPMechanism::laplace(2.0, 1.0).unwrap();
+        let noisy_weights = dp_mechanism.add_noise(&vec![0.0; 20000]);
+        let mean_abs = noisy_weights.iter().map(|v| v.abs()).sum::<f64>() / noisy_weights.len() as f64;
+        // E|X| equals the scale sensitivity / epsilon
+        assert!((mean_abs - 0.5).abs() < 0.03, "mean |noise| = {}", mean_abs);
+    }
+
+    // Test that a state dict is recovered from any threshold of the shares of each tensor
+    #[test]
+    fn test_share_state_dict() {
+        let state_dict = vec![
+            NamedTensor { name: "fc.weight".to_string(), shape: vec![2], dtype: "Float".to_string(), data: vec![0.5, -1.25] },
+            NamedTensor { name: "fc.bias".to_string(), shape: vec![1], dtype: "Float".to_string(), data: vec![3.0] },
+        ];
+        let shared = share_state_dict(&state_dict, 3, 2).unwrap();
+        assert_eq!(shared.len(), 6);
+
+        let subset = shared.iter().filter(|s| s.share.index != 2).cloned().collect::<Vec<_>>();
+        assert_eq!(reconstruct_state_dict(&subset, 2).unwrap(), state_dict);
+        let single = shared.iter().filter(|s| s.share.index == 1).cloned().collect::<Vec<_>>();
+        assert!(reconstruct_state_dict(&single, 2).is_err());
+    }
+
     // Test for secret_share_weights function
     #[test]
     fn test_secret_share_weights() {
@@ -131,11 +568,63 @@ mod tests {
         let num_shares = 1;
         let threshold = 1; // Example threshold
 
-        let shares = secret_share_weights(weights.clone(), num_shares, threshold, 0.0);
+        let shares = secret_share_weights(weights.clone(), num_shares, threshold, 0.0).unwrap();
 
         // Check if the number of shares matches the num_shares input
         assert_eq!(shares.len(), num_shares);
-        assert_eq!(shares[0].len(), weights.len());
+        assert_eq!(shares[0].values.len(), weights.len());
+        assert!(secret_share_weights(weights, 2, 3, 0.0).is_err());
+    }
+
+    // Test that any threshold subset of the shares recovers the weights
+    #[test]
+    fn test_reconstruct_shares() {
+        let weights = vec![0.0, 1.5, -2.25, 123.456, -0.000_1];
+        let shares = secret_share_weights(weights.clone(), 5, 3, 0.0).unwrap();
+
+        for a in 0..5 {
+            for b in (a + 1)..5 {
+                for c in (b + 1)..5 {
+                    let subset = vec![shares[c].clone(), shares[a].clone(), shares[b].clone()];
+                    let recovered = reconstruct_shares(&subset, 3).unwrap();
+                    for (w, r) in weights.iter().zip(recovered.iter()) {
+                        assert!((w - r).abs() <= 1.0 / FIXED_POINT_SCALE);
+                    }
+                }
+            }
+        }
+    }
+
+    // Test that fewer than threshold shares reveal nothing structured about the secret
+    #[test]
+    fn test_shares_below_threshold() {
+        let shares = secret_share_weights(vec![42.0], 3, 2, 0.0).unwrap();
+        assert!(reconstruct_shares(&shares[..1], 2).is_err());
+
+        // With threshold 2 a single share is uniform over the field whatever the secret is
+        for secret in [0.0, 1000.0] {
+            let samples = (0..2000)
+                .map(|_| secret_share_weights(vec![secret], 3, 2, 0.0).unwrap()[0].values[0])
+                .collect::<Vec<_>>();
+            let mean = samples.iter().map(|&v| v as f64 / FIELD_PRIME as f64).sum::<f64>() / samples.len() as f64;
+            assert!((mean - 0.5).abs() < 0.05);
+            assert!(samples.iter().all(|&v| v != encode_fixed_point(secret)));
+        }
+
+        // Any candidate secret is consistent with a single share: take the line through it and (0, -7.0)
+        let candidate = encode_fixed_point(-7.0);
+        let second = Share { index: 2, values: vec![field_sub(field_mul(2, shares[0].values[0]), candidate)] };
+        let forged = reconstruct_shares(&[shares[0].clone(), second], 2).unwrap();
+        assert_eq!(forged, vec![-7.0]);
+    }
+
+    // Test for the fixed-point encoding of negative and positive weights
+    #[test]
+    fn test_fixed_point_encoding() {
+        for weight in [0.0, 1.0, -1.0, 7.125, -2.5e6] {
+            assert!((decode_fixed_point(encode_fixed_point(weight)) - weight).abs() <= 1.0 / FIXED_POINT_SCALE);
+        }
+        assert_eq!(field_mul(field_inv(12345), 12345), 1);
     }
 
     // Test for encrypt_share function
@@ -162,14 +651,36 @@ mod tests {
     // Test for fed_avg_encrypted function
     #[test]
     fn test_fed_avg_encrypted() {
-        let client_updates = vec![
-            vec!["a".to_string(), "b".to_string(), "c".to_string()],
-            vec!["d".to_string(), "e".to_string(), "f".to_string()],
-        ];
+        let update = |value: f64| {
+            let named_tensor = NamedTensor {
+                name: "fc.weight".to_string(),
+                shape: vec![3],
+                dtype: "Float".to_string(),
+                data: vec![value; 3],
+            };
+            vec![encode_named_tensor(&named_tensor).unwrap()]
+        };
+        let client_updates = vec![update(1.0), update(5.0)];
 
-        let aggregated_weights = fed_avg_encrypted(client_updates);
+        let aggregated_weights = fed_avg_encrypted(client_updates, &[300, 100]).unwrap();
+
+        // Check that the average is weighted by the number of samples
+        assert_eq!(aggregated_weights.len(), 1);
+        assert_eq!(aggregated_weights[0].data, vec![2.0; 3]);
+    }
+
+    // Test that updates with different parameters cannot be averaged
+    #[test]
+    fn test_weighted_average_mismatch() {
+        let named_tensor = |name: &str| NamedTensor {
+            name: name.to_string(),
+            shape: vec![1],
+            dtype: "Float".to_string(),
+            data: vec![1.0],
+        };
+        let state_dicts = vec![vec![named_tensor("fc.weight")], vec![named_tensor("fc.bias")]];
 
-        // Check if the aggregation result is correct
-        assert_eq!(aggregated_weights.len(), 3); // Should match the number of weights
+        assert!(weighted_average(&state_dicts, &[1.0, 1.0]).is_err());
+        assert!(weighted_average(&state_dicts[..1], &[0.0]).is_err());
     }
 }
\ No newline at end of file
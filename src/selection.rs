@@ -0,0 +1,192 @@
+//Client selection: the cohort of a round is sampled among the clients that checked in
+//
+//Clients check in with what they know about themselves (number of samples, last training loss and how
+//likely they are to stay online). Once selection closes, the strategy of the server picks the cohort and
+//the other candidates are told to check in again for the next round.
+
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+
+/// Client asking to take part in a round
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckIn {
+    pub client_id: String,
+    /// Number of local training samples.
+    #[serde(default)]
+    pub num_samples: usize,
+    /// Loss of the last local training, unknown before the first one.
+    #[serde(default)]
+    pub loss: Option<f64>,
+    /// Estimated probability of staying online until the end of the round, 1 when not reported.
+    #[serde(default)]
+    pub availability: Option<f64>,
+}
+
+/// Strategy sampling the cohort of a round among the candidates
+pub trait ClientSelector: Send {
+    /// Name of the strategy, used in logs and responses
+    fn name(&self) -> &str;
+
+    /// Client ids of at most `max_participants` candidates taking part in the round
+    fn select(&mut self, candidates: &[CheckIn], max_participants: usize) -> Vec<String>;
+}
+
+/// Uniformly random fraction of the candidates
+pub struct UniformSelection {
+    pub fraction: f64,
+}
+
+impl ClientSelector for UniformSelection {
+    fn name(&self) -> &str {
+        "Uniform"
+    }
+
+    fn select(&mut self, candidates: &[CheckIn], max_participants: usize) -> Vec<String> {
+        let cohort_size = ((self.fraction * candidates.len() as f64).ceil() as usize).min(max_participants);
+        candidates
+            .choose_multiple(&mut thread_rng(), cohort_size)
+            .map(|candidate| candidate.client_id.clone())
+            .collect()
+    }
+}
+
+/// Candidates sorted by data size and split into strata, each stratum filling its share of the cohort
+pub struct StratifiedSelection {
+    pub num_strata: usize,
+}
+
+impl ClientSelector for StratifiedSelection {
+    fn name(&self) -> &str {
+        "Stratified"
+    }
+
+    fn select(&mut self, candidates: &[CheckIn], max_participants: usize) -> Vec<String> {
+        let mut sorted = candidates.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|candidate| candidate.num_samples);
+        let cohort_size = max_participants.min(sorted.len());
+        let num_strata = self.num_strata.clamp(1, sorted.len().max(1));
+
+        let mut rng = thread_rng();
+        let mut cohort = Vec::with_capacity(cohort_size);
+        for stratum in 0..num_strata {
+            let stratum_range = stratum * sorted.len() / num_strata..(stratum + 1) * sorted.len() / num_strata;
+            // Seats of the strata before this one are subtracted so that the shares add up to the cohort
+            let seats = (stratum + 1) * cohort_size / num_strata - stratum * cohort_size / num_strata;
+            cohort.extend(
+                sorted[stratum_range]
+                    .choose_multiple(&mut rng, seats)
+                    .map(|candidate| candidate.client_id.clone()),
+            );
+        }
+        cohort
+    }
+}
+
+/// Power-of-choice of Cho et al. (2020): `candidate_set_size` candidates are sampled in proportion to
+/// their data size and the ones with the highest loss are selected, clients without a loss first
+pub struct PowerOfChoice {
+    pub candidate_set_size: usize,
+}
+
+impl ClientSelector for PowerOfChoice {
+    fn name(&self) -> &str {
+        "PowerOfChoice"
+    }
+
+    fn select(&mut self, candidates: &[CheckIn], max_participants: usize) -> Vec<String> {
+        let set_size = self.candidate_set_size.max(max_participants).min(candidates.len());
+        let mut candidate_set = candidates
+            .choose_multiple_weighted(&mut thread_rng(), set_size, |candidate| candidate.num_samples.max(1) as f64)
+            .map(|sample| sample.collect::<Vec<_>>())
+            .unwrap_or_default();
+        candidate_set.sort_by(|a, b| {
+            let (a, b) = (a.loss.unwrap_or(f64::INFINITY), b.loss.unwrap_or(f64::INFINITY));
+            b.total_cmp(&a)
+        });
+        candidate_set
+            .into_iter()
+            .take(max_participants)
+            .map(|candidate| candidate.client_id.clone())
+            .collect()
+    }
+}
+
+/// Candidates likely to stay online until the end of the round, sampled in proportion to their availability
+pub struct AvailabilityAwareSelection {
+    /// Candidates reporting a lower availability are never selected.
+    pub min_availability: f64,
+}
+
+impl ClientSelector for AvailabilityAwareSelection {
+    fn name(&self) -> &str {
+        "AvailabilityAware"
+    }
+
+    fn select(&mut self, candidates: &[CheckIn], max_participants: usize) -> Vec<String> {
+        let available = candidates
+            .iter()
+            .filter(|candidate| candidate.availability.unwrap_or(1.0) >= self.min_availability)
+            .collect::<Vec<_>>();
+        available
+            .choose_multiple_weighted(&mut thread_rng(), max_participants.min(available.len()), |candidate| {
+                // Reported availabilities of 0 only pass a threshold of 0, keep them possible
+                candidate.availability.unwrap_or(1.0).max(1e-6)
+            })
+            .map(|sample| sample.map(|candidate| candidate.client_id.clone()).collect())
+            .unwrap_or_default()
+    }
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates() -> Vec<CheckIn> {
+        (0..10)
+            .map(|i| CheckIn {
+                client_id: format!("client-{}", i),
+                num_samples: 100 * (i + 1),
+                loss: if i == 0 { None } else { Some(i as f64) },
+                availability: Some(i as f64 / 10.0),
+            })
+            .collect()
+    }
+
+    // Test that uniform selection samples a fraction of the candidates bounded by the cohort size
+    #[test]
+    fn test_uniform_selection() {
+        let mut selector = UniformSelection { fraction: 0.5 };
+        assert_eq!(selector.select(&candidates(), 10).len(), 5);
+        assert_eq!(selector.select(&candidates(), 3).len(), 3);
+    }
+
+    // Test that every stratum of data sizes is represented
+    #[test]
+    fn test_stratified_selection() {
+        let mut selector = StratifiedSelection { num_strata: 2 };
+        let cohort = selector.select(&candidates(), 4);
+        assert_eq!(cohort.len(), 4);
+        let small = cohort.iter().filter(|id| id[7..].parse::<usize>().unwrap() < 5).count();
+        assert_eq!(small, 2);
+    }
+
+    // Test that power-of-choice prefers clients with unknown or high losses
+    #[test]
+    fn test_power_of_choice() {
+        let mut selector = PowerOfChoice { candidate_set_size: 10 };
+        let mut cohort = selector.select(&candidates(), 3);
+        cohort.sort();
+        assert_eq!(cohort, vec!["client-0", "client-8", "client-9"]);
+    }
+
+    // Test that candidates below the availability threshold are never selected
+    #[test]
+    fn test_availability_aware_selection() {
+        let mut selector = AvailabilityAwareSelection { min_availability: 0.75 };
+        let mut cohort = selector.select(&candidates(), 5);
+        cohort.sort();
+        assert_eq!(cohort, vec!["client-8", "client-9"]);
+    }
+}
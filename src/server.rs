@@ -5,20 +5,76 @@
 
 pub use actix_web::{get, post, web, App, HttpServer, Responder, HttpResponse};
 pub use serde::{Deserialize, Serialize};
-pub use log::info;
+pub use log::{error, info};
 pub use tch::{nn, nn::Module, nn::OptimizerConfig, Tensor};
 pub use std::sync::{Arc, Mutex};
 pub use reqwest::Response;
-use crate::secure_dp_utils::fed_avg_encrypted;
+use std::pin::Pin;
+use std::time::Instant;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::middleware::{from_fn, Next};
+use actix_web::http::header;
+use actix_web::{Error, HttpMessage, HttpRequest};
+use futures_util::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use crate::aggregation::{Aggregator, ClientUpdate, FedAvg, StalenessFunction};
+use crate::async_aggregation::{AsyncAggregator, AsyncStrategy};
+use crate::auth::{unix_time, ClientRegistry, RegistrationRequest, RequestCredentials, CLIENT_ID_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::central_dp::CentralDp;
+use crate::checkpoint::{Checkpoint, CheckpointConfig, CheckpointStore};
+use crate::distributed_dp::SkellamMechanism;
+use crate::model_registry::{ModelRecord, ModelRegistry, RoundMetrics};
+use crate::key_management::{EncryptionHeader, InMemoryKeyStore, KeyStore};
+use crate::paillier::{weighted_values_average, EncryptedAggregation, PaillierPublicKey, PartialDecryption};
+use crate::privacy_accountant::{PrivacyAccountant, SampledGaussian};
+use crate::round::{CheckInStatus, RoundConfig, RoundManager};
+use crate::selection::{CheckIn, ClientSelector};
+use crate::secure_aggregation::{
+    decode_weighted_average, AdvertisedKeys, MaskedInput, SecAggPhase, SecureAggregationServer, ShareKeysMessage,
+    UnmaskMessage,
+};
+use crate::secure_dp_utils::{reconstruct_state_dict, SharedTensor};
+use crate::tls::ServerTlsConfig;
+use crate::state_dict::{decode_state_dict, encode_named_tensor, load_state_dict, state_dict_from_var_store, validate_state_dict, StateDict};
 
 //Implemented by Sharvani Chelumalla
 /// Struct to represent weight updates sent to the server.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WeightsUpdate {
     pub model_weights: Vec<String>,
     pub num_samples: usize,
     pub loss: f64,
     pub model_version: usize,
+    /// Present when `model_weights` are encrypted with a session key agreed with the server.
+    #[serde(default)]
+    pub encryption: Option<EncryptionHeader>,
+    /// Identifier of the client, duplicate updates of a client in a round are refused. Anonymous when `None`.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Round the update was trained for, the current round when `None`.
+    #[serde(default)]
+    pub round_id: Option<u64>,
+    /// Threshold of the Shamir shares `model_weights` hold, one encoded `SharedTensor` per share of every
+    /// parameter. Plain named tensors when `None`.
+    #[serde(default)]
+    pub share_threshold: Option<usize>,
+}
+
+/// Largest request body the server reads, a full model upload is far above actix-web's default of 2 MiB
+pub const MAX_PAYLOAD_BYTES: usize = 64 * 1024 * 1024;
+
+/// Number of global models retained by default
+pub const DEFAULT_MODEL_HISTORY: usize = 5;
+
+/// Why `AppState::rollback` did not publish a retained model
+#[derive(Debug, Clone, PartialEq)]
+pub enum RollbackError {
+    /// The version is not, or no longer, in the model registry.
+    NotRetained,
+    /// The retained model could not be installed or recorded.
+    Failed(String),
 }
 
 //Implemented by Sai Pranavi Reddy Patlolla
@@ -26,48 +82,411 @@ pub struct WeightsUpdate {
 pub struct AppState {
     pub aggregation_goal: usize,
     pub current_model_version: Mutex<usize>,
-    pub client_updates: Mutex<Vec<WeightsUpdate>>,
+    /// Current round collecting the client updates for the next global model
+    pub rounds: Mutex<RoundManager<WeightsUpdate>>,
+    /// Number of versions an update may lag behind the global model, 0 only accepts the current version
+    pub max_staleness: usize,
+    /// Discount of tolerated stale updates
+    pub staleness_function: StalenessFunction,
+    /// Asynchronous aggregation publishing versions without rounds, synchronous rounds when `None`
+    pub asynchronous: Mutex<Option<AsyncAggregator>>,
     pub global_model: Mutex<nn::Sequential>,
+    /// Variables of the global model, served to clients as a state dict
+    pub global_var_store: Mutex<nn::VarStore>,
+    /// Strategy combining the client updates into the next global model
+    pub aggregator: Mutex<Box<dyn Aggregator>>,
+    /// Current secure aggregation round, an alternative to `update_model` where the server only sees the sum
+    pub secure_aggregation: Mutex<SecureAggregationServer>,
+    /// Number of surviving clients needed to unmask a secure aggregation round, a majority by default
+    pub secure_aggregation_threshold: usize,
+    /// Paillier aggregation over encrypted updates, disabled unless a public key is configured
+    pub paillier: Mutex<Option<EncryptedAggregation>>,
+    /// Server key pairs clients encrypt their updates for, rotated with every global model update
+    pub key_store: Mutex<Box<dyn KeyStore>>,
+    /// Privacy spent by the released global models, unlimited unless configured
+    pub privacy_accountant: Mutex<PrivacyAccountant>,
+    /// Mechanism every aggregation is accounted as, nothing is spent when `None`
+    pub round_mechanism: Option<SampledGaussian>,
+    /// Skellam noise the clients add to their secure aggregation inputs, plain weighted inputs when `None`
+    pub distributed_dp: Option<SkellamMechanism>,
+    /// Clients registered on `/register` and the revoked ones
+    pub clients: Mutex<ClientRegistry>,
+    /// Whether the client endpoints refuse unauthenticated requests
+    pub require_auth: bool,
+    /// Directory the server state is periodically saved to and resumed from, nothing is saved when `None`
+    pub checkpoints: Mutex<Option<CheckpointStore>>,
+    /// Recent global models served on `/models/{version}` and available for rollback
+    pub models: Mutex<ModelRegistry>,
+    /// Bearer token of the operator allowed to roll back the global model, rollback is disabled when `None`
+    pub admin_token: Option<String>,
 }
 //Implemented by Sai Pranavi Reddy Patlolla
 impl AppState{
-    /// Default global state if not defined by user
-    pub fn default() -> Self{
-        let vs = Arc::new(nn::VarStore::new(tch::Device::Cpu));
+    /// Global state with a user defined aggregation goal and strategy
+    pub fn new(aggregation_goal: usize, aggregator: Box<dyn Aggregator>) -> Self{
+        let vs = nn::VarStore::new(tch::Device::Cpu);
         let global_model = create_model(&vs.root());
-        AppState {
-            aggregation_goal: 1,
+        let app_state = AppState {
+            aggregation_goal,
             current_model_version: Mutex::new(0),
-            client_updates: Mutex::new(Vec::new()),
-            global_model: Mutex::new(global_model)
+            rounds: Mutex::new(new_round_manager(aggregation_goal)),
+            max_staleness: 0,
+            staleness_function: StalenessFunction::Constant,
+            asynchronous: Mutex::new(None),
+            global_model: Mutex::new(global_model),
+            global_var_store: Mutex::new(vs),
+            aggregator: Mutex::new(aggregator),
+            secure_aggregation: Mutex::new(new_secure_aggregation_round(aggregation_goal, aggregation_goal / 2 + 1)),
+            secure_aggregation_threshold: aggregation_goal / 2 + 1,
+            paillier: Mutex::new(None),
+            key_store: Mutex::new(Box::new(InMemoryKeyStore::new(2))),
+            privacy_accountant: Mutex::new(PrivacyAccountant::new(f64::INFINITY, 1e-5)),
+            round_mechanism: None,
+            distributed_dp: None,
+            clients: Mutex::new(ClientRegistry::new(None)),
+            require_auth: false,
+            checkpoints: Mutex::new(None),
+            models: Mutex::new(ModelRegistry::new(DEFAULT_MODEL_HISTORY).expect("the default model history is not empty")),
+            admin_token: None,
+        };
+        app_state.record_current_model(RoundMetrics::default());
+        app_state
+    }
+
+    /// Accounts every aggregation as `round_mechanism` and stops aggregating once the budget is exhausted
+    pub fn with_privacy_budget(mut self, accountant: PrivacyAccountant, round_mechanism: SampledGaussian) -> Self {
+        self.privacy_accountant = Mutex::new(accountant);
+        self.round_mechanism = Some(round_mechanism);
+        self
+    }
+
+    /// Central DP: the server clips and noises the aggregate with `central_dp` and accounts every round
+    /// as its Gaussian mechanism, `sampling_rate` being the fraction of the clients taking part in a round
+    pub fn with_central_dp(self, central_dp: CentralDp, accountant: PrivacyAccountant, sampling_rate: f64) -> Self {
+        let round_mechanism = central_dp.round_mechanism(sampling_rate);
+        *self.aggregator.lock().unwrap() = Box::new(central_dp);
+        self.with_privacy_budget(accountant, round_mechanism)
+    }
+
+    /// Distributed DP: secure aggregation clients send Skellam-noised deltas and every round is accounted
+    /// with the noise of the clients that actually took part. The mechanism must cover every model parameter.
+    pub fn with_distributed_dp(mut self, mechanism: SkellamMechanism, accountant: PrivacyAccountant) -> Result<Self, String> {
+        let num_parameters = self.num_parameters()?;
+        if mechanism.dimension != num_parameters {
+            return Err(format!(
+                "Distributed DP mechanism has dimension {} but the model has {} parameters",
+                mechanism.dimension, num_parameters
+            ));
+        }
+        self.privacy_accountant = Mutex::new(accountant);
+        self.distributed_dp = Some(mechanism);
+        Ok(self)
+    }
+
+    /// Number of parameters of the global model
+    pub fn num_parameters(&self) -> Result<usize, String> {
+        let layout = state_dict_from_var_store(&self.global_var_store.lock().unwrap())?;
+        Ok(layout.iter().map(|t| t.data.len()).sum())
+    }
+
+    /// Secure aggregation rounds that can be unmasked with `threshold` of the `aggregation_goal` clients
+    pub fn with_secure_aggregation_threshold(mut self, threshold: usize) -> Result<Self, String> {
+        self.secure_aggregation = Mutex::new(SecureAggregationServer::new(self.aggregation_goal, threshold)?);
+        self.secure_aggregation_threshold = threshold;
+        Ok(self)
+    }
+
+    /// Rounds with participation limits and deadlines instead of waiting for `aggregation_goal` updates
+    pub fn with_round_config(self, config: RoundConfig) -> Result<Self, String> {
+        *self.rounds.lock().unwrap() = RoundManager::new(config)?;
+        Ok(self)
+    }
+
+    /// Samples the participants of every round with `selector` among the clients checking in on `/check_in`
+    pub fn with_client_selection(self, selector: Box<dyn ClientSelector>) -> Self {
+        self.rounds.lock().unwrap().set_selector(selector);
+        self
+    }
+
+    /// Accepts updates up to `max_staleness` versions old, down-weighted by `staleness_function`
+    pub fn with_staleness_tolerance(mut self, max_staleness: usize, staleness_function: StalenessFunction) -> Self {
+        self.max_staleness = max_staleness;
+        self.staleness_function = staleness_function;
+        self
+    }
+
+    /// Asynchronous mode: updates are applied with `strategy` as they arrive instead of in rounds,
+    /// with the staleness tolerance configured before
+    pub fn with_async_aggregation(self, strategy: AsyncStrategy) -> Result<Self, String> {
+        let aggregator = AsyncAggregator::new(strategy, self.staleness_function, self.max_staleness)?;
+        *self.asynchronous.lock().unwrap() = Some(aggregator);
+        Ok(self)
+    }
+
+    /// Whether the privacy budget allows one more aggregation
+    pub fn can_aggregate(&self) -> bool {
+        match self.round_mechanism {
+            Some(mechanism) => self.privacy_accountant.lock().unwrap().can_spend(&mechanism),
+            None => true,
+        }
+    }
+
+    /// Enables the Paillier endpoints with the public key of `key_holders` key holders
+    pub fn with_paillier(self, public_key: PaillierPublicKey, key_holders: usize) -> Self {
+        *self.paillier.lock().unwrap() = Some(EncryptedAggregation::new(public_key, key_holders));
+        self
+    }
+
+    /// Requires registered clients on every client endpoint. Registration needs `registration_secret`, so
+    /// that revoked clients cannot register again under a new id.
+    pub fn with_authentication(mut self, registration_secret: String) -> Self {
+        self.clients = Mutex::new(ClientRegistry::new(Some(registration_secret)));
+        self.require_auth = true;
+        self
+    }
+
+    /// Bans a registered client, returns whether it was known
+    pub fn revoke_client(&self, client_id: &str) -> bool {
+        let revoked = self.clients.lock().unwrap().revoke(client_id);
+        if revoked {
+            info!("Revoked client {}", client_id);
+        }
+        revoked
+    }
+
+    /// Retains the last `capacity` global models instead of `DEFAULT_MODEL_HISTORY`
+    pub fn with_model_history(self, capacity: usize) -> Result<Self, String> {
+        *self.models.lock().unwrap() = ModelRegistry::new(capacity)?;
+        self.record_current_model(RoundMetrics::default());
+        Ok(self)
+    }
+
+    /// Enables `/models/{version}/rollback` and `/secure_aggregation/advance` for requests bearing `admin_token`
+    pub fn with_admin_token(mut self, admin_token: String) -> Self {
+        self.admin_token = Some(admin_token);
+        self
+    }
+
+    /// Saves checkpoints to `config.dir` and resumes from the newest one found there.
+    /// Applied last, once the aggregation strategy and the privacy budget are configured.
+    pub fn with_checkpoints(self, config: CheckpointConfig) -> Result<Self, String> {
+        let store = CheckpointStore::new(config)?;
+        if let Some((checkpoint, model_path)) = store.latest()? {
+            self.global_var_store.lock().unwrap().load(&model_path).map_err(|e| format!("Cannot load {}: {}", model_path.display(), e))?;
+            *self.current_model_version.lock().unwrap() = checkpoint.model_version;
+            let mut rounds = self.rounds.lock().unwrap();
+            rounds.resume(checkpoint.round_id, checkpoint.last_round);
+            if let Some(pending) = checkpoint.pending_round {
+                rounds.restore_pending(pending, Instant::now());
+            }
+            drop(rounds);
+            if let Some(state) = checkpoint.clients {
+                self.clients.lock().unwrap().restore_state(state)?;
+            }
+            if let Some(state) = checkpoint.aggregator {
+                self.aggregator.lock().unwrap().restore_state(state)?;
+            }
+            if let (Some(asynchronous), Some(state)) = (self.asynchronous.lock().unwrap().as_mut(), checkpoint.asynchronous) {
+                asynchronous.restore_state(state);
+            }
+            // The budget and delta come from the configuration, which may have changed since
+            self.privacy_accountant.lock().unwrap().restore_spending(&checkpoint.privacy_accountant)?;
+            let capacity = self.models.lock().unwrap().capacity();
+            *self.models.lock().unwrap() = ModelRegistry::new(capacity)?;
+            self.record_current_model(RoundMetrics::default());
+            info!("Resumed from the checkpoint of version {} at round {}", checkpoint.model_version, checkpoint.round_id);
+        }
+        *self.checkpoints.lock().unwrap() = Some(store);
+        Ok(self)
+    }
+
+    /// Saves a checkpoint if the server state changed since the last one, returns its version
+    pub fn save_checkpoint(&self) -> Result<Option<usize>, String> {
+        let mut checkpoints = self.checkpoints.lock().unwrap();
+        let store = match checkpoints.as_mut() {
+            Some(store) => store,
+            None => return Ok(None),
+        };
+        // Same lock order as the handlers updating the global model, which cannot run until the state is saved
+        let asynchronous = self.asynchronous.lock().unwrap();
+        let rounds = self.rounds.lock().unwrap();
+        let global_var_store = self.global_var_store.lock().unwrap();
+        let model_version = *self.current_model_version.lock().unwrap();
+
+        let checkpoint = Checkpoint {
+            model_version,
+            round_id: rounds.round_id(),
+            last_round: rounds.status(Instant::now()).last_round,
+            pending_round: Some(rounds.pending()),
+            clients: Some(self.clients.lock().unwrap().state()),
+            aggregator: self.aggregator.lock().unwrap().state(),
+            asynchronous: asynchronous.as_ref().map(AsyncAggregator::state),
+            privacy_accountant: self.privacy_accountant.lock().unwrap().clone(),
+            saved_at: unix_time(),
+        };
+        if store.is_saved(&checkpoint) {
+            return Ok(None);
+        }
+        let path = store.save(&checkpoint, |path| global_var_store.save(path).map_err(|e| e.to_string()))?;
+        info!("Saved checkpoint {}", path.display());
+        Ok(Some(model_version))
+    }
+
+    /// Publishes the weights of a retained `version` again as a new version, with the aggregator state saved
+    /// alongside them. The current round, the secure aggregation round and the Paillier aggregate are dropped
+    /// and the async buffer emptied since their updates were trained on the replaced model.
+    pub fn rollback(&self, version: usize) -> Result<usize, RollbackError> {
+        // Same lock order as `update_model`, the secure aggregation and Paillier handlers lock their protocol
+        // before the global model
+        let mut asynchronous = self.asynchronous.lock().unwrap();
+        let mut rounds = self.rounds.lock().unwrap();
+        let mut secure_aggregation = self.secure_aggregation.lock().unwrap();
+        let mut paillier = self.paillier.lock().unwrap();
+        let mut global_var_store = self.global_var_store.lock().unwrap();
+        let mut aggregator = self.aggregator.lock().unwrap();
+        let mut current_version = self.current_model_version.lock().unwrap();
+        let mut models = self.models.lock().unwrap();
+        let record = models.get(version).cloned().ok_or(RollbackError::NotRetained)?;
+
+        load_state_dict(&mut global_var_store, &record.state_dict).map_err(RollbackError::Failed)?;
+        if let Some(state) = record.aggregator.clone() {
+            aggregator.restore_state(state).map_err(RollbackError::Failed)?;
+        }
+        *current_version += 1;
+        models
+            .record(ModelRecord {
+                version: *current_version,
+                state_dict: record.state_dict,
+                aggregator: record.aggregator,
+                metrics: RoundMetrics { rolled_back_from: Some(version), ..Default::default() },
+                published_at: unix_time(),
+            })
+            .map_err(RollbackError::Failed)?;
+        rounds.abandon();
+        if let Some(asynchronous) = asynchronous.as_mut() {
+            asynchronous.clear_buffer();
+        }
+        *secure_aggregation = new_secure_aggregation_round(self.aggregation_goal, self.secure_aggregation_threshold);
+        if let Some(paillier) = paillier.as_mut() {
+            paillier.reset();
+        }
+        let next_key = self.key_store.lock().unwrap().rotate();
+        info!("Server key rotated, Round: {}", next_key.round);
+        info!("Rolled back to the model of version {}, Version: {}", version, current_version);
+        Ok(*current_version)
+    }
+
+    /// Records the installed global model in the model registry
+    fn record_current_model(&self, metrics: RoundMetrics) {
+        let global_var_store = self.global_var_store.lock().unwrap();
+        let aggregator = self.aggregator.lock().unwrap();
+        let version = *self.current_model_version.lock().unwrap();
+        if let Err(e) = state_dict_from_var_store(&global_var_store)
+            .and_then(|state_dict| record_model(self, version, state_dict, aggregator.state(), metrics))
+        {
+            error!("Cannot record the global model: {}", e);
         }
     }
+
+    /// Default global state if not defined by user
+    pub fn default() -> Self{
+        AppState::new(1, Box::new(FedAvg))
+    }
 }
 
 //Implemented by Sharvani Chelumalla
-/// A CNN construction using max-pooling and activation functions
+/// A CNN construction using max-pooling and activation functions.
+/// Layer names and shapes match `client::SimpleCNN` so that state dicts can be exchanged.
 pub fn create_model(vs: &nn::Path) -> nn::Sequential {
     nn::seq()
-        .add(nn::conv2d(vs, 1, 32, 3, nn::ConvConfig::default()))
-        .add_fn(|xs| xs.max_pool2d_default(2))
-        .add_fn(|xs| xs.view([-1, 32 * 14 * 14]))
-        .add(nn::linear(vs, 32 * 14 * 14, 128, Default::default()))
+        .add_fn(|xs| xs.view([-1, 1, 28, 28]))
+        .add(nn::conv2d(vs / "conv1", 1, 32, 3, nn::ConvConfig::default()))
+        .add_fn(|xs| xs.relu().max_pool2d_default(2))
+        .add_fn(|xs| xs.view([-1, 32 * 13 * 13]))
+        .add(nn::linear(vs / "fc1", 32 * 13 * 13, 128, Default::default()))
         .add_fn(|xs| xs.relu())
-        .add(nn::linear(vs, 128, 10, Default::default()))
+        .add(nn::linear(vs / "fc2", 128, 10, Default::default()))
 }
 
 //Implemented by Sai Pranavi Reddy Patlolla
-#[get("/get_model")]
-/// Stores the global model weights such that client can fetch the global weights
+/// Id of the client a request was authenticated as
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthenticatedClient(pub String);
+
+#[post("/register")]
+/// Registers a client and issues its id with a bearer token, or lets it sign its requests with a public key
+pub async fn register(request: web::Json<RegistrationRequest>, data: web::Data<AppState>) -> impl Responder {
+    match data.clients.lock().unwrap().register(&request) {
+        Ok(registration) => {
+            info!("Registered client {}", registration.client_id);
+            HttpResponse::Ok().json(registration)
+        }
+        Err(e) => HttpResponse::Forbidden().json(serde_json::json!({ "message": e })),
+    }
+}
+
+/// Middleware refusing requests of unregistered or revoked clients when the server requires authentication
+pub async fn authenticate(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let data = req.app_data::<web::Data<AppState>>().cloned();
+    let data = match data {
+        Some(data) if data.require_auth => data,
+        _ => return next.call(req).await.map(ServiceResponse::map_into_left_body),
+    };
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let credentials = RequestCredentials {
+        authorization: header("authorization"),
+        client_id: header(CLIENT_ID_HEADER),
+        timestamp: header(TIMESTAMP_HEADER),
+        signature: header(SIGNATURE_HEADER),
+    };
+
+    // Signatures cover the body, read it and hand it back to the handler
+    let mut body = web::BytesMut::new();
+    if credentials.is_signed() {
+        let mut payload = req.take_payload();
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk?;
+            if body.len() + chunk.len() > MAX_PAYLOAD_BYTES {
+                let message = format!("Request body is larger than {} bytes", MAX_PAYLOAD_BYTES);
+                let response = HttpResponse::PayloadTooLarge().json(serde_json::json!({ "message": message }));
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let body = body.clone().freeze();
+        let stream: Pin<Box<dyn Stream<Item = Result<web::Bytes, PayloadError>>>> =
+            Box::pin(futures_util::stream::once(async move { Ok(body) }));
+        req.set_payload(Payload::from(stream));
+    }
+
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or(req.path()).to_string();
+    let authenticated = data.clients.lock().unwrap().authenticate(&credentials, req.method().as_str(), &path, &body, unix_time());
+    match authenticated {
+        Ok(client_id) => {
+            req.extensions_mut().insert(AuthenticatedClient(client_id));
+            next.call(req).await.map(ServiceResponse::map_into_left_body)
+        }
+        Err(e) => {
+            info!("Refused unauthenticated request to {}: {}", path, e);
+            let response = HttpResponse::Unauthorized().json(serde_json::json!({ "message": e }));
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+#[get("/get_model", wrap = "from_fn(authenticate)")]
+/// Serves the global model weights as a state dict such that client can fetch the global weights
 pub async fn get_model(data: web::Data<AppState>) -> impl Responder {
-    let _global_model = data.global_model.lock().unwrap();
-    /*let model_state_dict = global_model
-        .parameters()
-        .iter()
-        .map(|(key, value)| (key.clone(), value.to_kind(tch::Kind::Float).to_vec()))
-        .collect::<Vec<_>>();
-     */
-    let model_state_dict = "Model State Dict".to_string();
+    let global_var_store = data.global_var_store.lock().unwrap();
+    let model_state_dict = match state_dict_from_var_store(&global_var_store) {
+        Ok(state_dict) => state_dict,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({ "message": e }));
+        }
+    };
 
     HttpResponse::Ok().json(serde_json::json!({
         "model_state_dict": model_state_dict,
@@ -75,51 +494,754 @@ pub async fn get_model(data: web::Data<AppState>) -> impl Responder {
     }))
 }
 
+#[get("/models", wrap = "from_fn(authenticate)")]
+/// Lists the retained global models with the metrics of the rounds that produced them
+pub async fn list_models(data: web::Data<AppState>) -> impl Responder {
+    let current_version = *data.current_model_version.lock().unwrap();
+    HttpResponse::Ok().json(serde_json::json!({
+        "current_model_version": current_version,
+        "models": data.models.lock().unwrap().summaries()
+    }))
+}
+
+#[get("/models/{version}", wrap = "from_fn(authenticate)")]
+/// Serves a retained global model by version
+pub async fn get_model_version(version: web::Path<usize>, data: web::Data<AppState>) -> impl Responder {
+    let version = version.into_inner();
+    let models = data.models.lock().unwrap();
+    match models.get(version) {
+        Some(record) => HttpResponse::Ok().json(serde_json::json!({
+            "model_state_dict": record.state_dict,
+            "model_version": record.version,
+            "metrics": record.metrics
+        })),
+        None => HttpResponse::NotFound().json(serde_json::json!({ "message": format!("Version {} is not retained", version) })),
+    }
+}
+
+#[post("/models/{version}/rollback")]
+/// Publishes a retained global model again, for the operator when a round degraded the model
+pub async fn rollback_model(version: web::Path<usize>, req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if !is_admin(&req, &data) {
+        return HttpResponse::Forbidden().json(serde_json::json!({ "message": "Rollback requires the admin token" }));
+    }
+
+    let version = version.into_inner();
+    match data.rollback(version) {
+        Ok(model_version) => HttpResponse::Ok().json(serde_json::json!({
+            "message": "Global model rolled back",
+            "rolled_back_from": version,
+            "model_version": model_version
+        })),
+        Err(RollbackError::NotRetained) => {
+            HttpResponse::NotFound().json(serde_json::json!({ "message": format!("Version {} is not retained", version) }))
+        }
+        Err(RollbackError::Failed(e)) => HttpResponse::InternalServerError().json(serde_json::json!({ "message": e })),
+    }
+}
+
+/// Whether a request bears the admin token, always false when none is configured
+fn is_admin(req: &HttpRequest, data: &AppState) -> bool {
+    // Digests take the same time to compare whatever the prefix the token shares with the header
+    match (&data.admin_token, req.headers().get(header::AUTHORIZATION)) {
+        (Some(admin_token), Some(authorization)) => {
+            Sha256::digest(authorization.as_bytes()) == Sha256::digest(format!("Bearer {}", admin_token).as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// Rounds aggregating the updates of `aggregation_goal` clients without deadlines
+fn new_round_manager(aggregation_goal: usize) -> RoundManager<WeightsUpdate> {
+    RoundManager::new(RoundConfig::fixed(aggregation_goal.max(1))).expect("a fixed round size is valid")
+}
+
+/// Secure aggregation round expecting `aggregation_goal` clients, surviving the loss of a minority of them
+fn new_secure_aggregation_round(aggregation_goal: usize, threshold: usize) -> SecureAggregationServer {
+    let expected_clients = aggregation_goal.max(1);
+    SecureAggregationServer::new(expected_clients, threshold.clamp(1, expected_clients)).unwrap()
+}
+
+/// Decodes the selected client updates, aggregates them and installs the new global weights
+fn aggregate_client_updates(data: &AppState, selected_clients: &[&WeightsUpdate], round_id: u64) -> Result<usize, String> {
+    let current_version = *data.current_model_version.lock().unwrap();
+    let updates = selected_clients
+        .iter()
+        .map(|client| {
+            let staleness = current_version.saturating_sub(client.model_version);
+            decode_state_dict(&client.model_weights)
+                .map(|weights| ClientUpdate::stale(weights, client.num_samples, staleness, &data.staleness_function))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let clients = selected_clients.iter().map(|client| (client.num_samples, client.loss)).collect::<Vec<_>>();
+    install_global_model(data, &updates, RoundMetrics::from_clients(Some(round_id), &clients))
+}
+
+/// Aggregates decoded client updates with the configured strategy, installs the new global weights and
+/// publishes them as the next version
+fn install_global_model(data: &AppState, updates: &[ClientUpdate], metrics: RoundMetrics) -> Result<usize, String> {
+    check_privacy_budget(data)?;
+    let mut global_var_store = data.global_var_store.lock().unwrap();
+    let global_weights = state_dict_from_var_store(&global_var_store)?;
+    let mut aggregator = data.aggregator.lock().unwrap();
+    let aggregated_weights = aggregator.aggregate(&global_weights, updates)?;
+    spend_privacy_budget(data)?;
+
+    // Install the new weights before publishing the new version.
+    load_state_dict(&mut global_var_store, &aggregated_weights)?;
+    info!("Aggregation with {} is successful!", aggregator.name());
+    let mut current_version = data.current_model_version.lock().unwrap();
+    *current_version += 1;
+    record_model(data, *current_version, state_dict_from_var_store(&global_var_store)?, aggregator.state(), metrics)?;
+
+    let next_key = data.key_store.lock().unwrap().rotate();
+    info!("Server key rotated, Round: {}", next_key.round);
+
+    Ok(*current_version)
+}
+
+/// Refuses an aggregation before it changes any state when its release would exceed the privacy budget
+fn check_privacy_budget(data: &AppState) -> Result<(), String> {
+    if !data.can_aggregate() {
+        return Err("Privacy budget exhausted: no further global model can be released".to_string());
+    }
+    Ok(())
+}
+
+/// Accounts for the release of an aggregated global model, once it is computed and before it is installed
+fn spend_privacy_budget(data: &AppState) -> Result<(), String> {
+    if let Some(mechanism) = &data.round_mechanism {
+        let epsilon = data.privacy_accountant.lock().unwrap().spend(mechanism)?;
+        info!("Privacy spent by the global model: epsilon = {:.3}", epsilon);
+    }
+    Ok(())
+}
+
+/// Adds a published global model to the model registry
+fn record_model(data: &AppState, version: usize, state_dict: StateDict, aggregator: Option<serde_json::Value>, metrics: RoundMetrics) -> Result<(), String> {
+    data.models.lock().unwrap().record(ModelRecord { version, state_dict, aggregator, metrics, published_at: unix_time() })
+}
+
+#[get("/public_key", wrap = "from_fn(authenticate)")]
+/// Serves the public key clients derive their session keys from
+pub async fn get_public_key(data: web::Data<AppState>) -> impl Responder {
+    let key_store = data.key_store.lock().unwrap();
+    match key_store.public_key(key_store.current_round()) {
+        Some(server_key) => HttpResponse::Ok().json(server_key),
+        None => HttpResponse::InternalServerError().json(serde_json::json!({ "message": "No server key available" })),
+    }
+}
+
+/// Replaces session-key encrypted weights with their plaintext encoding
+fn decrypt_weights_update(data: &AppState, update: &mut WeightsUpdate) -> Result<(), String> {
+    let header = match update.encryption.take() {
+        Some(header) => header,
+        None => return Ok(()),
+    };
+    let session_key = data.key_store.lock().unwrap().session_key(&header)?;
+    for weights in update.model_weights.iter_mut() {
+        let plaintext = session_key.decrypt(weights)?;
+        *weights = String::from_utf8(plaintext).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Replaces the Shamir shares of a secret-shared update with the named tensors they recover
+fn reconstruct_weights_update(update: &mut WeightsUpdate) -> Result<(), String> {
+    let threshold = match update.share_threshold.take() {
+        Some(threshold) => threshold,
+        None => return Ok(()),
+    };
+    let shared = update
+        .model_weights
+        .iter()
+        .map(|payload| serde_json::from_str::<SharedTensor>(payload).map_err(|e| format!("Invalid share payload: {}", e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    update.model_weights = reconstruct_state_dict(&shared, threshold)?
+        .iter()
+        .map(encode_named_tensor)
+        .collect::<Result<_, _>>()?;
+    Ok(())
+}
+
 //Implemented by Sai Pranavi Reddy Patlolla
-#[post("/update_model")]
+#[post("/update_model", wrap = "from_fn(authenticate)")]
 /// Updates the global model each time client sends the updated version of weights
-pub async fn update_model(update: web::Json<WeightsUpdate>, data: web::Data<AppState>) -> impl Responder {
+pub async fn update_model(
+    update: web::Json<WeightsUpdate>,
+    client: Option<web::ReqData<AuthenticatedClient>>,
+    data: web::Data<AppState>,
+) -> impl Responder {
     info!("Received model update from client with loss: {}",update.loss);
+    if !data.can_aggregate() {
+        return HttpResponse::Forbidden().json(serde_json::json!({ "message": "Privacy budget exhausted" }));
+    }
+    if let Some(conflict) = check_model_version(&data, update.model_version) {
+        return conflict;
+    }
+    let mut update = update.into_inner();
+    // Authenticated clients cannot submit under another identity
+    if let Some(client) = client {
+        update.client_id = Some(client.into_inner().0);
+    }
+    if let Err(e) = decrypt_weights_update(&data, &mut update).and_then(|_| reconstruct_weights_update(&mut update)) {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "message": e }));
+    }
 
-    let mut client_updates = data.client_updates.lock().unwrap();
-    client_updates.push(update.into_inner());
-
-    if client_updates.len() >= data.aggregation_goal {
-        let selected_clients = client_updates.split_off(0); // Select clients for aggregation
-        let encrypted_weights_list = selected_clients
-            .iter()
-            .map(|client| client.model_weights.clone())
-            .collect::<Vec<_>>();
+    // Reject payloads that cannot be decoded into the global model before they reach the aggregation.
+    let decoded = decode_state_dict(&update.model_weights)
+        .and_then(|state_dict| validate_state_dict(&data.global_var_store.lock().unwrap(), &state_dict));
+    if let Err(e) = decoded {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "message": e }));
+    }
 
-        let aggregated_encrypted_weights = fed_avg_encrypted(encrypted_weights_list);
-        info!("Aggregation is successful!");
+    if let Some(asynchronous) = data.asynchronous.lock().unwrap().as_mut() {
+        return match apply_async_update(&data, asynchronous, &update) {
+            Ok(Some(version)) => HttpResponse::Ok().json(serde_json::json!({
+                "message": "Global model updated",
+                "model_version": version
+            })),
+            Ok(None) => HttpResponse::Ok().json(serde_json::json!({
+                "message": format!(
+                    "Update buffered. Received {}/{} updates",
+                    asynchronous.buffered(),
+                    asynchronous.strategy.buffer_size()
+                )
+            })),
+            Err(e) => HttpResponse::Conflict().json(serde_json::json!({ "message": e })),
+        };
+    }
 
-        let mut current_version = data.current_model_version.lock().unwrap();
-        info!("Global model updated, Version: {}",current_version);
-        *current_version += 1;
+    let mut rounds = data.rounds.lock().unwrap();
+    let (client_id, round_id) = (update.client_id.clone(), update.round_id);
+    let submitted = rounds.submit(client_id, round_id, update, Instant::now());
+    // A passed training deadline may have made the round ready even if this update came too late
+    let aggregated = aggregate_ready_round(&data, &mut rounds);
+    if let Err(e) = submitted {
+        return HttpResponse::Conflict().json(serde_json::json!({ "message": e, "round_id": rounds.round_id() }));
+    }
 
+    if let Some(aggregated) = aggregated {
+        let (round_id, model_version) = match aggregated {
+            Ok(aggregated) => aggregated,
+            Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({ "message": e })),
+        };
         HttpResponse::Ok().json(serde_json::json!({
-            "message": "Global model updated with encrypted weights",
-            "encrypted_model_weights": aggregated_encrypted_weights,
-            "model_version": *current_version
+            "message": "Global model updated",
+            "model_version": model_version,
+            "round_id": round_id
         }))
     } else {
+        let status = rounds.status(Instant::now());
         HttpResponse::Ok().json(serde_json::json!({
             "message": format!(
                 "Waiting for more client updates. Received {}/{} updates",
-                client_updates.len(),
+                status.updates,
+                status.participants.len().max(status.min_participants)
+            ),
+            "round_id": status.round_id
+        }))
+    }
+}
+
+/// Aggregates the current round if it is ready, returning its id and the new model version
+fn aggregate_ready_round(data: &AppState, rounds: &mut RoundManager<WeightsUpdate>) -> Option<Result<(u64, usize), String>> {
+    if !rounds.ready_to_aggregate() {
+        return None;
+    }
+    let round_id = rounds.round_id();
+    // The updates stay in the round until the aggregation succeeded, a failed one is retried on the next request
+    let model_version = match aggregate_client_updates(data, &rounds.updates(), round_id) {
+        Ok(model_version) => model_version,
+        Err(e) => return Some(Err(e)),
+    };
+    if let Err(e) = rounds.finish() {
+        return Some(Err(e));
+    }
+    info!("Global model updated with round {}, Version: {}", round_id, model_version);
+    Some(Ok((round_id, model_version)))
+}
+
+/// Adds an update to the asynchronous aggregation and publishes the next version when the strategy produced one
+fn apply_async_update(data: &AppState, asynchronous: &mut AsyncAggregator, update: &WeightsUpdate) -> Result<Option<usize>, String> {
+    let local = decode_state_dict(&update.model_weights)?;
+    // Same lock order as `get_model`
+    let mut global_var_store = data.global_var_store.lock().unwrap();
+    let mut current_version = data.current_model_version.lock().unwrap();
+    let global = state_dict_from_var_store(&global_var_store)?;
+    // Buffered updates would never be released either
+    check_privacy_budget(data)?;
+    let next = match asynchronous.add_update(&global, *current_version, update.model_version, &local)? {
+        Some(next) => next,
+        None => return Ok(None),
+    };
+
+    spend_privacy_budget(data)?;
+    load_state_dict(&mut global_var_store, &next)?;
+    *current_version += 1;
+    let metrics = match asynchronous.strategy {
+        AsyncStrategy::FedAsync { .. } => RoundMetrics::from_clients(None, &[(update.num_samples, update.loss)]),
+        AsyncStrategy::FedBuff { buffer_size, .. } => RoundMetrics { num_clients: Some(buffer_size), ..Default::default() },
+    };
+    record_model(data, *current_version, state_dict_from_var_store(&global_var_store)?, None, metrics)?;
+    data.key_store.lock().unwrap().rotate();
+    info!("Global model updated asynchronously with {:?}, Version: {}", asynchronous.strategy, current_version);
+    Ok(Some(*current_version))
+}
+
+/// Conflict response for an update trained on a global model the server no longer accepts
+fn check_model_version(data: &AppState, model_version: usize) -> Option<HttpResponse> {
+    let current_version = *data.current_model_version.lock().unwrap();
+    if model_version <= current_version && current_version - model_version <= data.max_staleness {
+        return None;
+    }
+    info!("Refused update for model version {}, current version {}", model_version, current_version);
+    Some(HttpResponse::Conflict().json(serde_json::json!({
+        "message": format!(
+            "Version mismatch: update trained on version {} but the global model is at version {} (tolerated staleness {})",
+            model_version, current_version, data.max_staleness
+        ),
+        "model_version": model_version,
+        "current_model_version": current_version,
+        "max_staleness": data.max_staleness
+    })))
+}
+
+#[post("/check_in", wrap = "from_fn(authenticate)")]
+/// Registers a client for the current round and tells it whether it was selected
+pub async fn check_in(
+    check_in: web::Json<CheckIn>,
+    client: Option<web::ReqData<AuthenticatedClient>>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Asynchronous aggregation takes updates from every client
+    if data.asynchronous.lock().unwrap().is_some() {
+        return HttpResponse::Ok().json(serde_json::json!({
+            "status": CheckInStatus::Selected,
+            "model_version": *data.current_model_version.lock().unwrap()
+        }));
+    }
+    let mut rounds = data.rounds.lock().unwrap();
+    let now = Instant::now();
+    rounds.tick(now);
+    if let Some(Err(e)) = aggregate_ready_round(&data, &mut rounds) {
+        error!("Cannot aggregate round {}: {}", rounds.round_id(), e);
+    }
+    let mut check_in = check_in.into_inner();
+    if let Some(client) = client {
+        check_in.client_id = client.into_inner().0;
+    }
+    let status = rounds.check_in(check_in, now);
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": status,
+        "round_id": rounds.round_id(),
+        "model_version": *data.current_model_version.lock().unwrap(),
+        "retry_after_secs": rounds.status(now).deadline_in_secs
+    }))
+}
+
+#[get("/round", wrap = "from_fn(authenticate)")]
+/// Serves the phase, participants and deadline of the current round
+pub async fn get_round(data: web::Data<AppState>) -> impl Responder {
+    let mut rounds = data.rounds.lock().unwrap();
+    let now = Instant::now();
+    rounds.tick(now);
+    // Rounds whose training deadline passed are aggregated here when no more update arrives
+    if let Some(Err(e)) = aggregate_ready_round(&data, &mut rounds) {
+        error!("Cannot aggregate round {}: {}", rounds.round_id(), e);
+    }
+    HttpResponse::Ok().json(rounds.status(now))
+}
+
+/// Installs the result of a finished secure aggregation round and starts the next round. A round whose result
+/// cannot be installed is kept, without spending privacy, until `/secure_aggregation/advance` retries it.
+fn finish_secure_aggregation(data: &AppState, secure_aggregation: &mut SecureAggregationServer) -> Result<Option<usize>, String> {
+    let aggregate = match secure_aggregation.aggregate() {
+        Some(aggregate) => aggregate.clone(),
+        None => return Ok(None),
+    };
+    let survivors = secure_aggregation.survivors().ok().map(|survivors| survivors.len());
+
+    let layout = state_dict_from_var_store(&data.global_var_store.lock().unwrap())?;
+    let (average, total_samples, metrics, round_rdp) = match &data.distributed_dp {
+        Some(mechanism) => {
+            let (average, num_clients) = mechanism.decode_average(&layout, &aggregate)?;
+            let accountant = data.privacy_accountant.lock().unwrap();
+            let rdp = mechanism.round_rdp(&accountant.orders, num_clients);
+            if accountant.epsilon_after_rdp(&rdp) > accountant.epsilon_budget {
+                return Err("Privacy budget exhausted: no further global model can be released".to_string());
+            }
+            let metrics = RoundMetrics { num_clients: Some(num_clients), ..Default::default() };
+            (average, num_clients, metrics, Some((rdp, num_clients)))
+        }
+        None => {
+            let (average, total_samples) = decode_weighted_average(&layout, &aggregate)?;
+            let metrics = RoundMetrics { num_clients: survivors, num_samples: Some(total_samples), ..Default::default() };
+            (average, total_samples, metrics, None)
+        }
+    };
+    let model_version = install_global_model(data, &[ClientUpdate::new(average, total_samples)], metrics)?;
+    *secure_aggregation = new_secure_aggregation_round(data.aggregation_goal, data.secure_aggregation_threshold);
+    if let Some((rdp, num_clients)) = round_rdp {
+        let epsilon = data.privacy_accountant.lock().unwrap().spend_rdp(&rdp)?;
+        info!("Distributed DP round with {} clients, epsilon spent: {:.3}", num_clients, epsilon);
+    }
+    info!("Global model updated with secure aggregation, Version: {}", model_version);
+    Ok(Some(model_version))
+}
+
+/// Refuses masked inputs when the round could not be released within the privacy budget
+fn check_secure_aggregation_budget(data: &AppState, threshold: usize) -> Result<(), String> {
+    check_privacy_budget(data)?;
+    if let Some(mechanism) = &data.distributed_dp {
+        let accountant = data.privacy_accountant.lock().unwrap();
+        // The fewest clients that can finish the round add the least noise
+        let rdp = mechanism.round_rdp(&accountant.orders, threshold);
+        if accountant.epsilon_after_rdp(&rdp) > accountant.epsilon_budget {
+            return Err("Privacy budget exhausted: no further global model can be released".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Response of the secure aggregation endpoints that may finish the round
+fn secure_aggregation_response(data: &AppState, secure_aggregation: &mut SecureAggregationServer) -> HttpResponse {
+    match finish_secure_aggregation(data, secure_aggregation) {
+        Ok(Some(model_version)) => HttpResponse::Ok().json(serde_json::json!({
+            "message": "Global model updated",
+            "model_version": model_version
+        })),
+        Ok(None) => HttpResponse::Ok().json(serde_json::json!({ "phase": secure_aggregation.phase() })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({ "message": e })),
+    }
+}
+
+#[get("/secure_aggregation/status", wrap = "from_fn(authenticate)")]
+/// Current phase of the secure aggregation round
+pub async fn secure_aggregation_status(data: web::Data<AppState>) -> impl Responder {
+    let secure_aggregation = data.secure_aggregation.lock().unwrap();
+    HttpResponse::Ok().json(serde_json::json!({
+        "phase": secure_aggregation.phase(),
+        "threshold": secure_aggregation.threshold,
+        "distributed_dp": data.distributed_dp,
+        "model_version": *data.current_model_version.lock().unwrap()
+    }))
+}
+
+#[post("/secure_aggregation/advertise_keys", wrap = "from_fn(authenticate)")]
+/// Round 0: a client advertises its public keys
+pub async fn advertise_keys(keys: web::Json<AdvertisedKeys>, data: web::Data<AppState>) -> impl Responder {
+    let mut secure_aggregation = data.secure_aggregation.lock().unwrap();
+    match secure_aggregation.advertise_keys(keys.into_inner()) {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "phase": secure_aggregation.phase() })),
+        Err(e) => HttpResponse::BadRequest().json(serde_json::json!({ "message": e })),
+    }
+}
+
+#[get("/secure_aggregation/roster", wrap = "from_fn(authenticate)")]
+/// Public keys of every client of the round, once all keys are advertised
+pub async fn get_roster(data: web::Data<AppState>) -> impl Responder {
+    match data.secure_aggregation.lock().unwrap().roster() {
+        Ok(roster) => HttpResponse::Ok().json(serde_json::json!({ "roster": roster })),
+        Err(e) => HttpResponse::Conflict().json(serde_json::json!({ "message": e })),
+    }
+}
+
+#[post("/secure_aggregation/share_keys", wrap = "from_fn(authenticate)")]
+/// Round 1: a client uploads its shares encrypted for the other clients
+pub async fn share_keys(message: web::Json<ShareKeysMessage>, data: web::Data<AppState>) -> impl Responder {
+    let mut secure_aggregation = data.secure_aggregation.lock().unwrap();
+    match secure_aggregation.share_keys(message.into_inner()) {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "phase": secure_aggregation.phase() })),
+        Err(e) => HttpResponse::BadRequest().json(serde_json::json!({ "message": e })),
+    }
+}
+
+#[get("/secure_aggregation/shares/{client_id}", wrap = "from_fn(authenticate)")]
+/// Shares addressed to a client, once all shares are uploaded
+pub async fn get_shares(client_id: web::Path<u64>, data: web::Data<AppState>) -> impl Responder {
+    match data.secure_aggregation.lock().unwrap().shares_for(client_id.into_inner()) {
+        Ok((share_senders, encrypted_shares)) => HttpResponse::Ok().json(serde_json::json!({
+            "share_senders": share_senders,
+            "encrypted_shares": encrypted_shares
+        })),
+        Err(e) => HttpResponse::Conflict().json(serde_json::json!({ "message": e })),
+    }
+}
+
+#[post("/secure_aggregation/masked_input", wrap = "from_fn(authenticate)")]
+/// Round 2: a client uploads its masked and sample-weighted model
+pub async fn submit_masked_input(message: web::Json<MaskedInput>, data: web::Data<AppState>) -> impl Responder {
+    let expected_len = match weighted_input_len(&data) {
+        Ok(expected_len) => expected_len,
+        Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({ "message": e })),
+    };
+    if message.masked_input.len() != expected_len {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "message": format!("Expected {} masked values, got {}", expected_len, message.masked_input.len())
+        }));
+    }
+
+    let mut secure_aggregation = data.secure_aggregation.lock().unwrap();
+    if let Err(e) = check_secure_aggregation_budget(&data, secure_aggregation.threshold) {
+        return HttpResponse::Forbidden().json(serde_json::json!({ "message": e }));
+    }
+    match secure_aggregation.submit_masked_input(message.into_inner()) {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "phase": secure_aggregation.phase() })),
+        Err(e) => HttpResponse::BadRequest().json(serde_json::json!({ "message": e })),
+    }
+}
+
+#[get("/secure_aggregation/survivors", wrap = "from_fn(authenticate)")]
+/// Clients whose masked model is part of the sum, once all masked models are uploaded
+pub async fn get_survivors(data: web::Data<AppState>) -> impl Responder {
+    match data.secure_aggregation.lock().unwrap().survivors() {
+        Ok(survivors) => HttpResponse::Ok().json(serde_json::json!({ "survivors": survivors })),
+        Err(e) => HttpResponse::Conflict().json(serde_json::json!({ "message": e })),
+    }
+}
+
+#[post("/secure_aggregation/unmask", wrap = "from_fn(authenticate)")]
+/// Round 3: a client uploads the shares needed to remove the masks.
+/// The last expected message unmasks the sum and updates the global model.
+pub async fn unmask(message: web::Json<UnmaskMessage>, data: web::Data<AppState>) -> impl Responder {
+    let mut secure_aggregation = data.secure_aggregation.lock().unwrap();
+    if let Err(e) = secure_aggregation.unmask(message.into_inner()) {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "message": e }));
+    }
+    secure_aggregation_response(&data, &mut secure_aggregation)
+}
+
+#[post("/secure_aggregation/advance")]
+/// Closes the current phase without waiting for the clients that dropped out, for the operator since it drops
+/// every client that did not answer yet. Retries installing a finished round whose installation failed.
+pub async fn advance_secure_aggregation(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if !is_admin(&req, &data) {
+        return HttpResponse::Forbidden().json(serde_json::json!({ "message": "Advancing secure aggregation requires the admin token" }));
+    }
+    let mut secure_aggregation = data.secure_aggregation.lock().unwrap();
+    if secure_aggregation.phase() != SecAggPhase::Finished {
+        if let Err(e) = secure_aggregation.advance() {
+            return HttpResponse::Conflict().json(serde_json::json!({ "message": e }));
+        }
+    }
+    secure_aggregation_response(&data, &mut secure_aggregation)
+}
+
+/// Number of values of the global model, plus one for the number of samples
+fn weighted_input_len(data: &AppState) -> Result<usize, String> {
+    Ok(data.num_parameters()? + 1)
+}
+
+fn paillier_disabled() -> HttpResponse {
+    HttpResponse::NotFound().json(serde_json::json!({ "message": "Paillier aggregation is not enabled" }))
+}
+
+#[get("/paillier/public_key", wrap = "from_fn(authenticate)")]
+/// Public key clients encrypt their weighted models with
+pub async fn paillier_public_key(data: web::Data<AppState>) -> impl Responder {
+    match data.paillier.lock().unwrap().as_ref() {
+        Some(paillier) => HttpResponse::Ok().json(serde_json::json!({
+            "public_key": paillier.public_key,
+            "key_holders": paillier.key_holders,
+            "model_version": *data.current_model_version.lock().unwrap()
+        })),
+        None => paillier_disabled(),
+    }
+}
+
+#[post("/paillier/update_model", wrap = "from_fn(authenticate)")]
+/// Adds an encrypted weighted model to the running sum, `model_weights` holds the ciphertexts.
+/// The sum is handed to the key holders once the aggregation goal is reached.
+pub async fn paillier_update_model(update: web::Json<WeightsUpdate>, data: web::Data<AppState>) -> impl Responder {
+    let mut paillier = data.paillier.lock().unwrap();
+    let paillier = match paillier.as_mut() {
+        Some(paillier) => paillier,
+        None => return paillier_disabled(),
+    };
+    info!("Received encrypted model update from client with loss: {}", update.loss);
+
+    let expected_len = match (weighted_input_len(&data), paillier.public_key.slots_per_ciphertext()) {
+        (Ok(num_values), Ok(slots)) => num_values.div_ceil(slots),
+        (Err(e), _) | (_, Err(e)) => return HttpResponse::InternalServerError().json(serde_json::json!({ "message": e })),
+    };
+    if update.model_weights.len() != expected_len {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "message": format!("Expected {} ciphertexts, got {}", expected_len, update.model_weights.len())
+        }));
+    }
+    if let Err(e) = paillier.add_update(update.into_inner().model_weights) {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "message": e }));
+    }
+
+    if paillier.num_updates() >= data.aggregation_goal {
+        if let Err(e) = paillier.close_round() {
+            return HttpResponse::Conflict().json(serde_json::json!({ "message": e }));
+        }
+        HttpResponse::Ok().json(serde_json::json!({ "message": "Encrypted aggregate is waiting for decryption" }))
+    } else {
+        HttpResponse::Ok().json(serde_json::json!({
+            "message": format!(
+                "Waiting for more client updates. Received {}/{} updates",
+                paillier.num_updates(),
                 data.aggregation_goal
             )
         }))
     }
 }
 
+#[get("/paillier/aggregate", wrap = "from_fn(authenticate)")]
+/// Encrypted aggregate the key holders have to partially decrypt
+pub async fn paillier_aggregate(data: web::Data<AppState>) -> impl Responder {
+    match data.paillier.lock().unwrap().as_ref() {
+        Some(paillier) => match paillier.pending_aggregate() {
+            Some((aggregate, num_clients)) => HttpResponse::Ok().json(serde_json::json!({
+                "aggregate": aggregate,
+                "num_clients": num_clients
+            })),
+            None => HttpResponse::Conflict().json(serde_json::json!({ "message": "No aggregate is waiting for decryption" })),
+        },
+        None => paillier_disabled(),
+    }
+}
+
+#[post("/paillier/partial_decryption", wrap = "from_fn(authenticate)")]
+/// Stores the partial decryption of a key holder, which must be an authenticated client.
+/// The last one decrypts the aggregate and updates the global model.
+pub async fn paillier_partial_decryption(
+    message: web::Json<PartialDecryption>,
+    client: Option<web::ReqData<AuthenticatedClient>>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Key holder indexes are bound to client ids, which only authentication establishes
+    let client_id = match client {
+        Some(client) => client.into_inner().0,
+        None => {
+            return HttpResponse::Forbidden().json(serde_json::json!({
+                "message": "Partial decryptions are only accepted from authenticated key holders"
+            }))
+        }
+    };
+    let num_values = match weighted_input_len(&data) {
+        Ok(num_values) => num_values,
+        Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({ "message": e })),
+    };
+    let mut paillier = data.paillier.lock().unwrap();
+    let paillier = match paillier.as_mut() {
+        Some(paillier) => paillier,
+        None => return paillier_disabled(),
+    };
+    let message = message.into_inner();
+    let sums = match paillier.add_partial_decryption(message.index, &client_id, message.partial_decryption, num_values) {
+        Ok(Some(sums)) => sums,
+        Ok(None) => return HttpResponse::Ok().json(serde_json::json!({ "message": "Waiting for the other key holders" })),
+        Err(e) => return HttpResponse::BadRequest().json(serde_json::json!({ "message": e })),
+    };
+
+    // Release the global model before `install_global_model` locks it again
+    let layout = state_dict_from_var_store(&data.global_var_store.lock().unwrap());
+    let installed = layout
+        .and_then(|layout| weighted_values_average(&layout, &sums))
+        .and_then(|(average, total_samples)| {
+            let metrics = RoundMetrics { num_samples: Some(total_samples), ..Default::default() };
+            install_global_model(&data, &[ClientUpdate::new(average, total_samples)], metrics)
+        });
+    let model_version = match installed {
+        Ok(model_version) => model_version,
+        Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({ "message": e })),
+    };
+    info!("Global model updated with Paillier aggregation, Version: {}", model_version);
+    HttpResponse::Ok().json(serde_json::json!({
+        "message": "Global model updated",
+        "model_version": model_version
+    }))
+}
+
+/// JSON extractor configuration accepting full model uploads
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().limit(MAX_PAYLOAD_BYTES)
+}
+
+/// Registers every endpoint of the server on an actix-web application
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(json_config())
+        .service(register)
+        .service(get_model)
+        .service(list_models)
+        .service(get_model_version)
+        .service(rollback_model)
+        .service(get_public_key)
+        .service(update_model)
+        .service(get_round)
+        .service(check_in)
+        .service(secure_aggregation_status)
+        .service(advertise_keys)
+        .service(get_roster)
+        .service(share_keys)
+        .service(get_shares)
+        .service(submit_masked_input)
+        .service(get_survivors)
+        .service(unmask)
+        .service(advance_secure_aggregation)
+        .service(paillier_public_key)
+        .service(paillier_update_model)
+        .service(paillier_aggregate)
+        .service(paillier_partial_decryption);
+}
+
+/// Serves every endpoint with `app_state` on `address` until the server is stopped
+pub async fn run_server(app_state: AppState, address: (String, u16)) -> std::io::Result<()> {
+    serve(app_state, address, None).await
+}
+
+/// Serves every endpoint over TLS, only to clients with a certificate of its CA when `tls` has a client CA
+pub async fn run_server_tls(app_state: AppState, address: (String, u16), tls: &ServerTlsConfig) -> std::io::Result<()> {
+    let config = tls
+        .server_config()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    serve(app_state, address, Some(config)).await
+}
+
+async fn serve(app_state: AppState, address: (String, u16), tls: Option<rustls::ServerConfig>) -> std::io::Result<()> {
+    let app_state = web::Data::new(app_state);
+    let checkpoint_interval = app_state.checkpoints.lock().unwrap().as_ref().map(|store| store.config.interval_secs);
+    if let Some(interval_secs) = checkpoint_interval {
+        let app_state = app_state.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(interval_secs));
+            loop {
+                interval.tick().await;
+                if let Err(e) = app_state.save_checkpoint() {
+                    error!("Checkpoint failed: {}", e);
+                }
+            }
+        });
+    }
+    let server_state = app_state.clone();
+    let server = HttpServer::new(move || App::new().app_data(server_state.clone()).configure(configure));
+    let server = match tls {
+        Some(config) => {
+            info!("Server listening on https://{}:{}", address.0, address.1);
+            server.bind_rustls_0_23(address, config)?
+        }
+        None => {
+            info!("Server listening on {}:{}", address.0, address.1);
+            server.bind(address)?
+        }
+    };
+    server.run().await?;
+    // Keep the progress made since the last periodic checkpoint
+    app_state.save_checkpoint().map_err(std::io::Error::other)?;
+    Ok(())
+}
+
 //Tests
 //Unit tests are contributed by Sharvani Chelumalla & Sai Pranavi Reddy Patlolla
 #[cfg(test)]
 mod tests {
     use super::*;
     use actix_web::{test, App};
+    use crate::state_dict::{encode_named_tensor, weights_update, NamedTensor};
     use tch::{Device, nn};
     use serde_json::json;
     use actix_web::http;
@@ -143,7 +1265,10 @@ mod tests {
 
         // Parse the response body as JSON and check the contents
         let response_body: serde_json::Value = test::read_body_json(response).await;
-        assert!(response_body["model_state_dict"].is_string());
+        let state_dict: Vec<NamedTensor> = serde_json::from_value(response_body["model_state_dict"].clone()).unwrap();
+        let names = state_dict.iter().map(|t| t.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["conv1.bias", "conv1.weight", "fc1.bias", "fc1.weight", "fc2.bias", "fc2.weight"]);
+        assert_eq!(state_dict[3].shape, vec![128, 32 * 13 * 13]);
         assert!(response_body["model_version"].is_u64());
     }
 
@@ -154,16 +1279,14 @@ mod tests {
         let mut app = test::init_service(
             App::new()
                 .app_data(app_state.clone())
+                .app_data(json_config())
                 .service(update_model)
         ).await;
 
-        // Create a sample WeightsUpdate
-        let weights_update = WeightsUpdate {
-            model_weights: vec!["weight1".to_string(), "weight2".to_string()],
-            num_samples: 100,
-            loss: 0.25,
-            model_version: 1,
-        };
+        // Create a sample WeightsUpdate holding a shifted copy of the global weights
+        let mut client_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        client_weights.iter_mut().for_each(|t| t.data.iter_mut().for_each(|v| *v += 1.0));
+        let weights_update = weights_update(&client_weights, 0);
 
         // Send a POST request to the '/update_model' endpoint with the WeightsUpdate
         let req = test::TestRequest::post()
@@ -177,8 +1300,784 @@ mod tests {
 
         // Parse the response body as JSON and check the contents
         let response_body: serde_json::Value = test::read_body_json(response).await;
-        assert_eq!(response_body["message"], "Global model updated with encrypted weights");
-        assert!(response_body["encrypted_model_weights"].is_array());
-        assert!(response_body["model_version"].is_u64());
+        assert_eq!(response_body["message"], "Global model updated");
+        assert_eq!(response_body["model_version"], 1);
+
+        // The global model now holds the weights of the only client
+        let global_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        for (global, client) in global_weights.iter().zip(client_weights.iter()) {
+            for (g, c) in global.data.iter().zip(client.data.iter()) {
+                assert!((g - c).abs() < 1e-5);
+            }
+        }
+    }
+
+    // Test that undecodable weights are rejected without bumping the version
+    #[tokio::test]
+    async fn test_update_model_invalid_weights() {
+        let app_state = web::Data::new(AppState::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .app_data(json_config())
+                .service(update_model)
+        ).await;
+
+        let weights_update = WeightsUpdate {
+            model_weights: vec!["weight1".to_string(), "weight2".to_string()],
+            ..weights_update(&Vec::new(), 0)
+        };
+        let req = test::TestRequest::post()
+            .uri("/update_model")
+            .set_json(&weights_update)
+            .to_request();
+        let response = test::call_service(&app, req).await;
+
+        assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+        assert_eq!(*app_state.current_model_version.lock().unwrap(), 0);
+    }
+
+    // Test a secure aggregation round over HTTP where one of three clients drops before masking
+    #[tokio::test]
+    async fn test_secure_aggregation_round() {
+        use crate::secure_aggregation::{encode_weighted_input, SecureAggregationClient};
+
+        let app_state = web::Data::new(AppState::new(3, Box::new(FedAvg)).with_admin_token("admin-secret".to_string()));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(get_roster)
+                .service(advertise_keys)
+                .service(share_keys)
+                .service(get_shares)
+                .app_data(json_config())
+                .service(submit_masked_input)
+                .service(get_survivors)
+                .service(unmask)
+                .service(advance_secure_aggregation)
+        ).await;
+
+        let layout = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        let mut clients = (1..=3).map(|id| SecureAggregationClient::new(id, 2).unwrap()).collect::<Vec<_>>();
+        let inputs = [(1.0, 10), (4.0, 30), (100.0, 50)]
+            .iter()
+            .map(|&(value, num_samples)| {
+                let mut weights = layout.clone();
+                weights.iter_mut().for_each(|t| t.data.fill(value));
+                encode_weighted_input(&weights, num_samples)
+            })
+            .collect::<Vec<_>>();
+
+        for client in &clients {
+            let req = test::TestRequest::post().uri("/secure_aggregation/advertise_keys").set_json(client.advertise_keys()).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        }
+        let req = test::TestRequest::get().uri("/secure_aggregation/roster").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let roster: Vec<AdvertisedKeys> = serde_json::from_value(body["roster"].clone()).unwrap();
+
+        for client in clients.iter_mut() {
+            let message = client.share_keys(&roster).unwrap();
+            let req = test::TestRequest::post().uri("/secure_aggregation/share_keys").set_json(&message).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        }
+
+        // The third client drops out before sending its masked model
+        for (client, input) in clients.iter_mut().zip(inputs.iter()).take(2) {
+            let req = test::TestRequest::get().uri(&format!("/secure_aggregation/shares/{}", client.client_id)).to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            let share_senders: Vec<u64> = serde_json::from_value(body["share_senders"].clone()).unwrap();
+            let encrypted_shares = serde_json::from_value::<Vec<_>>(body["encrypted_shares"].clone()).unwrap();
+            let message = client.mask_input(input, &share_senders, &encrypted_shares).unwrap();
+            let req = test::TestRequest::post().uri("/secure_aggregation/masked_input").set_json(&message).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        }
+        // Only the operator may drop the clients that did not answer
+        let req = test::TestRequest::post().uri("/secure_aggregation/advance").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::FORBIDDEN);
+        let req = test::TestRequest::post()
+            .uri("/secure_aggregation/advance")
+            .insert_header(("Authorization", "Bearer admin-secret"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/secure_aggregation/survivors").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let survivors: Vec<u64> = serde_json::from_value(body["survivors"].clone()).unwrap();
+        assert_eq!(survivors, vec![1, 2]);
+
+        let mut last_body = json!(null);
+        for client in clients.iter().take(2) {
+            let req = test::TestRequest::post().uri("/secure_aggregation/unmask").set_json(client.unmask(&survivors).unwrap()).to_request();
+            last_body = test::call_and_read_body_json(&app, req).await;
+        }
+        assert_eq!(last_body["model_version"], 1);
+
+        // Sample-weighted average of the two survivors: (1 * 10 + 4 * 30) / 40
+        let global_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        assert!(global_weights.iter().flat_map(|t| t.data.iter()).all(|v| (v - 3.25).abs() < 1e-5));
+    }
+
+    // Test that the Paillier endpoints reject malformed ciphertexts and early decryptions
+    #[tokio::test]
+    async fn test_paillier_endpoints() {
+        use crate::paillier::generate_keypair;
+
+        let disabled = test::init_service(
+            App::new().app_data(web::Data::new(AppState::default())).service(paillier_public_key)
+        ).await;
+        let req = test::TestRequest::get().uri("/paillier/public_key").to_request();
+        assert_eq!(test::call_service(&disabled, req).await.status(), http::StatusCode::NOT_FOUND);
+
+        let (public_key, _) = generate_keypair(512).unwrap();
+        let app_state = web::Data::new(AppState::new(2, Box::new(FedAvg)).with_paillier(public_key.clone(), 2));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(paillier_public_key)
+                .service(paillier_update_model)
+                .service(paillier_aggregate)
+                .service(paillier_partial_decryption)
+        ).await;
+
+        let req = test::TestRequest::get().uri("/paillier/public_key").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(serde_json::from_value::<PaillierPublicKey>(body["public_key"].clone()).unwrap(), public_key);
+
+        let weights_update = WeightsUpdate {
+            model_weights: public_key.encrypt_values(&[1.0, 2.0]).unwrap(),
+            ..weights_update(&Vec::new(), 0)
+        };
+        let req = test::TestRequest::post().uri("/paillier/update_model").set_json(&weights_update).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get().uri("/paillier/aggregate").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::CONFLICT);
+
+        // Key holders must be authenticated
+        let partial = PartialDecryption { index: 0, partial_decryption: vec!["1".to_string()] };
+        let req = test::TestRequest::post().uri("/paillier/partial_decryption").set_json(&partial).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::FORBIDDEN);
+        assert_eq!(*app_state.current_model_version.lock().unwrap(), 0);
+    }
+
+    // Test that the global model is updated once every key holder partially decrypted the aggregate
+    #[actix_web::test]
+    async fn test_paillier_round() {
+        use crate::paillier::{generate_keypair, weighted_values};
+
+        let (public_key, private_key) = generate_keypair(512).unwrap();
+        let app_state = AppState::new(2, Box::new(FedAvg))
+            .with_paillier(public_key.clone(), 2)
+            .with_authentication("secret".to_string());
+        let authorizations = (0..2)
+            .map(|_| {
+                let request = RegistrationRequest { public_key: None, registration_secret: Some("secret".to_string()) };
+                let token = app_state.clients.lock().unwrap().register(&request).unwrap().token.unwrap();
+                ("authorization", format!("Bearer {}", token))
+            })
+            .collect::<Vec<_>>();
+        // A small model keeps the encryption fast
+        let vs = nn::VarStore::new(Device::Cpu);
+        let _ = vs.root().var("w", &[3], nn::Init::Const(0.0));
+        *app_state.global_var_store.lock().unwrap() = vs;
+        let app_state = web::Data::new(app_state);
+        let app = test::init_service(App::new().app_data(app_state.clone()).configure(configure)).await;
+
+        let layout = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        for (value, num_samples) in [(1.0, 10), (4.0, 30)] {
+            let mut weights = layout.clone();
+            weights[0].data = vec![value; 3];
+            let weights_update = WeightsUpdate {
+                model_weights: public_key.encrypt_values(&weighted_values(&weights, num_samples)).unwrap(),
+                num_samples,
+                ..weights_update(&Vec::new(), 0)
+            };
+            let req = test::TestRequest::post()
+                .uri("/paillier/update_model")
+                .insert_header(authorizations[0].clone())
+                .set_json(&weights_update)
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        }
+
+        let req = test::TestRequest::get().uri("/paillier/aggregate").insert_header(authorizations[0].clone()).to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let aggregate: Vec<String> = serde_json::from_value(body["aggregate"].clone()).unwrap();
+        let shares = private_key.split(2).unwrap();
+        let partials = shares
+            .iter()
+            .enumerate()
+            .map(|(index, share)| PartialDecryption { index, partial_decryption: share.partial_decrypt(&public_key, &aggregate).unwrap() })
+            .collect::<Vec<_>>();
+        let partial_decryption = |partial: &PartialDecryption, authorization: &(&'static str, String)| {
+            test::TestRequest::post()
+                .uri("/paillier/partial_decryption")
+                .insert_header(authorization.clone())
+                .set_json(partial)
+                .to_request()
+        };
+        let body: serde_json::Value = test::call_and_read_body_json(&app, partial_decryption(&partials[0], &authorizations[0])).await;
+        assert_eq!(body["message"], "Waiting for the other key holders");
+        // The first key holder cannot send its partial decryption again nor the one of the other index
+        for partial in &partials {
+            let req = partial_decryption(partial, &authorizations[0]);
+            assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::BAD_REQUEST);
+        }
+        let body: serde_json::Value = test::call_and_read_body_json(&app, partial_decryption(&partials[1], &authorizations[1])).await;
+        assert_eq!(body["model_version"], 1);
+
+        let global_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        for v in &global_weights[0].data {
+            assert!((v - 3.25).abs() < 1e-5);
+        }
+    }
+
+    // Test that an update encrypted with a session key is decrypted and rotates the server key
+    #[tokio::test]
+    async fn test_update_model_encrypted() {
+        use crate::key_management::{client_session_key, ServerPublicKey};
+
+        let app_state = web::Data::new(AppState::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(get_public_key)
+                .app_data(json_config())
+                .service(update_model)
+        ).await;
+
+        let req = test::TestRequest::get().uri("/public_key").to_request();
+        let server_key: ServerPublicKey = test::call_and_read_body_json(&app, req).await;
+        let (header, session_key) = client_session_key(&server_key).unwrap();
+
+        let client_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        let mut weights_update = WeightsUpdate {
+            model_weights: client_weights
+                .iter()
+                .map(|t| session_key.encrypt(encode_named_tensor(t).unwrap().as_bytes()))
+                .collect(),
+            ..weights_update(&client_weights, 0)
+        };
+
+        // Without the header the server cannot decode the ciphertexts
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::BAD_REQUEST);
+
+        weights_update.encryption = Some(header);
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        let response_body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(response_body["model_version"], 1);
+
+        let req = test::TestRequest::get().uri("/public_key").to_request();
+        let next_key: ServerPublicKey = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(next_key.round, server_key.round + 1);
+        assert_ne!(next_key.public_key, server_key.public_key);
     }
-}
\ No newline at end of file
+
+    // Test that a secret-shared update is recovered from the shares it holds
+    #[actix_web::test]
+    async fn test_update_model_secret_shared() {
+        use crate::secure_dp_utils::share_state_dict;
+
+        let app_state = web::Data::new(AppState::default());
+        let app = test::init_service(App::new().app_data(app_state.clone()).configure(configure)).await;
+
+        let mut client_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        client_weights.iter_mut().for_each(|t| t.data.fill(0.5));
+        // One share of every tensor is lost, two of three are enough
+        let shares = share_state_dict(&client_weights, 3, 2).unwrap();
+        let mut weights_update = WeightsUpdate {
+            model_weights: shares
+                .iter()
+                .filter(|shared| shared.share.index != 3)
+                .map(|shared| serde_json::to_string(shared).unwrap())
+                .collect(),
+            share_threshold: Some(3),
+            ..weights_update(&client_weights, 0)
+        };
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::BAD_REQUEST);
+
+        weights_update.share_threshold = Some(2);
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        let response_body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(response_body["model_version"], 1);
+        assert_eq!(state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap(), client_weights);
+    }
+
+    // Test that the server stops aggregating once its privacy budget is exhausted
+    #[tokio::test]
+    async fn test_update_model_privacy_budget() {
+        let accountant = PrivacyAccountant::new(5.0, 1e-5);
+        let app_state = web::Data::new(AppState::default().with_privacy_budget(accountant, SampledGaussian::single(2.0)));
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .app_data(json_config())
+                .service(update_model)
+        ).await;
+
+        let client_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        let mut weights_update = weights_update(&client_weights, 0);
+
+        let mut statuses = Vec::new();
+        for _ in 0..10 {
+            weights_update.model_version = *app_state.current_model_version.lock().unwrap();
+            let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+            statuses.push(test::call_service(&app, req).await.status());
+        }
+        let accepted = statuses.iter().filter(|&&status| status == http::StatusCode::OK).count();
+        assert!(accepted > 0 && accepted < 10);
+        assert_eq!(statuses.last(), Some(&http::StatusCode::FORBIDDEN));
+        assert_eq!(*app_state.current_model_version.lock().unwrap(), accepted);
+        assert!(app_state.privacy_accountant.lock().unwrap().spent_epsilon() <= 5.0);
+    }
+
+    // Test that a failed aggregation releases no model and spends no privacy budget
+    #[actix_web::test]
+    async fn test_failed_aggregation_privacy_budget() {
+        use crate::robust_aggregation::Bulyan;
+
+        let accountant = PrivacyAccountant::new(5.0, 1e-5);
+        let app_state = AppState::new(1, Box::new(Bulyan::new(1))).with_privacy_budget(accountant, SampledGaussian::single(2.0));
+        let app_state = web::Data::new(app_state);
+        let app = test::init_service(App::new().app_data(app_state.clone()).configure(configure)).await;
+
+        let client_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        let weights_update = weights_update(&client_weights, 0);
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        test::call_service(&app, req).await;
+        assert_eq!(*app_state.current_model_version.lock().unwrap(), 0);
+        assert_eq!(app_state.privacy_accountant.lock().unwrap().rounds(), 0);
+    }
+
+    // Test that central DP replaces the aggregator and accounts its noise
+    #[actix_web::test]
+    async fn test_with_central_dp() {
+        let central_dp = CentralDp::new(Box::new(FedAvg), 1.0, 1.2);
+        let app_state = AppState::default().with_central_dp(central_dp, PrivacyAccountant::new(8.0, 1e-5), 0.1);
+
+        assert_eq!(app_state.aggregator.lock().unwrap().name(), "DP-FedAvg");
+        let mechanism = app_state.round_mechanism.unwrap();
+        assert_eq!(mechanism.noise_multiplier, 1.2);
+        assert_eq!(mechanism.sampling_rate, 0.1);
+        assert!(app_state.can_aggregate());
+    }
+
+    // Test that the secure aggregation status announces the distributed DP mechanism to the clients
+    #[actix_web::test]
+    async fn test_distributed_dp_status() {
+        let app_state = AppState::new(3, Box::new(FedAvg));
+        let num_parameters = weighted_input_len(&app_state).unwrap() - 1;
+        let mismatched = SkellamMechanism::new(1.0, 1.0, 3, 10).unwrap();
+        assert!(AppState::new(3, Box::new(FedAvg)).with_distributed_dp(mismatched, PrivacyAccountant::new(8.0, 1e-5)).is_err());
+        let mechanism = SkellamMechanism::new(1.0, 1.0, 3, num_parameters).unwrap();
+        let app_state = web::Data::new(app_state.with_distributed_dp(mechanism, PrivacyAccountant::new(8.0, 1e-5)).unwrap());
+        let app = test::init_service(App::new().app_data(app_state.clone()).service(secure_aggregation_status)).await;
+
+        let req = test::TestRequest::get().uri("/secure_aggregation/status").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let announced: SkellamMechanism = serde_json::from_value(body["distributed_dp"].clone()).unwrap();
+        assert_eq!(announced, mechanism);
+    }
+
+    // Test that masked inputs are refused once a distributed DP round could not be released within the budget
+    #[actix_web::test]
+    async fn test_distributed_dp_budget() {
+        let app_state = AppState::new(3, Box::new(FedAvg));
+        let num_parameters = weighted_input_len(&app_state).unwrap() - 1;
+        let mechanism = SkellamMechanism::new(1.0, 1.0, 3, num_parameters).unwrap();
+        let app_state = web::Data::new(app_state.with_distributed_dp(mechanism, PrivacyAccountant::new(1e-3, 1e-5)).unwrap());
+        let app = test::init_service(App::new().app_data(app_state.clone()).configure(configure)).await;
+
+        let message = MaskedInput { client_id: 1, masked_input: vec![0; num_parameters + 1] };
+        let req = test::TestRequest::post().uri("/secure_aggregation/masked_input").set_json(&message).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::FORBIDDEN);
+        assert_eq!(app_state.privacy_accountant.lock().unwrap().rounds(), 0);
+    }
+
+    // Test that `configure` serves the endpoints of the library
+    #[actix_web::test]
+    async fn test_configure() {
+        let app = test::init_service(App::new().app_data(web::Data::new(AppState::default())).configure(configure)).await;
+
+        let req = test::TestRequest::get().uri("/get_model").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        let req = test::TestRequest::get().uri("/secure_aggregation/status").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        let req = test::TestRequest::get().uri("/paillier/public_key").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::NOT_FOUND);
+    }
+
+    // Test that the round refuses a duplicate update of a client and reports its progress on /round
+    #[actix_web::test]
+    async fn test_round_duplicate_update() {
+        let app_state = web::Data::new(AppState::new(2, Box::new(FedAvg)));
+        let app = test::init_service(App::new().app_data(app_state.clone()).configure(configure)).await;
+
+        let client_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        let weights_update = WeightsUpdate {
+            client_id: Some("client-1".to_string()),
+            round_id: Some(0),
+            ..weights_update(&client_weights, 0)
+        };
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::CONFLICT);
+
+        let req = test::TestRequest::get().uri("/round").to_request();
+        let status: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(status["round_id"], 0);
+        assert_eq!(status["phase"], "selecting");
+        assert_eq!(status["participants"], json!(["client-1"]));
+        assert_eq!(status["updates"], 1);
+    }
+
+    // Test that stale updates get a 409 describing the current version unless their staleness is tolerated
+    #[actix_web::test]
+    async fn test_update_model_stale_version() {
+        let app_state = web::Data::new(AppState::default());
+        *app_state.current_model_version.lock().unwrap() = 3;
+        let app = test::init_service(App::new().app_data(app_state.clone()).app_data(json_config()).service(update_model)).await;
+
+        let client_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        let weights_update = weights_update(&client_weights, 1);
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), http::StatusCode::CONFLICT);
+        let response_body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(response_body["current_model_version"], 3);
+        assert_eq!(response_body["model_version"], 1);
+
+        let app_state = web::Data::new(AppState::default().with_staleness_tolerance(2, StalenessFunction::Polynomial { exponent: 0.5 }));
+        *app_state.current_model_version.lock().unwrap() = 3;
+        let app = test::init_service(App::new().app_data(app_state.clone()).app_data(json_config()).service(update_model)).await;
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        let response_body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(response_body["model_version"], 4);
+    }
+
+    // Test that FedBuff publishes a version every K updates whatever version they were trained on
+    #[actix_web::test]
+    async fn test_update_model_fed_buff() {
+        let strategy = AsyncStrategy::FedBuff { buffer_size: 2, server_learning_rate: 1.0 };
+        let app_state = AppState::new(10, Box::new(FedAvg))
+            .with_staleness_tolerance(3, StalenessFunction::Hinge { slope: 1.0, cutoff: 0 })
+            .with_async_aggregation(strategy)
+            .unwrap();
+        let app_state = web::Data::new(app_state);
+        let app = test::init_service(App::new().app_data(app_state.clone()).app_data(json_config()).service(update_model)).await;
+
+        let client_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        let weights_update = weights_update(&client_weights, 0);
+        let mut versions = Vec::new();
+        for _ in 0..5 {
+            let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+            let response_body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            versions.push(response_body["model_version"].as_u64());
+        }
+        assert_eq!(versions, vec![None, Some(1), None, Some(2), None]);
+
+        // Version 0 is now too stale
+        *app_state.current_model_version.lock().unwrap() = 4;
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::CONFLICT);
+    }
+
+    // Test that clients learn on /check_in whether the selector put them in the cohort
+    #[actix_web::test]
+    async fn test_check_in_selection() {
+        use crate::selection::UniformSelection;
+
+        let config = RoundConfig { min_participants: 1, max_participants: 2, selection_timeout: None, training_timeout: None };
+        let app_state = AppState::new(2, Box::new(FedAvg))
+            .with_round_config(config)
+            .unwrap()
+            .with_client_selection(Box::new(UniformSelection { fraction: 0.5 }));
+        let app = test::init_service(App::new().app_data(web::Data::new(app_state)).configure(configure)).await;
+
+        let mut statuses = Vec::new();
+        for client_id in ["client-1", "client-2"] {
+            let candidate = CheckIn { client_id: client_id.to_string(), num_samples: 100, loss: None, availability: None };
+            let req = test::TestRequest::post().uri("/check_in").set_json(&candidate).to_request();
+            let response_body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            statuses.push(response_body["status"].clone());
+        }
+        // The second check-in closes the selection, half of the candidates are selected
+        assert_eq!(statuses[0], "waiting");
+
+        let req = test::TestRequest::get().uri("/round").to_request();
+        let status: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(status["phase"], "training");
+        assert_eq!(status["participants"].as_array().unwrap().len(), 1);
+    }
+
+    // Test that a round whose training deadline passed is aggregated with the updates it received in time
+    #[actix_web::test]
+    async fn test_round_training_deadline() {
+        let timeout = std::time::Duration::from_millis(50);
+        let config = RoundConfig { min_participants: 1, max_participants: 2, selection_timeout: None, training_timeout: Some(timeout) };
+        let app_state = web::Data::new(AppState::new(2, Box::new(FedAvg)).with_round_config(config).unwrap());
+        let app = test::init_service(App::new().app_data(app_state.clone()).configure(configure)).await;
+
+        for client_id in ["client-1", "client-2"] {
+            let candidate = CheckIn { client_id: client_id.to_string(), num_samples: 100, loss: None, availability: None };
+            let req = test::TestRequest::post().uri("/check_in").set_json(&candidate).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        }
+        let client_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        let mut weights_update = WeightsUpdate {
+            client_id: Some("client-1".to_string()),
+            round_id: Some(0),
+            ..weights_update(&client_weights, 0)
+        };
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+
+        // The straggler is refused but the round is aggregated without it
+        std::thread::sleep(timeout * 2);
+        weights_update.client_id = Some("client-2".to_string());
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::CONFLICT);
+        assert_eq!(*app_state.current_model_version.lock().unwrap(), 1);
+
+        let req = test::TestRequest::get().uri("/round").to_request();
+        let status: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(status["round_id"], 1);
+        assert_eq!(status["last_round"]["outcome"], "aggregated");
+        assert_eq!(status["last_round"]["updates"], 1);
+
+        // Without any more update, polling the round aggregates it once the deadline passed
+        for client_id in ["client-1", "client-2"] {
+            let candidate = CheckIn { client_id: client_id.to_string(), num_samples: 100, loss: None, availability: None };
+            let req = test::TestRequest::post().uri("/check_in").set_json(&candidate).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        }
+        weights_update.model_version = 1;
+        weights_update.round_id = Some(1);
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        std::thread::sleep(timeout * 2);
+        let req = test::TestRequest::get().uri("/round").to_request();
+        let status: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(status["round_id"], 2);
+        assert_eq!(*app_state.current_model_version.lock().unwrap(), 2);
+    }
+
+    // Test that the model endpoints need a registered client and refuse it once revoked
+    #[actix_web::test]
+    async fn test_authentication() {
+        use crate::auth::Registration;
+
+        let app_state = web::Data::new(AppState::default().with_authentication("secret".to_string()));
+        let app = test::init_service(App::new().app_data(app_state.clone()).configure(configure)).await;
+
+        for uri in ["/get_model", "/round", "/public_key", "/secure_aggregation/status"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::UNAUTHORIZED);
+        }
+
+        let request = RegistrationRequest { public_key: None, registration_secret: Some("secret".to_string()) };
+        let req = test::TestRequest::post().uri("/register").set_json(&request).to_request();
+        let registration: Registration = test::call_and_read_body_json(&app, req).await;
+        let authorization = ("authorization", format!("Bearer {}", registration.token.unwrap()));
+        let req = test::TestRequest::get().uri("/get_model").insert_header(authorization.clone()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+
+        assert!(app_state.revoke_client(&registration.client_id));
+        let req = test::TestRequest::get().uri("/get_model").insert_header(authorization).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::UNAUTHORIZED);
+        // Registering again needs the secret
+        let req = test::TestRequest::post().uri("/register").set_json(RegistrationRequest::default()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::FORBIDDEN);
+    }
+
+    // Test that signed updates reach the handler with their body and are submitted under the signer's id
+    #[actix_web::test]
+    async fn test_update_model_signed() {
+        use crate::auth::{encode_public_key, generate_signing_key, ClientCredentials};
+
+        let app_state = web::Data::new(AppState::new(2, Box::new(FedAvg)).with_authentication("secret".to_string()));
+        let app = test::init_service(App::new().app_data(app_state.clone()).configure(configure)).await;
+
+        let signing_key = generate_signing_key();
+        let request = RegistrationRequest {
+            public_key: Some(encode_public_key(&signing_key)),
+            registration_secret: Some("secret".to_string()),
+        };
+        let registration = app_state.clients.lock().unwrap().register(&request).unwrap();
+        let credentials = ClientCredentials::Ed25519 { client_id: registration.client_id.clone(), signing_key };
+
+        let client_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        let weights_update = WeightsUpdate {
+            client_id: Some("someone-else".to_string()),
+            ..weights_update(&client_weights, 0)
+        };
+        let body = serde_json::to_vec(&weights_update).unwrap();
+        let mut req = test::TestRequest::post()
+            .uri("/update_model")
+            .insert_header(("content-type", "application/json"))
+            .set_payload(body.clone());
+        for header in credentials.headers("POST", "/update_model", &body, unix_time()) {
+            req = req.insert_header(header);
+        }
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), http::StatusCode::OK);
+        let status = app_state.rounds.lock().unwrap().status(Instant::now());
+        assert_eq!(status.participants, vec![registration.client_id.clone()]);
+
+        // Signed bodies are only buffered up to the payload limit
+        let oversized = vec![b' '; MAX_PAYLOAD_BYTES + 1];
+        let mut req = test::TestRequest::post()
+            .uri("/update_model")
+            .insert_header(("content-type", "application/json"))
+            .set_payload(oversized.clone());
+        for header in credentials.headers("POST", "/update_model", &oversized, unix_time()) {
+            req = req.insert_header(header);
+        }
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), http::StatusCode::PAYLOAD_TOO_LARGE);
+
+        // A body that was not signed is refused
+        let mut req = test::TestRequest::post()
+            .uri("/update_model")
+            .insert_header(("content-type", "application/json"))
+            .set_payload(body);
+        for header in credentials.headers("POST", "/update_model", b"{}", unix_time()) {
+            req = req.insert_header(header);
+        }
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), http::StatusCode::UNAUTHORIZED);
+    }
+
+    // Test that a restarted server resumes the model, version, round and aggregator state of its last checkpoint
+    #[actix_web::test]
+    async fn test_checkpoint_resume() {
+        use crate::aggregation::FedAvgM;
+
+        let dir = std::env::temp_dir().join(format!("rustfl-server-checkpoints-{}", std::process::id()));
+        let config = CheckpointConfig { dir: dir.to_string_lossy().to_string(), interval_secs: 60, keep: 2 };
+        let app_state = AppState::new(1, Box::new(FedAvgM::new(1.0, 0.9)))
+            .with_privacy_budget(PrivacyAccountant::new(8.0, 1e-5), SampledGaussian::single(2.0))
+            .with_checkpoints(config.clone())
+            .unwrap();
+        let app_state = web::Data::new(app_state);
+        let app = test::init_service(App::new().app_data(app_state.clone()).configure(configure)).await;
+
+        let mut client_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        client_weights[0].data.iter_mut().for_each(|x| *x += 1.0);
+        let weights_update = weights_update(&client_weights, 0);
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        assert_eq!(app_state.save_checkpoint().unwrap(), Some(1));
+        // Nothing changed since
+        assert_eq!(app_state.save_checkpoint().unwrap(), None);
+
+        // The budget was raised in the settings before the restart
+        let restarted = AppState::new(1, Box::new(FedAvgM::new(1.0, 0.9)))
+            .with_privacy_budget(PrivacyAccountant::new(16.0, 1e-5), SampledGaussian::single(2.0))
+            .with_checkpoints(config)
+            .unwrap();
+        assert_eq!(*restarted.current_model_version.lock().unwrap(), 1);
+        let accountant = restarted.privacy_accountant.lock().unwrap().clone();
+        assert_eq!(accountant.epsilon_budget, 16.0);
+        assert_eq!(accountant.rounds(), 1);
+        assert_eq!(accountant.spent_epsilon(), app_state.privacy_accountant.lock().unwrap().spent_epsilon());
+        assert_eq!(restarted.rounds.lock().unwrap().round_id(), 1);
+        assert_eq!(restarted.aggregator.lock().unwrap().state(), app_state.aggregator.lock().unwrap().state());
+        let resumed = state_dict_from_var_store(&restarted.global_var_store.lock().unwrap()).unwrap();
+        assert_eq!(resumed, state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Test that the updates of the open round and the registered clients survive a restart
+    #[actix_web::test]
+    async fn test_checkpoint_pending_round() {
+        let dir = std::env::temp_dir().join(format!("rustfl-server-pending-{}", std::process::id()));
+        let config = CheckpointConfig { dir: dir.to_string_lossy().to_string(), interval_secs: 60, keep: 2 };
+        let app_state = web::Data::new(AppState::new(2, Box::new(FedAvg)).with_checkpoints(config.clone()).unwrap());
+        let app = test::init_service(App::new().app_data(app_state.clone()).configure(configure)).await;
+        let registration = app_state.clients.lock().unwrap().register(&RegistrationRequest::default()).unwrap();
+
+        let client_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+        let weights_update = |client_id: &str| WeightsUpdate {
+            client_id: Some(client_id.to_string()),
+            round_id: Some(0),
+            ..weights_update(&client_weights, 0)
+        };
+        let req = test::TestRequest::post().uri("/update_model").set_json(weights_update("a")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        assert_eq!(app_state.save_checkpoint().unwrap(), Some(0));
+        assert_eq!(app_state.save_checkpoint().unwrap(), None);
+
+        let restarted = web::Data::new(AppState::new(2, Box::new(FedAvg)).with_checkpoints(config).unwrap());
+        assert_eq!(restarted.rounds.lock().unwrap().status(Instant::now()).updates, 1);
+        assert_eq!(restarted.clients.lock().unwrap().state(), app_state.clients.lock().unwrap().state());
+        assert!(restarted.clients.lock().unwrap().state().token_hashes.contains_key(&registration.client_id));
+        let app = test::init_service(App::new().app_data(restarted.clone()).configure(configure)).await;
+        let req = test::TestRequest::post().uri("/update_model").set_json(weights_update("a")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::CONFLICT);
+        let req = test::TestRequest::post().uri("/update_model").set_json(weights_update("b")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+        assert_eq!(*restarted.current_model_version.lock().unwrap(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Test that a retained model is served by version and that the admin can publish it again
+    #[actix_web::test]
+    async fn test_model_rollback() {
+        let app_state = web::Data::new(AppState::default().with_admin_token("admin-secret".to_string()));
+        let app = test::init_service(App::new().app_data(app_state.clone()).configure(configure)).await;
+        let initial_weights = state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap();
+
+        let mut client_weights = initial_weights.clone();
+        client_weights[0].data.iter_mut().for_each(|x| *x += 1.0);
+        let weights_update = weights_update(&client_weights, 0);
+        let req = test::TestRequest::post().uri("/update_model").set_json(&weights_update).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/models").to_request();
+        let listing: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(listing["current_model_version"], 1);
+        assert_eq!(listing["models"][1]["metrics"]["mean_loss"], 0.25);
+
+        let req = test::TestRequest::get().uri("/models/0").to_request();
+        let model: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let state_dict: Vec<NamedTensor> = serde_json::from_value(model["model_state_dict"].clone()).unwrap();
+        assert_eq!(state_dict, initial_weights);
+        let req = test::TestRequest::get().uri("/models/99").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::NOT_FOUND);
+
+        // Only the operator may roll back
+        let req = test::TestRequest::post().uri("/models/0/rollback").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::FORBIDDEN);
+        let req = test::TestRequest::post()
+            .uri("/models/0/rollback")
+            .insert_header(("Authorization", "Bearer admin-secreT"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::FORBIDDEN);
+        let req = test::TestRequest::post()
+            .uri("/models/99/rollback")
+            .insert_header(("Authorization", "Bearer admin-secret"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::NOT_FOUND);
+        let round_id = app_state.rounds.lock().unwrap().round_id();
+        let key_round = app_state.key_store.lock().unwrap().current_round();
+        let keys = crate::secure_aggregation::SecureAggregationClient::new(1, 1).unwrap().advertise_keys();
+        app_state.secure_aggregation.lock().unwrap().advertise_keys(keys).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/models/0/rollback")
+            .insert_header(("Authorization", "Bearer admin-secret"))
+            .to_request();
+        let response: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(response["model_version"], 2);
+        // Updates trained for the abandoned round are refused by the round id check
+        assert_eq!(app_state.rounds.lock().unwrap().round_id(), round_id + 1);
+        // Masked inputs and session keys of the replaced model are dropped as well
+        assert_eq!(app_state.key_store.lock().unwrap().current_round(), key_round + 1);
+        assert_eq!(app_state.secure_aggregation.lock().unwrap().phase(), SecAggPhase::AdvertiseKeys);
+        assert!(app_state.secure_aggregation.lock().unwrap().roster().is_err());
+        assert_eq!(*app_state.current_model_version.lock().unwrap(), 2);
+        assert_eq!(state_dict_from_var_store(&app_state.global_var_store.lock().unwrap()).unwrap(), initial_weights);
+        assert_eq!(app_state.models.lock().unwrap().get(2).unwrap().metrics.rolled_back_from, Some(0));
+    }
+}
@@ -0,0 +1,925 @@
+//Deployment settings of clients and servers
+//
+//Settings are read from a TOML or YAML file, then overridden by environment variables and validated.
+//Variables are named after the section and the field, `RUSTFL_CLIENT_BATCH_SIZE=128` or
+//`RUSTFL_SERVER_PORT=9000`, nested fields are separated by a double underscore as in
+//`RUSTFL_SERVER_AGGREGATOR__STRATEGY=fed_adam`. Values are parsed as JSON and fall back to strings.
+
+use std::path::Path;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::aggregation::{Aggregator, FedAvg, FedAvgM, FedOpt, StalenessFunction};
+use crate::async_aggregation::AsyncStrategy;
+use crate::central_dp::{AdaptiveClipping, CentralDp};
+use crate::checkpoint::CheckpointConfig;
+use crate::client::Config;
+use crate::distributed_dp::SkellamMechanism;
+use crate::key_management::InMemoryKeyStore;
+use crate::paillier::PaillierPublicKey;
+use crate::privacy_accountant::PrivacyAccountant;
+use crate::round::RoundConfig;
+use crate::selection::{
+    AvailabilityAwareSelection, ClientSelector, PowerOfChoice, StratifiedSelection, UniformSelection,
+};
+use crate::robust_aggregation::{Bulyan, CoordinateMedian, GeometricMedian, Krum, TrimmedMean};
+use crate::server::{AppState, DEFAULT_MODEL_HISTORY};
+use crate::tls::{ClientTlsConfig, ServerTlsConfig};
+
+/// Prefix of the environment variables overriding the settings
+pub const ENV_PREFIX: &str = "RUSTFL_";
+
+/// Fields selecting the variant of a section, e.g. the aggregation strategy
+const TAG_FIELDS: [&str; 2] = ["strategy", "function"];
+
+/// Settings of a client, the fields of `Config` and where to find the server and the data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSettings {
+    /// Base URL of the server.
+    pub server_url: String,
+    /// Directory of the MNIST training files.
+    pub data_dir: String,
+    pub learning_rate: f64,
+    pub batch_size: usize,
+    pub noise_level: f64,
+    pub num_rounds: usize,
+    pub sensitivity: f64,
+    pub epsilon: f64,
+    pub proximal_mu: f64,
+    /// Total epsilon the client may spend, unlimited when absent.
+    pub privacy_budget: Option<f64>,
+    pub target_delta: f64,
+    /// Trains with DP-SGD, which needs a finite `privacy_budget`.
+    pub dp_sgd: bool,
+    pub max_grad_norm: f64,
+    /// Identifier sent with every update, anonymous when absent.
+    pub client_id: Option<String>,
+    /// Registers with the server and authenticates every request with this method when present.
+    pub authentication: Option<AuthenticationSettings>,
+    /// Secret required by servers restricting registration.
+    pub registration_secret: Option<String>,
+    /// Private CA and client certificate for `https` servers, the public roots only when absent.
+    pub tls: Option<ClientTlsConfig>,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        let config = Config::default();
+        ClientSettings {
+            server_url: "http://0.0.0.0:8081".to_string(),
+            data_dir: "mnist_data/MNIST/raw".to_string(),
+            learning_rate: config.learning_rate,
+            batch_size: config.batch_size,
+            noise_level: config.noise_level,
+            num_rounds: config.num_rounds,
+            sensitivity: config.sensitivity,
+            epsilon: config.epsilon,
+            proximal_mu: config.proximal_mu,
+            privacy_budget: None,
+            target_delta: config.target_delta,
+            dp_sgd: config.dp_sgd,
+            max_grad_norm: config.max_grad_norm,
+            client_id: None,
+            authentication: None,
+            registration_secret: None,
+            tls: None,
+        }
+    }
+}
+
+impl ClientSettings {
+    /// Training configuration described by these settings
+    pub fn config(&self) -> Config {
+        let mut config = Config::new(
+            self.learning_rate,
+            self.batch_size,
+            self.noise_level,
+            self.num_rounds,
+            self.sensitivity,
+            self.epsilon,
+        );
+        config.proximal_mu = self.proximal_mu;
+        config.privacy_budget = self.privacy_budget.unwrap_or(f64::INFINITY);
+        config.target_delta = self.target_delta;
+        config.dp_sgd = self.dp_sgd;
+        config.max_grad_norm = self.max_grad_norm;
+        config.client_id = self.client_id.clone();
+        config
+    }
+
+    /// Checks that every value is in its valid range
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.server_url.starts_with("http://") && !self.server_url.starts_with("https://") {
+            return Err(format!("client.server_url must be an http(s) URL, got {:?}", self.server_url));
+        }
+        positive("client.learning_rate", self.learning_rate)?;
+        positive("client.sensitivity", self.sensitivity)?;
+        positive("client.epsilon", self.epsilon)?;
+        positive("client.max_grad_norm", self.max_grad_norm)?;
+        non_negative("client.noise_level", self.noise_level)?;
+        non_negative("client.proximal_mu", self.proximal_mu)?;
+        probability("client.target_delta", self.target_delta)?;
+        if let Some(privacy_budget) = self.privacy_budget {
+            positive("client.privacy_budget", privacy_budget)?;
+        }
+        // DP-SGD spreads the budget over the rounds to calibrate its noise
+        if self.dp_sgd && !self.privacy_budget.is_some_and(f64::is_finite) {
+            return Err("client.dp_sgd needs a finite client.privacy_budget".to_string());
+        }
+        if self.batch_size == 0 || self.num_rounds == 0 {
+            return Err("client.batch_size and client.num_rounds must be at least 1".to_string());
+        }
+        if let Some(tls) = &self.tls {
+            if !self.server_url.starts_with("https://") {
+                return Err(format!("client.tls needs an https server_url, got {:?}", self.server_url));
+            }
+            tls.validate().map_err(|e| format!("client.{}", e))?;
+        }
+        Ok(())
+    }
+}
+
+/// How a client authenticates its requests after registering
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthenticationSettings {
+    /// Bearer token issued by the server.
+    Bearer,
+    /// Ed25519 signature of every request with a key generated by the client.
+    Ed25519,
+}
+
+impl AuthenticationSettings {
+    /// Whether requests are signed
+    pub fn signed(&self) -> bool {
+        *self == AuthenticationSettings::Ed25519
+    }
+}
+
+/// Aggregation strategy of the server, selected by its `strategy` name
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AggregatorSettings {
+    FedAvg,
+    FedAvgM { server_learning_rate: f64, momentum: f64 },
+    FedAdagrad { server_learning_rate: f64, tau: f64 },
+    FedAdam { server_learning_rate: f64, tau: f64 },
+    FedYogi { server_learning_rate: f64, tau: f64 },
+    CoordinateMedian,
+    TrimmedMean { beta: f64 },
+    Krum { num_byzantine: usize, num_selected: usize },
+    Bulyan { num_byzantine: usize },
+    GeometricMedian { max_iterations: usize, tolerance: f64 },
+}
+
+impl AggregatorSettings {
+    /// Aggregator described by these settings
+    pub fn aggregator(&self) -> Box<dyn Aggregator> {
+        match *self {
+            AggregatorSettings::FedAvg => Box::new(FedAvg),
+            AggregatorSettings::FedAvgM { server_learning_rate, momentum } => Box::new(FedAvgM::new(server_learning_rate, momentum)),
+            AggregatorSettings::FedAdagrad { server_learning_rate, tau } => Box::new(FedOpt::fed_adagrad(server_learning_rate, tau)),
+            AggregatorSettings::FedAdam { server_learning_rate, tau } => Box::new(FedOpt::fed_adam(server_learning_rate, tau)),
+            AggregatorSettings::FedYogi { server_learning_rate, tau } => Box::new(FedOpt::fed_yogi(server_learning_rate, tau)),
+            AggregatorSettings::CoordinateMedian => Box::new(CoordinateMedian),
+            AggregatorSettings::TrimmedMean { beta } => Box::new(TrimmedMean::new(beta)),
+            AggregatorSettings::Krum { num_byzantine, num_selected } => Box::new(Krum::multi(num_byzantine, num_selected)),
+            AggregatorSettings::Bulyan { num_byzantine } => Box::new(Bulyan::new(num_byzantine)),
+            AggregatorSettings::GeometricMedian { max_iterations, tolerance } => {
+                Box::new(GeometricMedian::new(max_iterations, tolerance))
+            }
+        }
+    }
+
+    /// Checks the parameters of the strategy for rounds of `aggregation_goal` clients
+    pub fn validate(&self, aggregation_goal: usize) -> Result<(), String> {
+        match *self {
+            AggregatorSettings::FedAvg | AggregatorSettings::CoordinateMedian => Ok(()),
+            AggregatorSettings::FedAvgM { server_learning_rate, momentum } => {
+                positive("server.aggregator.server_learning_rate", server_learning_rate)?;
+                if !(0.0..1.0).contains(&momentum) {
+                    return Err(format!("server.aggregator.momentum must be in [0, 1), got {}", momentum));
+                }
+                Ok(())
+            }
+            AggregatorSettings::FedAdagrad { server_learning_rate, tau }
+            | AggregatorSettings::FedAdam { server_learning_rate, tau }
+            | AggregatorSettings::FedYogi { server_learning_rate, tau } => {
+                positive("server.aggregator.server_learning_rate", server_learning_rate)?;
+                positive("server.aggregator.tau", tau)
+            }
+            AggregatorSettings::TrimmedMean { beta } => {
+                if !(0.0..0.5).contains(&beta) {
+                    return Err(format!("server.aggregator.beta must be in [0, 0.5), got {}", beta));
+                }
+                Ok(())
+            }
+            AggregatorSettings::Krum { num_byzantine, num_selected } => {
+                at_least("server.aggregation_goal", aggregation_goal, 2 * num_byzantine + 3)?;
+                if num_selected == 0 || num_selected > aggregation_goal - num_byzantine {
+                    return Err(format!(
+                        "server.aggregator.num_selected must be between 1 and {}, got {}",
+                        aggregation_goal - num_byzantine,
+                        num_selected
+                    ));
+                }
+                Ok(())
+            }
+            AggregatorSettings::Bulyan { num_byzantine } => {
+                at_least("server.aggregation_goal", aggregation_goal, 4 * num_byzantine + 3)
+            }
+            AggregatorSettings::GeometricMedian { max_iterations, tolerance } => {
+                at_least("server.aggregator.max_iterations", max_iterations, 1)?;
+                positive("server.aggregator.tolerance", tolerance)
+            }
+        }
+    }
+}
+
+/// Client selection strategy of the server, selected by its `strategy` name
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SelectionSettings {
+    Uniform { fraction: f64 },
+    Stratified { num_strata: usize },
+    PowerOfChoice { candidate_set_size: usize },
+    AvailabilityAware { min_availability: f64 },
+}
+
+impl SelectionSettings {
+    /// Selector described by these settings
+    pub fn selector(&self) -> Box<dyn ClientSelector> {
+        match *self {
+            SelectionSettings::Uniform { fraction } => Box::new(UniformSelection { fraction }),
+            SelectionSettings::Stratified { num_strata } => Box::new(StratifiedSelection { num_strata }),
+            SelectionSettings::PowerOfChoice { candidate_set_size } => Box::new(PowerOfChoice { candidate_set_size }),
+            SelectionSettings::AvailabilityAware { min_availability } => {
+                Box::new(AvailabilityAwareSelection { min_availability })
+            }
+        }
+    }
+
+    /// Checks the parameters of the strategy
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            SelectionSettings::Uniform { fraction } if !(fraction > 0.0 && fraction <= 1.0) => {
+                Err(format!("server.selection.fraction must be in (0, 1], got {}", fraction))
+            }
+            SelectionSettings::Stratified { num_strata } => at_least("server.selection.num_strata", num_strata, 1),
+            SelectionSettings::AvailabilityAware { min_availability } if !(0.0..=1.0).contains(&min_availability) => {
+                Err(format!("server.selection.min_availability must be in [0, 1], got {}", min_availability))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Server-side differential privacy applied on top of the aggregator
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CentralDpSettings {
+    pub clip_norm: f64,
+    pub noise_multiplier: f64,
+    /// Fraction of the client population taking part in a round.
+    pub sampling_rate: f64,
+    /// Adapts the clipping norm to a quantile of the update norms when present.
+    pub adaptive_clipping: Option<AdaptiveClipping>,
+}
+
+impl Default for CentralDpSettings {
+    fn default() -> Self {
+        CentralDpSettings {
+            clip_norm: 1.0,
+            noise_multiplier: 1.0,
+            sampling_rate: 1.0,
+            adaptive_clipping: None,
+        }
+    }
+}
+
+/// Distributed DP: secure aggregation clients add Skellam noise to their clipped deltas, one share per client
+/// of the `aggregation_goal`, and the server accounts the noise of the clients that took part
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DistributedDpSettings {
+    pub clip_norm: f64,
+    /// Noise of the sum once every client contributed, relative to `clip_norm`.
+    pub noise_multiplier: f64,
+}
+
+impl Default for DistributedDpSettings {
+    fn default() -> Self {
+        DistributedDpSettings {
+            clip_norm: 1.0,
+            noise_multiplier: 1.0,
+        }
+    }
+}
+
+/// Paillier aggregation of encrypted models, the private key being split among registered clients
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PaillierSettings {
+    /// Modulus of the public key as a hexadecimal string.
+    pub public_key: String,
+    /// Number of clients holding a share of the private key, every one of them decrypts each round.
+    pub key_holders: usize,
+}
+
+impl PaillierSettings {
+    /// Public key clients encrypt their models with
+    pub fn public_key(&self) -> PaillierPublicKey {
+        PaillierPublicKey { n: self.public_key.clone() }
+    }
+}
+
+/// Participation limits and deadlines of the rounds, every field falls back to the aggregation goal
+/// or to no deadline when absent
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoundSettings {
+    pub min_participants: Option<usize>,
+    pub max_participants: Option<usize>,
+    /// Seconds from the first participant until selection ends.
+    pub selection_timeout_secs: Option<f64>,
+    /// Seconds from the end of selection until stragglers are dropped.
+    pub training_timeout_secs: Option<f64>,
+}
+
+impl RoundSettings {
+    /// Round configuration of a server aggregating `aggregation_goal` updates by default
+    pub fn round_config(&self, aggregation_goal: usize) -> Result<RoundConfig, String> {
+        let timeout = |name: &str, secs: Option<f64>| match secs {
+            Some(secs) => positive(name, secs).map(|_| Some(Duration::from_secs_f64(secs))),
+            None => Ok(None),
+        };
+        Ok(RoundConfig {
+            min_participants: self.min_participants.unwrap_or(aggregation_goal),
+            max_participants: self.max_participants.unwrap_or(aggregation_goal),
+            selection_timeout: timeout("server.round.selection_timeout_secs", self.selection_timeout_secs)?,
+            training_timeout: timeout("server.round.training_timeout_secs", self.training_timeout_secs)?,
+        })
+    }
+}
+
+/// Settings of a server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub host: String,
+    pub port: u16,
+    /// Number of client updates aggregated into each new global model.
+    pub aggregation_goal: usize,
+    pub aggregator: AggregatorSettings,
+    /// Surviving clients needed to unmask a secure aggregation round, a majority when absent.
+    pub secure_aggregation_threshold: Option<usize>,
+    /// Number of rounds whose encryption keys are kept for late updates.
+    pub retained_key_rounds: usize,
+    /// Total epsilon the released models may spend, unlimited when absent.
+    pub privacy_budget: Option<f64>,
+    pub target_delta: f64,
+    pub central_dp: Option<CentralDpSettings>,
+    /// Accounts the noise secure aggregation clients add themselves when present, instead of central DP.
+    pub distributed_dp: Option<DistributedDpSettings>,
+    /// Serves Paillier aggregation on `/paillier/*` when present, needs `require_auth` to tell the key holders apart.
+    pub paillier: Option<PaillierSettings>,
+    pub round: RoundSettings,
+    /// Number of versions an update may lag behind the global model.
+    pub max_staleness: usize,
+    /// Discount of stale updates, they weigh fully by default.
+    pub staleness: StalenessFunction,
+    /// Applies updates as they arrive with FedAsync or FedBuff instead of in rounds when present, without central DP.
+    pub asynchronous: Option<AsyncStrategy>,
+    /// Samples the participants of every round among the clients that checked in, first come first served when absent.
+    pub selection: Option<SelectionSettings>,
+    /// Refuses requests of clients that did not register on `/register`, needs `registration_secret`.
+    pub require_auth: bool,
+    /// Secret clients must present to register, anyone may register when absent.
+    pub registration_secret: Option<String>,
+    /// Serves over TLS with this certificate, and only to clients with a certificate of `client_ca_path` when set.
+    pub tls: Option<ServerTlsConfig>,
+    /// Saves the server state periodically and resumes from the newest checkpoint on startup when present.
+    pub checkpoint: Option<CheckpointConfig>,
+    /// Number of previous global models served on `/models/{version}` and available for rollback.
+    pub model_history: usize,
+    /// Token of the operator allowed to roll back the global model and to advance secure aggregation past
+    /// dropped clients, both are disabled when absent.
+    pub admin_token: Option<String>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            host: "0.0.0.0".to_string(),
+            port: 8081,
+            aggregation_goal: 1,
+            aggregator: AggregatorSettings::FedAvg,
+            secure_aggregation_threshold: None,
+            retained_key_rounds: 2,
+            privacy_budget: None,
+            target_delta: 1e-5,
+            central_dp: None,
+            distributed_dp: None,
+            paillier: None,
+            round: RoundSettings::default(),
+            max_staleness: 0,
+            staleness: StalenessFunction::Constant,
+            asynchronous: None,
+            selection: None,
+            require_auth: false,
+            registration_secret: None,
+            tls: None,
+            checkpoint: None,
+            model_history: DEFAULT_MODEL_HISTORY,
+            admin_token: None,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Checks that every value is in its valid range
+    pub fn validate(&self) -> Result<(), String> {
+        at_least("server.aggregation_goal", self.aggregation_goal, 1)?;
+        at_least("server.retained_key_rounds", self.retained_key_rounds, 1)?;
+        at_least("server.model_history", self.model_history, 1)?;
+        // With open registration a revoked client would register again
+        if self.require_auth && self.registration_secret.is_none() {
+            return Err("server.require_auth needs a server.registration_secret".to_string());
+        }
+        if let Some(threshold) = self.secure_aggregation_threshold {
+            if threshold == 0 || threshold > self.aggregation_goal {
+                return Err(format!(
+                    "server.secure_aggregation_threshold must be between 1 and the aggregation goal {}, got {}",
+                    self.aggregation_goal, threshold
+                ));
+            }
+        }
+        probability("server.target_delta", self.target_delta)?;
+        self.staleness.validate().map_err(|e| format!("server.staleness: {}", e))?;
+        if let Some(asynchronous) = &self.asynchronous {
+            asynchronous.validate().map_err(|e| format!("server.asynchronous: {}", e))?;
+            // Asynchronous updates bypass the aggregator, so they would be published without the central noise
+            if self.central_dp.is_some() {
+                return Err("server.central_dp cannot be combined with server.asynchronous".to_string());
+            }
+        }
+        if let Some(selection) = &self.selection {
+            selection.validate()?;
+        }
+        if let Some(privacy_budget) = self.privacy_budget {
+            positive("server.privacy_budget", privacy_budget)?;
+        }
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint.validate().map_err(|e| format!("server.{}", e))?;
+        }
+        self.aggregator.validate(self.aggregation_goal)?;
+        let round = self.round.round_config(self.aggregation_goal)?;
+        if round.min_participants == 0 || round.min_participants > round.max_participants {
+            return Err(format!(
+                "server.round.min_participants must be between 1 and max_participants {}, got {}",
+                round.max_participants, round.min_participants
+            ));
+        }
+        if let Some(central_dp) = &self.central_dp {
+            positive("server.central_dp.clip_norm", central_dp.clip_norm)?;
+            non_negative("server.central_dp.noise_multiplier", central_dp.noise_multiplier)?;
+            if !(central_dp.sampling_rate > 0.0 && central_dp.sampling_rate <= 1.0) {
+                return Err(format!("server.central_dp.sampling_rate must be in (0, 1], got {}", central_dp.sampling_rate));
+            }
+        }
+        if let Some(distributed_dp) = &self.distributed_dp {
+            positive("server.distributed_dp.clip_norm", distributed_dp.clip_norm)?;
+            non_negative("server.distributed_dp.noise_multiplier", distributed_dp.noise_multiplier)?;
+            // Both account the released models with their own mechanism
+            if self.central_dp.is_some() {
+                return Err("server.distributed_dp cannot be combined with server.central_dp".to_string());
+            }
+        }
+        if let Some(paillier) = &self.paillier {
+            at_least("server.paillier.key_holders", paillier.key_holders, 1)?;
+            paillier
+                .public_key()
+                .slots_per_ciphertext()
+                .map_err(|e| format!("server.paillier.public_key: {}", e))?;
+            // Partial decryptions are bound to the authenticated key holders
+            if !self.require_auth {
+                return Err("server.paillier needs server.require_auth".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Address the server binds to
+    pub fn bind_address(&self) -> (String, u16) {
+        (self.host.clone(), self.port)
+    }
+
+    /// Global state of a server running with these settings
+    pub fn app_state(&self) -> Result<AppState, String> {
+        let mut app_state = AppState::new(self.aggregation_goal, self.aggregator.aggregator());
+        if let Some(threshold) = self.secure_aggregation_threshold {
+            app_state = app_state.with_secure_aggregation_threshold(threshold)?;
+        }
+        app_state = app_state
+            .with_round_config(self.round.round_config(self.aggregation_goal)?)?
+            .with_staleness_tolerance(self.max_staleness, self.staleness);
+        if let Some(strategy) = self.asynchronous {
+            app_state = app_state.with_async_aggregation(strategy)?;
+        }
+        if let Some(selection) = &self.selection {
+            app_state = app_state.with_client_selection(selection.selector());
+        }
+        if self.require_auth {
+            let registration_secret = self.registration_secret.clone().ok_or("server.require_auth needs a registration_secret")?;
+            app_state = app_state.with_authentication(registration_secret);
+        }
+        if let Some(paillier) = &self.paillier {
+            app_state = app_state.with_paillier(paillier.public_key(), paillier.key_holders);
+        }
+        app_state.key_store = std::sync::Mutex::new(Box::new(InMemoryKeyStore::new(self.retained_key_rounds)));
+
+        let accountant = PrivacyAccountant::new(self.privacy_budget.unwrap_or(f64::INFINITY), self.target_delta);
+        app_state = if let Some(settings) = &self.central_dp {
+            let inner = self.aggregator.aggregator();
+            let central_dp = match settings.adaptive_clipping {
+                Some(adaptive) => CentralDp::adaptive(inner, settings.clip_norm, settings.noise_multiplier, adaptive)?,
+                None => CentralDp::new(inner, settings.clip_norm, settings.noise_multiplier),
+            };
+            app_state.with_central_dp(central_dp, accountant, settings.sampling_rate)
+        } else if let Some(settings) = &self.distributed_dp {
+            let mechanism = SkellamMechanism::new(
+                settings.clip_norm,
+                settings.noise_multiplier,
+                self.aggregation_goal,
+                app_state.num_parameters()?,
+            )?;
+            app_state.with_distributed_dp(mechanism, accountant)?
+        } else {
+            app_state.privacy_accountant = std::sync::Mutex::new(accountant);
+            app_state
+        };
+        app_state = app_state.with_model_history(self.model_history)?;
+        if let Some(admin_token) = &self.admin_token {
+            app_state = app_state.with_admin_token(admin_token.clone());
+        }
+        match &self.checkpoint {
+            Some(checkpoint) => app_state.with_checkpoints(checkpoint.clone()),
+            None => Ok(app_state),
+        }
+    }
+}
+
+/// Settings of a deployment, both sections are optional in the files
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub client: ClientSettings,
+    pub server: ServerSettings,
+}
+
+impl Settings {
+    /// Parses TOML settings
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| format!("Invalid TOML settings: {}", e))
+    }
+
+    /// Parses YAML settings
+    pub fn from_yaml(text: &str) -> Result<Self, String> {
+        serde_yaml::from_str(text).map_err(|e| format!("Invalid YAML settings: {}", e))
+    }
+
+    /// Reads a settings file, TOML or YAML depending on its extension
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Settings::from_toml(&text),
+            Some("yaml") | Some("yml") => Settings::from_yaml(&text),
+            _ => Err(format!("{} is neither a .toml nor a .yaml file", path.display())),
+        }
+    }
+
+    /// Settings of the file at `path` (defaults without a file) overridden by the environment and validated
+    pub fn load<P: AsRef<Path>>(path: Option<P>) -> Result<Self, String> {
+        let mut settings = match path {
+            Some(path) => Settings::from_file(path)?,
+            None => Settings::default(),
+        };
+        settings.apply_overrides(std::env::vars())?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Applies the `RUSTFL_*` variables among `vars`, other variables are ignored
+    pub fn apply_overrides<I: IntoIterator<Item = (String, String)>>(&mut self, vars: I) -> Result<(), String> {
+        let mut tree = serde_json::to_value(&*self).map_err(|e| e.to_string())?;
+        let mut overridden = false;
+        // A strategy is set before its parameters, which depend on it
+        let mut vars = vars.into_iter().collect::<Vec<_>>();
+        vars.sort_by_key(|(name, _)| !TAG_FIELDS.iter().any(|tag| name.ends_with(&format!("__{}", tag.to_uppercase()))));
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else { continue };
+            let key = key.to_lowercase();
+            let (section, field) = key
+                .split_once('_')
+                .filter(|(section, _)| *section == "client" || *section == "server")
+                .ok_or(format!("{} does not name a client or server setting", name))?;
+            let mut path = vec![section];
+            path.extend(field.split("__"));
+            set_path(&mut tree, &path, &value).map_err(|e| format!("{}: {}", name, e))?;
+            overridden = true;
+        }
+        if overridden {
+            *self = serde_json::from_value(tree).map_err(|e| format!("Invalid environment override: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Checks both sections
+    pub fn validate(&self) -> Result<(), String> {
+        self.client.validate()?;
+        self.server.validate()
+    }
+}
+
+/// Sets the field at `path` of a JSON tree, creating the objects of unset optional sections
+fn set_path(tree: &mut Value, path: &[&str], value: &str) -> Result<(), String> {
+    let (field, parents) = path.split_last().ok_or("Empty setting name".to_string())?;
+    let mut node = tree;
+    for (depth, parent) in parents.iter().enumerate() {
+        let object = node.as_object_mut().ok_or(format!("{} is not a section", path[..depth].join(".")))?;
+        let child = object.get_mut(*parent).ok_or(format!("Unknown setting {}", path[..=depth].join(".")))?;
+        if child.is_null() {
+            *child = Value::Object(Default::default());
+        }
+        node = child;
+    }
+    let object = node.as_object_mut().ok_or(format!("{} is not a section", parents.join(".")))?;
+    if TAG_FIELDS.contains(field) {
+        // Another variant has other parameters
+        object.clear();
+    } else if !object.is_empty() && !TAG_FIELDS.iter().any(|tag| object.contains_key(*tag)) && !object.contains_key(*field) {
+        // Unset optional sections and variants accept any field, checked on deserialization
+        return Err(format!("Unknown setting {}", path.join(".")));
+    }
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+    object.insert(field.to_string(), value);
+    Ok(())
+}
+
+fn positive(name: &str, value: f64) -> Result<(), String> {
+    if value > 0.0 && value.is_finite() {
+        Ok(())
+    } else {
+        Err(format!("{} must be positive, got {}", name, value))
+    }
+}
+
+fn non_negative(name: &str, value: f64) -> Result<(), String> {
+    if value >= 0.0 && value.is_finite() {
+        Ok(())
+    } else {
+        Err(format!("{} must be non-negative, got {}", name, value))
+    }
+}
+
+fn probability(name: &str, value: f64) -> Result<(), String> {
+    if value > 0.0 && value < 1.0 {
+        Ok(())
+    } else {
+        Err(format!("{} must be in (0, 1), got {}", name, value))
+    }
+}
+
+fn at_least(name: &str, value: usize, minimum: usize) -> Result<(), String> {
+    if value >= minimum {
+        Ok(())
+    } else {
+        Err(format!("{} must be at least {}, got {}", name, minimum, value))
+    }
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paillier::generate_keypair;
+
+    // Test that a TOML file sets the client configuration and the server strategy
+    #[test]
+    fn test_settings_from_toml() {
+        let settings = Settings::from_toml(
+            r#"
+            [client]
+            server_url = "https://10.0.0.1:9000"
+            batch_size = 128
+            epsilon = 1.5
+            privacy_budget = 8.0
+            authentication = "ed25519"
+            tls = { ca_path = "ca.pem", cert_path = "client.pem", key_path = "client.key" }
+
+            [server]
+            port = 9000
+            require_auth = true
+            registration_secret = "secret"
+            aggregation_goal = 5
+            secure_aggregation_threshold = 3
+            aggregator = { strategy = "fed_avg_m", server_learning_rate = 1.0, momentum = 0.9 }
+
+            max_staleness = 1
+            selection = { strategy = "power_of_choice", candidate_set_size = 10 }
+
+            [server.round]
+            max_participants = 8
+            training_timeout_secs = 30.0
+            "#,
+        )
+        .unwrap();
+        settings.validate().unwrap();
+
+        let config = settings.client.config();
+        assert_eq!(config.batch_size, 128);
+        assert_eq!(config.epsilon, 1.5);
+        assert_eq!(config.privacy_budget, 8.0);
+        assert_eq!(config.learning_rate, Config::default().learning_rate);
+        assert!(settings.client.authentication.unwrap().signed());
+        assert_eq!(settings.client.tls.as_ref().unwrap().ca_path.as_deref(), Some("ca.pem"));
+        assert_eq!(settings.server.bind_address(), ("0.0.0.0".to_string(), 9000));
+
+        let app_state = settings.server.app_state().unwrap();
+        assert_eq!(app_state.aggregator.lock().unwrap().name(), "FedAvgM");
+        assert_eq!(app_state.secure_aggregation.lock().unwrap().threshold, 3);
+        let rounds = app_state.rounds.lock().unwrap();
+        assert_eq!((rounds.config().min_participants, rounds.config().max_participants), (5, 8));
+        assert_eq!(rounds.config().training_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(app_state.max_staleness, 1);
+        assert!(app_state.require_auth);
+    }
+
+    // Test that YAML files are read as well, with central DP or asynchronous aggregation on the server
+    #[test]
+    fn test_settings_from_yaml() {
+        let settings = Settings::from_yaml(
+            "server:\n  aggregation_goal: 10\n  privacy_budget: 4.0\n  central_dp:\n    clip_norm: 0.5\n    noise_multiplier: 1.1\n    sampling_rate: 0.1\n",
+        )
+        .unwrap();
+        settings.validate().unwrap();
+        assert_eq!(settings.client, ClientSettings::default());
+
+        let app_state = settings.server.app_state().unwrap();
+        assert_eq!(app_state.aggregator.lock().unwrap().name(), "DP-FedAvg");
+        assert_eq!(app_state.privacy_accountant.lock().unwrap().epsilon_budget, 4.0);
+        assert_eq!(app_state.round_mechanism.unwrap().sampling_rate, 0.1);
+
+        let settings = Settings::from_yaml(
+            "server:\n  aggregation_goal: 10\n  max_staleness: 4\n  asynchronous:\n    strategy: fed_buff\n    buffer_size: 5\n    server_learning_rate: 1.0\n",
+        )
+        .unwrap();
+        settings.validate().unwrap();
+        let app_state = settings.server.app_state().unwrap();
+        let asynchronous = app_state.asynchronous.lock().unwrap();
+        assert_eq!(asynchronous.as_ref().unwrap().strategy.buffer_size(), 5);
+        assert_eq!(asynchronous.as_ref().unwrap().max_staleness, 4);
+    }
+
+    // Test that distributed DP and Paillier aggregation are enabled from the settings
+    #[test]
+    fn test_settings_distributed_dp_and_paillier() {
+        let (public_key, _) = generate_keypair(512).unwrap();
+        let settings = Settings::from_toml(&format!(
+            r#"
+            [server]
+            aggregation_goal = 3
+            privacy_budget = 8.0
+            require_auth = true
+            registration_secret = "secret"
+            distributed_dp = {{ clip_norm = 0.5, noise_multiplier = 1.2 }}
+            paillier = {{ public_key = "{}", key_holders = 2 }}
+            "#,
+            public_key.n
+        ))
+        .unwrap();
+        settings.validate().unwrap();
+
+        let app_state = settings.server.app_state().unwrap();
+        let mechanism = app_state.distributed_dp.unwrap();
+        assert_eq!((mechanism.clip_norm, mechanism.noise_multiplier, mechanism.num_clients), (0.5, 1.2, 3));
+        assert_eq!(mechanism.dimension, app_state.num_parameters().unwrap());
+        assert_eq!(app_state.privacy_accountant.lock().unwrap().epsilon_budget, 8.0);
+        let paillier = app_state.paillier.lock().unwrap();
+        assert_eq!(paillier.as_ref().unwrap().public_key, public_key);
+        assert_eq!(paillier.as_ref().unwrap().key_holders, 2);
+    }
+
+    // Test that environment variables override the file and that typos are rejected
+    #[test]
+    fn test_settings_env_overrides() {
+        let mut settings = Settings::default();
+        let vars = [
+            ("RUSTFL_CLIENT_EPSILON", "2.5"),
+            ("RUSTFL_CLIENT_SERVER_URL", "http://server:8081"),
+            ("RUSTFL_SERVER_AGGREGATION_GOAL", "4"),
+            ("RUSTFL_SERVER_AGGREGATOR__BETA", "0.25"),
+            ("RUSTFL_SERVER_AGGREGATOR__STRATEGY", "trimmed_mean"),
+            ("RUSTFL_SERVER_CENTRAL_DP__CLIP_NORM", "2.0"),
+            ("RUSTFL_SERVER_STALENESS__EXPONENT", "0.5"),
+            ("RUSTFL_SERVER_STALENESS__FUNCTION", "polynomial"),
+            ("RUSTFL_SERVER_CHECKPOINT__DIR", "/var/lib/rustfl"),
+            ("RUSTFL_SERVER_MODEL_HISTORY", "10"),
+            ("RUSTFL_SERVER_ADMIN_TOKEN", "admin-secret"),
+            ("HOME", "/root"),
+        ];
+        settings
+            .apply_overrides(vars.iter().map(|(name, value)| (name.to_string(), value.to_string())))
+            .unwrap();
+
+        assert_eq!(settings.client.epsilon, 2.5);
+        assert_eq!(settings.client.server_url, "http://server:8081");
+        assert_eq!(settings.server.aggregation_goal, 4);
+        assert_eq!(settings.server.aggregator, AggregatorSettings::TrimmedMean { beta: 0.25 });
+        assert_eq!(settings.server.central_dp.as_ref().unwrap().clip_norm, 2.0);
+        assert_eq!(settings.server.central_dp.as_ref().unwrap().noise_multiplier, 1.0);
+        assert_eq!(settings.server.staleness, StalenessFunction::Polynomial { exponent: 0.5 });
+        let checkpoint = settings.server.checkpoint.as_ref().unwrap();
+        assert_eq!((checkpoint.dir.as_str(), checkpoint.keep), ("/var/lib/rustfl", CheckpointConfig::default().keep));
+        assert_eq!(settings.server.model_history, 10);
+        assert_eq!(settings.server.admin_token.as_deref(), Some("admin-secret"));
+
+        let typo = [("RUSTFL_CLIENT_EPSILOM".to_string(), "2.5".to_string())];
+        assert!(settings.apply_overrides(typo).is_err());
+        let typo = [("RUSTFL_SERVER_AGGREGATOR__BETTA".to_string(), "0.1".to_string())];
+        assert!(settings.apply_overrides(typo).is_err());
+    }
+
+    // Test that out of range values are rejected
+    #[test]
+    fn test_settings_validation() {
+        let mut settings = Settings::default();
+        settings.client.epsilon = 0.0;
+        assert!(settings.validate().unwrap_err().contains("client.epsilon"));
+
+        let mut settings = Settings::default();
+        settings.client.dp_sgd = true;
+        assert!(settings.validate().unwrap_err().contains("client.privacy_budget"));
+        settings.client.privacy_budget = Some(f64::INFINITY);
+        assert!(settings.validate().is_err());
+        settings.client.privacy_budget = Some(8.0);
+        assert!(settings.validate().is_ok());
+
+        let mut settings = Settings::default();
+        settings.server.aggregation_goal = 3;
+        settings.server.secure_aggregation_threshold = Some(4);
+        assert!(settings.validate().unwrap_err().contains("secure_aggregation_threshold"));
+
+        let mut settings = Settings::default();
+        settings.server.aggregation_goal = 5;
+        settings.server.aggregator = AggregatorSettings::Bulyan { num_byzantine: 1 };
+        assert!(settings.validate().is_err());
+        settings.server.aggregation_goal = 7;
+        assert!(settings.validate().is_ok());
+
+        settings.server.selection = Some(SelectionSettings::Uniform { fraction: 0.0 });
+        assert!(settings.validate().unwrap_err().contains("server.selection.fraction"));
+
+        let mut settings = Settings::default();
+        settings.server.checkpoint = Some(CheckpointConfig { interval_secs: 0, ..Default::default() });
+        assert!(settings.validate().unwrap_err().contains("server.checkpoint"));
+
+        let mut settings = Settings::default();
+        settings.server.asynchronous = Some(AsyncStrategy::FedAsync { mixing_rate: 0.5 });
+        settings.server.central_dp = Some(CentralDpSettings::default());
+        assert!(settings.validate().unwrap_err().contains("server.central_dp"));
+
+        let mut settings = Settings::default();
+        settings.server.require_auth = true;
+        assert!(settings.validate().unwrap_err().contains("server.registration_secret"));
+
+        let mut settings = Settings::default();
+        settings.server.model_history = 0;
+        assert!(settings.validate().unwrap_err().contains("server.model_history"));
+
+        let mut settings = Settings::default();
+        settings.server.distributed_dp = Some(DistributedDpSettings { clip_norm: 0.0, ..Default::default() });
+        assert!(settings.validate().unwrap_err().contains("server.distributed_dp.clip_norm"));
+        settings.server.distributed_dp = Some(DistributedDpSettings::default());
+        settings.server.central_dp = Some(CentralDpSettings::default());
+        assert!(settings.validate().unwrap_err().contains("server.central_dp"));
+
+        let mut settings = Settings::default();
+        settings.server.require_auth = true;
+        settings.server.registration_secret = Some("secret".to_string());
+        settings.server.paillier = Some(PaillierSettings { public_key: "not hex".to_string(), key_holders: 2 });
+        assert!(settings.validate().unwrap_err().contains("server.paillier.public_key"));
+        settings.server.paillier = Some(PaillierSettings { public_key: "ff".repeat(64), key_holders: 0 });
+        assert!(settings.validate().unwrap_err().contains("server.paillier.key_holders"));
+        settings.server.paillier = Some(PaillierSettings { public_key: "ff".repeat(64), key_holders: 2 });
+        assert!(settings.validate().is_ok());
+        settings.server.require_auth = false;
+        assert!(settings.validate().unwrap_err().contains("server.require_auth"));
+
+        let mut settings = Settings::default();
+        settings.client.tls = Some(ClientTlsConfig::default());
+        assert!(settings.validate().unwrap_err().contains("client.tls"));
+    }
+}
@@ -0,0 +1,251 @@
+//State dict transport shared by the client and the server
+
+use serde::{Deserialize, Serialize};
+use tch::{nn, Device, Kind, Tensor};
+
+/// A single named parameter of a model in a form that can be sent over HTTP.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NamedTensor {
+    /// Name of the variable in the `VarStore`, e.g. `fc1.weight`.
+    pub name: String,
+    pub shape: Vec<i64>,
+    /// Element kind of the tensor, e.g. `Float`.
+    pub dtype: String,
+    /// Flattened values of the tensor in row-major order.
+    pub data: Vec<f64>,
+}
+
+/// All the named parameters of a model, sorted by name.
+pub type StateDict = Vec<NamedTensor>;
+
+impl NamedTensor {
+    /// Reads a tensor into a transport-friendly named tensor
+    pub fn from_tensor(name: &str, tensor: &Tensor) -> Result<NamedTensor, String> {
+        let flat = tensor
+            .to_device(Device::Cpu)
+            .to_kind(Kind::Double)
+            .flatten(0, -1);
+        let data = Vec::<f64>::try_from(&flat).map_err(|e| format!("Failed to read '{}': {}", name, e))?;
+
+        Ok(NamedTensor {
+            name: name.to_string(),
+            shape: tensor.size(),
+            dtype: format!("{:?}", tensor.kind()),
+            data,
+        })
+    }
+
+    /// Builds a tensor with the stored shape and kind on the given device
+    pub fn to_tensor(&self, device: Device) -> Result<Tensor, String> {
+        let numel: i64 = self.shape.iter().product();
+        if numel as usize != self.data.len() {
+            return Err(format!(
+                "Parameter '{}' has {} values but shape {:?} needs {}",
+                self.name,
+                self.data.len(),
+                self.shape,
+                numel
+            ));
+        }
+        let kind = parse_kind(&self.dtype)?;
+
+        Ok(Tensor::from_slice(&self.data)
+            .view(self.shape.as_slice())
+            .to_kind(kind)
+            .to_device(device))
+    }
+}
+
+/// Parses the dtype string written by `NamedTensor::from_tensor`
+pub fn parse_kind(dtype: &str) -> Result<Kind, String> {
+    match dtype {
+        "Uint8" => Ok(Kind::Uint8),
+        "Int8" => Ok(Kind::Int8),
+        "Int16" => Ok(Kind::Int16),
+        "Int" => Ok(Kind::Int),
+        "Int64" => Ok(Kind::Int64),
+        "Half" => Ok(Kind::Half),
+        "Float" => Ok(Kind::Float),
+        "Double" => Ok(Kind::Double),
+        "Bool" => Ok(Kind::Bool),
+        "BFloat16" => Ok(Kind::BFloat16),
+        _ => Err(format!("Unsupported dtype '{}'", dtype)),
+    }
+}
+
+/// Encodes a named tensor into the payload string carried by `WeightsUpdate::model_weights`
+pub fn encode_named_tensor(named_tensor: &NamedTensor) -> Result<String, String> {
+    serde_json::to_string(named_tensor).map_err(|e| format!("Failed to encode '{}': {}", named_tensor.name, e))
+}
+
+/// Decodes the payload strings of a `WeightsUpdate` into a state dict sorted by name
+pub fn decode_state_dict(payloads: &[String]) -> Result<StateDict, String> {
+    let mut state_dict = payloads
+        .iter()
+        .map(|payload| serde_json::from_str::<NamedTensor>(payload).map_err(|e| format!("Invalid weights payload: {}", e)))
+        .collect::<Result<StateDict, String>>()?;
+    state_dict.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(state_dict)
+}
+
+/// Checks that two state dicts sorted by name hold the same parameters with the same shapes
+pub fn check_same_layout(expected: &[NamedTensor], actual: &[NamedTensor]) -> Result<(), String> {
+    if expected.len() != actual.len() {
+        return Err(format!("Expected {} parameters but got {}", expected.len(), actual.len()));
+    }
+    for (e, a) in expected.iter().zip(actual.iter()) {
+        if e.name != a.name || e.shape != a.shape || e.data.len() != a.data.len() {
+            return Err(format!(
+                "Parameter mismatch: expected '{}' {:?}, got '{}' {:?}",
+                e.name, e.shape, a.name, a.shape
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Serializes every named variable of the var store
+pub fn state_dict_from_var_store(vs: &nn::VarStore) -> Result<StateDict, String> {
+    let mut state_dict = vs
+        .variables()
+        .iter()
+        .map(|(name, tensor)| NamedTensor::from_tensor(name, tensor))
+        .collect::<Result<StateDict, String>>()?;
+    state_dict.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(state_dict)
+}
+
+/// Checks that a state dict has exactly the names and shapes of the var store variables
+pub fn validate_state_dict(vs: &nn::VarStore, state_dict: &[NamedTensor]) -> Result<(), String> {
+    let variables = vs.variables();
+
+    if state_dict.len() != variables.len() {
+        return Err(format!(
+            "State dict has {} parameters but the model expects {}",
+            state_dict.len(),
+            variables.len()
+        ));
+    }
+    for named_tensor in state_dict {
+        let variable = variables
+            .get(&named_tensor.name)
+            .ok_or(format!("Unexpected parameter '{}' in state dict", named_tensor.name))?;
+        if variable.size() != named_tensor.shape {
+            return Err(format!(
+                "Shape mismatch for '{}': model has {:?}, state dict has {:?}",
+                named_tensor.name,
+                variable.size(),
+                named_tensor.shape
+            ));
+        }
+        let numel: i64 = named_tensor.shape.iter().product();
+        if numel as usize != named_tensor.data.len() {
+            return Err(format!(
+                "Parameter '{}' has {} values but shape {:?} needs {}",
+                named_tensor.name,
+                named_tensor.data.len(),
+                named_tensor.shape,
+                numel
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads a state dict into the var store after checking that names and shapes match
+pub fn load_state_dict(vs: &mut nn::VarStore, state_dict: &[NamedTensor]) -> Result<(), String> {
+    // Validate everything first so that a bad state dict never leaves the model half-loaded.
+    validate_state_dict(vs, state_dict)?;
+
+    let mut variables = vs.variables();
+    let device = vs.device();
+    for named_tensor in state_dict {
+        let source = named_tensor.to_tensor(device)?;
+        let variable = variables.get_mut(&named_tensor.name).unwrap();
+        tch::no_grad(|| variable.f_copy_(&source.to_kind(variable.kind())))
+            .map_err(|e| format!("Failed to load '{}': {}", named_tensor.name, e))?;
+    }
+
+    Ok(())
+}
+
+/// State dict of a single tensor holding `values`, the fixture of the aggregation tests
+#[cfg(test)]
+pub fn state_dict(values: Vec<f64>) -> StateDict {
+    vec![NamedTensor {
+        name: "fc.weight".to_string(),
+        shape: vec![values.len() as i64],
+        dtype: "Float".to_string(),
+        data: values,
+    }]
+}
+
+/// Plain update of `state_dict` trained on `model_version`, the fixture of the server tests
+#[cfg(test)]
+pub fn weights_update(state_dict: &StateDict, model_version: usize) -> crate::server::WeightsUpdate {
+    crate::server::WeightsUpdate {
+        model_weights: state_dict.iter().map(|t| encode_named_tensor(t).unwrap()).collect(),
+        num_samples: 100,
+        loss: 0.25,
+        model_version,
+        encryption: None,
+        client_id: None,
+        round_id: None,
+        share_threshold: None,
+    }
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_var_store() -> nn::VarStore {
+        let vs = nn::VarStore::new(Device::Cpu);
+        let _layer = nn::linear(&vs.root() / "fc", 3, 2, Default::default());
+        vs
+    }
+
+    // Test that a state dict round-trips between two var stores
+    #[test]
+    fn test_load_state_dict() {
+        let source = build_var_store();
+        let mut target = build_var_store();
+
+        let state_dict = state_dict_from_var_store(&source).unwrap();
+        assert_eq!(state_dict.len(), 2);
+        assert_eq!(state_dict[0].name, "fc.bias");
+        assert_eq!(state_dict[1].shape, vec![2, 3]);
+
+        load_state_dict(&mut target, &state_dict).unwrap();
+        assert_eq!(state_dict_from_var_store(&target).unwrap(), state_dict);
+    }
+
+    // Test that encoded payloads decode back into the same state dict
+    #[test]
+    fn test_encode_decode_state_dict() {
+        let state_dict = state_dict_from_var_store(&build_var_store()).unwrap();
+        let payloads = state_dict.iter().rev().map(|t| encode_named_tensor(t).unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(decode_state_dict(&payloads).unwrap(), state_dict);
+        assert!(decode_state_dict(&["weight1".to_string()]).is_err());
+    }
+
+    // Test that shape and name mismatches are rejected
+    #[test]
+    fn test_load_state_dict_mismatch() {
+        let mut target = build_var_store();
+        let mut state_dict = state_dict_from_var_store(&target).unwrap();
+
+        state_dict[1].shape = vec![3, 2];
+        assert!(load_state_dict(&mut target, &state_dict).unwrap_err().contains("Shape mismatch"));
+
+        state_dict[1].shape = vec![2, 3];
+        state_dict[1].name = "fc.unknown".to_string();
+        assert!(load_state_dict(&mut target, &state_dict).unwrap_err().contains("Unexpected parameter"));
+    }
+}
@@ -0,0 +1,194 @@
+//TLS between clients and servers
+//
+//The server presents a PEM certificate chain and, with a client CA configured, only accepts clients whose
+//certificate chains to that CA (mutual TLS). Clients trust the public roots and an optional private CA, and
+//present their own certificate to servers requiring one. Certificates and keys are read from PEM files.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use serde::{Deserialize, Serialize};
+
+/// Certificate of the server and the CA of its clients when they must present a certificate
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTlsConfig {
+    /// PEM certificate chain of the server, its own certificate first.
+    pub cert_path: String,
+    /// PEM private key of the server certificate.
+    pub key_path: String,
+    /// PEM certificates of the CA client certificates must chain to, clients are not asked for one when absent.
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+}
+
+impl ServerTlsConfig {
+    /// Whether clients must present a certificate
+    pub fn mutual(&self) -> bool {
+        self.client_ca_path.is_some()
+    }
+
+    /// rustls configuration of the server
+    pub fn server_config(&self) -> Result<ServerConfig, String> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = match &self.client_ca_path {
+            Some(client_ca_path) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(root_store(client_ca_path)?), provider)
+                    .build()
+                    .map_err(|e| format!("Invalid client CA {}: {}", client_ca_path, e))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        builder
+            .with_single_cert(read_certs(&self.cert_path)?, read_key(&self.key_path)?)
+            .map_err(|e| format!("Invalid server certificate {}: {}", self.cert_path, e))
+    }
+}
+
+/// Private CA of the server and certificate of the client for servers requiring mutual TLS
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientTlsConfig {
+    /// PEM certificates of a CA trusted in addition to the public roots.
+    pub ca_path: Option<String>,
+    /// PEM certificate chain presented to the server, with `key_path`.
+    pub cert_path: Option<String>,
+    /// PEM private key of the client certificate.
+    pub key_path: Option<String>,
+}
+
+impl ClientTlsConfig {
+    /// Checks that the client certificate comes with its key
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cert_path.is_some() != self.key_path.is_some() {
+            return Err("tls.cert_path and tls.key_path must be set together".to_string());
+        }
+        Ok(())
+    }
+
+    /// HTTP client speaking TLS with this configuration
+    pub fn http_client(&self) -> Result<reqwest::Client, String> {
+        self.validate()?;
+        let mut builder = reqwest::Client::builder().use_rustls_tls();
+        if let Some(ca_path) = &self.ca_path {
+            let pem = std::fs::read(ca_path).map_err(|e| format!("Cannot read {}: {}", ca_path, e))?;
+            for certificate in reqwest::Certificate::from_pem_bundle(&pem).map_err(|e| format!("Invalid CA {}: {}", ca_path, e))? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if let (Some(cert_path), Some(key_path)) = (&self.cert_path, &self.key_path) {
+            let mut pem = std::fs::read(key_path).map_err(|e| format!("Cannot read {}: {}", key_path, e))?;
+            pem.extend(std::fs::read(cert_path).map_err(|e| format!("Cannot read {}: {}", cert_path, e))?);
+            let identity = reqwest::Identity::from_pem(&pem).map_err(|e| format!("Invalid client certificate {}: {}", cert_path, e))?;
+            builder = builder.identity(identity);
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificates in {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {}", path));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("Invalid private key in {}: {}", path, e))?
+        .ok_or(format!("No private key in {}", path))
+}
+
+fn root_store(path: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for certificate in read_certs(path)? {
+        roots.add(certificate).map_err(|e| format!("Invalid CA certificate in {}: {}", path, e))?;
+    }
+    Ok(roots)
+}
+
+//Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use actix_web::{web, App, HttpServer};
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+
+    // Writes a CA, a server certificate for localhost and a client certificate signed by the CA
+    fn generate_pki(dir: &PathBuf) {
+        std::fs::create_dir_all(dir).unwrap();
+        let write = |name: &str, pem: String| std::fs::write(dir.join(name), pem).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "RustFL test CA");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        write("ca.pem", ca.pem());
+
+        for (name, usage) in [("server", ExtendedKeyUsagePurpose::ServerAuth), ("client", ExtendedKeyUsagePurpose::ClientAuth)] {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+            params.extended_key_usages = vec![usage];
+            let certificate = params.signed_by(&key, &ca, &ca_key).unwrap();
+            write(&format!("{}.pem", name), certificate.pem());
+            write(&format!("{}.key", name), key.serialize_pem());
+        }
+    }
+
+    // Test that a mutual TLS server only answers clients presenting a certificate of its CA
+    #[actix_web::test]
+    async fn test_mutual_tls() {
+        let dir = std::env::temp_dir().join(format!("rustfl-tls-{}", std::process::id()));
+        generate_pki(&dir);
+        let path = |name: &str| Some(dir.join(name).to_string_lossy().to_string());
+
+        let server_tls = ServerTlsConfig {
+            cert_path: path("server.pem").unwrap(),
+            key_path: path("server.key").unwrap(),
+            client_ca_path: path("ca.pem"),
+        };
+        let server = HttpServer::new(|| App::new().route("/", web::get().to(|| async { "ok" })))
+            .bind_rustls_0_23(("127.0.0.1", 0), server_tls.server_config().unwrap())
+            .unwrap();
+        let url = format!("https://localhost:{}/", server.addrs()[0].port());
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let client_tls = ClientTlsConfig { ca_path: path("ca.pem"), cert_path: path("client.pem"), key_path: path("client.key") };
+        let response = client_tls.http_client().unwrap().get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        let anonymous = ClientTlsConfig { ca_path: path("ca.pem"), ..Default::default() };
+        assert!(anonymous.http_client().unwrap().get(&url).send().await.is_err());
+        let untrusting = ClientTlsConfig { ca_path: None, ..client_tls };
+        assert!(untrusting.http_client().unwrap().get(&url).send().await.is_err());
+
+        handle.stop(false).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Test that incomplete configurations are rejected
+    #[test]
+    fn test_tls_config_validation() {
+        let client_tls = ClientTlsConfig { cert_path: Some("client.pem".to_string()), ..Default::default() };
+        assert!(client_tls.validate().is_err());
+        let server_tls = ServerTlsConfig { cert_path: "missing.pem".to_string(), key_path: "missing.key".to_string(), client_ca_path: None };
+        assert!(server_tls.server_config().unwrap_err().contains("missing.pem"));
+    }
+}